@@ -0,0 +1,199 @@
+//! Allowing and denying clients by their IP address, `User-Agent` or
+//! `Referer`.
+//!
+//! Access lists of CIDR ranges can be configured for the whole server and
+//! per mount. Admins can also ban addresses while the server is running,
+//! through `/admin/ban`, optionally for a limited time.
+//!
+//! Listeners of a mount can also be filtered by their `User-Agent`, e.g.
+//! to block stream rippers while normal players connect, and by their
+//! `Referer`, so that other sites cannot embed the stream.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use ipnet::IpNet;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AccessList {
+    /// If not empty, only clients in these ranges are allowed, e.g.
+    /// `["10.0.0.0/8", "2001:db8::/32"]`
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Clients in these ranges are rejected, even if they are in an
+    /// `allow` range
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
+impl AccessList {
+    /// Whether a client at `ip` is allowed
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// An address that was banned by an admin
+#[derive(Debug, Clone, Serialize)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    /// When the ban is lifted, if ever
+    pub expires: Option<String>,
+}
+
+/// The addresses that were banned by admins
+#[derive(Debug, Default)]
+pub struct Bans {
+    bans: Mutex<HashMap<IpAddr, Option<SystemTime>>>,
+}
+
+impl Bans {
+    /// Ban `ip`, for `duration` or until it is unbanned
+    pub fn ban(&self, ip: IpAddr, duration: Option<Duration>) {
+        let expires = duration.map(|duration| SystemTime::now() + duration);
+        self.bans.lock().unwrap().insert(ip.to_canonical(), expires);
+    }
+
+    /// Lift the ban of `ip`, returning whether it was banned
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        self.bans
+            .lock()
+            .unwrap()
+            .remove(&ip.to_canonical())
+            .is_some()
+    }
+
+    /// Whether `ip` is banned
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut bans = self.bans.lock().unwrap();
+        match bans.get(&ip) {
+            Some(Some(expires)) if *expires <= SystemTime::now() => {
+                bans.remove(&ip);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// The addresses that are currently banned
+    pub fn list(&self) -> Vec<BanInfo> {
+        let now = SystemTime::now();
+        let mut bans = self.bans.lock().unwrap();
+        bans.retain(|_, expires| expires.map(|e| e > now).unwrap_or(true));
+
+        let mut list: Vec<_> = bans
+            .iter()
+            .map(|(ip, expires)| BanInfo {
+                ip: *ip,
+                expires: expires.map(|e| humantime::format_rfc3339_seconds(e).to_string()),
+            })
+            .collect();
+        list.sort_by_key(|ban| ban.ip);
+        list
+    }
+}
+
+/// Regular expressions that the `User-Agent` of listeners is matched
+/// against
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UserAgentRules {
+    /// If not empty, only listeners with a `User-Agent` that matches one
+    /// of these are allowed
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Listeners with a `User-Agent` that matches one of these are
+    /// rejected, e.g. `["(?i)streamripper", "^Wget/"]`
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// The compiled [`UserAgentRules`] of a mount
+#[derive(Debug)]
+pub struct UserAgentFilter {
+    allow: Vec<Regex>,
+    deny: Vec<Regex>,
+}
+
+impl UserAgentFilter {
+    /// Compile `rules`. Invalid expressions are logged and left out.
+    pub fn new(rules: &UserAgentRules) -> Self {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .filter_map(|pattern| {
+                    Regex::new(pattern)
+                        .map_err(|e| error!("Invalid User-Agent pattern {:?}: {}", pattern, e))
+                        .ok()
+                })
+                .collect()
+        };
+
+        Self {
+            allow: compile(&rules.allow),
+            deny: compile(&rules.deny),
+        }
+    }
+
+    /// Whether a listener with `user_agent` is allowed. Listeners that do
+    /// not send a `User-Agent` are matched as if they sent an empty one.
+    pub fn allows(&self, user_agent: Option<&str>) -> bool {
+        let user_agent = user_agent.unwrap_or_default();
+        !self.deny.iter().any(|regex| regex.is_match(user_agent))
+            && (self.allow.is_empty() || self.allow.iter().any(|regex| regex.is_match(user_agent)))
+    }
+}
+
+/// The sites that may embed a mount
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RefererRules {
+    /// The domains that listeners must be referred from, e.g.
+    /// `["example.com"]`. Subdomains of these domains are allowed too.
+    pub domains: Vec<String>,
+    /// Also allow listeners that send no `Referer`, e.g. desktop players
+    #[serde(default)]
+    pub allow_missing: bool,
+}
+
+impl RefererRules {
+    /// Whether a listener with `referer` is allowed
+    pub fn allows(&self, referer: Option<&str>) -> bool {
+        let referer = match referer {
+            Some(referer) => referer,
+            None => return self.allow_missing,
+        };
+
+        let host = referer
+            .split_once("://")
+            .map(|(_, rest)| rest)
+            .unwrap_or(referer);
+        let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+        let host = host.rsplit('@').next().unwrap_or_default();
+        let host = match host.rsplit_once(':') {
+            Some((host, port)) if port.bytes().all(|b| b.is_ascii_digit()) => host,
+            _ => host,
+        };
+        let host = host.to_ascii_lowercase();
+
+        self.domains.iter().any(|domain| {
+            let domain = domain.trim_start_matches("*.").to_ascii_lowercase();
+            host == domain
+                || host
+                    .strip_suffix(&domain)
+                    .map(|sub| sub.ends_with('.'))
+                    .unwrap_or(false)
+        })
+    }
+}
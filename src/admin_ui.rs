@@ -0,0 +1,33 @@
+//! The web admin UI, which is served from the binary under `/admin/ui/`.
+//!
+//! The UI is a single page that uses the admin API with the credentials
+//! that the user logs in with, so its files are served to anyone.
+
+/// The files of the UI: their name, content type and contents
+const FILES: [(&str, &str, &str); 3] = [
+    (
+        "index.html",
+        "text/html; charset=utf-8",
+        include_str!("admin_ui/index.html"),
+    ),
+    (
+        "admin.js",
+        "text/javascript; charset=utf-8",
+        include_str!("admin_ui/admin.js"),
+    ),
+    (
+        "admin.css",
+        "text/css; charset=utf-8",
+        include_str!("admin_ui/admin.css"),
+    ),
+];
+
+/// The content type and contents of the file called `name`, where an
+/// empty name refers to the page itself
+pub fn file(name: &str) -> Option<(&'static str, &'static str)> {
+    let name = if name.is_empty() { "index.html" } else { name };
+    FILES
+        .iter()
+        .find(|(file_name, _, _)| *file_name == name)
+        .map(|(_, content_type, contents)| (*content_type, *contents))
+}
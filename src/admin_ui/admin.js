@@ -0,0 +1,264 @@
+// A small admin UI on top of the admin API. The credentials are kept in
+// the session storage of the browser and sent with every request.
+
+const login = document.querySelector("#login")
+const admin = document.querySelector("#admin")
+const mounts = document.querySelector("#mounts")
+const message = document.querySelector("#message")
+const template = document.querySelector("#mount_template")
+
+const REFRESH_MS = 2000
+
+function authorization() {
+    return sessionStorage.getItem("authorization")
+}
+
+function show_message(text) {
+    message.textContent = text
+}
+
+async function api(path, options = {}) {
+    const headers = { ...options.headers, "Authorization": authorization() }
+    const response = await fetch(path, { ...options, headers })
+    if (response.status == 401) {
+        sessionStorage.removeItem("authorization")
+        show_login()
+        throw new Error("Not authorized")
+    }
+    if (!response.ok) {
+        throw new Error(path + ": " + response.status + " " + (await response.text()))
+    }
+    return response
+}
+
+function query(params) {
+    return new URLSearchParams(params).toString()
+}
+
+function format_duration(secs) {
+    const hours = Math.floor(secs / 3600)
+    const minutes = Math.floor(secs / 60) % 60
+    return hours + "h " + minutes + "m"
+}
+
+function format_bytes(bytes) {
+    const units = ["B", "KiB", "MiB", "GiB", "TiB"]
+    let unit = 0
+    while (bytes >= 1024 && unit < units.length - 1) {
+        bytes /= 1024
+        unit++
+    }
+    return bytes.toFixed(unit == 0 ? 0 : 1) + " " + units[unit]
+}
+
+function show_login() {
+    admin.hidden = true
+    login.hidden = false
+}
+
+login.addEventListener("submit", async (event) => {
+    event.preventDefault()
+    const user = login.elements.user.value || "admin"
+    const password = login.elements.password.value
+    sessionStorage.setItem("authorization", "Basic " + btoa(user + ":" + password))
+    login.elements.password.value = ""
+    await refresh()
+})
+
+document.querySelector("#logout").addEventListener("click", () => {
+    sessionStorage.removeItem("authorization")
+    mounts.replaceChildren()
+    show_login()
+})
+
+document.querySelector("#reload").addEventListener("click", async () => {
+    try {
+        const response = await api("/admin/reloadconfig", { method: "POST" })
+        const summary = await response.json()
+        const changes = Object.entries(summary)
+            .filter(([_, names]) => names.length > 0)
+            .map(([kind, names]) => kind.replaceAll("_", " ") + ": " + names.join(", "))
+        show_message(changes.length > 0 ? "Reloaded. " + changes.join("; ") : "Reloaded, nothing changed")
+    } catch (e) {
+        show_message(e.message)
+    }
+})
+
+function mount_element(name) {
+    for (const element of mounts.children) {
+        if (element.dataset.name == name) {
+            return element
+        }
+    }
+
+    const element = template.content.firstElementChild.cloneNode(true)
+    element.dataset.name = name
+    element.querySelector(".name").textContent = name
+
+    element.querySelector(".kill_source").addEventListener("click", async () => {
+        if (!confirm("Disconnect the source of " + name + "?")) {
+            return
+        }
+        try {
+            await api("/admin/killsource?" + query({ mount: name }))
+            await refresh()
+        } catch (e) {
+            show_message(e.message)
+        }
+    })
+
+    element.querySelector(".toggle_listeners").addEventListener("click", async () => {
+        const table = element.querySelector(".listeners")
+        table.hidden = !table.hidden
+        await update_listeners(name, element)
+    })
+
+    element.querySelector(".metadata").addEventListener("submit", async (event) => {
+        event.preventDefault()
+        const form = event.target
+        try {
+            await api("/admin/metadata?" + query({ mount: name, mode: "updinfo", song: form.elements.song.value }))
+            form.elements.song.value = ""
+            await refresh()
+        } catch (e) {
+            show_message(e.message)
+        }
+    })
+
+    const access = element.querySelector(".access")
+    access.addEventListener("submit", async (event) => {
+        event.preventDefault()
+        const max_listeners = access.elements.max_listeners.value
+        const update = {
+            source_auth: access.elements.source_auth.value || null,
+            sub_auth: access.elements.sub_auth.value || null,
+            max_listeners: max_listeners === "" ? null : Number(max_listeners),
+        }
+        try {
+            await api("/admin/mount_config?" + query({ mount: name }), {
+                method: "POST",
+                headers: { "Content-Type": "application/json" },
+                body: JSON.stringify(update),
+            })
+            show_message("Saved the settings of " + name)
+        } catch (e) {
+            show_message(e.message)
+        }
+    })
+    load_access(name, access)
+
+    mounts.appendChild(element)
+    return element
+}
+
+async function load_access(name, form) {
+    try {
+        const access = await (await api("/admin/mount_config?" + query({ mount: name }))).json()
+        form.elements.source_auth.value = access.source_auth ?? ""
+        form.elements.sub_auth.value = access.sub_auth ?? ""
+        form.elements.max_listeners.value = access.max_listeners ?? ""
+    } catch (e) {
+        show_message(e.message)
+    }
+}
+
+async function update_listeners(name, element) {
+    const table = element.querySelector(".listeners")
+    if (table.hidden) {
+        return
+    }
+
+    const sessions = await (await api("/admin/listclients?" + query({ mount: name, format: "json" }))).json()
+    const rows = sessions.map((session) => {
+        const row = document.createElement("tr")
+        for (const value of [
+            session.id,
+            session.remote_ip + (session.country ? " (" + session.country + ")" : ""),
+            session.user_agent ?? "",
+            format_duration(session.connected_secs),
+            format_bytes(session.bytes_sent),
+        ]) {
+            const cell = document.createElement("td")
+            cell.textContent = value
+            row.appendChild(cell)
+        }
+
+        const kick = document.createElement("button")
+        kick.textContent = "Kick"
+        kick.addEventListener("click", async () => {
+            try {
+                await api("/admin/killclient?" + query({ mount: name, id: session.id }))
+                await update_listeners(name, element)
+            } catch (e) {
+                show_message(e.message)
+            }
+        })
+        const cell = document.createElement("td")
+        cell.appendChild(kick)
+        row.appendChild(cell)
+        return row
+    })
+    table.querySelector("tbody").replaceChildren(...rows)
+}
+
+async function refresh() {
+    if (!authorization()) {
+        show_login()
+        return
+    }
+
+    let infos
+    try {
+        infos = await (await api("/admin/mounts")).json()
+    } catch (e) {
+        show_message(e.message)
+        return
+    }
+    login.hidden = true
+    admin.hidden = false
+
+    infos.sort((a, b) => a.name.localeCompare(b.name))
+    const names = infos.map((info) => info.name)
+    for (const element of [...mounts.children]) {
+        if (!names.includes(element.dataset.name)) {
+            element.remove()
+        }
+    }
+
+    let listeners = 0
+    for (const info of infos) {
+        listeners += info.subscribers
+        const element = mount_element(info.name)
+
+        const on_air = element.querySelector(".on_air")
+        on_air.textContent = info.on_air ? "On air" : "Off air"
+        on_air.classList.toggle("live", info.on_air)
+        element.querySelector(".kill_source").disabled = !info.on_air
+
+        const stats = [
+            info.subscribers + (info.max_listeners != null ? " of " + info.max_listeners : "") + " listeners",
+            "peak " + info.peak_subscribers,
+            format_bytes(info.bytes_out) + " sent",
+        ]
+        if (info.source_connected_secs != null) {
+            stats.push("source connected for " + format_duration(info.source_connected_secs))
+        }
+        if (info.source_user) {
+            stats.push("by " + info.source_user)
+        }
+        element.querySelector(".stats").textContent = stats.join(" · ")
+        element.querySelector(".song").textContent = info.song ? "Now playing: " + info.song : ""
+
+        try {
+            await update_listeners(info.name, element)
+        } catch (e) {
+            show_message(e.message)
+        }
+    }
+
+    document.querySelector("#summary").textContent =
+        infos.length + " mounts, " + listeners + " listeners"
+}
+
+refresh()
+setInterval(refresh, REFRESH_MS)
@@ -1,7 +1,15 @@
-use serde::{Deserialize, Serialize};
-use serde_with::with_prefix;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::state::{IceMeta, Mount};
+use serde::{Deserialize, Serialize, Serializer};
+use serde_with::{skip_serializing_none, with_prefix};
+
+use crate::{
+    fingerprint::Fingerprint,
+    history::Sample,
+    pool::PoolMetrics,
+    sessions::SessionInfo,
+    state::{IceMeta, Mount, State, Stats},
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MountInfo {
@@ -10,11 +18,37 @@ pub struct MountInfo {
     stream_url: String,
     bytes_out: usize,
     bytes_in: usize,
+    /// The largest amount of subscribers that were connected at once
+    peak_subscribers: usize,
+    /// The amount of times a subscriber connected
+    subscriber_connections: usize,
+    /// The amount of times a source connected
+    source_connects: usize,
+    /// How long the current source has been connected, in seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_connected_secs: Option<u64>,
+    /// The user name with which the current source authenticated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source_user: Option<String>,
+    /// How long the server has been running, in seconds
+    uptime_secs: u64,
     on_air: bool,
     requires_source_auth: bool,
     requires_sub_auth: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
+    max_listeners: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     song: Option<String>,
+    /// The integrated loudness of the current source, in LUFS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loudness: Option<f64>,
+    loudness_exceeds_target: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<Fingerprint>,
+    /// Features that the mount is configured to use, but that were not
+    /// compiled in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    missing_features: Vec<String>,
     #[serde(flatten, with = "ice_prefix")]
     metadata: IceMeta,
 }
@@ -30,11 +64,281 @@ impl MountInfo {
             stream_url,
             bytes_in: stats.bytes_in,
             bytes_out: stats.bytes_out,
+            peak_subscribers: stats.peak_listeners,
+            subscriber_connections: stats.listener_connections,
+            source_connects: stats.source_connects,
+            source_connected_secs: mount.is_connected().then_some(stats.source_connected_secs),
+            source_user: mount.source_user(),
+            uptime_secs: stats.uptime_secs,
             metadata: mount.metadata(),
             on_air: mount.is_connected(),
-            song: mount.song().clone(),
-            requires_source_auth: mount.source_auth().is_some(),
-            requires_sub_auth: mount.sub_auth().is_some(),
+            song: mount.song(),
+            requires_source_auth: mount.requires_source_auth(),
+            requires_sub_auth: mount.requires_sub_auth(),
+            max_listeners: mount.max_listeners(),
+            loudness: mount.loudness(),
+            loudness_exceeds_target: mount.exceeds_loudness_target(),
+            fingerprint: mount.fingerprint(),
+            missing_features: mount
+                .missing_features()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        }
+    }
+}
+
+/// A point-in-time snapshot of a single mount
+#[derive(Debug, Clone, Serialize)]
+pub struct MountMetrics {
+    pub name: String,
+    pub content_type: String,
+    pub on_air: bool,
+    pub song: Option<String>,
+    /// The integrated loudness of the current source, in LUFS
+    pub loudness: Option<f64>,
+    pub loudness_exceeds_target: bool,
+    pub stats: Stats,
+}
+
+/// A point-in-time snapshot of the state of the server, intended
+/// for embedders that wish to display it without going through the
+/// HTTP API.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerMetrics {
+    /// All mounts, sorted by name
+    pub mounts: Vec<MountMetrics>,
+    /// The amount of sources that are currently connected
+    pub sources: usize,
+    /// The amount of subscribers that are currently connected
+    pub listeners: usize,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub buffer_pool: PoolMetrics,
+    /// The connected listeners, ordered by the time they connected
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl ServerMetrics {
+    pub fn from_state(state: &State) -> Self {
+        let mut mounts: Vec<MountMetrics> = state
+            .mounts()
+            .into_iter()
+            .map(|(name, mount)| MountMetrics {
+                name,
+                content_type: mount.content_type(),
+                on_air: mount.is_connected(),
+                song: mount.song(),
+                loudness: mount.loudness(),
+                loudness_exceeds_target: mount.exceeds_loudness_target(),
+                stats: mount.stats(),
+            })
+            .collect();
+        mounts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let on_air = mounts.iter().filter(|m| m.on_air);
+
+        Self {
+            sources: on_air.clone().count(),
+            listeners: on_air.clone().map(|m| m.stats.sub_count).sum(),
+            bytes_in: mounts.iter().map(|m| m.stats.bytes_in).sum(),
+            bytes_out: mounts.iter().map(|m| m.stats.bytes_out).sum(),
+            buffer_pool: state.buffer_pool().metrics(),
+            sessions: state.sessions().list(),
+            mounts,
+        }
+    }
+}
+
+/// The status of the server in the schema of Icecast's
+/// `/status-json.xsl`, which many web players and widgets read
+#[derive(Debug, Clone, Serialize)]
+pub struct IcecastStatus {
+    icestats: IceStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct IceStats {
+    admin: String,
+    host: String,
+    location: String,
+    server_id: String,
+    server_start: String,
+    server_start_iso8601: String,
+    /// Like Icecast, a single source is serialized as an object rather
+    /// than as a list, and the field is left out without sources
+    #[serde(skip_serializing_if = "Vec::is_empty", serialize_with = "one_or_many")]
+    source: Vec<IceSource>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+struct IceSource {
+    audio_info: Option<String>,
+    bitrate: Option<u32>,
+    channels: Option<u32>,
+    genre: Option<String>,
+    listener_peak: usize,
+    listeners: usize,
+    listenurl: String,
+    samplerate: Option<u32>,
+    server_description: Option<String>,
+    server_name: Option<String>,
+    server_type: String,
+    server_url: Option<String>,
+    stream_start: Option<String>,
+    stream_start_iso8601: Option<String>,
+    title: Option<String>,
+    /// Always `null`, as in Icecast
+    #[serde(serialize_with = "null")]
+    dummy: (),
+}
+
+fn one_or_many<S: Serializer>(sources: &[IceSource], serializer: S) -> Result<S::Ok, S::Error> {
+    match sources {
+        [source] => source.serialize(serializer),
+        sources => sources.serialize(serializer),
+    }
+}
+
+fn null<S: Serializer>(_: &(), serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_none()
+}
+
+impl IcecastStatus {
+    /// The status of the connected, visible mounts in `state`, where
+    /// `host` is the name of the server and `listen_url` returns the URL
+    /// of a mount
+    pub fn from_state(
+        state: &State,
+        host: &str,
+        listen_url: impl Fn(&str, &Mount) -> String,
+    ) -> Self {
+        let (server_start, server_start_iso8601) = icecast_times(state.started());
+
+        let mut mounts = state.visible_mounts();
+        mounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let source = mounts
+            .into_iter()
+            .filter(|(_, mount)| mount.is_connected())
+            .map(|(name, mount)| {
+                let meta = mount.metadata();
+                let stats = mount.stats_handle();
+                let (stream_start, stream_start_iso8601) =
+                    match mount.source_connected_at().map(icecast_times) {
+                        Some((start, start_iso8601)) => (Some(start), Some(start_iso8601)),
+                        None => (None, None),
+                    };
+
+                IceSource {
+                    audio_info: meta.audio_info().map(String::from),
+                    bitrate: mount.bitrate(),
+                    channels: meta.channels(),
+                    genre: meta.genre().map(String::from),
+                    listener_peak: stats.subscribers().peak(),
+                    listeners: stats.subscribers().count(),
+                    listenurl: listen_url(&name, &mount),
+                    samplerate: meta.samplerate(),
+                    server_description: meta.description().map(String::from),
+                    server_name: meta.name().map(String::from),
+                    server_type: mount.content_type(),
+                    server_url: meta.url().map(String::from),
+                    stream_start,
+                    stream_start_iso8601,
+                    title: mount.song(),
+                    dummy: (),
+                }
+            })
+            .collect();
+
+        Self {
+            icestats: IceStats {
+                admin: "icemaster@localhost".to_string(),
+                host: host.to_string(),
+                location: "Earth".to_string(),
+                server_id: format!("Peroxidecast {}", env!("CARGO_PKG_VERSION")),
+                server_start,
+                server_start_iso8601,
+                source,
+            },
         }
     }
 }
+
+/// The current song of a mount, served under `/currentsong`
+#[derive(Debug, Clone, Serialize)]
+pub struct CurrentSong {
+    pub mount: String,
+    pub on_air: bool,
+    pub song: Option<String>,
+    /// A URL about the song, e.g. of the album art
+    pub url: Option<String>,
+    /// The time at which the song started, in RFC 3339 format
+    pub started_at: Option<String>,
+}
+
+/// The statistics history of a mount, served under `/api/history`
+#[derive(Debug, Clone, Serialize)]
+pub struct MountHistory {
+    pub mount: String,
+    /// The start of the requested range, in RFC 3339 format
+    pub from: String,
+    /// The end of the requested range, in RFC 3339 format
+    pub to: String,
+    /// Whether the samples come from the history database, or from the
+    /// in-memory history (which does not survive restarts)
+    pub persistent: bool,
+    pub samples: Vec<HistorySample>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HistorySample {
+    /// The time at which the sample was taken, in RFC 3339 format
+    pub time: String,
+    pub listeners: usize,
+    pub bytes_in: usize,
+    pub bytes_out: usize,
+    pub source_connects: usize,
+}
+
+impl From<&Sample> for HistorySample {
+    fn from(sample: &Sample) -> Self {
+        Self {
+            time: humantime::format_rfc3339_seconds(sample.time).to_string(),
+            listeners: sample.stats.sub_count,
+            bytes_in: sample.stats.bytes_in,
+            bytes_out: sample.stats.bytes_out,
+            source_connects: sample.stats.source_connects,
+        }
+    }
+}
+
+/// Format `time` like Icecast does: as an RFC 2822 date, and as an ISO
+/// 8601 date with a numeric offset
+fn icecast_times(time: SystemTime) -> (String, String) {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // e.g. 2024-02-11T12:00:00Z
+    let rfc3339 = humantime::format_rfc3339_seconds(time).to_string();
+    let iso8601 = format!("{}+0000", rfc3339.trim_end_matches('Z'));
+
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86400)
+        .unwrap_or(0);
+    let month = rfc3339[5..7].parse::<usize>().unwrap_or(1);
+    let rfc2822 = format!(
+        "{}, {} {} {} {} +0000",
+        WEEKDAYS[(days % 7) as usize],
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[0..4],
+        &rfc3339[11..19],
+    );
+
+    (rfc2822, iso8601)
+}
@@ -0,0 +1,525 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    hash::Hash,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString},
+    Argon2, PasswordVerifier,
+};
+use b64::{FromBase64, ToBase64};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+/// The maximum amount of remembered verified credentials
+const MAX_VERIFIED: usize = 1024;
+
+/// The maximum amount of remembered decisions of a [`DecisionCache`]
+const MAX_DECISIONS: usize = 10_000;
+
+/// Pairs of configured credentials and `Authorization` values that were
+/// verified, so that slow password hashes are only computed once
+static VERIFIED: LazyLock<Mutex<HashSet<(String, String)>>> = LazyLock::new(Mutex::default);
+
+/// A class of authentication mechanism, ordered from weakest to strongest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMechanism {
+    /// `Authorization: Basic <credentials>`
+    Basic,
+    /// `Authorization: Bearer <token>`, with an opaque token
+    Token,
+    /// `Authorization: Bearer <token>`, with a JSON Web Token
+    Jwt,
+}
+
+impl AuthMechanism {
+    /// Determine the mechanism used by the value of an `Authorization` header
+    pub fn of(authorization: &str) -> Option<Self> {
+        let (scheme, credentials) = authorization.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+
+        if scheme.eq_ignore_ascii_case("Basic") {
+            Some(Self::Basic)
+        } else if scheme.eq_ignore_ascii_case("Bearer") {
+            let is_jwt = credentials.split('.').count() == 3
+                && credentials.split('.').all(|part| {
+                    !part.is_empty()
+                        && part
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+                });
+
+            if is_jwt {
+                Some(Self::Jwt)
+            } else {
+                Some(Self::Token)
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// What a client that failed to authenticate tried to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Source,
+    Listener,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Source => write!(f, "source"),
+            Role::Listener => write!(f, "listener"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Log that the client at `ip` failed to authenticate as `role` for
+/// `target`, a mount or an API path.
+///
+/// Every failure is logged as a single line in the same format, so that
+/// tools like fail2ban can ban clients that brute force credentials:
+///
+/// ```text
+/// Authentication failed: ip=192.0.2.1 role=source target=/live user="source"
+/// ```
+///
+/// The user name is only logged for `Basic` credentials, and is `-` if
+/// there is none.
+pub fn log_failure(role: Role, ip: IpAddr, target: &str, authorization: Option<&str>) {
+    let user = authorization
+        .and_then(basic_credentials)
+        .map(|(user, _)| format!("{:?}", user))
+        .unwrap_or_else(|| "-".to_string());
+    warn!(
+        target: "peroxidecast::auth",
+        "Authentication failed: ip={} role={} target={} user={}",
+        ip.to_canonical(),
+        role,
+        target,
+        user
+    );
+}
+
+/// The allow/deny decisions of an authentication backend, remembered for a
+/// while so that a burst of reconnecting clients does not hit the backend
+/// for every connection
+#[derive(Debug)]
+pub struct DecisionCache<K> {
+    ttl: Duration,
+    decisions: Mutex<HashMap<K, (bool, Instant)>>,
+}
+
+impl<K: Eq + Hash> DecisionCache<K> {
+    /// Remember decisions for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            decisions: Mutex::default(),
+        }
+    }
+
+    /// The decision about `key`, if it was made recently enough
+    pub fn get(&self, key: &K) -> Option<bool> {
+        match self.decisions.lock().unwrap().get(key) {
+            Some((allowed, expires)) if Instant::now() < *expires => Some(*allowed),
+            _ => None,
+        }
+    }
+
+    /// Remember the decision about `key` for the TTL of the cache
+    pub fn insert(&self, key: K, allowed: bool) {
+        self.insert_for(key, allowed, self.ttl);
+    }
+
+    /// Remember the decision about `key` for the TTL of the cache, or for
+    /// `ttl` if that is shorter, e.g. because the credentials expire
+    pub fn insert_for(&self, key: K, allowed: bool, ttl: Duration) {
+        let now = Instant::now();
+        let mut decisions = self.decisions.lock().unwrap();
+        if decisions.len() >= MAX_DECISIONS {
+            decisions.retain(|_, (_, expires)| now < *expires);
+        }
+        if decisions.len() < MAX_DECISIONS {
+            decisions.insert(key, (allowed, now + ttl.min(self.ttl)));
+        }
+    }
+}
+
+/// What an admin API key may be used for, ordered from least to most
+/// privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    /// Read statistics, listener lists and the like, e.g. for public
+    /// dashboards, but change nothing and see no credentials
+    Read,
+    /// Everything that the admin credentials may be used for
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyConfig {
+    /// The key, which clients send as `Authorization: Bearer <key>`
+    pub key: String,
+    pub scope: ApiKeyScope,
+}
+
+/// The scope of the API key in the value of an `Authorization` header,
+/// if it carries one of `keys`
+pub fn api_key_scope(keys: &[ApiKeyConfig], authorization: &str) -> Option<ApiKeyScope> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Bearer") {
+        return None;
+    }
+
+    let token = token.trim();
+    keys.iter()
+        .filter(|key| secrets_equal(&key.key, token))
+        .map(|key| key.scope)
+        .max()
+}
+
+/// Whether the secrets `a` and `b` are equal. This takes the same time
+/// wherever they differ, so that a secret cannot be guessed byte by byte
+/// from how long its rejection takes.
+pub fn secrets_equal(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+/// Identify the client that sent the value of an `Authorization` header:
+/// the user name for `Basic` credentials, the subject (`sub`) of a JSON
+/// Web Token, or else a fingerprint of the token (`token:<hex>`).
+///
+/// The identifier is shown in the API and written to billing records, so
+/// it never contains the secret itself.
+pub fn credential_id(authorization: &str) -> Option<String> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    let credentials = credentials.trim();
+
+    if scheme.eq_ignore_ascii_case("Basic") {
+        let decoded = credentials.from_base64().ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        decoded.split(':').next().map(String::from)
+    } else if scheme.eq_ignore_ascii_case("Bearer") {
+        let subject = (AuthMechanism::of(authorization) == Some(AuthMechanism::Jwt))
+            .then(|| jwt_subject(credentials))
+            .flatten();
+        Some(subject.unwrap_or_else(|| token_fingerprint(credentials)))
+    } else {
+        None
+    }
+}
+
+/// The `sub` claim of the JSON Web Token `token`, without verifying it
+fn jwt_subject(token: &str) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Claims {
+        sub: Option<String>,
+    }
+
+    let payload = token.split('.').nth(1)?.from_base64().ok()?;
+    serde_json::from_slice::<Claims>(&payload).ok()?.sub
+}
+
+/// A short identifier of `token` from which the token cannot be recovered
+fn token_fingerprint(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    let hex: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("token:{}", hex)
+}
+
+/// The user name and password in the value of an `Authorization` header
+/// with `Basic` credentials
+pub fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+
+    let decoded = credentials.trim().from_base64().ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// The value of an `Authorization` header with the `Basic` credentials
+/// `user` and `password`
+pub fn basic_authorization(user: &str, password: &str) -> String {
+    let credentials = format!("{}:{}", user, password);
+    format!("Basic {}", credentials.as_bytes().to_base64(b64::STANDARD))
+}
+
+/// Whether the value of an `Authorization` header matches `expected`, a
+/// configured credential.
+///
+/// That is one of:
+/// * the exact value of the header, e.g. `Basic <base64>`
+/// * `<user>:<hash>`, with a bcrypt or Argon2 hash of the password of
+///   `<user>`
+/// * `<user>:<password>`
+/// * `<password>`, which is matched whatever the user name is, like
+///   Icecast does for encoders that send `source:<password>`
+///
+/// All but the first are matched by `Basic` credentials.
+pub fn verify(expected: &str, authorization: &str) -> bool {
+    if AuthMechanism::of(expected).is_some() {
+        return secrets_equal(expected, authorization);
+    }
+
+    let (user, hash) = match expected.split_once(':') {
+        Some((user, hash)) if is_password_hash(hash) => (user, hash),
+        Some((user, expected_password)) => {
+            return basic_credentials(authorization)
+                .map(|(given_user, password)| {
+                    given_user == user && secrets_equal(&password, expected_password)
+                })
+                .unwrap_or(false)
+        }
+        None => {
+            return basic_credentials(authorization)
+                .map(|(_, password)| secrets_equal(&password, expected))
+                .unwrap_or(false)
+        }
+    };
+
+    let key = (expected.to_string(), authorization.to_string());
+    if VERIFIED.lock().unwrap().contains(&key) {
+        return true;
+    }
+
+    let verified = match basic_credentials(authorization) {
+        Some((given_user, password)) => given_user == user && verify_password(&password, hash),
+        None => false,
+    };
+    if verified {
+        let mut cache = VERIFIED.lock().unwrap();
+        if cache.len() >= MAX_VERIFIED {
+            cache.clear();
+        }
+        cache.insert(key);
+    }
+    verified
+}
+
+/// Whether the value of an `Authorization` header carries the `Basic`
+/// credentials of one of `users`, which maps user names to passwords or
+/// password hashes
+pub fn verify_users(users: &BTreeMap<String, String>, authorization: &str) -> bool {
+    let (user, password) = match basic_credentials(authorization) {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    match users.get(&user) {
+        Some(hash) if is_password_hash(hash) => {
+            verify(&format!("{}:{}", user, hash), authorization)
+        }
+        Some(expected) => secrets_equal(expected, &password),
+        None => false,
+    }
+}
+
+/// An algorithm with which [`hash_password`] can hash passwords
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Argon2,
+    Bcrypt,
+}
+
+impl std::str::FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "argon2" | "argon2id" => Ok(Self::Argon2),
+            "bcrypt" => Ok(Self::Bcrypt),
+            _ => Err(format!(
+                "unknown algorithm {:?}, expected argon2 or bcrypt",
+                s
+            )),
+        }
+    }
+}
+
+/// Hash `password` with a random salt, such that [`verify_password`]
+/// accepts it
+pub fn hash_password(password: &str, algorithm: HashAlgorithm) -> Result<String, String> {
+    match algorithm {
+        HashAlgorithm::Argon2 => {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|e| e.to_string())
+        }
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(password, bcrypt::DEFAULT_COST).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Whether `hash` is a bcrypt or Argon2 password hash
+pub fn is_password_hash(hash: &str) -> bool {
+    hash.starts_with("$2") || hash.starts_with("$argon2")
+}
+
+/// Whether `password` matches `hash`, a bcrypt or Argon2 password hash
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_exact_header() {
+        let expected = basic_authorization("source", "hackme");
+        assert!(verify(&expected, &basic_authorization("source", "hackme")));
+        assert!(!verify(&expected, &basic_authorization("source", "wrong")));
+        assert!(verify("Bearer abc", "Bearer abc"));
+        assert!(!verify("Bearer abc", "Bearer abd"));
+    }
+
+    #[test]
+    fn verify_user_and_password() {
+        assert!(verify("dj:hackme", &basic_authorization("dj", "hackme")));
+        assert!(!verify("dj:hackme", &basic_authorization("dj", "wrong")));
+        assert!(!verify(
+            "dj:hackme",
+            &basic_authorization("other", "hackme")
+        ));
+        assert!(!verify("dj:hackme", "Bearer hackme"));
+    }
+
+    #[test]
+    fn verify_password_only() {
+        assert!(verify("hackme", &basic_authorization("source", "hackme")));
+        assert!(verify("hackme", &basic_authorization("anyone", "hackme")));
+        assert!(!verify("hackme", &basic_authorization("source", "wrong")));
+        assert!(!verify("hackme", "Bearer hackme"));
+        assert!(!verify("hackme", "hackme"));
+    }
+
+    #[test]
+    fn verify_user_and_hash() {
+        let bcrypt = format!("dj:{}", bcrypt::hash("hackme", 4).unwrap());
+        assert!(verify(&bcrypt, &basic_authorization("dj", "hackme")));
+        assert!(!verify(&bcrypt, &basic_authorization("dj", "wrong")));
+        assert!(!verify(&bcrypt, &basic_authorization("other", "hackme")));
+        assert!(!verify(&bcrypt, "Bearer hackme"));
+
+        let argon2 = format!(
+            "dj:{}",
+            hash_password("hackme", HashAlgorithm::Argon2).unwrap()
+        );
+        assert!(verify(&argon2, &basic_authorization("dj", "hackme")));
+        assert!(!verify(&argon2, &basic_authorization("dj", "wrong")));
+    }
+
+    #[test]
+    fn basic_credentials_require_basic_scheme() {
+        assert_eq!(
+            basic_credentials(&basic_authorization("dj", "a:b")),
+            Some(("dj".to_string(), "a:b".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer ZGo6aGFja21l"), None);
+        assert_eq!(basic_credentials("Basic not-base64!"), None);
+        assert_eq!(basic_credentials("Basic"), None);
+    }
+
+    #[test]
+    fn mechanism_of_authorization() {
+        assert_eq!(
+            AuthMechanism::of("Basic ZGo6aGFja21l"),
+            Some(AuthMechanism::Basic)
+        );
+        assert_eq!(
+            AuthMechanism::of("bearer opaque"),
+            Some(AuthMechanism::Token)
+        );
+        assert_eq!(AuthMechanism::of("Bearer a.b.c"), Some(AuthMechanism::Jwt));
+        assert_eq!(AuthMechanism::of("Bearer a..c"), Some(AuthMechanism::Token));
+        assert_eq!(AuthMechanism::of("Digest abc"), None);
+        assert_eq!(AuthMechanism::of("hackme"), None);
+    }
+
+    #[test]
+    fn credential_id_hides_secrets() {
+        assert_eq!(
+            credential_id(&basic_authorization("dj", "hackme")),
+            Some("dj".to_string())
+        );
+
+        // {"alg":"HS256"}.{"sub":"alice"}.<signature>
+        let jwt = "Bearer eyJhbGciOiJIUzI1NiJ9.eyJzdWIiOiJhbGljZSJ9.c2ln";
+        assert_eq!(credential_id(jwt), Some("alice".to_string()));
+
+        let opaque = credential_id("Bearer s3cr3t-api-key").unwrap();
+        assert!(opaque.starts_with("token:"));
+        assert!(!opaque.contains("s3cr3t"));
+        assert_eq!(credential_id("Bearer s3cr3t-api-key"), Some(opaque));
+
+        // {"alg":"HS256"}.{}.<signature>
+        let anonymous = credential_id("Bearer eyJhbGciOiJIUzI1NiJ9.e30.c2ln").unwrap();
+        assert!(anonymous.starts_with("token:"));
+        assert_eq!(credential_id("Digest abc"), None);
+    }
+
+    #[test]
+    fn secrets_are_compared_exactly() {
+        assert!(secrets_equal("hackme", "hackme"));
+        assert!(secrets_equal("", ""));
+        assert!(!secrets_equal("hackme", "hackmf"));
+        assert!(!secrets_equal("hackme", "hackm"));
+        assert!(!secrets_equal("hackme", "HACKME"));
+    }
+
+    #[test]
+    fn api_key_scopes() {
+        let keys = [
+            ApiKeyConfig {
+                key: "reader".to_string(),
+                scope: ApiKeyScope::Read,
+            },
+            ApiKeyConfig {
+                key: "writer".to_string(),
+                scope: ApiKeyScope::Admin,
+            },
+        ];
+        assert_eq!(
+            api_key_scope(&keys, "Bearer reader"),
+            Some(ApiKeyScope::Read)
+        );
+        assert_eq!(
+            api_key_scope(&keys, "bearer writer"),
+            Some(ApiKeyScope::Admin)
+        );
+        assert_eq!(api_key_scope(&keys, "Bearer readers"), None);
+        assert_eq!(api_key_scope(&keys, "Basic reader"), None);
+    }
+}
@@ -0,0 +1,161 @@
+//! Estimation of the throughput that listeners can sustain, used to hint
+//! them towards a rendition of a stream that they can keep up with. This
+//! gives plain ICY clients a poor man's adaptive bitrate: a client (or the
+//! page embedding it) can reconnect to the suggested mount.
+
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::state::{Mount, State};
+
+/// The interval over which writes to a listener are aggregated
+const WINDOW: Duration = Duration::from_secs(5);
+/// The weight of the most recent window in an estimate
+const SMOOTHING: f64 = 0.3;
+/// How long the estimate for a client is remembered after it disconnects
+const ESTIMATE_TTL: Duration = Duration::from_secs(10 * 60);
+/// Only suggest renditions that a listener can sustain with this much
+/// throughput to spare
+const HEADROOM: f64 = 1.25;
+
+/// Estimates the throughput of a single listener from how long writing
+/// to its socket takes.
+///
+/// Writes complete immediately while the socket's send buffer has room,
+/// and block once the client cannot keep up. The time per byte is
+/// smoothed rather than the throughput, so that a window in which the
+/// client fell behind weighs much heavier than one in which it did not.
+///
+/// A client is only noticed to fall behind once the send buffer is full,
+/// so a smaller `send_buffer_size` makes estimates converge faster.
+#[derive(Debug)]
+pub struct Estimator {
+    window_start: Instant,
+    window_bytes: u64,
+    window_busy: Duration,
+    secs_per_byte: Option<f64>,
+}
+
+impl Default for Estimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Estimator {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            window_bytes: 0,
+            window_busy: Duration::ZERO,
+            secs_per_byte: None,
+        }
+    }
+
+    /// Record that writing `bytes` took `busy`
+    pub fn record(&mut self, bytes: usize, busy: Duration) {
+        self.window_bytes += bytes as u64;
+        self.window_busy += busy;
+
+        if self.window_start.elapsed() < WINDOW {
+            return;
+        }
+
+        if self.window_bytes > 0 {
+            let sample = self.window_busy.as_secs_f64() / self.window_bytes as f64;
+            self.secs_per_byte = Some(match self.secs_per_byte {
+                Some(current) => current + SMOOTHING * (sample - current),
+                None => sample,
+            });
+        }
+
+        self.window_start = Instant::now();
+        self.window_bytes = 0;
+        self.window_busy = Duration::ZERO;
+    }
+
+    /// The estimated sustainable throughput, in bytes per second
+    pub fn estimate(&self) -> Option<u64> {
+        self.secs_per_byte.map(|secs| {
+            if secs > 0.0 {
+                (1.0 / secs).min(u64::MAX as f64) as u64
+            } else {
+                u64::MAX
+            }
+        })
+    }
+}
+
+/// The last throughput estimates of clients that disconnected, so that
+/// they can be given a hint when they reconnect
+#[derive(Debug, Default)]
+pub struct BandwidthEstimates {
+    estimates: DashMap<IpAddr, (u64, Instant)>,
+}
+
+impl BandwidthEstimates {
+    pub fn record(&self, ip: IpAddr, bytes_per_sec: u64) {
+        self.estimates
+            .retain(|_, (_, time)| time.elapsed() < ESTIMATE_TTL);
+        self.estimates.insert(ip, (bytes_per_sec, Instant::now()));
+    }
+
+    /// Drop the estimates of the IP addresses that match `filter`,
+    /// returning the amount of estimates that were dropped
+    pub fn purge(&self, filter: impl Fn(&IpAddr) -> bool) -> usize {
+        let before = self.estimates.len();
+        self.estimates.retain(|ip, _| !filter(ip));
+        before - self.estimates.len()
+    }
+
+    /// The last estimate for `ip`, in bytes per second
+    pub fn get(&self, ip: &IpAddr) -> Option<u64> {
+        self.estimates
+            .get(ip)
+            .filter(|e| e.1.elapsed() < ESTIMATE_TTL)
+            .map(|e| e.0)
+    }
+}
+
+/// The rendition of `mount_name` that a listener that can sustain
+/// `bytes_per_sec` should listen to: the one with the highest bitrate it
+/// can keep up with, or the one with the lowest bitrate if it cannot keep
+/// up with any of them.
+///
+/// Returns `None` if `mount_name` itself is the best choice, or if the
+/// bitrates of the renditions are not known.
+pub fn suggest_rendition(
+    state: &State,
+    mount_name: &str,
+    mount: &Mount,
+    bytes_per_sec: u64,
+) -> Option<String> {
+    if mount.renditions().is_empty() {
+        return None;
+    }
+
+    let mut candidates: Vec<(&str, u64)> = std::iter::once((mount_name, mount.bitrate()))
+        .chain(mount.renditions().iter().map(|name| {
+            let bitrate = state.find_mount(name).and_then(|m| m.bitrate());
+            (name.as_str(), bitrate)
+        }))
+        .filter_map(|(name, kbps)| Some((name, kbps? as u64 * 1000 / 8)))
+        .collect();
+    candidates.sort_by_key(|(_, rate)| *rate);
+
+    let (suggested, _) = candidates
+        .iter()
+        .rev()
+        .find(|(_, rate)| *rate as f64 * HEADROOM <= bytes_per_sec as f64)
+        .or_else(|| candidates.first())?;
+
+    if *suggested == mount_name {
+        None
+    } else {
+        Some(suggested.to_string())
+    }
+}
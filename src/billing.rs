@@ -0,0 +1,246 @@
+//! Periodic per-mount usage records, e.g. for billing the customers of a
+//! hosting provider per mount.
+//!
+//! Every interval, one record per mount is written as a line of JSON
+//! (newline-delimited JSON) to a file and/or sent in a `POST` request
+//! to a URL. The schema of the records is described by [`UsageRecord`],
+//! and is only changed in a compatible way unless [`SCHEMA_VERSION`]
+//! is increased.
+//!
+//! Usage is sampled every second, so listeners that stay connected for
+//! less than a second may not be counted.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, warn};
+
+use crate::{net, state::State};
+
+/// The version of the schema of [`UsageRecord`]
+pub const SCHEMA_VERSION: u32 = 1;
+
+const DEFAULT_INTERVAL_SECS: u64 = 300;
+
+/// The interval at which usage is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BillingConfig {
+    /// The interval at which usage records are emitted, in seconds.
+    /// Defaults to 5 minutes.
+    pub interval_secs: Option<u64>,
+    /// Append the records to this file
+    pub file: Option<PathBuf>,
+    /// Send the records of each interval to this `http://` URL, in one
+    /// `POST` request with content type `application/x-ndjson`
+    pub url: Option<String>,
+}
+
+impl BillingConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            interval_secs: Some(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            ..self
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1))
+    }
+}
+
+/// The usage of a mount during one interval
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageRecord {
+    /// Always [`SCHEMA_VERSION`]
+    pub schema: u32,
+    pub mount: String,
+    /// The start of the interval, in RFC 3339 format
+    pub start: String,
+    /// The end of the interval, in RFC 3339 format
+    pub end: String,
+    /// The amount of bytes sent to listeners
+    pub bytes_out: u64,
+    /// The sum of the time that each listener was connected, in seconds
+    pub listener_seconds: u64,
+    /// `listener_seconds`, in hours
+    pub listener_hours: f64,
+    /// The largest amount of listeners that was connected at once
+    pub peak_listeners: usize,
+    /// The usage of the listeners that authenticated, by who they
+    /// authenticated as (see [`auth::credential_id`](crate::auth::credential_id))
+    pub credentials: BTreeMap<String, CredentialUsage>,
+}
+
+/// The usage of the listeners of a mount that authenticated with the
+/// same credential
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CredentialUsage {
+    pub bytes_out: u64,
+    pub listener_seconds: u64,
+    pub listener_hours: f64,
+}
+
+#[derive(Debug, Default)]
+struct MountUsage {
+    bytes_out: u64,
+    listener_seconds: u64,
+    peak_listeners: usize,
+    credentials: BTreeMap<String, CredentialUsage>,
+}
+
+/// Start emitting usage records for all mounts in `state`
+pub fn spawn(config: BillingConfig, state: Arc<State>) {
+    tokio::spawn(run(config, state));
+}
+
+async fn run(config: BillingConfig, state: Arc<State>) {
+    let mut sample = tokio::time::interval(SAMPLE_INTERVAL);
+    let mut emit = tokio::time::interval(config.interval());
+    sample.tick().await;
+    emit.tick().await;
+
+    let mut start = SystemTime::now();
+    let mut usage: HashMap<String, MountUsage> = HashMap::new();
+    // The amount of bytes sent in total, by session ID
+    let mut session_bytes: HashMap<u64, u64> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            _ = sample.tick() => {
+                let sessions = state.sessions().list();
+                let mut listeners: HashMap<&str, usize> = HashMap::new();
+                let mut seen = HashMap::with_capacity(sessions.len());
+
+                for session in &sessions {
+                    let previous = session_bytes.get(&session.id).copied().unwrap_or(0);
+                    let bytes = session.bytes_sent.saturating_sub(previous);
+                    seen.insert(session.id, session.bytes_sent);
+
+                    let mount = usage.entry(session.mount.clone()).or_default();
+                    mount.bytes_out += bytes;
+                    mount.listener_seconds += SAMPLE_INTERVAL.as_secs();
+                    *listeners.entry(&session.mount).or_default() += 1;
+
+                    if let Some(credential) = &session.credential {
+                        let credential = mount.credentials.entry(credential.clone()).or_default();
+                        credential.bytes_out += bytes;
+                        credential.listener_seconds += SAMPLE_INTERVAL.as_secs();
+                    }
+                }
+                session_bytes = seen;
+
+                for (mount, listeners) in listeners {
+                    let mount = usage.get_mut(mount).expect("Usage was recorded above");
+                    mount.peak_listeners = mount.peak_listeners.max(listeners);
+                }
+            }
+            _ = emit.tick() => {
+                let end = SystemTime::now();
+                let records = records(&state, start, end, std::mem::take(&mut usage));
+                start = end;
+                write(&config, &records).await;
+            }
+        }
+    }
+}
+
+/// Turn the usage of the mounts during an interval into records, one for
+/// every mount
+fn records(
+    state: &State,
+    start: SystemTime,
+    end: SystemTime,
+    mut usage: HashMap<String, MountUsage>,
+) -> Vec<UsageRecord> {
+    let start = humantime::format_rfc3339_seconds(start).to_string();
+    let end = humantime::format_rfc3339_seconds(end).to_string();
+    let hours = |seconds: u64| seconds as f64 / 3600.0;
+
+    let mut mounts: Vec<String> = state.mounts().into_iter().map(|(name, _)| name).collect();
+    mounts.extend(usage.keys().cloned());
+    mounts.sort();
+    mounts.dedup();
+
+    mounts
+        .into_iter()
+        .map(|mount| {
+            let usage = usage.remove(&mount).unwrap_or_default();
+            let credentials = usage
+                .credentials
+                .into_iter()
+                .map(|(credential, usage)| {
+                    let listener_hours = hours(usage.listener_seconds);
+                    (
+                        credential,
+                        CredentialUsage {
+                            listener_hours,
+                            ..usage
+                        },
+                    )
+                })
+                .collect();
+
+            UsageRecord {
+                schema: SCHEMA_VERSION,
+                mount,
+                start: start.clone(),
+                end: end.clone(),
+                bytes_out: usage.bytes_out,
+                listener_seconds: usage.listener_seconds,
+                listener_hours: hours(usage.listener_seconds),
+                peak_listeners: usage.peak_listeners,
+                credentials,
+            }
+        })
+        .collect()
+}
+
+async fn write(config: &BillingConfig, records: &[UsageRecord]) {
+    if records.is_empty() {
+        return;
+    }
+
+    let mut ndjson = Vec::new();
+    for record in records {
+        serde_json::to_writer(&mut ndjson, record).expect("Records can always be serialized");
+        ndjson.push(b'\n');
+    }
+
+    if let Some(path) = &config.file {
+        let file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await;
+        let result = match file {
+            Ok(mut file) => file.write_all(&ndjson).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Could not write usage records to {}: {}", path.display(), e);
+        }
+    }
+
+    if let Some(url) = &config.url {
+        match net::post(url, "application/x-ndjson", &ndjson).await {
+            Ok(response) if response.is_success() => {}
+            Ok(response) => warn!(
+                "Sending usage records to {} failed with status {}",
+                url, response.status
+            ),
+            Err(e) => warn!("Could not send usage records to {}: {}", url, e),
+        }
+    }
+
+    debug!("Emitted {} usage records", records.len());
+}
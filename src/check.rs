@@ -0,0 +1,331 @@
+//! Validation of the configuration without starting the server, for
+//! `--check-config`.
+//!
+//! Besides parse errors, this reports settings that the server would
+//! ignore or only complain about once it is running: unknown keys,
+//! mounts that conflict with each other and files that do not exist.
+
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+use regex::Regex;
+
+use crate::{
+    cli::CliArgs,
+    config::{Config, MountConfig},
+    dependencies::DependencyGraph,
+    geoip::GeoIp,
+    jwt::JwtVerifier,
+    templates::Templates,
+    url_auth::UrlAuthConfig,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The server would fail to start, or a setting would not work
+    Error,
+    /// The configuration works, but probably not as intended
+    Warning,
+}
+
+/// A problem with the configuration
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// The outcome of checking the configuration
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    /// Whether the configuration has no errors. Warnings are allowed.
+    pub fn passed(&self) -> bool {
+        self.findings
+            .iter()
+            .all(|finding| finding.severity != Severity::Error)
+    }
+
+    fn error(&mut self, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity: Severity::Error,
+            message: message.into(),
+        });
+    }
+
+    fn warning(&mut self, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity: Severity::Warning,
+            message: message.into(),
+        });
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for finding in &self.findings {
+            match finding.severity {
+                Severity::Error => writeln!(f, "ERROR {}", finding.message)?,
+                Severity::Warning => writeln!(f, "WARN  {}", finding.message)?,
+            }
+        }
+
+        if self.passed() {
+            write!(f, "Configuration is valid")
+        } else {
+            write!(f, "Configuration is invalid")
+        }
+    }
+}
+
+/// Check the configuration file and CLI options in `args`
+pub fn run(args: &CliArgs) -> Report {
+    let mut report = Report::default();
+
+    if let Some(path) = args.config_file() {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => check_keys(&contents, &mut report),
+            Err(e) => report.error(format!("Cannot read {}: {}", path.display(), e)),
+        }
+        if !report.passed() {
+            return report;
+        }
+    }
+
+    match args.load_config() {
+        Ok(config) => check_config(&config, &mut report),
+        Err(e) => report.error(e),
+    }
+    report
+}
+
+/// Report parse errors and the keys that are not used by any setting
+fn check_keys(contents: &str, report: &mut Report) {
+    let mut unknown = Vec::new();
+    let deserializer = &mut toml::Deserializer::new(contents);
+    let parsed: Result<Config, _> =
+        serde_ignored::deserialize(deserializer, |path| unknown.push(path.to_string()));
+
+    match parsed {
+        Ok(_) => {
+            unknown.extend(unknown_mount_keys(contents));
+            for key in unknown {
+                report.warning(format!("Unknown key `{}` is ignored", key));
+            }
+        }
+        Err(e) => report.error(format!("Cannot parse the configuration file: {}", e)),
+    }
+}
+
+/// The unknown keys of mounts, which are not reported by
+/// `serde_ignored` because mounts have a flattened field
+fn unknown_mount_keys(contents: &str) -> Vec<String> {
+    let known = match serde_json::to_value(MountConfig::default()) {
+        Ok(serde_json::Value::Object(known)) => known,
+        _ => return Vec::new(),
+    };
+    let Ok(toml::Value::Table(config)) = toml::from_str(contents) else {
+        return Vec::new();
+    };
+
+    let mut mounts: Vec<(String, &toml::value::Table)> = Vec::new();
+    if let Some(toml::Value::Table(defaults)) = config.get("mount_defaults") {
+        mounts.push(("mount_defaults".to_string(), defaults));
+    }
+    if let Some(toml::Value::Table(configured)) = config.get("mounts") {
+        for (name, mount) in configured {
+            if let toml::Value::Table(mount) = mount {
+                mounts.push((format!("mounts.{}", name), mount));
+            }
+        }
+    }
+
+    let mut unknown = Vec::new();
+    for (path, mount) in mounts {
+        for key in mount.keys() {
+            // The keys of the flattened `StreamUrl`
+            let stream_url = key == "url_type" || key == "url_value";
+            if !stream_url && !known.contains_key(key) {
+                unknown.push(format!("{}.{}", path, key));
+            }
+        }
+    }
+    unknown
+}
+
+/// Whether `path` exists, reporting it as missing if it doesn't
+fn check_exists(report: &mut Report, setting: &str, path: &Path) -> bool {
+    let exists = path.exists();
+    if !exists {
+        report.error(format!("{}: {} does not exist", setting, path.display()));
+    }
+    exists
+}
+
+fn check_config(config: &Config, report: &mut Report) {
+    if config.admin_credentials().is_none()
+        && config.admin_htpasswd.is_none()
+        && config.api_keys.is_empty()
+    {
+        report.warning("No admin credentials are configured, so the admin API cannot be used");
+    }
+
+    if let Some(dir) = &config.static_source_dir {
+        if check_exists(report, "static_source_dir", dir) && !dir.is_dir() {
+            report.error(format!(
+                "static_source_dir: {} is not a directory",
+                dir.display()
+            ));
+        }
+    }
+    if let Some(path) = &config.admin_htpasswd {
+        check_exists(report, "admin_htpasswd", path);
+    }
+    if let Some(path) = &config.geoip_db {
+        if check_exists(report, "geoip_db", path) {
+            if let Err(e) = GeoIp::open(path) {
+                report.error(format!("geoip_db: {}", e));
+            }
+        }
+    }
+    if let Some(dir) = config.playlist_log.as_deref().and_then(Path::parent) {
+        if !dir.as_os_str().is_empty() {
+            check_exists(report, "playlist_log", dir);
+        }
+    }
+    if let Some(dir) = config
+        .history_db
+        .as_ref()
+        .and_then(|history_db| history_db.path.parent())
+    {
+        if !dir.as_os_str().is_empty() {
+            check_exists(report, "history_db.path", dir);
+        }
+    }
+    if let Some(jwt) = &config.jwt {
+        if let Err(e) = JwtVerifier::new(jwt.clone()) {
+            report.error(format!("jwt: {}", e));
+        }
+    }
+    if let Some(base_url) = &config.base_url {
+        if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+            report.error(format!(
+                "base_url {:?} does not start with `http://` or `https://`",
+                base_url
+            ));
+        }
+    }
+    for webhook in &config.webhooks {
+        if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+            report.error(format!(
+                "webhooks: url {:?} does not start with `http://` or `https://`",
+                webhook.url
+            ));
+        }
+    }
+    if let Some(templates) = &config.templates {
+        if let Err(e) = Templates::load(templates) {
+            report.error(format!("templates: {}", e));
+        }
+    }
+
+    check_mounts(config, report);
+}
+
+fn check_mounts(config: &Config, report: &mut Report) {
+    let mut normalized: BTreeMap<String, &str> = BTreeMap::new();
+    for (name, mount) in &config.mounts {
+        if !name.starts_with('/') {
+            report.error(format!(
+                "Mount {:?} does not start with `/`, so it cannot be requested",
+                name
+            ));
+        }
+
+        let key = name.trim_end_matches('/').to_lowercase();
+        if let Some(other) = normalized.insert(key, name) {
+            report.warning(format!(
+                "Mounts {} and {} only differ in case or a trailing `/`",
+                other, name
+            ));
+        }
+
+        for (setting, path) in [
+            ("source_htpasswd", &mount.source_htpasswd),
+            ("sub_htpasswd", &mount.sub_htpasswd),
+            ("on_connect", &mount.on_connect),
+            ("on_disconnect", &mount.on_disconnect),
+        ] {
+            if let Some(path) = path {
+                check_exists(report, &format!("mounts.{:?}.{}", name, setting), path);
+            }
+        }
+
+        for rendition in &mount.renditions {
+            if !config.mounts.contains_key(rendition) {
+                report.error(format!(
+                    "Mount {} lists rendition {}, which is not configured",
+                    name, rendition
+                ));
+            }
+        }
+
+        if let Some(user_agents) = &mount.user_agents {
+            for pattern in user_agents.allow.iter().chain(&user_agents.deny) {
+                if let Err(e) = Regex::new(pattern) {
+                    report.error(format!(
+                        "Mount {} has an invalid User-Agent pattern {:?}: {}",
+                        name, pattern, e
+                    ));
+                }
+            }
+        }
+
+        if mount.require_tls && config.trusted_proxies.is_empty() {
+            report.warning(format!(
+                "Mount {} requires TLS, but without trusted_proxies no listener counts as using TLS",
+                name
+            ));
+        }
+
+        if let Some(e) = mount.auth_url.as_ref().and_then(UrlAuthConfig::url_error) {
+            report.error(format!("Mount {} auth_url: {}", name, e));
+        }
+
+        if mount.countries.is_some() && config.geoip_db.is_none() {
+            report.warning(format!(
+                "Mount {} restricts countries, but without a geoip_db the country of listeners is unknown",
+                name
+            ));
+        }
+    }
+
+    if let Some(e) = config
+        .mount_defaults
+        .as_ref()
+        .and_then(|defaults| defaults.auth_url.as_ref())
+        .and_then(UrlAuthConfig::url_error)
+    {
+        report.error(format!("mount_defaults auth_url: {}", e));
+    }
+
+    if let Some(default_mount) = &config.default_mount {
+        if !default_mount.mount.starts_with('/') {
+            report.error(format!(
+                "default_mount {:?} does not start with `/`",
+                default_mount.mount
+            ));
+        } else if !config.mounts.contains_key(&default_mount.mount) {
+            report.warning(format!(
+                "default_mount {} is not configured, so it only exists while a source sends to it",
+                default_mount.mount
+            ));
+        }
+    }
+
+    if let Err(e) = DependencyGraph::from_config(config).startup_order() {
+        report.error(format!("Mount dependencies: {}", e));
+    }
+}
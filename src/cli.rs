@@ -1,8 +1,13 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::config::Config;
+use crate::{auth::HashAlgorithm, config::Config};
 
 #[derive(Parser)]
 /// An IceShout2-compatible audio streaming server.
@@ -14,10 +19,25 @@ pub struct CliArgs {
     config_file: Option<PathBuf>,
 
     /// The authorization header to treat as admin
-    /// authorization
+    /// authorization, `<user>:<password>`, `<user>:<hash>` with a
+    /// bcrypt or Argon2 hash of the admin password, or just the
+    /// admin password
     #[clap(short = 'a', long)]
     admin_authorization: Option<String>,
 
+    /// The user name of admins, used together with `--admin-password`
+    /// instead of `--admin-authorization`
+    #[clap(long)]
+    admin_username: Option<String>,
+
+    /// The password of admins, or a bcrypt or Argon2 hash of it
+    #[clap(long)]
+    admin_password: Option<String>,
+
+    /// The address to listen on, e.g. `0.0.0.0:8000`
+    #[clap(short = 'l', long)]
+    listen: Option<SocketAddr>,
+
     /// Allow clients that connect with a SOURCE request to create
     /// new mountpoints without authentication
     #[clap(short = 'A', long)]
@@ -26,42 +46,207 @@ pub struct CliArgs {
     /// The directory from which to serve static files from.
     #[clap(short, long)]
     static_files_dir: Option<PathBuf>,
+
+    /// The maximum amount of subscribers connected to all
+    /// mounts combined
+    #[clap(long)]
+    max_clients: Option<usize>,
+
+    /// Record this many of the most recent failed or rejected
+    /// connections, for debugging client compatibility issues.
+    #[clap(long)]
+    recent_failures: Option<usize>,
+
+    /// Start on an ephemeral port, send data from a source to a
+    /// listener through the full pipeline, report the result and exit.
+    #[clap(long)]
+    pub self_test: bool,
+
+    /// Validate the configuration, report any problems and exit.
+    #[clap(long)]
+    pub check_config: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Inspect the configuration
+    #[clap(subcommand)]
+    Config(ConfigCommand),
+    /// Read a password from standard input and print a hash of it, which
+    /// can be used in the configuration instead of the password
+    HashPassword {
+        /// The algorithm to hash with, `argon2` or `bcrypt`
+        #[clap(long, default_value = "argon2")]
+        algorithm: HashAlgorithm,
+        /// Print `<user>:<hash>`, as used by `source_auth`, `sub_auth`
+        /// and `admin_authorization`, instead of only the hash
+        #[clap(long)]
+        user: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the configuration that the server runs with, i.e. the
+    /// configuration file merged with the CLI options, as TOML.
+    ///
+    /// Settings that are not set are shown with their defaults. Settings
+    /// without a default (e.g. optional features) are left out. There is
+    /// no environment variable layer: the configuration only comes from
+    /// the file and the CLI options.
+    ///
+    /// Credentials and secrets are shown as `<redacted>`, so that the
+    /// output can be shared, e.g. in a bug report.
+    Dump {
+        /// Show credentials and secrets instead of `<redacted>`
+        #[clap(long)]
+        show_secrets: bool,
+    },
 }
 
-impl Into<Config> for CliArgs {
-    fn into(self) -> Config {
-        let file_config: Option<Config> = self.config_file.map(|config| {
-            let res = match std::fs::read(&config) {
-                Ok(contents) => contents,
-                Err(e) => {
-                    panic!(
+impl CliArgs {
+    /// The configuration file to load, if any
+    pub fn config_file(&self) -> Option<&Path> {
+        self.config_file.as_deref()
+    }
+
+    /// Load the configuration file, if any, and apply the CLI options to
+    /// it.
+    ///
+    /// This can be called again to reload the configuration.
+    pub fn load_config(&self) -> Result<Config, String> {
+        let file_config: Option<Config> = match &self.config_file {
+            Some(config) => {
+                let res = std::fs::read(config).map_err(|e| {
+                    format!(
                         "Failed to open/read config file {:?}. Error: {:?}",
                         config, e
                     )
-                }
-            };
-
-            match std::str::from_utf8(&res) {
-                Ok(file_contents) => match toml::from_str(file_contents) {
-                    Ok(value) => value,
-                    Err(_) => panic!("Failed to parse config file."),
-                },
-                Err(e) => panic!("Failed to read config file. Error: {:?}", e),
+                })?;
+
+                let file_contents = std::str::from_utf8(&res)
+                    .map_err(|e| format!("Failed to read config file. Error: {:?}", e))?;
+                let value = toml::from_str(file_contents)
+                    .map_err(|e| format!("Failed to parse config file. Error: {}", e))?;
+                Some(value)
             }
-        });
+            None => None,
+        };
 
         let my_config = Config {
-            static_source_dir: self.static_files_dir,
-            admin_authorization: self.admin_authorization,
+            static_source_dir: self.static_files_dir.clone(),
+            admin_authorization: self.admin_authorization.clone(),
+            admin_username: self.admin_username.clone(),
+            admin_password: self.admin_password.clone(),
+            admin_htpasswd: None,
+            api_keys: Vec::new(),
             allow_unauthenticated_mounts: self.allow_unauthenticated_mounts,
+            max_clients: self.max_clients,
+            max_connections_per_ip: None,
+            ip_access: None,
+            trusted_proxies: Vec::new(),
+            geoip_db: None,
+            rate_limit: None,
+            recent_failures: self.recent_failures,
+            quirk_rules: Vec::new(),
+            listen: self.listen,
+            socket: Default::default(),
+            security: None,
+            cors: None,
+            buffering_proxies: None,
+            retention: None,
+            egress: None,
+            billing: None,
+            statsd: None,
+            influxdb: None,
+            history_db: None,
+            playlist_log: None,
+            log_rotation: None,
+            webhooks: Vec::new(),
+            yp: None,
+            jwt: None,
             default_stream_url: None,
+            base_url: None,
+            default_mount: None,
+            templates: None,
+            mount_defaults: None,
             mounts: BTreeMap::new(),
         };
 
-        if let Some(fcfg) = file_config {
+        Ok(if let Some(fcfg) = file_config {
             fcfg.merge(my_config)
         } else {
             my_config
+        })
+    }
+}
+
+impl From<CliArgs> for Config {
+    fn from(args: CliArgs) -> Config {
+        match args.load_config() {
+            Ok(config) => config,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+/// Read a password from standard input.
+///
+/// On a terminal, the password is prompted for twice without echoing it.
+pub fn read_password() -> Result<String, String> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        let mut line = String::new();
+        stdin.read_line(&mut line).map_err(|e| e.to_string())?;
+        return Ok(line.trim_end_matches(['\r', '\n']).to_string());
+    }
+
+    let prompt = |prompt: &str| {
+        eprint!("{}", prompt);
+        let echo = set_echo(false);
+        let mut line = String::new();
+        let read = stdin.read_line(&mut line);
+        if echo {
+            set_echo(true);
+        }
+        eprintln!();
+        read.map(|_| line.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| e.to_string())
+    };
+
+    let password = prompt("Password: ")?;
+    if password.is_empty() {
+        return Err("The password is empty".to_string());
+    }
+    if prompt("Repeat password: ")? != password {
+        return Err("The passwords do not match".to_string());
+    }
+    Ok(password)
+}
+
+/// Turn echoing of the terminal on standard input on or off, returning
+/// whether it was changed
+#[cfg(unix)]
+fn set_echo(enabled: bool) -> bool {
+    // Safety: `termios` is fully initialized by `tcgetattr` before use
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return false;
         }
+        if enabled {
+            termios.c_lflag |= libc::ECHO;
+        } else {
+            termios.c_lflag &= !libc::ECHO;
+        }
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) == 0
     }
 }
+
+#[cfg(not(unix))]
+fn set_echo(_enabled: bool) -> bool {
+    false
+}
@@ -1,24 +1,408 @@
-use std::{collections::BTreeMap, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
 
-use crate::state::StreamUrl;
+use crate::{
+    access::{AccessList, RefererRules, UserAgentRules},
+    auth::{ApiKeyConfig, AuthMechanism},
+    billing::BillingConfig,
+    egress::EgressConfig,
+    fingerprint::FingerprintConfig,
+    geoip::CountryRules,
+    history_db::HistoryDbConfig,
+    influxdb::InfluxDbConfig,
+    jwt::JwtConfig,
+    logfile::LogRotationConfig,
+    net::CorsConfig,
+    pool,
+    proxy::BufferingProxyConfig,
+    quirks::{QuirkProfile, QuirkRule},
+    ratelimit::RateLimitConfig,
+    relay::RelayConfig,
+    retention::RetentionConfig,
+    security::SecurityConfig,
+    state::{self, StreamUrl},
+    statsd::StatsdConfig,
+    taps::TapConfig,
+    templates::TemplateConfig,
+    transcription::TranscriptionConfig,
+    url_auth::UrlAuthConfig,
+    webhooks::WebhookConfig,
+    yp::YpConfig,
+};
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct MountConfig {
+    /// The credentials that sources must send: the value of their
+    /// `Authorization` header, `<user>:<password>`, `<user>:<hash>` with a
+    /// bcrypt or Argon2 hash of the password, or just the password, which
+    /// is accepted with any user name (encoders usually send `source`)
     pub source_auth: Option<String>,
+    /// The credentials that subscribers must send, in the same format as
+    /// `source_auth`
     pub sub_auth: Option<String>,
+    /// An `htpasswd` file with the credentials that sources may use, in
+    /// addition to `source_auth`
+    pub source_htpasswd: Option<PathBuf>,
+    /// An `htpasswd` file with the credentials that subscribers may use,
+    /// in addition to `sub_auth`
+    pub sub_htpasswd: Option<PathBuf>,
+    /// Users that may send to this mount (e.g. different DJs), in addition
+    /// to `source_auth`. Maps user names to passwords, or to bcrypt or
+    /// Argon2 hashes of them.
+    #[serde(default)]
+    pub source_users: BTreeMap<String, String>,
+    /// Users that may subscribe to this mount, in addition to `sub_auth`,
+    /// in the same format as `source_users`
+    #[serde(default)]
+    pub sub_users: BTreeMap<String, String>,
     #[serde(flatten)]
     pub stream_url: Option<StreamUrl>,
+    #[serde(default)]
     pub permanent: bool,
+    /// The maximum amount of subscribers of this mount
+    pub max_listeners: Option<usize>,
+    /// Compress the data sent to subscribers that accept gzip.
+    ///
+    /// This is only worthwhile for mounts that carry text (e.g. JSON or
+    /// transcripts), and is ignored for audio and video mounts.
+    #[serde(default)]
+    pub compress: bool,
+    /// The maximum amount of bytes read from the source at once, and
+    /// thus the maximum size of the chunks sent to subscribers.
+    ///
+    /// Larger chunks reduce overhead for high-bitrate streams at the
+    /// cost of latency. Defaults to 16 KiB.
+    pub chunk_size: Option<usize>,
+    /// Compatibility quirks to apply to all subscribers of this mount
+    pub quirks: Option<QuirkProfile>,
+    /// The weakest authentication mechanism that subscribers may use.
+    ///
+    /// If set, subscribers must always authenticate, with credentials
+    /// that are valid: a JSON Web Token (see `jwt`) or the credentials
+    /// of a subscriber of this mount.
+    pub min_sub_auth: Option<AuthMechanism>,
+    /// Only allow subscribers that connected over TLS.
+    ///
+    /// Peroxidecast does not terminate TLS itself, so this requires a
+    /// reverse proxy that terminates TLS and sets (or overwrites)
+    /// `X-Forwarded-Proto`, and that is listed in `trusted_proxies`.
+    #[serde(default)]
+    pub require_tls: bool,
+    /// Measure the integrated loudness (EBU R128) of this mount, and flag
+    /// it when it exceeds this target, in LUFS (e.g. `-23.0`)
+    pub loudness_target: Option<f64>,
+    /// Replicate the data and metadata of this mount to message brokers
+    #[serde(default)]
+    pub taps: Vec<TapConfig>,
+    /// Compute acoustic fingerprints of this mount, so that the tracks
+    /// that are played can be identified
+    pub fingerprint: Option<FingerprintConfig>,
+    /// Pull the data of this mount from upstream servers instead of
+    /// accepting sources
+    pub relay: Option<RelayConfig>,
+    /// Caption this mount using a speech-to-text service
+    pub transcription: Option<TranscriptionConfig>,
+    /// Mounts that must be started before this one, e.g. the mounts that
+    /// a relay or caption mount is derived from
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// The nominal bitrate of this mount, in kbit/s. If not set, the
+    /// bitrate that the source reports in `ice-audio-info` is used.
+    pub bitrate_kbps: Option<u32>,
+    /// Mounts that carry the same stream at other bitrates. Listeners
+    /// are hinted towards the one that their connection can sustain.
+    #[serde(default)]
+    pub renditions: Vec<String>,
+    /// Headers to add to the responses to subscribers, e.g.
+    /// `{ "Cache-Control" = "no-store" }`. These replace the headers
+    /// that would otherwise be sent with the same name.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Keep the last this many seconds of data of this mount, so that
+    /// they can be downloaded from `/api/v1/mounts/<name>/snapshot`
+    pub snapshot_secs: Option<u64>,
+    /// Tags to add to the points of this mount that are written to
+    /// InfluxDB, e.g. `{ customer = "acme" }`
+    #[serde(default)]
+    pub influxdb_tags: BTreeMap<String, String>,
+    /// Remember this many of the last played songs of this mount, so that
+    /// they can be listed at `/api/mounts/<name>/history` (or at
+    /// `/api/v1/mounts/<name>/history`). Defaults to 10.
+    pub song_history: Option<usize>,
+    /// A command to run when a source connects to this mount. It is
+    /// passed the name of the mount as its argument.
+    pub on_connect: Option<PathBuf>,
+    /// A command to run when the source of this mount disconnects. It is
+    /// passed the name of the mount as its argument.
+    pub on_disconnect: Option<PathBuf>,
+    /// List this mount in YP directories. This overrides the
+    /// `ice-public` header sent by the source.
+    pub public: Option<bool>,
+    /// Leave this mount out of `/mount_info`, the status pages and YP
+    /// directories. Listeners can still play it if they know its URL.
+    #[serde(default)]
+    pub hidden: bool,
+    /// Let an HTTP endpoint decide whether listeners may subscribe, in
+    /// addition to `sub_auth`
+    pub auth_url: Option<UrlAuthConfig>,
+    /// Only allow listeners with a URL that is signed with this secret,
+    /// see [`signed_url`](crate::signed_url)
+    pub url_signing_secret: Option<String>,
+    /// The IP addresses that may connect to this mount, in addition to
+    /// the `ip_access` of the server
+    pub ip_access: Option<AccessList>,
+    /// The countries from which listeners may subscribe to this mount.
+    /// This requires the `geoip_db` of the server.
+    pub countries: Option<CountryRules>,
+    /// The `User-Agent`s with which listeners may subscribe to this mount
+    pub user_agents: Option<UserAgentRules>,
+    /// Only allow listeners that are referred from these sites, or that
+    /// have a signed URL or a JSON Web Token
+    pub referers: Option<RefererRules>,
+}
+
+/// What secrets are replaced with by [`Config::redacted`]
+const REDACTED: &str = "<redacted>";
+
+impl MountConfig {
+    /// Whether sources must authenticate with credentials configured
+    /// here
+    pub fn has_source_auth(&self) -> bool {
+        self.source_auth.is_some()
+            || self.source_htpasswd.is_some()
+            || !self.source_users.is_empty()
+    }
+
+    /// This configuration with its credentials and secrets replaced
+    pub fn redacted(self) -> Self {
+        let redact_users = |users: BTreeMap<String, String>| {
+            users
+                .into_keys()
+                .map(|user| (user, REDACTED.to_string()))
+                .collect()
+        };
+        Self {
+            source_auth: self.source_auth.map(|_| REDACTED.to_string()),
+            sub_auth: self.sub_auth.map(|_| REDACTED.to_string()),
+            source_users: redact_users(self.source_users),
+            sub_users: redact_users(self.sub_users),
+            url_signing_secret: self.url_signing_secret.map(|_| REDACTED.to_string()),
+            ..self
+        }
+    }
+
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            chunk_size: Some(self.chunk_size.unwrap_or(pool::DEFAULT_CHUNK_SIZE)),
+            song_history: Some(self.song_history.unwrap_or(state::DEFAULT_SONG_HISTORY)),
+            auth_url: self.auth_url.map(UrlAuthConfig::with_defaults),
+            fingerprint: self.fingerprint.map(FingerprintConfig::with_defaults),
+            relay: self.relay.map(RelayConfig::with_defaults),
+            transcription: self.transcription.map(TranscriptionConfig::with_defaults),
+            ..self
+        }
+    }
+}
+
+/// The mount that requests for `/` are redirected to, so that the bare
+/// address of the server can be played
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DefaultMountConfig {
+    /// The name of the mount, e.g. `/live`
+    pub mount: String,
+    /// Also redirect requests for paths that are neither a mount nor
+    /// part of the API
+    #[serde(default)]
+    pub redirect_unknown: bool,
+}
+
+const DEFAULT_HEADER_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_HEADER_BYTES: usize = 8192;
+const DEFAULT_MAX_HEADERS: usize = 64;
+
+/// Options applied to every accepted TCP connection
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SocketConfig {
+    /// Disable Nagle's algorithm, trading some bandwidth for latency
+    #[serde(default)]
+    pub nodelay: bool,
+    /// Send TCP keepalive probes after the connection has been idle for
+    /// this many seconds, so that dead subscribers are detected
+    pub keepalive_secs: Option<u64>,
+    /// The size of the kernel's send buffer, in bytes
+    pub send_buffer_size: Option<usize>,
+    /// Close connections that have not sent their request headers
+    /// within this many seconds. Defaults to 10 seconds.
+    pub header_timeout_secs: Option<u64>,
+    /// The maximum size of the request line and headers, in bytes.
+    /// Defaults to 8 KiB.
+    pub max_header_bytes: Option<usize>,
+    /// The maximum amount of request headers. Defaults to 64.
+    pub max_headers: Option<usize>,
+}
+
+impl SocketConfig {
+    /// The time within which clients must send their request headers
+    pub fn header_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.header_timeout_secs
+                .unwrap_or(DEFAULT_HEADER_TIMEOUT_SECS),
+        )
+    }
+
+    /// The maximum size of the request line and headers, in bytes
+    pub fn max_header_bytes(&self) -> usize {
+        self.max_header_bytes.unwrap_or(DEFAULT_MAX_HEADER_BYTES)
+    }
+
+    /// The maximum amount of request headers
+    pub fn max_headers(&self) -> usize {
+        self.max_headers.unwrap_or(DEFAULT_MAX_HEADERS)
+    }
+
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            header_timeout_secs: Some(self.header_timeout().as_secs()),
+            max_header_bytes: Some(self.max_header_bytes()),
+            max_headers: Some(self.max_headers()),
+            ..self
+        }
+    }
+
+    pub fn merge(self, other: SocketConfig) -> Self {
+        Self {
+            nodelay: other.nodelay || self.nodelay,
+            keepalive_secs: other.keepalive_secs.or(self.keepalive_secs),
+            send_buffer_size: other.send_buffer_size.or(self.send_buffer_size),
+            header_timeout_secs: other.header_timeout_secs.or(self.header_timeout_secs),
+            max_header_bytes: other.max_header_bytes.or(self.max_header_bytes),
+            max_headers: other.max_headers.or(self.max_headers),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
     pub static_source_dir: Option<PathBuf>,
     pub default_stream_url: Option<StreamUrl>,
+    /// The URL at which clients reach the server, e.g.
+    /// `https://radio.example.com`. Playlists and the status pages link
+    /// to mounts under it, unless a mount has a static `stream_url`,
+    /// instead of under the host that a client requested.
+    pub base_url: Option<String>,
+    /// The credentials that admins must send, in the same format as the
+    /// `source_auth` of mounts
     pub admin_authorization: Option<String>,
+    /// The user name of admins, used together with `admin_password` as an
+    /// alternative to `admin_authorization`
+    #[serde(default, alias = "admin_user")]
+    pub admin_username: Option<String>,
+    /// The password of admins, or a bcrypt or Argon2 hash of it
+    #[serde(default)]
+    pub admin_password: Option<String>,
+    /// An `htpasswd` file with the credentials that admins may use, in
+    /// addition to `admin_authorization`
+    pub admin_htpasswd: Option<PathBuf>,
+    /// Keys that give access to the admin API without the admin
+    /// credentials, either to all of it or only to the endpoints that
+    /// change nothing
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
     pub allow_unauthenticated_mounts: bool,
+    /// The maximum amount of subscribers connected to all mounts combined
+    pub max_clients: Option<usize>,
+    /// The maximum amount of concurrent connections from a single IP address
+    pub max_connections_per_ip: Option<usize>,
+    /// The IP addresses that may connect to the server
+    pub ip_access: Option<AccessList>,
+    /// The reverse proxies whose `X-Forwarded-Proto` header is trusted,
+    /// e.g. `["127.0.0.1/32"]`. The header is ignored for other clients,
+    /// which are assumed to have connected over plain HTTP.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// A MaxMind GeoIP2 or GeoLite2 country (or city) database, used to
+    /// look up the country of listeners
+    pub geoip_db: Option<PathBuf>,
+    /// Limit the requests that every client IP address may make to
+    /// `/admin/*` and `/mount_info`
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Record the headers and first bytes of this many of the most recent
+    /// failed or rejected connections, viewable by admins at
+    /// `/admin/debug/recent_failures`
+    pub recent_failures: Option<usize>,
+    /// Compatibility quirks to apply to subscribers based on their
+    /// `User-Agent`. These take precedence over the built-in rules.
+    #[serde(default)]
+    pub quirk_rules: Vec<QuirkRule>,
+    /// The address to listen on. Defaults to `127.0.0.1:8080`. This is
+    /// not changed by reloading the configuration.
+    pub listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub socket: SocketConfig,
+    /// Drop root privileges once the listening socket is bound. This is
+    /// not changed by reloading the configuration.
+    pub security: Option<SecurityConfig>,
+    /// Allow browser players on other origins to use the API and to
+    /// play streams
+    pub cors: Option<CorsConfig>,
+    /// Detect listeners behind buffering proxies (e.g. CDN edges) and
+    /// disconnect them when they stall, instead of letting them hold on
+    /// to a listener slot and a queue of data
+    pub buffering_proxies: Option<BufferingProxyConfig>,
+    /// How long recorded data (statistics and failed connections) is kept
+    pub retention: Option<RetentionConfig>,
+    /// The outgoing bandwidth of the server, and how much of it is
+    /// reserved for sources and admin traffic
+    pub egress: Option<EgressConfig>,
+    /// Periodic per-mount usage records
+    pub billing: Option<BillingConfig>,
+    /// Push per-mount metrics to a StatsD server
+    pub statsd: Option<StatsdConfig>,
+    /// Write per-mount statistics to InfluxDB
+    pub influxdb: Option<InfluxDbConfig>,
+    /// Store the statistics history in a SQLite database, so that it
+    /// survives restarts
+    pub history_db: Option<HistoryDbConfig>,
+    /// Append the songs played on all mounts to this file, in the format
+    /// of Icecast's `playlist.log`
+    pub playlist_log: Option<PathBuf>,
+    /// When the log files written by the server are rotated. Logs can
+    /// also be reopened by sending `SIGUSR1`, for use with `logrotate`.
+    pub log_rotation: Option<LogRotationConfig>,
+    /// Notify HTTP endpoints about sources, listeners, mounts and
+    /// metadata changes
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// List public mounts in YP directories, e.g. `dir.xiph.org`
+    pub yp: Option<YpConfig>,
+    /// Accept JSON Web Tokens from listeners and sources, in addition to
+    /// the credentials configured for mounts
+    pub jwt: Option<JwtConfig>,
+    /// Redirect requests for `/` to a mount, instead of serving the
+    /// `index.html` of `static_source_dir`
+    pub default_mount: Option<DefaultMountConfig>,
+    /// Replace the built-in status page and error pages with templates.
+    /// The templates are read on startup.
+    pub templates: Option<TemplateConfig>,
+    /// The settings of mounts that are not configured in `mounts`, i.e.
+    /// the mounts that sources create dynamically.
+    ///
+    /// If these require sources to authenticate, only sources with these
+    /// credentials may create mounts, whether `allow_unauthenticated_mounts`
+    /// is set or not. Otherwise, mounts are protected with the credentials
+    /// of the source that created them.
+    pub mount_defaults: Option<MountConfig>,
     pub mounts: BTreeMap<String, MountConfig>,
 }
 
@@ -32,9 +416,45 @@ impl Config {
 
         let static_source_dir = other.static_source_dir.or(self.static_source_dir);
         let default_stream_url = other.default_stream_url.or(self.default_stream_url);
+        let base_url = other.base_url.or(self.base_url);
         let admin_authorization = other.admin_authorization.or(self.admin_authorization);
+        let admin_username = other.admin_username.or(self.admin_username);
+        let admin_password = other.admin_password.or(self.admin_password);
+        let admin_htpasswd = other.admin_htpasswd.or(self.admin_htpasswd);
+        let mut api_keys = other.api_keys;
+        api_keys.extend(self.api_keys);
         let allow_unauthenticated_mounts =
             other.allow_unauthenticated_mounts || self.allow_unauthenticated_mounts;
+        let max_clients = other.max_clients.or(self.max_clients);
+        let max_connections_per_ip = other.max_connections_per_ip.or(self.max_connections_per_ip);
+        let ip_access = other.ip_access.or(self.ip_access);
+        let mut trusted_proxies = other.trusted_proxies;
+        trusted_proxies.extend(self.trusted_proxies);
+        let geoip_db = other.geoip_db.or(self.geoip_db);
+        let rate_limit = other.rate_limit.or(self.rate_limit);
+        let recent_failures = other.recent_failures.or(self.recent_failures);
+        let mut quirk_rules = other.quirk_rules;
+        quirk_rules.extend(self.quirk_rules);
+        let listen = other.listen.or(self.listen);
+        let socket = self.socket.merge(other.socket);
+        let security = other.security.or(self.security);
+        let cors = other.cors.or(self.cors);
+        let buffering_proxies = other.buffering_proxies.or(self.buffering_proxies);
+        let retention = other.retention.or(self.retention);
+        let egress = other.egress.or(self.egress);
+        let billing = other.billing.or(self.billing);
+        let statsd = other.statsd.or(self.statsd);
+        let influxdb = other.influxdb.or(self.influxdb);
+        let history_db = other.history_db.or(self.history_db);
+        let playlist_log = other.playlist_log.or(self.playlist_log);
+        let log_rotation = other.log_rotation.or(self.log_rotation);
+        let mut webhooks = other.webhooks;
+        webhooks.extend(self.webhooks);
+        let yp = other.yp.or(self.yp);
+        let jwt = other.jwt.or(self.jwt);
+        let default_mount = other.default_mount.or(self.default_mount);
+        let templates = other.templates.or(self.templates);
+        let mount_defaults = other.mount_defaults.or(self.mount_defaults);
         let mut mounts = self.mounts;
         for (k, v) in other.mounts {
             mounts.insert(k, v);
@@ -43,9 +463,148 @@ impl Config {
         Self {
             static_source_dir,
             default_stream_url,
+            base_url,
             admin_authorization,
+            admin_username,
+            admin_password,
+            admin_htpasswd,
+            api_keys,
             allow_unauthenticated_mounts,
+            max_clients,
+            max_connections_per_ip,
+            ip_access,
+            trusted_proxies,
+            geoip_db,
+            rate_limit,
+            recent_failures,
+            quirk_rules,
+            listen,
+            socket,
+            security,
+            cors,
+            buffering_proxies,
+            retention,
+            egress,
+            billing,
+            statsd,
+            influxdb,
+            history_db,
+            playlist_log,
+            log_rotation,
+            webhooks,
+            yp,
+            jwt,
+            default_mount,
+            templates,
+            mount_defaults,
             mounts,
         }
     }
+
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults, e.g. to show the configuration that the server
+    /// actually runs with
+    pub fn with_defaults(self) -> Self {
+        Self {
+            listen: Some(self.listen_addr()),
+            socket: self.socket.with_defaults(),
+            rate_limit: self.rate_limit.map(RateLimitConfig::with_defaults),
+            buffering_proxies: self
+                .buffering_proxies
+                .map(BufferingProxyConfig::with_defaults),
+            // The statistics history is always kept
+            retention: Some(self.retention.unwrap_or_default().with_defaults()),
+            egress: self.egress.map(EgressConfig::with_defaults),
+            billing: self.billing.map(BillingConfig::with_defaults),
+            statsd: self.statsd.map(StatsdConfig::with_defaults),
+            influxdb: self.influxdb.map(InfluxDbConfig::with_defaults),
+            history_db: self.history_db.map(HistoryDbConfig::with_defaults),
+            log_rotation: self.log_rotation.map(LogRotationConfig::with_defaults),
+            webhooks: self
+                .webhooks
+                .into_iter()
+                .map(WebhookConfig::with_defaults)
+                .collect(),
+            yp: self.yp.map(YpConfig::with_defaults),
+            jwt: self.jwt.map(JwtConfig::with_defaults),
+            mount_defaults: self.mount_defaults.map(MountConfig::with_defaults),
+            mounts: self
+                .mounts
+                .into_iter()
+                .map(|(name, mount)| (name, mount.with_defaults()))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// This configuration with its credentials and secrets replaced by
+    /// `<redacted>`, e.g. to show it in a bug report. User names and the
+    /// scopes of API keys are kept.
+    pub fn redacted(self) -> Self {
+        let redact = |secret: Option<String>| secret.map(|_| REDACTED.to_string());
+        Self {
+            admin_authorization: redact(self.admin_authorization),
+            admin_password: redact(self.admin_password),
+            api_keys: self
+                .api_keys
+                .into_iter()
+                .map(|key| ApiKeyConfig {
+                    key: REDACTED.to_string(),
+                    ..key
+                })
+                .collect(),
+            influxdb: self.influxdb.map(|influxdb| InfluxDbConfig {
+                token: redact(influxdb.token),
+                ..influxdb
+            }),
+            jwt: self.jwt.map(|jwt| JwtConfig {
+                secret: redact(jwt.secret),
+                ..jwt
+            }),
+            mount_defaults: self.mount_defaults.map(MountConfig::redacted),
+            mounts: self
+                .mounts
+                .into_iter()
+                .map(|(name, mount)| (name, mount.redacted()))
+                .collect(),
+            ..self
+        }
+    }
+
+    /// This configuration in the format of the configuration file
+    pub fn to_toml(&self) -> Result<String, String> {
+        // Going through a `Value` puts plain values before tables, as
+        // TOML requires
+        toml::Value::try_from(self)
+            .and_then(|value| toml::to_string_pretty(&value))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Whether the client at `ip` is a reverse proxy whose forwarding
+    /// headers are trusted
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address to listen on
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen
+            .unwrap_or(SocketAddr::from((Ipv4Addr::LOCALHOST, 8080)))
+    }
+
+    /// The credentials that admins must send, in the same format as the
+    /// `source_auth` of mounts: `admin_authorization` if it is set, and
+    /// otherwise `<admin_username>:<admin_password>`
+    pub fn admin_credentials(&self) -> Option<String> {
+        match (
+            &self.admin_authorization,
+            &self.admin_username,
+            &self.admin_password,
+        ) {
+            (Some(authorization), _, _) => Some(authorization.clone()),
+            (None, Some(user), Some(password)) => Some(format!("{}:{}", user, password)),
+            _ => None,
+        }
+    }
 }
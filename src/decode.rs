@@ -0,0 +1,171 @@
+//! Decoding of the audio sent by sources, for analyses such as loudness
+//! measurement and fingerprinting.
+//!
+//! The data of a source is decoded on a blocking thread, so that
+//! analysing a mount never slows down the delivery of its data.
+
+use std::{io::Read, sync::Arc};
+
+use symphonia::core::{
+    audio::{SampleBuffer, SignalSpec},
+    codecs::DecoderOptions,
+    errors::Error as DecodeError,
+    formats::FormatOptions,
+    io::{MediaSourceStream, ReadOnlySource},
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tracing::{debug, info, warn};
+
+use crate::state::{Chunk, DataSender, Mount};
+
+/// The amount of chunks that may be queued for the decoder
+const DECODER_QUEUE_CHUNKS: usize = 64;
+
+/// An analysis of the decoded audio of a mount
+pub trait Analyzer {
+    /// A short description of the analysis, used in log messages
+    const NAME: &'static str;
+
+    /// Process a block of decoded, interleaved samples
+    fn process(&mut self, samples: &[f32], spec: SignalSpec);
+
+    /// Called once the source has disconnected
+    fn finish(&mut self) {}
+}
+
+/// Decode the data sent to `data_tx` and feed it to the analyzer created
+/// by `make_analyzer`, until the source disconnects.
+///
+/// The analyzer is created on the decoding thread, so it does not have
+/// to be `Send`.
+pub fn spawn<A, F>(mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender, make_analyzer: F)
+where
+    A: Analyzer,
+    F: FnOnce() -> A + Send + 'static,
+{
+    let (chunk_tx, chunk_rx) = mpsc::channel(DECODER_QUEUE_CHUNKS);
+    let mut data_rx = data_tx.subscribe();
+    let name = mount_name.to_string();
+    let analysis = A::NAME;
+
+    tokio::spawn(async move {
+        loop {
+            match data_rx.recv().await {
+                Ok(chunk) => {
+                    if chunk_tx.send(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("{} of mount {} skipped {} chunks", analysis, name, missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    let content_type = mount.content_type();
+    let name = mount_name.to_string();
+    tokio::task::spawn_blocking(move || {
+        let reader = ChunkReader {
+            rx: chunk_rx,
+            current: None,
+            position: 0,
+        };
+
+        let mut analyzer = make_analyzer();
+        if let Err(e) = decode(&content_type, reader, &mut analyzer) {
+            warn!("{} of mount {} failed: {}", A::NAME, name, e);
+        }
+        analyzer.finish();
+        info!("Stopped {} of mount {}", A::NAME.to_lowercase(), name);
+    });
+}
+
+fn decode(
+    content_type: &str,
+    reader: ChunkReader,
+    analyzer: &mut impl Analyzer,
+) -> Result<(), DecodeError> {
+    let stream = MediaSourceStream::new(Box::new(ReadOnlySource::new(reader)), Default::default());
+
+    let mut hint = Hint::new();
+    hint.mime_type(content_type);
+
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format
+        .default_track()
+        .ok_or(DecodeError::Unsupported("stream has no audio track"))?;
+    let track_id = track.id;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples: Option<SampleBuffer<f32>> = None;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Corrupt or skipped data, which the decoder recovers from
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let spec = *decoded.spec();
+        let buffer = match &mut samples {
+            Some(buffer) if buffer.capacity() >= decoded.capacity() => buffer,
+            _ => samples.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        buffer.copy_interleaved_ref(decoded);
+        analyzer.process(buffer.samples(), spec);
+    }
+}
+
+/// Presents the chunks sent by a source as a blocking reader
+struct ChunkReader {
+    rx: mpsc::Receiver<Chunk>,
+    current: Option<Chunk>,
+    position: usize,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(chunk) = &self.current {
+                if self.position < chunk.len() {
+                    let len = buf.len().min(chunk.len() - self.position);
+                    buf[..len].copy_from_slice(&chunk[self.position..self.position + len]);
+                    self.position += len;
+                    return Ok(len);
+                }
+            }
+
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.current = Some(chunk);
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
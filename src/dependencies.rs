@@ -0,0 +1,153 @@
+//! Dependencies between mounts, which determine the order in which mounts
+//! and the subsystems attached to them are started.
+//!
+//! Besides the dependencies that mounts declare with `depends_on`, a
+//! configured caption mount implicitly depends on the mount it captions.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyError {
+    /// `mount` depends on a mount that is not configured
+    Unknown { mount: String, dependency: String },
+    /// The mounts depend on each other
+    Cycle(Vec<String>),
+}
+
+impl Display for DependencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyError::Unknown { mount, dependency } => write!(
+                f,
+                "mount {} depends on {}, which is not configured",
+                mount, dependency
+            ),
+            DependencyError::Cycle(mounts) => {
+                write!(f, "mounts {} depend on each other", mounts.join(", "))
+            }
+        }
+    }
+}
+
+/// A mount and its direct dependencies and dependents, as shown in
+/// the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct MountDependencies {
+    pub name: String,
+    pub depends_on: Vec<String>,
+    pub dependents: Vec<String>,
+}
+
+/// The dependency graph of all mounts, as shown in the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct DependencyReport {
+    /// Why the declared dependencies cannot be satisfied, if they can't
+    pub error: Option<String>,
+    /// All mounts, in startup order if there is one
+    pub mounts: Vec<MountDependencies>,
+}
+
+/// The dependencies between all configured mounts
+#[derive(Debug, Clone)]
+pub struct DependencyGraph {
+    dependencies: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DependencyGraph {
+    pub fn from_config(config: &Config) -> Self {
+        let mut dependencies: BTreeMap<String, BTreeSet<String>> = config
+            .mounts
+            .iter()
+            .map(|(name, mount)| (name.clone(), mount.depends_on.iter().cloned().collect()))
+            .collect();
+
+        for (name, mount) in &config.mounts {
+            if let Some(transcription) = &mount.transcription {
+                let caption_mount = transcription
+                    .caption_mount
+                    .clone()
+                    .unwrap_or_else(|| format!("{}/captions", name));
+                if let Some(deps) = dependencies.get_mut(&caption_mount) {
+                    deps.insert(name.clone());
+                }
+            }
+        }
+
+        Self { dependencies }
+    }
+
+    /// All mounts, ordered such that every mount comes after the mounts
+    /// it depends on. Mounts that do not depend on each other are
+    /// ordered by name, so the order is deterministic.
+    pub fn startup_order(&self) -> Result<Vec<String>, DependencyError> {
+        for (mount, deps) in &self.dependencies {
+            if let Some(dependency) = deps.iter().find(|d| !self.dependencies.contains_key(*d)) {
+                return Err(DependencyError::Unknown {
+                    mount: mount.clone(),
+                    dependency: dependency.clone(),
+                });
+            }
+        }
+
+        let mut remaining = self.dependencies.clone();
+        let mut order = Vec::with_capacity(remaining.len());
+
+        while !remaining.is_empty() {
+            let ready = remaining
+                .iter()
+                .find(|(_, deps)| deps.iter().all(|d| !remaining.contains_key(d)))
+                .map(|(name, _)| name.clone());
+
+            if let Some(ready) = ready {
+                remaining.remove(&ready);
+                order.push(ready);
+            } else {
+                return Err(DependencyError::Cycle(remaining.into_keys().collect()));
+            }
+        }
+
+        Ok(order)
+    }
+
+    /// The order in which mounts should be stopped: dependents before
+    /// the mounts they depend on
+    pub fn shutdown_order(&self) -> Result<Vec<String>, DependencyError> {
+        let mut order = self.startup_order()?;
+        order.reverse();
+        Ok(order)
+    }
+
+    /// The direct dependencies and dependents of every mount
+    pub fn report(&self) -> DependencyReport {
+        let (order, error) = match self.startup_order() {
+            Ok(order) => (order, None),
+            Err(e) => (
+                self.dependencies.keys().cloned().collect(),
+                Some(e.to_string()),
+            ),
+        };
+
+        let mounts = order
+            .into_iter()
+            .map(|name| MountDependencies {
+                depends_on: self.dependencies[&name].iter().cloned().collect(),
+                dependents: self
+                    .dependencies
+                    .iter()
+                    .filter(|(_, deps)| deps.contains(&name))
+                    .map(|(dependent, _)| dependent.clone())
+                    .collect(),
+                name,
+            })
+            .collect();
+
+        DependencyReport { error, mounts }
+    }
+}
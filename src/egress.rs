@@ -0,0 +1,122 @@
+//! Reservation of outgoing bandwidth for sources and admin traffic.
+//!
+//! If the capacity of the uplink is configured, the data sent to
+//! listeners is limited to the part of it that is not reserved. Once
+//! listeners use up their share, new listeners are turned away and
+//! existing ones are slowed down (and eventually disconnected for lagging
+//! behind), so that an overloaded server degrades the experience of its
+//! listeners instead of dropping its encoders.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const DEFAULT_RESERVE_PERCENT: u8 = 10;
+
+/// How long after the last congested write new listeners are turned away
+const CONGESTION_HOLD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EgressConfig {
+    /// The outgoing bandwidth of the server, in bytes per second
+    pub capacity_bytes_per_sec: u64,
+    /// The percentage of the capacity that listeners may not use, so that
+    /// it stays available for sources and admin traffic. Defaults to 10%.
+    pub reserve_percent: Option<u8>,
+}
+
+impl EgressConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            reserve_percent: Some(self.reserve_percent.unwrap_or(DEFAULT_RESERVE_PERCENT)),
+            ..self
+        }
+    }
+
+    /// The bandwidth that listeners may use, in bytes per second
+    pub fn listener_bytes_per_sec(&self) -> u64 {
+        let reserve = self
+            .reserve_percent
+            .unwrap_or(DEFAULT_RESERVE_PERCENT)
+            .min(100) as u64;
+        self.capacity_bytes_per_sec * (100 - reserve) / 100
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// The amount of bytes that may be sent right away. Negative if
+    /// listeners have to wait before sending more.
+    tokens: f64,
+    refilled: Instant,
+    congested: Option<Instant>,
+}
+
+/// A token bucket that limits the data sent to all listeners together
+#[derive(Debug)]
+pub struct EgressLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl EgressLimiter {
+    pub fn new(config: &EgressConfig) -> Self {
+        let bytes_per_sec = config.listener_bytes_per_sec().max(1) as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled: Instant::now(),
+                congested: None,
+            }),
+        }
+    }
+
+    /// Wait until `bytes` may be sent to a listener
+    pub async fn reserve(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * self.bytes_per_sec;
+            // At most a second worth of data may be sent at once
+            bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+
+            if bucket.tokens < 0.0 {
+                if bucket.congested.is_none() {
+                    warn!("Listeners are using up their share of the outgoing bandwidth");
+                }
+                bucket.congested = Some(now);
+                Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Whether listeners have recently used up their share of the outgoing
+    /// bandwidth, in which case no new listeners should be accepted.
+    pub fn is_congested(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        match bucket.congested {
+            Some(congested) if congested.elapsed() < CONGESTION_HOLD => true,
+            Some(_) => {
+                info!("Listeners are no longer limited by the outgoing bandwidth");
+                bucket.congested = None;
+                false
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Events about mounts, sources and listeners, which are published to
+//! everyone who is interested in them, e.g. [webhooks](crate::webhooks).
+
+use std::{net::IpAddr, time::SystemTime};
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// The amount of events that may be queued for a slow subscriber
+const EVENT_QUEUE: usize = 256;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    SourceConnected {
+        mount: String,
+    },
+    SourceDisconnected {
+        mount: String,
+    },
+    ListenerConnected {
+        mount: String,
+        session: u64,
+        remote_ip: IpAddr,
+    },
+    ListenerDisconnected {
+        mount: String,
+        session: u64,
+        remote_ip: IpAddr,
+        reason: String,
+        connected_secs: u64,
+        bytes_sent: u64,
+    },
+    MountCreated {
+        mount: String,
+    },
+    MountRemoved {
+        mount: String,
+    },
+    MetadataChanged {
+        mount: String,
+        song: Option<String>,
+        url: Option<String>,
+    },
+}
+
+impl EventKind {
+    /// The name of the event, as used in its JSON representation
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::SourceConnected { .. } => "source_connected",
+            EventKind::SourceDisconnected { .. } => "source_disconnected",
+            EventKind::ListenerConnected { .. } => "listener_connected",
+            EventKind::ListenerDisconnected { .. } => "listener_disconnected",
+            EventKind::MountCreated { .. } => "mount_created",
+            EventKind::MountRemoved { .. } => "mount_removed",
+            EventKind::MetadataChanged { .. } => "metadata_changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    /// The time at which the event happened, in RFC 3339 format
+    pub time: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+/// The publisher of all events of a server
+#[derive(Debug, Clone)]
+pub struct Events {
+    tx: broadcast::Sender<Event>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self {
+            tx: broadcast::channel(EVENT_QUEUE).0,
+        }
+    }
+}
+
+impl Events {
+    /// Publish an event that happened just now
+    pub fn emit(&self, kind: EventKind) {
+        let event = Event {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            kind,
+        };
+        // Sending only fails if nobody is interested, which is fine.
+        self.tx.send(event).ok();
+    }
+
+    /// Subscribe to all events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.tx.subscribe()
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use httparse::Header;
+use serde::Serialize;
+
+/// The maximum amount of bytes of a failed request that are recorded
+const RECORDED_BYTES: usize = 2048;
+
+/// Request headers that carry credentials, whose values are not recorded
+const CREDENTIAL_HEADERS: [&str; 3] = ["Authorization", "Proxy-Authorization", "Cookie"];
+
+/// What the values of credential headers are replaced with
+const REDACTED: &str = "<redacted>";
+
+fn is_credential_header(name: &str) -> bool {
+    CREDENTIAL_HEADERS
+        .iter()
+        .any(|header| header.eq_ignore_ascii_case(name.trim()))
+}
+
+/// `request` with the values of credential headers replaced
+fn redact_request(request: &str) -> String {
+    request
+        .split_inclusive('\n')
+        .map(|line| match line.split_once(':') {
+            Some((name, value)) if is_credential_header(name) => {
+                let end = &value[value.trim_end_matches(['\r', '\n']).len()..];
+                format!("{}: {}{}", name, REDACTED, end)
+            }
+            _ => line.to_string(),
+        })
+        .collect()
+}
+
+/// A connection that failed or was rejected
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedConnection {
+    #[serde(skip)]
+    recorded: SystemTime,
+    time: String,
+    remote: String,
+    reason: String,
+    /// The request headers, with the values of credential headers
+    /// replaced by `<redacted>`
+    headers: Vec<(String, String)>,
+    /// The first bytes sent by the client, lossily decoded as UTF-8
+    request: String,
+}
+
+impl FailedConnection {
+    pub fn new(
+        remote: impl Display,
+        reason: impl Display,
+        headers: &[Header<'_>],
+        request: &[u8],
+    ) -> Self {
+        let headers = headers
+            .iter()
+            .filter(|h| !h.name.is_empty())
+            .map(|h| {
+                let value = if is_credential_header(h.name) {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(h.value).to_string()
+                };
+                (h.name.to_string(), value)
+            })
+            .collect();
+        let request = String::from_utf8_lossy(&request[..request.len().min(RECORDED_BYTES)]);
+
+        let recorded = SystemTime::now();
+        Self {
+            recorded,
+            time: humantime::format_rfc3339_seconds(recorded).to_string(),
+            remote: remote.to_string(),
+            reason: reason.to_string(),
+            headers,
+            request: redact_request(&request),
+        }
+    }
+
+    /// The IP address of the client, if it is known
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        self.remote.parse::<SocketAddr>().ok().map(|a| a.ip())
+    }
+}
+
+/// A ring buffer of the most recent failed connections, used to
+/// diagnose client compatibility issues.
+#[derive(Debug, Default)]
+pub struct FailureLog {
+    entries: Mutex<VecDeque<FailedConnection>>,
+}
+
+impl FailureLog {
+    /// Record `failure`, keeping at most `capacity` entries
+    pub fn record(&self, capacity: usize, failure: FailedConnection) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.push_back(failure);
+        while entries.len() > capacity {
+            entries.pop_front();
+        }
+    }
+
+    /// Drop the failures that are older than `max_age`
+    pub fn prune(&self, max_age: Duration) {
+        let cutoff = SystemTime::now() - max_age;
+        self.purge(|f| f.recorded < cutoff);
+    }
+
+    /// Drop the failures that match `filter`, returning the amount of
+    /// failures that were dropped
+    pub fn purge(&self, filter: impl Fn(&FailedConnection) -> bool) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        let before = entries.len();
+        entries.retain(|f| !filter(f));
+        before - entries.len()
+    }
+
+    /// The recorded failures, oldest first
+    pub fn entries(&self) -> Vec<FailedConnection> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_redacted() {
+        let request = b"GET /live HTTP/1.1\r\nHost: radio\r\nauthorization: Basic c2VjcmV0\r\nCookie: id=1\r\n\r\n";
+        let headers = [
+            Header {
+                name: "Host",
+                value: b"radio",
+            },
+            Header {
+                name: "authorization",
+                value: b"Basic c2VjcmV0",
+            },
+            Header {
+                name: "Cookie",
+                value: b"id=1",
+            },
+        ];
+        let failure = FailedConnection::new("127.0.0.1:1234", "test", &headers, request);
+
+        assert_eq!(
+            failure.headers,
+            [
+                ("Host".to_string(), "radio".to_string()),
+                ("authorization".to_string(), REDACTED.to_string()),
+                ("Cookie".to_string(), REDACTED.to_string()),
+            ]
+        );
+        assert_eq!(
+            failure.request,
+            "GET /live HTTP/1.1\r\nHost: radio\r\nauthorization: <redacted>\r\nCookie: <redacted>\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn truncated_credentials_are_redacted() {
+        assert_eq!(
+            redact_request("GET / HTTP/1.1\r\nProxy-Authorization: Basic c2Vj"),
+            "GET / HTTP/1.1\r\nProxy-Authorization: <redacted>"
+        );
+    }
+}
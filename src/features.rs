@@ -0,0 +1,30 @@
+//! Optional subsystems, which can be left out of the build with cargo
+//! features to keep the binary small.
+
+use crate::config::MountConfig;
+
+/// Whether EBU R128 loudness measurement is compiled in
+pub const LOUDNESS: bool = cfg!(feature = "loudness");
+/// Whether acoustic fingerprinting is compiled in
+pub const FINGERPRINT: bool = cfg!(feature = "fingerprint");
+
+/// The features that are compiled in
+pub fn enabled() -> Vec<&'static str> {
+    [("loudness", LOUDNESS), ("fingerprint", FINGERPRINT)]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name)
+        .collect()
+}
+
+/// The features that `config` uses, but that are not compiled in
+pub fn missing(config: &MountConfig) -> Vec<&'static str> {
+    let mut missing = Vec::new();
+    if config.loudness_target.is_some() && !LOUDNESS {
+        missing.push("loudness");
+    }
+    if config.fingerprint.is_some() && !FINGERPRINT {
+        missing.push("fingerprint");
+    }
+    missing
+}
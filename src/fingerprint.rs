@@ -0,0 +1,198 @@
+//! Acoustic fingerprinting of mounts, so that the tracks that are played
+//! can be identified (e.g. through AcoustID) for royalty reporting, even
+//! if the encoder does not send any metadata.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "fingerprint")]
+pub use fingerprinter::spawn;
+
+const DEFAULT_INTERVAL_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FingerprintConfig {
+    /// The length of the audio that each fingerprint covers, in seconds.
+    /// Defaults to 30 seconds.
+    pub interval_secs: Option<u64>,
+    /// `POST` every fingerprint as JSON to this `http://` URL
+    pub forward_url: Option<String>,
+}
+
+impl FingerprintConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            interval_secs: Some(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            ..self
+        }
+    }
+}
+
+/// A fingerprint of an interval of the audio of a mount
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The time at which the interval ended
+    pub time: String,
+    /// The length of the interval, in seconds
+    pub duration: u64,
+    /// The compressed, base64url-encoded Chromaprint fingerprint
+    pub fingerprint: String,
+}
+
+#[cfg(feature = "fingerprint")]
+mod fingerprinter {
+    use std::{sync::Arc, time::SystemTime};
+
+    use b64::ToBase64;
+    use rusty_chromaprint::{Configuration, FingerprintCompressor, Fingerprinter};
+    use serde::Serialize;
+    use symphonia::core::audio::SignalSpec;
+    use tokio::runtime::Handle;
+    use tracing::{debug, warn};
+
+    use super::{Fingerprint, DEFAULT_INTERVAL_SECS};
+    use crate::{
+        decode::{self, Analyzer},
+        net,
+        state::{DataSender, Mount},
+    };
+
+    #[derive(Serialize)]
+    struct ForwardedFingerprint<'a> {
+        mount: &'a str,
+        #[serde(flatten)]
+        fingerprint: &'a Fingerprint,
+    }
+
+    /// Start fingerprinting `mount`, if it is configured to be fingerprinted.
+    /// Fingerprinting stops once the source that sends to `data_tx`
+    /// disconnects.
+    pub fn spawn(mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender) {
+        let config = if let Some(config) = mount.fingerprint_config() {
+            config.clone()
+        } else {
+            return;
+        };
+
+        let name = mount_name.to_string();
+        let fingerprinted = mount.clone();
+        let runtime = Handle::current();
+        decode::spawn(mount_name, mount, data_tx, move || IntervalFingerprinter {
+            name,
+            mount: fingerprinted,
+            interval: config.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1),
+            forward_url: config.forward_url,
+            runtime,
+            configuration: Configuration::preset_test2(),
+            current: None,
+            samples: Vec::new(),
+        });
+    }
+
+    struct IntervalFingerprinter {
+        name: String,
+        mount: Arc<Mount>,
+        interval: u64,
+        forward_url: Option<String>,
+        runtime: Handle,
+        configuration: Configuration,
+        /// The fingerprinter for the current interval, the format of the
+        /// audio it was started for, and the amount of frames it consumed
+        current: Option<(Fingerprinter, SignalSpec, u64)>,
+        samples: Vec<i16>,
+    }
+
+    impl Analyzer for IntervalFingerprinter {
+        const NAME: &'static str = "Fingerprinting";
+
+        fn process(&mut self, samples: &[f32], spec: SignalSpec) {
+            if self
+                .current
+                .as_ref()
+                .map(|(_, s, _)| *s != spec)
+                .unwrap_or(true)
+            {
+                let mut fingerprinter = Fingerprinter::new(&self.configuration);
+                if fingerprinter
+                    .start(spec.rate, spec.channels.count() as u32)
+                    .is_err()
+                {
+                    return;
+                }
+                self.current = Some((fingerprinter, spec, 0));
+            }
+
+            self.samples.clear();
+            self.samples.extend(
+                samples
+                    .iter()
+                    .map(|s| (s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16),
+            );
+
+            if let Some((fingerprinter, _, frames)) = &mut self.current {
+                fingerprinter.consume(&self.samples);
+                *frames += (samples.len() / spec.channels.count().max(1)) as u64;
+
+                if *frames >= spec.rate as u64 * self.interval {
+                    self.emit();
+                }
+            }
+        }
+
+        fn finish(&mut self) {
+            self.emit();
+        }
+    }
+
+    impl IntervalFingerprinter {
+        fn emit(&mut self) {
+            let (mut fingerprinter, spec, frames) = if let Some(current) = self.current.take() {
+                current
+            } else {
+                return;
+            };
+
+            fingerprinter.finish();
+            let raw = fingerprinter.fingerprint();
+            if raw.is_empty() {
+                return;
+            }
+
+            let fingerprint = Fingerprint {
+                time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+                duration: frames / spec.rate.max(1) as u64,
+                fingerprint: FingerprintCompressor::from(&self.configuration)
+                    .compress(raw)
+                    .to_base64(b64::URL_SAFE),
+            };
+            debug!(
+                "Computed a fingerprint of {} seconds of mount {}",
+                fingerprint.duration, self.name
+            );
+
+            if let Some(url) = &self.forward_url {
+                let body = serde_json::to_vec(&ForwardedFingerprint {
+                    mount: &self.name,
+                    fingerprint: &fingerprint,
+                })
+                .expect("Fingerprints can always be serialized");
+                let url = url.clone();
+                let name = self.name.clone();
+
+                self.runtime.spawn(async move {
+                    match net::post(&url, "application/json", &body).await {
+                        Ok(response) if response.is_success() => {}
+                        Ok(response) => warn!(
+                            "Forwarding a fingerprint of mount {} failed with status {}",
+                            name, response.status
+                        ),
+                        Err(e) => warn!("Could not forward a fingerprint of mount {}: {}", name, e),
+                    }
+                });
+            }
+
+            self.mount.set_fingerprint(Some(fingerprint));
+        }
+    }
+}
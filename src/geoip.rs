@@ -0,0 +1,64 @@
+//! Looking up the country of listeners in a MaxMind GeoIP2 (or GeoLite2)
+//! country or city database, so that mounts can be restricted to some
+//! countries, e.g. for streams that are only licensed in some regions.
+
+use std::{net::IpAddr, path::Path};
+
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+
+/// The countries from which listeners may subscribe to a mount, by their
+/// ISO 3166-1 alpha-2 codes, e.g. `["NL", "BE"]`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CountryRules {
+    /// If not empty, only listeners in these countries are allowed. This
+    /// rejects listeners whose country is not known.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Listeners in these countries are rejected
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl CountryRules {
+    /// Whether a listener in `country` is allowed
+    pub fn allows(&self, country: Option<&str>) -> bool {
+        let listed = |codes: &[String]| {
+            country
+                .map(|country| codes.iter().any(|code| code.eq_ignore_ascii_case(country)))
+                .unwrap_or(false)
+        };
+
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    /// Load the database at `path`
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let reader = Reader::open_readfile(path).map_err(|e| e.to_string())?;
+        Ok(Self { reader })
+    }
+
+    /// The ISO 3166-1 alpha-2 code of the country of `ip`, if it is known
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let record: geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+        record
+            .country
+            .or(record.registered_country)
+            .and_then(|country| country.iso_code)
+            .map(String::from)
+    }
+}
@@ -0,0 +1,113 @@
+//! Types and logic for the Grafana JSON datasource API, which is served
+//! under `/api/v1/grafana`.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{history::StatsHistory, state::Stats};
+
+type Metric = (&'static str, fn(&Stats) -> usize);
+
+const METRICS: [Metric; 6] = [
+    ("listeners", |s| s.sub_count),
+    ("bytes_in", |s| s.bytes_in),
+    ("bytes_out", |s| s.bytes_out),
+    ("source_connects", |s| s.source_connects),
+    ("peak_listeners", |s| s.peak_listeners),
+    ("listener_connections", |s| s.listener_connections),
+];
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRequest {
+    range: Range,
+    targets: Vec<Target>,
+    #[serde(rename = "maxDataPoints")]
+    max_data_points: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Range {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Target {
+    target: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimeSeries {
+    target: String,
+    /// Pairs of value and timestamp in milliseconds since the UNIX epoch
+    datapoints: Vec<(usize, u128)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricOption {
+    label: String,
+    value: String,
+}
+
+/// All targets that can be queried, named `<mount>:<metric>`
+pub fn search(history: &StatsHistory) -> Vec<String> {
+    history
+        .mounts()
+        .iter()
+        .flat_map(|mount| METRICS.iter().map(move |(m, _)| format!("{}:{}", mount, m)))
+        .collect()
+}
+
+/// [`search`], in the format expected by newer versions of the datasource
+pub fn metrics(history: &StatsHistory) -> Vec<MetricOption> {
+    search(history)
+        .into_iter()
+        .map(|target| MetricOption {
+            label: target.clone(),
+            value: target,
+        })
+        .collect()
+}
+
+/// Answer a query, or `None` if the requested time range is invalid
+pub fn query(history: &StatsHistory, request: &QueryRequest) -> Option<Vec<TimeSeries>> {
+    let from = humantime::parse_rfc3339_weak(&request.range.from).ok()?;
+    let to = humantime::parse_rfc3339_weak(&request.range.to).ok()?;
+
+    let series = request
+        .targets
+        .iter()
+        .filter_map(|target| {
+            let (mount, metric) = target.target.rsplit_once(':')?;
+            let (_, value) = METRICS.iter().find(|(m, _)| *m == metric)?;
+
+            let samples = history.query(mount, from, to);
+            let step = request
+                .max_data_points
+                .filter(|max| *max > 0)
+                .map(|max| samples.len().div_ceil(max))
+                .unwrap_or(1)
+                .max(1);
+
+            let datapoints = samples
+                .iter()
+                .step_by(step)
+                .map(|sample| (value(&sample.stats), unix_millis(sample.time)))
+                .collect();
+
+            Some(TimeSeries {
+                target: target.target.clone(),
+                datapoints,
+            })
+        })
+        .collect();
+
+    Some(series)
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
@@ -0,0 +1,112 @@
+//! Zero-downtime restarts by handing the listening socket over to a new
+//! process.
+//!
+//! When the server receives `SIGUSR2`, it starts its (possibly upgraded)
+//! binary again with the same arguments, passing it the listening socket
+//! in the `PEROXIDECAST_LISTEN_FD` environment variable. The new process
+//! accepts connections on the same socket, so no connection attempt is
+//! refused, while the old process stops accepting and exits once its
+//! sources and listeners have disconnected.
+
+use std::{
+    io,
+    net::TcpListener,
+    process::{Child, Command},
+};
+
+/// The environment variable with the file descriptor of an inherited
+/// listening socket
+pub const LISTEN_FD_VAR: &str = "PEROXIDECAST_LISTEN_FD";
+
+/// Take the listening socket that was handed over by the previous
+/// process, if any
+#[cfg(unix)]
+pub fn inherited_listener() -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let fd: i32 = std::env::var(LISTEN_FD_VAR).ok()?.parse().ok()?;
+    std::env::remove_var(LISTEN_FD_VAR);
+
+    // Safety: the previous process passed this descriptor to us for
+    // exactly this purpose, and it is not used anywhere else
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    socket2::SockRef::from(&listener).set_cloexec(true).ok()?;
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Start this binary again with the same arguments, handing it
+/// `listener`
+#[cfg(unix)]
+pub fn spawn_successor(listener: &impl std::os::fd::AsRawFd) -> io::Result<Child> {
+    use std::os::fd::BorrowedFd;
+
+    let fd = listener.as_raw_fd();
+    // Safety: `listener` outlives this function
+    let socket = unsafe { BorrowedFd::borrow_raw(fd) };
+    socket2::SockRef::from(&socket).set_cloexec(false)?;
+
+    let child = Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_VAR, fd.to_string())
+        .spawn();
+
+    socket2::SockRef::from(&socket).set_cloexec(true)?;
+    child
+}
+
+#[cfg(not(unix))]
+pub fn spawn_successor<T>(_listener: &T) -> io::Result<Child> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Receives the requests to hand the listening socket over, i.e.
+/// `SIGUSR2`
+pub struct HandoverSignal {
+    #[cfg(unix)]
+    signal: Option<tokio::signal::unix::Signal>,
+}
+
+impl HandoverSignal {
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let signal = signal(SignalKind::user_defined2())
+                .map_err(|e| {
+                    tracing::warn!(
+                        "Could not listen for SIGUSR2, the server cannot be restarted without downtime: {}",
+                        e
+                    )
+                })
+                .ok();
+            Self { signal }
+        }
+
+        #[cfg(not(unix))]
+        Self {}
+    }
+
+    /// Wait for the next request
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+
+        std::future::pending().await
+    }
+}
+
+impl Default for HandoverSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::{api::ServerMetrics, state::Stats};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub time: SystemTime,
+    pub stats: Stats,
+}
+
+/// A bounded, in-memory history of the statistics of all mounts
+#[derive(Debug, Default)]
+pub struct StatsHistory {
+    mounts: Mutex<HashMap<String, VecDeque<Sample>>>,
+}
+
+impl StatsHistory {
+    /// Record the statistics of all mounts in `metrics`
+    pub fn record(&self, time: SystemTime, metrics: &ServerMetrics) {
+        let mut mounts = self.mounts.lock().unwrap();
+
+        for mount in &metrics.mounts {
+            mounts
+                .entry(mount.name.clone())
+                .or_default()
+                .push_back(Sample {
+                    time,
+                    stats: mount.stats,
+                });
+        }
+    }
+
+    /// Drop the samples that are older than `max_age`
+    pub fn prune(&self, max_age: Duration) {
+        let cutoff = SystemTime::now() - max_age;
+        self.mounts.lock().unwrap().retain(|_, samples| {
+            while samples.front().map(|s| s.time < cutoff).unwrap_or(false) {
+                samples.pop_front();
+            }
+            !samples.is_empty()
+        });
+    }
+
+    /// Drop all samples, returning the amount of samples that were dropped
+    pub fn clear(&self) -> usize {
+        let mut mounts = self.mounts.lock().unwrap();
+        let dropped = mounts.values().map(|s| s.len()).sum();
+        mounts.clear();
+        dropped
+    }
+
+    /// The names of all mounts for which samples are available
+    pub fn mounts(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.mounts.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// All samples for `mount` that were taken between `from` and `to`
+    pub fn query(&self, mount: &str, from: SystemTime, to: SystemTime) -> Vec<Sample> {
+        self.mounts
+            .lock()
+            .unwrap()
+            .get(mount)
+            .map(|samples| {
+                samples
+                    .iter()
+                    .filter(|s| s.time >= from && s.time <= to)
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,191 @@
+//! Persistence of the statistics history of all mounts in a local SQLite
+//! database, so that it survives restarts of the server.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    api::ServerMetrics,
+    history::Sample,
+    state::{State, Stats},
+};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryDbConfig {
+    /// The SQLite database file, which is created if it does not exist
+    pub path: PathBuf,
+    /// The interval at which the statistics of all mounts are stored, in
+    /// seconds. Defaults to 60 seconds.
+    pub interval_secs: Option<u64>,
+    /// How long stored statistics are kept, in seconds. By default, they
+    /// are kept forever.
+    pub retention_secs: Option<u64>,
+}
+
+impl HistoryDbConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            interval_secs: Some(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            ..self
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1))
+    }
+
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention_secs.map(Duration::from_secs)
+    }
+}
+
+/// A statistics history that is stored in a SQLite database
+#[derive(Debug)]
+pub struct HistoryDb {
+    connection: Mutex<Connection>,
+}
+
+impl HistoryDb {
+    /// Open the database at `path`, creating it if necessary
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS samples (
+                time INTEGER NOT NULL,
+                mount TEXT NOT NULL,
+                listeners INTEGER NOT NULL,
+                bytes_in INTEGER NOT NULL,
+                bytes_out INTEGER NOT NULL,
+                source_connects INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS samples_mount_time ON samples (mount, time);",
+        )?;
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Store the statistics of all mounts in `metrics`
+    pub fn record(&self, time: SystemTime, metrics: &ServerMetrics) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT INTO samples (time, mount, listeners, bytes_in, bytes_out, source_connects)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for mount in &metrics.mounts {
+                insert.execute(params![
+                    unix_secs(time),
+                    mount.name,
+                    mount.stats.sub_count as i64,
+                    mount.stats.bytes_in as i64,
+                    mount.stats.bytes_out as i64,
+                    mount.stats.source_connects as i64,
+                ])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Remove the samples that are older than `max_age`, returning the
+    /// amount of samples that were removed
+    pub fn prune(&self, max_age: Duration) -> rusqlite::Result<usize> {
+        let cutoff = unix_secs(SystemTime::now() - max_age);
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM samples WHERE time < ?1", params![cutoff])
+    }
+
+    /// Remove all samples, returning the amount of samples that were
+    /// removed
+    pub fn clear(&self) -> rusqlite::Result<usize> {
+        self.connection
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM samples", [])
+    }
+
+    /// All samples for `mount` that were taken between `from` and `to`
+    pub fn query(
+        &self,
+        mount: &str,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> rusqlite::Result<Vec<Sample>> {
+        let connection = self.connection.lock().unwrap();
+        let mut select = connection.prepare_cached(
+            "SELECT time, listeners, bytes_in, bytes_out, source_connects FROM samples
+            WHERE mount = ?1 AND time >= ?2 AND time <= ?3 ORDER BY time",
+        )?;
+
+        let samples = select
+            .query_map(params![mount, unix_secs(from), unix_secs(to)], |row| {
+                Ok(Sample {
+                    time: UNIX_EPOCH + Duration::from_secs(row.get::<_, i64>(0)?.max(0) as u64),
+                    stats: Stats {
+                        sub_count: row.get::<_, i64>(1)? as usize,
+                        bytes_in: row.get::<_, i64>(2)? as usize,
+                        bytes_out: row.get::<_, i64>(3)? as usize,
+                        source_connects: row.get::<_, i64>(4)? as usize,
+                        ..Stats::new()
+                    },
+                })
+            })?
+            .collect();
+        samples
+    }
+}
+
+/// Start storing the statistics of all mounts in `state` in its history
+/// database
+pub fn spawn(config: HistoryDbConfig, state: Arc<State>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval());
+        loop {
+            interval.tick().await;
+
+            let state = state.clone();
+            let retention = config.retention();
+            let result = tokio::task::spawn_blocking(move || {
+                let Some(db) = state.history_db() else {
+                    return Ok(());
+                };
+                db.record(SystemTime::now(), &ServerMetrics::from_state(&state))?;
+                if let Some(retention) = retention {
+                    let removed = db.prune(retention)?;
+                    if removed > 0 {
+                        debug!("Removed {} samples from the history database", removed);
+                    }
+                }
+                Ok::<_, rusqlite::Error>(())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Could not store statistics in the history database: {}", e),
+                Err(e) => warn!("Storing statistics in the history database failed: {}", e),
+            }
+        }
+    });
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
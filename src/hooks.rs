@@ -0,0 +1,129 @@
+//! Commands that are run when a source connects to or disconnects from a
+//! mount, like Icecast's `on-connect` and `on-disconnect` scripts.
+//!
+//! The command is passed the name of the mount as its only argument, and
+//! details about the mount in the environment:
+//!
+//! * `PEROXIDECAST_EVENT`: `source_connected` or `source_disconnected`
+//! * `PEROXIDECAST_MOUNT`: the name of the mount
+//! * `PEROXIDECAST_CONTENT_TYPE`: the content type of the mount
+//! * `PEROXIDECAST_SONG`: the current song, if any
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+};
+
+use tokio::{process::Command, sync::broadcast::error::RecvError};
+use tracing::{debug, warn};
+
+use crate::{config::MountConfig, events::EventKind, state::State};
+
+#[derive(Debug, Clone, Default)]
+struct MountHooks {
+    on_connect: Option<PathBuf>,
+    on_disconnect: Option<PathBuf>,
+}
+
+/// Start running the commands configured in `mounts` when sources
+/// connect or disconnect
+pub fn spawn(mounts: &BTreeMap<String, MountConfig>, state: Arc<State>) {
+    let hooks: BTreeMap<String, MountHooks> = mounts
+        .iter()
+        .filter(|(_, config)| config.on_connect.is_some() || config.on_disconnect.is_some())
+        .map(|(name, config)| {
+            let hooks = MountHooks {
+                on_connect: config.on_connect.clone(),
+                on_disconnect: config.on_disconnect.clone(),
+            };
+            (name.clone(), hooks)
+        })
+        .collect();
+
+    if hooks.is_empty() {
+        return;
+    }
+
+    let mut events = state.events().subscribe();
+    tokio::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} events, some mount commands were not run", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let (mount_name, command) = match &event.kind {
+                EventKind::SourceConnected { mount } => {
+                    (mount, hooks.get(mount).and_then(|h| h.on_connect.as_ref()))
+                }
+                EventKind::SourceDisconnected { mount } => (
+                    mount,
+                    hooks.get(mount).and_then(|h| h.on_disconnect.as_ref()),
+                ),
+                _ => continue,
+            };
+
+            if let Some(command) = command {
+                run(command, event.kind.name(), mount_name, &state);
+            }
+        }
+    });
+}
+
+/// Run `command` for `event` on mount `mount_name`, without waiting for
+/// it to finish
+fn run(command: &Path, event: &str, mount_name: &str, state: &State) {
+    let mount = state.find_mount(mount_name);
+    let content_type = mount.as_ref().map(|m| m.content_type()).unwrap_or_default();
+    let song = mount.as_ref().and_then(|m| m.song()).unwrap_or_default();
+
+    let child = Command::new(command)
+        .arg(mount_name)
+        .env("PEROXIDECAST_EVENT", event)
+        .env("PEROXIDECAST_MOUNT", mount_name)
+        .env("PEROXIDECAST_CONTENT_TYPE", content_type)
+        .env("PEROXIDECAST_SONG", song)
+        .stdin(Stdio::null())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            warn!(
+                "Could not run {} for mount {}: {}",
+                command.display(),
+                mount_name,
+                e
+            );
+            return;
+        }
+    };
+
+    let command = command.to_path_buf();
+    let mount_name = mount_name.to_string();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => {
+                debug!("{} for mount {} finished", command.display(), mount_name)
+            }
+            Ok(status) => warn!(
+                "{} for mount {} failed: {}",
+                command.display(),
+                mount_name,
+                status
+            ),
+            Err(e) => warn!(
+                "Could not wait for {} for mount {}: {}",
+                command.display(),
+                mount_name,
+                e
+            ),
+        }
+    });
+}
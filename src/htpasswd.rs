@@ -0,0 +1,291 @@
+//! Credentials stored in an Apache `htpasswd` file, with one
+//! `user:hash` entry per line.
+//!
+//! Supported are bcrypt (`$2y$`, `htpasswd -B`), Argon2 (`$argon2id$`),
+//! Apache's MD5 (`$apr1$`, the default of `htpasswd`), MD5-crypt (`$1$`),
+//! SHA-1 (`{SHA}`, `htpasswd -s`) and plain text (`htpasswd -p`)
+//! entries. The file is reloaded whenever it changes.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::SystemTime,
+};
+
+use b64::ToBase64;
+use md5::{Digest, Md5};
+use sha1::Sha1;
+use tracing::{info, warn};
+
+use crate::auth;
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// The modification time of the file when it was loaded
+    modified: Option<SystemTime>,
+    /// The hash of the password of every user
+    users: HashMap<String, String>,
+    /// `Authorization` values that were verified since the file was
+    /// loaded, so that slow hashes are only computed once
+    verified: HashSet<String>,
+    /// Whether the last attempt to load the file failed
+    failed: bool,
+}
+
+#[derive(Debug)]
+pub struct Htpasswd {
+    path: PathBuf,
+    entries: RwLock<Entries>,
+}
+
+impl Htpasswd {
+    /// Use the credentials in the file at `path`. If the file cannot be
+    /// read, nobody is authorized until it can.
+    pub fn open(path: &Path) -> Self {
+        let htpasswd = Self {
+            path: path.to_path_buf(),
+            entries: RwLock::default(),
+        };
+        htpasswd.reload_if_changed();
+        htpasswd
+    }
+
+    fn reload_if_changed(&self) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified == self.entries.read().unwrap().modified {
+            return;
+        }
+
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let users: HashMap<String, String> = contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .filter_map(|line| line.split_once(':'))
+                    .map(|(user, hash)| (user.to_string(), hash.to_string()))
+                    .collect();
+                info!("Loaded {} users from {}", users.len(), self.path.display());
+                *self.entries.write().unwrap() = Entries {
+                    modified,
+                    users,
+                    verified: HashSet::new(),
+                    failed: false,
+                };
+            }
+            Err(e) => {
+                let mut entries = self.entries.write().unwrap();
+                // Only complain once about a file that went missing
+                if !entries.failed {
+                    warn!("Could not read {}: {}", self.path.display(), e);
+                }
+                *entries = Entries {
+                    failed: true,
+                    ..Entries::default()
+                };
+            }
+        }
+    }
+
+    /// Whether the value of an `Authorization` header carries `Basic`
+    /// credentials of a user in the file
+    pub fn verify(&self, authorization: &str) -> bool {
+        self.reload_if_changed();
+
+        if self
+            .entries
+            .read()
+            .unwrap()
+            .verified
+            .contains(authorization)
+        {
+            return true;
+        }
+
+        let (user, password) = match auth::basic_credentials(authorization) {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        let hash = match self.entries.read().unwrap().users.get(&user) {
+            Some(hash) => hash.clone(),
+            None => return false,
+        };
+
+        if verify_hash(&password, &hash) {
+            self.entries
+                .write()
+                .unwrap()
+                .verified
+                .insert(authorization.to_string());
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if auth::is_password_hash(hash) {
+        auth::verify_password(password, hash)
+    } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+        auth::secrets_equal(&md5_crypt(password, salt(rest), "$apr1$"), hash)
+    } else if let Some(rest) = hash.strip_prefix("$1$") {
+        auth::secrets_equal(&md5_crypt(password, salt(rest), "$1$"), hash)
+    } else if let Some(digest) = hash.strip_prefix("{SHA}") {
+        auth::secrets_equal(
+            &Sha1::digest(password.as_bytes()).to_base64(b64::STANDARD),
+            digest,
+        )
+    } else {
+        auth::secrets_equal(password, hash)
+    }
+}
+
+/// The salt of an MD5-crypt hash, given the hash without its magic prefix
+fn salt(rest: &str) -> &str {
+    let salt = rest.split('$').next().unwrap_or_default();
+    &salt[..salt.len().min(8)]
+}
+
+/// The MD5-crypt hash of `password`, as invented by Poul-Henning Kamp.
+/// Apache's `$apr1$` variant only differs in its `magic` prefix.
+fn md5_crypt(password: &str, salt: &str, magic: &str) -> String {
+    const ITOA64: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+    let password = password.as_bytes();
+    let salt_bytes = salt.as_bytes();
+
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt_bytes)
+        .chain_update(password)
+        .finalize();
+
+    let mut context = Md5::new()
+        .chain_update(password)
+        .chain_update(magic.as_bytes())
+        .chain_update(salt_bytes);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+
+    // Slow down brute forcing
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt_bytes);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    let mut out = format!("{}{}$", magic, salt);
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            out.push(ITOA64[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    let d = |i: usize| digest[i] as u32;
+    push((d(0) << 16) | (d(6) << 8) | d(12), 4);
+    push((d(1) << 16) | (d(7) << 8) | d(13), 4);
+    push((d(2) << 16) | (d(8) << 8) | d(14), 4);
+    push((d(3) << 16) | (d(9) << 8) | d(15), 4);
+    push((d(4) << 16) | (d(10) << 8) | d(5), 4);
+    push(d(11), 2);
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APR1: &str = "$apr1$r31....$0CvtbaJxv3SVfI4hUsLQI/";
+    const MD5: &str = "$1$saltsalt$wbqibtPfZGh6mU/qSPBFw/";
+    const SHA: &str = "{SHA}zJWX0x8FA73tXfMQ618o+01J+w8=";
+
+    #[test]
+    fn md5_crypt_matches_htpasswd_and_openssl() {
+        assert_eq!(md5_crypt("hackme", "r31....", "$apr1$"), APR1);
+        assert_eq!(md5_crypt("hackme", "saltsalt", "$1$"), MD5);
+    }
+
+    #[test]
+    fn verify_hash_formats() {
+        let bcrypt = bcrypt::hash("hackme", 4).unwrap();
+        for hash in [APR1, MD5, SHA, bcrypt.as_str(), "hackme"] {
+            assert!(verify_hash("hackme", hash), "{}", hash);
+            assert!(!verify_hash("wrong", hash), "{}", hash);
+        }
+        // A plain text entry is not mistaken for a hash of itself
+        assert!(!verify_hash(SHA, SHA.trim_start_matches("{SHA}")));
+    }
+
+    #[test]
+    fn salt_is_cut_at_eight_characters() {
+        assert_eq!(salt("r31....$0Cvt"), "r31....");
+        assert_eq!(salt("saltsaltsalt$wbq"), "saltsalt");
+        assert_eq!(salt(""), "");
+    }
+
+    #[test]
+    fn verify_users_in_file() {
+        let path =
+            std::env::temp_dir().join(format!("peroxidecast-htpasswd-{}", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "# comment\n\n  dj:{}\nmd5:{}\nsha:{}\nplain:hackme\nbroken\n",
+                APR1, MD5, SHA
+            ),
+        )
+        .unwrap();
+        let htpasswd = Htpasswd::open(&path);
+
+        for user in ["dj", "md5", "sha", "plain"] {
+            assert!(
+                htpasswd.verify(&auth::basic_authorization(user, "hackme")),
+                "{}",
+                user
+            );
+            assert!(
+                !htpasswd.verify(&auth::basic_authorization(user, "wrong")),
+                "{}",
+                user
+            );
+        }
+        assert!(!htpasswd.verify(&auth::basic_authorization("broken", "")));
+        assert!(!htpasswd.verify(&auth::basic_authorization("nobody", "hackme")));
+        assert!(!htpasswd.verify("Bearer hackme"));
+
+        fs::remove_file(&path).unwrap();
+        let missing = Htpasswd::open(&path);
+        assert!(!missing.verify(&auth::basic_authorization("dj", "hackme")));
+    }
+}
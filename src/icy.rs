@@ -0,0 +1,104 @@
+//! Interleaving of ICY metadata with the data sent to listeners that
+//! ask for it with `Icy-MetaData: 1`.
+
+/// The amount of data bytes between two metadata blocks
+pub const METAINT: usize = 16000;
+
+/// The maximum length of the contents of a metadata block
+const MAX_BLOCK_LEN: usize = 255 * 16;
+
+/// Inserts a metadata block after every [`METAINT`] bytes of data
+#[derive(Debug)]
+pub struct IcyMuxer {
+    /// The amount of data bytes until the next metadata block
+    until_meta: usize,
+    /// The song and song URL that were announced last
+    song: (Option<String>, Option<String>),
+}
+
+impl Default for IcyMuxer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IcyMuxer {
+    pub fn new() -> Self {
+        Self {
+            until_meta: METAINT,
+            song: (None, None),
+        }
+    }
+
+    /// Append the data in `input` to `out`, interleaved with metadata
+    /// blocks.
+    ///
+    /// A block announces the next message returned by `message`, if there
+    /// is one. Otherwise, it announces the title and URL returned by `song`
+    /// if they changed since they were last announced, so that a message
+    /// is shown until the next song starts.
+    pub fn push(
+        &mut self,
+        mut input: &[u8],
+        song: impl Fn() -> (Option<String>, Option<String>),
+        mut message: impl FnMut() -> Option<String>,
+        out: &mut Vec<u8>,
+    ) {
+        while !input.is_empty() {
+            let len = self.until_meta.min(input.len());
+            out.extend_from_slice(&input[..len]);
+            input = &input[len..];
+            self.until_meta -= len;
+
+            if self.until_meta == 0 {
+                self.until_meta = METAINT;
+
+                if let Some(message) = message() {
+                    Self::write_block(Some(&message), None, out);
+                    continue;
+                }
+
+                let song = song();
+                if song != self.song {
+                    Self::write_block(
+                        Some(song.0.as_deref().unwrap_or("")),
+                        song.1.as_deref(),
+                        out,
+                    );
+                    self.song = song;
+                } else {
+                    Self::write_block(None, None, out);
+                }
+            }
+        }
+    }
+
+    /// Write a metadata block announcing `title` (and `url`), or an empty
+    /// block
+    fn write_block(title: Option<&str>, url: Option<&str>, out: &mut Vec<u8>) {
+        let title = if let Some(title) = title {
+            title
+        } else {
+            out.push(0);
+            return;
+        };
+
+        let url = url
+            .map(|url| format!("StreamUrl='{}';", url.replace(['\r', '\n', '\''], "")))
+            .filter(|url| url.len() <= MAX_BLOCK_LEN / 2)
+            .unwrap_or_default();
+
+        let title = title.replace(['\r', '\n'], " ");
+        let mut max_title_len = MAX_BLOCK_LEN - "StreamTitle='';".len() - url.len();
+        while !title.is_char_boundary(max_title_len.min(title.len())) {
+            max_title_len -= 1;
+        }
+        let title = &title[..max_title_len.min(title.len())];
+
+        let mut block = format!("StreamTitle='{}';{}", title, url).into_bytes();
+        block.resize(block.len().div_ceil(16) * 16, 0);
+
+        out.push((block.len() / 16) as u8);
+        out.extend_from_slice(&block);
+    }
+}
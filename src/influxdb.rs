@@ -0,0 +1,157 @@
+//! Periodic export of the statistics of all mounts to InfluxDB, using the
+//! line protocol over HTTP.
+//!
+//! Every interval, one point is written per mount, tagged with the name of
+//! the mount, the global tags and the `influxdb_tags` of the mount.
+//! Timestamps are in nanoseconds, which is the default precision of both
+//! the v1 (`/write?db=...`) and v2 (`/api/v2/write?org=...&bucket=...`)
+//! write endpoints.
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{net, state::State};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+const DEFAULT_MEASUREMENT: &str = "peroxidecast_mount";
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InfluxDbConfig {
+    /// The `http://` URL of the write endpoint, including the database or
+    /// the organization and bucket
+    pub url: String,
+    /// The API token, sent as `Authorization: Token <token>`
+    pub token: Option<String>,
+    /// The measurement that points are written to. Defaults to
+    /// `peroxidecast_mount`.
+    pub measurement: Option<String>,
+    /// The interval at which points are written, in seconds. Defaults to
+    /// 60 seconds.
+    pub interval_secs: Option<u64>,
+    /// Tags to add to the points of all mounts
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl InfluxDbConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            measurement: Some(
+                self.measurement
+                    .unwrap_or_else(|| DEFAULT_MEASUREMENT.to_string()),
+            ),
+            interval_secs: Some(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            ..self
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1))
+    }
+
+    pub fn measurement(&self) -> &str {
+        self.measurement.as_deref().unwrap_or(DEFAULT_MEASUREMENT)
+    }
+}
+
+/// Start writing the statistics of all mounts in `state`
+pub fn spawn(config: InfluxDbConfig, state: Arc<State>) {
+    tokio::spawn(run(config, state));
+}
+
+async fn run(config: InfluxDbConfig, state: Arc<State>) {
+    let mut interval = tokio::time::interval(config.interval());
+    let authorization = config.token.as_ref().map(|t| format!("Token {}", t));
+
+    loop {
+        interval.tick().await;
+
+        let body = lines(&config, &state, SystemTime::now());
+        if body.is_empty() {
+            continue;
+        }
+
+        let headers: Vec<_> = authorization
+            .iter()
+            .map(|a| ("Authorization", a.as_str()))
+            .collect();
+        match net::post_with_headers(&config.url, "text/plain", &headers, body.as_bytes()).await {
+            Ok(response) if response.is_success() => {
+                debug!("Wrote {} points to InfluxDB", body.lines().count())
+            }
+            Ok(response) => warn!(
+                "Writing to InfluxDB at {} failed with status {}: {}",
+                config.url,
+                response.status,
+                String::from_utf8_lossy(&response.body).trim()
+            ),
+            Err(e) => warn!("Could not write to InfluxDB at {}: {}", config.url, e),
+        }
+    }
+}
+
+/// The points of all mounts at `time`, in line protocol
+fn lines(config: &InfluxDbConfig, state: &State, time: SystemTime) -> String {
+    let timestamp = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for (name, mount) in state.mounts() {
+        let stats = mount.stats();
+
+        let mut tags = config.tags.clone();
+        tags.extend(mount.influxdb_tags().clone());
+        tags.insert("mount".to_string(), name);
+
+        out.push_str(&escape(config.measurement(), &[',', ' ']));
+        for (key, value) in &tags {
+            if value.is_empty() {
+                continue;
+            }
+            out.push(',');
+            out.push_str(&escape(key, &[',', '=', ' ']));
+            out.push('=');
+            out.push_str(&escape(value, &[',', '=', ' ']));
+        }
+
+        out.push_str(&format!(
+            " listeners={}i,listener_peak={}i,on_air={},bytes_in={}i,bytes_out={}i,source_connects={}i",
+            stats.sub_count,
+            mount.stats_handle().subscribers().peak(),
+            mount.is_connected(),
+            stats.bytes_in,
+            stats.bytes_out,
+            stats.source_connects,
+        ));
+        if let Some(loudness) = mount.loudness().filter(|l| l.is_finite()) {
+            out.push_str(&format!(",loudness={}", loudness));
+        }
+
+        out.push_str(&format!(" {}\n", timestamp));
+    }
+
+    out
+}
+
+/// Escape `special` characters in a measurement, tag key or tag value
+fn escape(value: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
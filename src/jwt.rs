@@ -0,0 +1,410 @@
+//! Authentication of listeners and sources with JSON Web Tokens issued by
+//! an identity provider, as an alternative to the credentials configured
+//! for mounts.
+//!
+//! Tokens are sent as `Authorization: Bearer <token>`. Their signature is
+//! checked against a shared secret, a public key, or the keys published
+//! at a JWKS URL, and their expiry, issuer and audience are validated.
+//! What a token grants is taken from its space-separated `scope` claim:
+//!
+//! * `listen:<mount>`: subscribe to `<mount>`
+//! * `source:<mount>`: send to `<mount>`, creating it if necessary
+//!
+//! where `<mount>` may be `*` for all mounts.
+//!
+//! Decisions are cached per token, scope and mount (but never beyond the
+//! expiry of the token), so that reconnecting players do not have their
+//! token verified for every connection.
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{auth::DecisionCache, net, state::State};
+
+const DEFAULT_JWKS_REFRESH_SECS: u64 = 3600;
+const DEFAULT_CACHE_SECS: u64 = 60;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JwtConfig {
+    /// The secret of tokens signed with HMAC (`HS256` and the like)
+    pub secret: Option<String>,
+    /// A PEM file with the RSA, EC or Ed25519 public key of the issuer
+    pub public_key: Option<PathBuf>,
+    /// The `https://` URL of the JSON Web Key Set of the issuer. Plain
+    /// `http://` is refused, as it would let anyone on the path inject
+    /// keys and mint tokens.
+    pub jwks_url: Option<String>,
+    /// How often the key set is fetched again, in seconds. Defaults to
+    /// an hour.
+    pub jwks_refresh_secs: Option<u64>,
+    /// The issuer (`iss`) that tokens must have
+    pub issuer: Option<String>,
+    /// The audience (`aud`) that tokens must have
+    pub audience: Option<String>,
+    /// How long a decision about a token is remembered, in seconds.
+    /// Defaults to 60 seconds.
+    pub cache_secs: Option<u64>,
+}
+
+impl JwtConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            jwks_refresh_secs: Some(self.jwks_refresh_secs.unwrap_or(DEFAULT_JWKS_REFRESH_SECS)),
+            cache_secs: Some(self.cache_secs.unwrap_or(DEFAULT_CACHE_SECS)),
+            ..self
+        }
+    }
+
+    pub fn jwks_refresh(&self) -> Duration {
+        Duration::from_secs(
+            self.jwks_refresh_secs
+                .unwrap_or(DEFAULT_JWKS_REFRESH_SECS)
+                .max(1),
+        )
+    }
+
+    pub fn cache(&self) -> Duration {
+        Duration::from_secs(self.cache_secs.unwrap_or(DEFAULT_CACHE_SECS))
+    }
+}
+
+/// What a token may be used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scope {
+    Listen,
+    Source,
+}
+
+impl Scope {
+    fn prefix(&self) -> &'static str {
+        match self {
+            Scope::Listen => "listen:",
+            Scope::Source => "source:",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[serde(default)]
+    scope: String,
+    exp: Option<u64>,
+}
+
+/// The kinds of keys, which must match the algorithm of a token so that
+/// e.g. a public key cannot be used as an HMAC secret
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyKind {
+    Hmac,
+    Rsa,
+    Ec,
+    Ed,
+}
+
+impl KeyKind {
+    fn of(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => KeyKind::Hmac,
+            Algorithm::ES256 | Algorithm::ES384 => KeyKind::Ec,
+            Algorithm::EdDSA => KeyKind::Ed,
+            _ => KeyKind::Rsa,
+        }
+    }
+}
+
+pub struct JwtVerifier {
+    config: JwtConfig,
+    keys: Vec<(KeyKind, DecodingKey)>,
+    jwks: RwLock<JwkSet>,
+    cache: DecisionCache<(String, Scope, String)>,
+}
+
+impl std::fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtVerifier")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtVerifier {
+    /// Set up verification with the secret and public key in `config`.
+    /// The key set at `jwks_url` is fetched by [`spawn`].
+    pub fn new(config: JwtConfig) -> Result<Self, String> {
+        if let Some(url) = &config.jwks_url {
+            if !url.starts_with("https://") {
+                return Err(format!("jwks_url {:?} does not start with `https://`", url));
+            }
+        }
+
+        let mut keys = Vec::new();
+        if let Some(secret) = &config.secret {
+            keys.push((KeyKind::Hmac, DecodingKey::from_secret(secret.as_bytes())));
+        }
+        if let Some(path) = &config.public_key {
+            let pem = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            let key = DecodingKey::from_rsa_pem(&pem)
+                .map(|key| (KeyKind::Rsa, key))
+                .or_else(|_| DecodingKey::from_ec_pem(&pem).map(|key| (KeyKind::Ec, key)))
+                .or_else(|_| DecodingKey::from_ed_pem(&pem).map(|key| (KeyKind::Ed, key)))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            keys.push(key);
+        }
+
+        Ok(Self {
+            cache: DecisionCache::new(config.cache()),
+            config,
+            keys,
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        })
+    }
+
+    /// Whether the value of an `Authorization` header is a valid token
+    /// that grants `scope` on mount `mount_name`
+    pub fn allows(&self, authorization: &str, scope: Scope, mount_name: &str) -> bool {
+        let token = match authorization.trim().split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => token.trim(),
+            _ => return false,
+        };
+
+        let key = (token.to_string(), scope, mount_name.to_string());
+        if let Some(allowed) = self.cache.get(&key) {
+            return allowed;
+        }
+
+        match self.claims(token) {
+            Ok(claims) => {
+                let allowed = claims.scope.split_whitespace().any(|granted| {
+                    granted
+                        .strip_prefix(scope.prefix())
+                        .map(|mount| mount == "*" || mount == mount_name)
+                        .unwrap_or(false)
+                });
+                match claims.exp {
+                    Some(exp) => {
+                        let now = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        let left = Duration::from_secs(exp).saturating_sub(now);
+                        self.cache.insert_for(key, allowed, left);
+                    }
+                    None => self.cache.insert(key, allowed),
+                }
+                allowed
+            }
+            Err(e) => {
+                // Rejections are not cached, so that tokens are accepted
+                // as soon as the key set that they are signed with is
+                // fetched
+                debug!("Rejected a token for mount {}: {}", mount_name, e);
+                false
+            }
+        }
+    }
+
+    fn claims(&self, token: &str) -> Result<Claims, String> {
+        let header = decode_header(token).map_err(|e| e.to_string())?;
+        let kind = KeyKind::of(header.alg);
+
+        // Tokens without the configured issuer or audience are rejected,
+        // not only those with a different one
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.config.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.required_spec_claims.insert("iss".to_string());
+        }
+        match &self.config.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                validation.required_spec_claims.insert("aud".to_string());
+            }
+            None => validation.validate_aud = false,
+        }
+
+        let jwks = self.jwks.read().unwrap();
+        let jwk_keys = jwks
+            .keys
+            .iter()
+            .filter(|jwk| header.kid.is_none() || jwk.common.key_id == header.kid)
+            .filter_map(|jwk| {
+                let jwk_kind = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => KeyKind::Rsa,
+                    AlgorithmParameters::EllipticCurve(_) => KeyKind::Ec,
+                    AlgorithmParameters::OctetKeyPair(_) => KeyKind::Ed,
+                    AlgorithmParameters::OctetKey(_) => KeyKind::Hmac,
+                };
+                DecodingKey::from_jwk(jwk).ok().map(|key| (jwk_kind, key))
+            });
+
+        let mut last_error = "no key for the algorithm of the token".to_string();
+        for (_, key) in self
+            .keys
+            .iter()
+            .cloned()
+            .chain(jwk_keys)
+            .filter(|(key_kind, _)| *key_kind == kind)
+        {
+            match decode::<Claims>(token, &key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = e.to_string(),
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Fetch the key set at `jwks_url`
+    async fn refresh_jwks(&self, url: &str) -> Result<usize, String> {
+        let response = net::get(url).await.map_err(|e| e.to_string())?;
+        if !response.is_success() {
+            return Err(format!("status {}", response.status));
+        }
+        let jwks: JwkSet = serde_json::from_slice(&response.body).map_err(|e| e.to_string())?;
+        let count = jwks.keys.len();
+        *self.jwks.write().unwrap() = jwks;
+        Ok(count)
+    }
+}
+
+/// Start fetching the key set of the JWT verifier of `state`, if it has
+/// a JWKS URL
+pub fn spawn(state: Arc<State>) {
+    let url = match state.jwt().and_then(|jwt| jwt.config.jwks_url.clone()) {
+        Some(url) => url,
+        None => return,
+    };
+
+    tokio::spawn(async move {
+        loop {
+            let Some(jwt) = state.jwt() else {
+                return;
+            };
+            match jwt.refresh_jwks(&url).await {
+                Ok(count) => info!("Fetched {} keys from {}", count, url),
+                Err(e) => warn!("Could not fetch the keys at {}: {}", url, e),
+            }
+            tokio::time::sleep(jwt.config.jwks_refresh()).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(secret: &str, claims: serde_json::Value) -> String {
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap();
+        format!("Bearer {}", token)
+    }
+
+    fn verifier(issuer: Option<&str>, audience: Option<&str>) -> JwtVerifier {
+        JwtVerifier::new(JwtConfig {
+            secret: Some("secret".to_string()),
+            issuer: issuer.map(String::from),
+            audience: audience.map(String::from),
+            ..JwtConfig::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn scopes_grant_mounts() {
+        let verifier = verifier(None, None);
+        let exp = now() + 600;
+
+        let live = token("secret", json!({ "scope": "listen:/live", "exp": exp }));
+        assert!(verifier.allows(&live, Scope::Listen, "/live"));
+        assert!(!verifier.allows(&live, Scope::Listen, "/other"));
+        assert!(!verifier.allows(&live, Scope::Source, "/live"));
+
+        let all = token(
+            "secret",
+            json!({ "scope": "source:/a listen:*", "exp": exp }),
+        );
+        assert!(verifier.allows(&all, Scope::Listen, "/other"));
+        assert!(verifier.allows(&all, Scope::Source, "/a"));
+        assert!(!verifier.allows(&all, Scope::Source, "/b"));
+
+        let none = token("secret", json!({ "exp": exp }));
+        assert!(!verifier.allows(&none, Scope::Listen, "/live"));
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let verifier = verifier(None, None);
+        let claims = json!({ "scope": "listen:*", "exp": now() + 600 });
+
+        assert!(!verifier.allows(&token("other", claims.clone()), Scope::Listen, "/live"));
+        let expired = json!({ "scope": "listen:*", "exp": now() - 600 });
+        assert!(!verifier.allows(&token("secret", expired), Scope::Listen, "/live"));
+        let valid = token("secret", claims);
+        let basic = valid.replacen("Bearer", "Basic", 1);
+        assert!(!verifier.allows(&basic, Scope::Listen, "/live"));
+        assert!(!verifier.allows("Bearer not-a-token", Scope::Listen, "/live"));
+        assert!(!verifier.allows("Bearer", Scope::Listen, "/live"));
+    }
+
+    #[test]
+    fn validates_issuer_and_audience() {
+        let verifier = verifier(Some("idp"), Some("radio"));
+        let exp = now() + 600;
+
+        let valid = json!({ "scope": "listen:*", "exp": exp, "iss": "idp", "aud": "radio" });
+        assert!(verifier.allows(&token("secret", valid), Scope::Listen, "/live"));
+        let issuer = json!({ "scope": "listen:*", "exp": exp, "iss": "other", "aud": "radio" });
+        assert!(!verifier.allows(&token("secret", issuer), Scope::Listen, "/live"));
+        let audience = json!({ "scope": "listen:*", "exp": exp, "iss": "idp", "aud": "other" });
+        assert!(!verifier.allows(&token("secret", audience), Scope::Listen, "/live"));
+        let missing = json!({ "scope": "listen:*", "exp": exp });
+        assert!(!verifier.allows(&token("secret", missing), Scope::Listen, "/live"));
+    }
+
+    #[test]
+    fn key_kind_must_match_algorithm() {
+        assert_eq!(KeyKind::of(Algorithm::HS256), KeyKind::Hmac);
+        assert_eq!(KeyKind::of(Algorithm::RS256), KeyKind::Rsa);
+        assert_eq!(KeyKind::of(Algorithm::PS512), KeyKind::Rsa);
+        assert_eq!(KeyKind::of(Algorithm::ES256), KeyKind::Ec);
+        assert_eq!(KeyKind::of(Algorithm::EdDSA), KeyKind::Ed);
+    }
+
+    #[test]
+    fn jwks_url_must_use_https() {
+        let verifier = |url: &str| {
+            JwtVerifier::new(JwtConfig {
+                jwks_url: Some(url.to_string()),
+                ..JwtConfig::default()
+            })
+        };
+        assert!(verifier("https://idp.example.com/.well-known/jwks.json").is_ok());
+        assert!(verifier("http://idp.example.com/.well-known/jwks.json").is_err());
+    }
+}
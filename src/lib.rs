@@ -0,0 +1,62 @@
+//! Peroxidecast, an IceShout2-compatible audio streaming server.
+//!
+//! Besides the `peroxidecast` binary, the server can be embedded in
+//! other applications through the [`Server`] handle.
+
+pub mod access;
+pub mod admin_ui;
+pub mod api;
+pub mod auth;
+pub mod bandwidth;
+pub mod billing;
+pub mod check;
+pub mod cli;
+pub mod config;
+#[cfg(feature = "decode")]
+pub mod decode;
+pub mod dependencies;
+pub mod egress;
+pub mod events;
+pub mod failures;
+pub mod features;
+pub mod fingerprint;
+pub mod geoip;
+pub mod grafana;
+pub mod handover;
+pub mod history;
+pub mod history_db;
+pub mod hooks;
+pub mod htpasswd;
+pub mod icy;
+pub mod influxdb;
+pub mod jwt;
+pub mod logfile;
+#[cfg(feature = "loudness")]
+pub mod loudness;
+pub mod net;
+pub mod playlist_log;
+pub mod pool;
+pub mod prometheus;
+pub mod proxy;
+pub mod quirks;
+pub mod ratelimit;
+pub mod relay;
+pub mod retention;
+pub mod security;
+pub mod selftest;
+pub mod server;
+pub mod sessions;
+pub mod signed_url;
+pub mod snapshot;
+pub mod state;
+pub mod statsd;
+pub mod status_page;
+pub mod systemd;
+pub mod taps;
+pub mod templates;
+pub mod transcription;
+pub mod url_auth;
+pub mod webhooks;
+pub mod yp;
+
+pub use server::Server;
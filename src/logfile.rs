@@ -0,0 +1,144 @@
+//! Append-only log files that are rotated by size or age, or reopened on
+//! request so that they can be rotated by external tools like
+//! `logrotate`.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const DEFAULT_KEEP: usize = 5;
+
+/// When the log files of the server are rotated. Rotated files get a
+/// numeric suffix, e.g. `playlist.log.1` for the most recent one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogRotationConfig {
+    /// Rotate a log once it would exceed this size, in bytes
+    pub max_bytes: Option<u64>,
+    /// Rotate a log once it has been written to for this long, in seconds
+    pub max_age_secs: Option<u64>,
+    /// The amount of rotated files that are kept. Defaults to 5.
+    pub keep: Option<usize>,
+}
+
+impl LogRotationConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            keep: Some(self.keep.unwrap_or(DEFAULT_KEEP)),
+            ..self
+        }
+    }
+
+    pub fn max_age(&self) -> Option<Duration> {
+        self.max_age_secs.map(Duration::from_secs)
+    }
+
+    pub fn keep(&self) -> usize {
+        self.keep.unwrap_or(DEFAULT_KEEP)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    file: File,
+    size: u64,
+    opened: Instant,
+}
+
+/// A log file to which lines are appended
+#[derive(Debug)]
+pub struct LogFile {
+    path: PathBuf,
+    rotation: LogRotationConfig,
+    inner: Mutex<Inner>,
+}
+
+impl LogFile {
+    /// Open the log at `path` for appending, creating it if necessary
+    pub fn open(path: &Path, rotation: LogRotationConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            rotation,
+            inner: Mutex::new(Self::open_inner(path)?),
+        })
+    }
+
+    fn open_inner(path: &Path) -> std::io::Result<Inner> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Inner {
+            size: file.metadata()?.len(),
+            file,
+            opened: Instant::now(),
+        })
+    }
+
+    /// Append `line`, rotating the log first if it is due
+    pub fn write(&self, line: &str) -> std::io::Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let too_large = self
+            .rotation
+            .max_bytes
+            .map(|max| inner.size + line.len() as u64 > max)
+            .unwrap_or(false);
+        let too_old = self
+            .rotation
+            .max_age()
+            .map(|max| inner.opened.elapsed() >= max)
+            .unwrap_or(false);
+        // An empty log is never rotated, even if a single line exceeds
+        // the maximum size
+        if inner.size > 0 && (too_large || too_old) {
+            self.rotate(&mut inner)?;
+        }
+
+        inner.file.write_all(line.as_bytes())?;
+        inner.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Close and reopen the log, e.g. after it was moved away by
+    /// `logrotate`. If that fails, writing to the old file continues.
+    pub fn reopen(&self) {
+        match Self::open_inner(&self.path) {
+            Ok(inner) => {
+                *self.inner.lock().unwrap() = inner;
+                info!("Reopened log {}", self.path.display());
+            }
+            Err(e) => warn!("Could not reopen log {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn rotate(&self, inner: &mut Inner) -> std::io::Result<()> {
+        let keep = self.rotation.keep();
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+
+        if keep == 0 {
+            fs::remove_file(&self.path).ok();
+        } else {
+            fs::remove_file(rotated(keep)).ok();
+            for n in (1..keep).rev() {
+                fs::rename(rotated(n), rotated(n + 1)).ok();
+            }
+            if let Err(e) = fs::rename(&self.path, rotated(1)) {
+                warn!("Could not rotate log {}: {}", self.path.display(), e);
+            }
+        }
+
+        info!("Rotated log {}", self.path.display());
+        *inner = Self::open_inner(&self.path)?;
+        Ok(())
+    }
+}
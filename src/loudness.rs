@@ -0,0 +1,96 @@
+//! Integrated loudness (EBU R128) measurement of mounts, used for
+//! broadcast-compliance reporting.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use ebur128::{EbuR128, Mode};
+use symphonia::core::audio::SignalSpec;
+use tracing::{debug, warn};
+
+use crate::{
+    decode::{self, Analyzer},
+    state::{DataSender, Mount},
+};
+
+/// How often the integrated loudness of a mount is updated
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Start measuring the loudness of `mount`, if it has a loudness
+/// target. Measuring stops once the source that sends to `data_tx`
+/// disconnects.
+pub fn spawn(mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender) {
+    if mount.loudness_target().is_none() {
+        return;
+    }
+
+    mount.set_loudness(None);
+
+    let name = mount_name.to_string();
+    let meter_mount = mount.clone();
+    decode::spawn(mount_name, mount, data_tx, move || LoudnessMeter {
+        name,
+        mount: meter_mount,
+        meter: None,
+        last_report: Instant::now(),
+    });
+}
+
+struct LoudnessMeter {
+    name: String,
+    mount: Arc<Mount>,
+    meter: Option<EbuR128>,
+    last_report: Instant,
+}
+
+impl Analyzer for LoudnessMeter {
+    const NAME: &'static str = "Loudness measurement";
+
+    fn process(&mut self, samples: &[f32], spec: SignalSpec) {
+        let meter = if let Some(meter) = &mut self.meter {
+            meter
+        } else if let Ok(meter) = EbuR128::new(spec.channels.count() as u32, spec.rate, Mode::I) {
+            self.meter.insert(meter)
+        } else {
+            return;
+        };
+
+        meter.add_frames_f32(samples).ok();
+
+        if self.last_report.elapsed() >= REPORT_INTERVAL {
+            self.last_report = Instant::now();
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.report();
+    }
+}
+
+impl LoudnessMeter {
+    fn report(&self) {
+        let loudness = match self.meter.as_ref().map(|m| m.loudness_global()) {
+            Some(Ok(loudness)) if loudness.is_finite() => loudness,
+            _ => return,
+        };
+
+        self.mount.set_loudness(Some(loudness));
+
+        if let Some(target) = self.mount.loudness_target() {
+            if loudness > target {
+                warn!(
+                    "Mount {} has an integrated loudness of {:.1} LUFS, exceeding its target of {:.1} LUFS",
+                    self.name, loudness, target
+                );
+            } else {
+                debug!(
+                    "Mount {} has an integrated loudness of {:.1} LUFS",
+                    self.name, loudness
+                );
+            }
+        }
+    }
+}
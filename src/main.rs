@@ -1,81 +1,99 @@
-use std::{sync::Arc, time::Duration};
-
 use clap::StructOpt;
-use cli::CliArgs;
-use config::Config;
-use log::{debug, error};
-use net::SocketHandler;
-use state::{IceMeta, Mount, State, Stats};
-use tokio::{net::TcpListener, sync::RwLock};
-
-mod api;
-mod cli;
-mod config;
-mod net;
-mod state;
+use peroxidecast::{
+    auth, check,
+    cli::{self, CliArgs, Command, ConfigCommand},
+    handover, security, selftest, systemd, Server,
+};
+use tokio::net::TcpListener;
+use tracing::error;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
-    // Leak the config so we can access it globally
-    let cfg: &mut Config = Box::leak(Box::new(CliArgs::parse().into()));
-
-    pretty_env_logger::init();
-
-    let tcp_listener = TcpListener::bind(("127.0.0.1", 8080));
-
-    let mut state = State::new();
-
-    for (mount_name, config) in &cfg.mounts {
-        let mount = Mount::new(
-            "".to_string(),
-            tokio::sync::mpsc::unbounded_channel().0,
-            tokio::sync::watch::channel(Stats::new()).1,
-            config.source_auth.clone(),
-            config.sub_auth.clone(),
-            config.permanent,
-            IceMeta::default(),
-            config.stream_url.clone(),
-        );
-
-        state.add_mount(mount_name.to_string(), mount);
+    let args = CliArgs::parse();
+    if let Some(Command::HashPassword { algorithm, user }) = &args.command {
+        let hash =
+            cli::read_password().and_then(|password| auth::hash_password(&password, *algorithm));
+        match (hash, user) {
+            (Ok(hash), Some(user)) => println!("{}:{}", user, hash),
+            (Ok(hash), None) => println!("{}", hash),
+            (Err(e), _) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
     }
 
-    let state = Arc::new(RwLock::new(state));
+    if args.check_config {
+        let report = check::run(&args);
+        println!("{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
 
-    let tcp_listener = match tcp_listener.await {
-        Ok(value) => value,
+    let self_test = args.self_test;
+    let cfg = match args.load_config() {
+        Ok(cfg) => cfg,
         Err(e) => {
-            error!("Socket error: {:?}", e);
-            panic!()
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
     };
 
-    let state_clone = state.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            debug!("Mount stats:");
-            for stat in state_clone.read().await.get_mount_stats() {
-                debug!("{}: {}", stat.0, stat.1)
+    if let Some(Command::Config(ConfigCommand::Dump { show_secrets })) = &args.command {
+        let cfg = if *show_secrets { cfg } else { cfg.redacted() };
+        match cfg.with_defaults().to_toml() {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => {
+                eprintln!("Cannot print the configuration: {}", e);
+                std::process::exit(1);
             }
-            state_clone.write().await.clean_disconnected_mounts();
         }
-    });
+        return;
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+
+    if self_test {
+        let report = selftest::run(cfg).await;
+        println!("{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
 
-    loop {
-        match tcp_listener.accept().await {
-            Ok((socket, addr)) => {
-                let state = state.clone();
-                let handler = SocketHandler::new(
-                    cfg.clone(),
-                    socket.local_addr().unwrap(),
-                    addr,
-                    socket,
-                    state,
-                );
-                tokio::spawn(handler.run());
+    let handed_over = handover::inherited_listener();
+    let is_handed_over = handed_over.is_some();
+    let inherited = handed_over.or_else(systemd::activated_listener);
+    let tcp_listener = match inherited {
+        Some(listener) => match listener
+            .set_nonblocking(true)
+            .and_then(|_| TcpListener::from_std(listener))
+        {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Inherited socket error: {:?}", e);
+                panic!()
             }
-            Err(e) => error!("Socket error: {:?}", e),
+        },
+        None => match TcpListener::bind(cfg.listen_addr()).await {
+            Ok(value) => value,
+            Err(e) => {
+                error!("Socket error: {:?}", e);
+                panic!()
+            }
+        },
+    };
+
+    if let Some(security) = &cfg.security {
+        if let Err(e) = security::drop_privileges(security, is_handed_over) {
+            error!("Could not drop privileges: {}", e);
+            panic!()
         }
     }
+
+    Server::new(cfg)
+        .with_config_loader(move || args.load_config())
+        .run(tcp_listener)
+        .await;
 }
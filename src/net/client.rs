@@ -0,0 +1,484 @@
+//! A minimal HTTP client, used to talk to sidecar services and to pull
+//! streams from upstream servers.
+//!
+//! Requests are sent as HTTP/1.0 so that responses are never chunked.
+//! `https://` URLs are verified against the Mozilla root certificates
+//! (as bundled by `webpki-roots`). Connecting and reading the
+//! response are bounded in time, and responses that are read as a whole
+//! in size, so that a stuck or hostile server cannot hold up or exhaust
+//! the server.
+
+use std::{
+    future::Future,
+    io::{Error, ErrorKind},
+    pin::Pin,
+    sync::{Arc, LazyLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+/// The maximum size of the head of a response
+const MAX_RESPONSE_HEAD: usize = 16 * 1024;
+
+/// The maximum size of the body of a response that is read as a whole
+const MAX_RESPONSE_BODY: usize = 4 * 1024 * 1024;
+
+/// The time within which a connection must be established
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The time within which a response must be read, after connecting: the
+/// whole response for [`get`] and [`post`], and the head for [`open`]
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The TLS configuration of all `https://` connections
+static TLS: LazyLock<TlsConnector> = LazyLock::new(|| {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+/// A connection to a server, which is encrypted for `https://` URLs
+#[derive(Debug)]
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The value of the first header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A response of which only the head has been read
+#[derive(Debug)]
+pub struct HttpStream {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    /// The part of the body that was read along with the head
+    pub body_start: Vec<u8>,
+    pub stream: Connection,
+}
+
+impl HttpStream {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// Find the value of the header called `name`, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The parts of a URL that are needed to send a request
+#[derive(Debug, PartialEq, Eq)]
+struct Target<'a> {
+    tls: bool,
+    /// The address to connect to, with a port
+    address: String,
+    /// The host as it appears in the URL, for the `Host` header
+    host: &'a str,
+    /// The host without its port and brackets, to verify the certificate of
+    name: &'a str,
+    path: &'a str,
+}
+
+/// Split an `http://` or `https://` URL into its [`Target`]. The host may
+/// be an IPv6 address in brackets, e.g. `http://[::1]:8080/`.
+fn split_url(url: &str) -> std::io::Result<Target<'_>> {
+    let invalid = |message: &str| Error::new(ErrorKind::InvalidInput, message.to_string());
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        return Err(invalid("only http:// and https:// URLs are supported"));
+    };
+
+    let (host, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (name, port) = match host.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((name, "")) => (name, None),
+            Some((name, port)) => (
+                name,
+                Some(
+                    port.strip_prefix(':')
+                        .ok_or_else(|| invalid("invalid host in URL"))?,
+                ),
+            ),
+            None => return Err(invalid("unterminated IPv6 address in URL")),
+        },
+        None => match host.split_once(':') {
+            Some((name, port)) => (name, Some(port)),
+            None => (host, None),
+        },
+    };
+    let address = match port {
+        Some(port) if port.parse::<u16>().is_err() => return Err(invalid("invalid port in URL")),
+        Some(_) => host.to_string(),
+        None => format!("{}:{}", host, if tls { 443 } else { 80 }),
+    };
+
+    Ok(Target {
+        tls,
+        address,
+        host,
+        name,
+        path,
+    })
+}
+
+/// Connect to `target`, and set up TLS if it asks for it
+async fn connect(target: &Target<'_>) -> std::io::Result<Connection> {
+    let stream = TcpStream::connect(&target.address).await?;
+    if !target.tls {
+        return Ok(Connection::Plain(stream));
+    }
+
+    let name = ServerName::try_from(target.name.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let stream = TLS.connect(name, stream).await?;
+    Ok(Connection::Tls(Box::new(stream)))
+}
+
+/// Run `future`, failing with a `TimedOut` error if it takes longer than
+/// `duration`
+async fn timeout<T>(
+    duration: Duration,
+    what: &str,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    tokio::time::timeout(duration, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::new(
+                ErrorKind::TimedOut,
+                format!("{} timed out", what),
+            ))
+        })
+}
+
+async fn send_request(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> std::io::Result<Connection> {
+    let target = split_url(url)?;
+    let mut stream = timeout(CONNECT_TIMEOUT, "connecting", connect(&target)).await?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: peroxidecast\r\n",
+        method, target.path, target.host
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+
+    let mut request = request.into_bytes();
+    request.extend_from_slice(body);
+    stream.write_all(&request).await?;
+
+    Ok(stream)
+}
+
+/// Send a `POST` request with `body` to `url`
+pub async fn post(url: &str, content_type: &str, body: &[u8]) -> std::io::Result<HttpResponse> {
+    post_with_headers(url, content_type, &[], body).await
+}
+
+/// Send a `POST` request with `body` and additional `headers` to `url`
+pub async fn post_with_headers(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> std::io::Result<HttpResponse> {
+    let content_length = body.len().to_string();
+    let mut all_headers = vec![
+        ("Content-Type", content_type),
+        ("Content-Length", content_length.as_str()),
+    ];
+    all_headers.extend_from_slice(headers);
+    let stream = send_request("POST", url, &all_headers, body).await?;
+    timeout(
+        RESPONSE_TIMEOUT,
+        "reading the response",
+        read_response(stream),
+    )
+    .await
+}
+
+/// Send a `GET` request to `url`, and read the whole response
+pub async fn get(url: &str) -> std::io::Result<HttpResponse> {
+    let stream = send_request("GET", url, &[], &[]).await?;
+    timeout(
+        RESPONSE_TIMEOUT,
+        "reading the response",
+        read_response(stream),
+    )
+    .await
+}
+
+async fn read_response(stream: Connection) -> std::io::Result<HttpResponse> {
+    let limit = MAX_RESPONSE_HEAD + MAX_RESPONSE_BODY;
+    let mut response = Vec::new();
+    stream
+        .take(limit as u64 + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() > limit {
+        return Err(Error::new(ErrorKind::InvalidData, "response too large"));
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut parsed = httparse::Response::new(&mut headers);
+    match parsed.parse(&response) {
+        Ok(httparse::Status::Complete(len)) => Ok(HttpResponse {
+            status: parsed.code.unwrap_or_default(),
+            headers: parsed
+                .headers
+                .iter()
+                .map(|h| {
+                    (
+                        h.name.to_string(),
+                        String::from_utf8_lossy(h.value).to_string(),
+                    )
+                })
+                .collect(),
+            body: response[len..].to_vec(),
+        }),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "malformed HTTP response",
+        )),
+    }
+}
+
+/// Send a request without a body to `url`, and read the head of the
+/// response. The body can be read from the returned stream.
+///
+/// Responses that start with an `ICY` status line, as sent by old
+/// SHOUTcast servers, are treated as HTTP/1.0 responses.
+pub async fn open(
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+) -> std::io::Result<HttpStream> {
+    let stream = send_request(method, url, headers, &[]).await?;
+    timeout(
+        RESPONSE_TIMEOUT,
+        "reading the response head",
+        read_head(stream),
+    )
+    .await
+}
+
+async fn read_head(mut stream: Connection) -> std::io::Result<HttpStream> {
+    let mut buffer = Vec::with_capacity(4096);
+    loop {
+        if buffer.len() >= MAX_RESPONSE_HEAD {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "response head too large",
+            ));
+        }
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "connection closed before the response head was complete",
+            ));
+        }
+
+        let icy = buffer.starts_with(b"ICY ");
+        let mut normalized;
+        let response = if icy {
+            normalized = b"HTTP/1.0 ".to_vec();
+            normalized.extend_from_slice(&buffer[4..]);
+            &normalized[..]
+        } else {
+            &buffer[..]
+        };
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Response::new(&mut headers);
+        match parsed.parse(response) {
+            Ok(httparse::Status::Complete(len)) => {
+                let headers = parsed
+                    .headers
+                    .iter()
+                    .map(|h| {
+                        (
+                            h.name.to_string(),
+                            String::from_utf8_lossy(h.value).to_string(),
+                        )
+                    })
+                    .collect();
+                // The ICY status line is 5 bytes shorter than its replacement
+                let len = if icy { len - 5 } else { len };
+
+                return Ok(HttpStream {
+                    status: parsed.code.unwrap_or_default(),
+                    headers,
+                    body_start: buffer[len..].to_vec(),
+                    stream,
+                });
+            }
+            Ok(httparse::Status::Partial) => {}
+            Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_urls() {
+        let split = |url| {
+            let target = split_url(url).unwrap();
+            (
+                target.tls,
+                target.address,
+                target.host,
+                target.name,
+                target.path,
+            )
+        };
+        assert_eq!(
+            split("http://example.com"),
+            (
+                false,
+                "example.com:80".to_string(),
+                "example.com",
+                "example.com",
+                "/"
+            )
+        );
+        assert_eq!(
+            split("https://example.com:8443/a?b"),
+            (
+                true,
+                "example.com:8443".to_string(),
+                "example.com:8443",
+                "example.com",
+                "/a?b"
+            )
+        );
+        assert_eq!(
+            split("https://example.com/jwks"),
+            (
+                true,
+                "example.com:443".to_string(),
+                "example.com",
+                "example.com",
+                "/jwks"
+            )
+        );
+        assert_eq!(
+            split("http://[::1]/x"),
+            (false, "[::1]:80".to_string(), "[::1]", "::1", "/x")
+        );
+        assert_eq!(
+            split("http://[2001:db8::1]:8000/"),
+            (
+                false,
+                "[2001:db8::1]:8000".to_string(),
+                "[2001:db8::1]:8000",
+                "2001:db8::1",
+                "/"
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        for url in [
+            "ftp://example.com/",
+            "http://[::1/",
+            "http://[::1]8000/",
+            "http://example.com:http/",
+            "http://::1/",
+        ] {
+            assert!(split_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::{self, Write};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+
+/// Responses smaller than this are not worth compressing
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// A `Content-Encoding` that API responses can be compressed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// The encoding to use for a client that sent `accept_encoding`,
+    /// preferring gzip
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let mut gzip = false;
+        let mut deflate = false;
+        for coding in accept_encoding.split(',') {
+            let mut params = coding.split(';');
+            let name = params.next().unwrap_or("").trim();
+            // Codings with `q=0` are not acceptable
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            if refused {
+                continue;
+            }
+
+            if name.eq_ignore_ascii_case("gzip") || name.eq_ignore_ascii_case("x-gzip") {
+                gzip = true;
+            } else if name.eq_ignore_ascii_case("deflate") {
+                deflate = true;
+            } else if name == "*" {
+                gzip = true;
+            }
+        }
+
+        if gzip {
+            Some(Self::Gzip)
+        } else if deflate {
+            Some(Self::Deflate)
+        } else {
+            None
+        }
+    }
+
+    /// The value of the `Content-Encoding` header
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Compress `data` with this encoding
+    pub fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            // The `deflate` coding is the zlib format (RFC 9110 8.4.1.2)
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
@@ -1,25 +1,41 @@
 use std::{
+    io::Write,
+    net::{IpAddr, SocketAddr},
     sync::Arc,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
+use flate2::{write::GzEncoder, Compression};
 use httparse::Header;
-use log::{debug, info, trace, warn};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufReader},
     net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-    sync::{
-        mpsc::{UnboundedReceiver, UnboundedSender},
-        RwLock,
-    },
+    sync::{broadcast::error::RecvError, watch},
 };
+use tracing::{debug, info, trace, warn};
 
 use crate::{
+    auth::{self, AuthMechanism},
+    bandwidth::{self, Estimator},
     config::Config,
-    state::{IceMeta, Mount, StatSender, State, Stats, SubReceiver},
+    egress::EgressLimiter,
+    events::EventKind,
+    icy::{self, IcyMuxer},
+    jwt::Scope,
+    pool::BufferPool,
+    proxy::{BufferingProxyConfig, ProxyTracker, Verdict},
+    quirks::{self, Quirks},
+    sessions::{MoveTo, Session},
+    signed_url, snapshot,
+    state::{ConnectionSlot, DataReceiver, DataSender, IceMeta, Mount, MountStats, State},
+    taps, transcription,
 };
 
-use super::BasicHttpResponse;
+use super::{find_header, BasicHttpResponse, Query};
+
+/// The amount of chunks that may be queued for a subscriber before
+/// it is considered to be lagging behind and is disconnected
+const SUBSCRIBER_QUEUE_CHUNKS: usize = 256;
 
 #[derive(Debug, Clone)]
 pub enum CreateConnectorError {
@@ -29,25 +45,233 @@ pub enum CreateConnectorError {
     SourceMissingContentType,
     Unauthorized,
     MountNotConnected(String),
+    ServerFull,
+    MountFull(String),
+    /// Listeners are using up their share of the outgoing bandwidth
+    Congested,
+    TlsRequired,
+    /// The client may not connect to the mount, because of its IP
+    /// address, country, `User-Agent` or `Referer`
+    Forbidden,
+}
+
+/// What is known about a listener when it asks for the data of a mount
+pub(crate) struct ListenerRequest<'a> {
+    pub remote_ip: IpAddr,
+    pub mount_path: &'a str,
+    pub user_agent: Option<&'a str>,
+    pub referer: Option<&'a str>,
+    /// The `X-Forwarded-Proto` header, if the client is a trusted proxy
+    /// (see [`forwarded_proto`])
+    pub forwarded_proto: Option<&'a str>,
+    /// The credentials of the listener, including a token from the query
+    /// (see [`listener_authorization`])
+    pub authorization: Option<&'a str>,
+    pub query: &'a Query,
+    pub is_admin: bool,
+    /// Whether the listener only probes the mount (with a HEAD request),
+    /// without receiving its data
+    pub head: bool,
+}
+
+/// The credentials of a listener: the `Authorization` header, or else a
+/// bearer token in the query.
+///
+/// Clients that cannot set headers (e.g. browser audio elements) may pass
+/// a bearer token in the query instead, as in RFC 6750.
+pub(crate) fn listener_authorization(authorization: Option<&str>, query: &Query) -> Option<String> {
+    authorization.map(|s| s.to_string()).or_else(|| {
+        query
+            .get("access_token")
+            .map(|token| format!("Bearer {}", token))
+    })
+}
+
+/// The `X-Forwarded-Proto` header `header` of a client at `remote_ip`, if
+/// it is a trusted proxy. Anyone else could claim to use TLS.
+pub(crate) fn forwarded_proto<'a>(
+    config: &Config,
+    remote_ip: IpAddr,
+    header: Option<&'a str>,
+) -> Option<&'a str> {
+    header.filter(|_| config.is_trusted_proxy(remote_ip))
+}
+
+/// Check whether the listener that made `request` may receive the data of
+/// `mount`, whether as a stream or otherwise (e.g. as a snapshot).
+///
+/// Admins need no credentials, signed URL or `Referer`, but are subject to
+/// the other rules.
+pub(crate) async fn admit_listener(
+    state: &State,
+    mount: &Mount,
+    request: &ListenerRequest<'_>,
+) -> Result<(), CreateConnectorError> {
+    let remote = request.remote_ip;
+    let mount_path = request.mount_path;
+    let authorization = request.authorization;
+
+    if !mount.allows_ip(remote) {
+        warn!(
+            "{} is not allowed to subscribe to mount {}",
+            remote, mount_path
+        );
+        return Err(CreateConnectorError::Forbidden);
+    }
+
+    let country = state.country(remote);
+    if !mount.allows_country(country.as_deref()) {
+        warn!(
+            "{} in country {:?} is not allowed to subscribe to mount {}",
+            remote, country, mount_path
+        );
+        return Err(CreateConnectorError::Forbidden);
+    }
+
+    if !mount.allows_user_agent(request.user_agent) {
+        warn!(
+            "{} with User-Agent {:?} is not allowed to subscribe to mount {}",
+            remote, request.user_agent, mount_path
+        );
+        return Err(CreateConnectorError::Forbidden);
+    }
+
+    if mount.require_tls() && request.forwarded_proto != Some("https") {
+        warn!(
+            "{} did not connect to mount {} over TLS",
+            remote, mount_path
+        );
+        return Err(CreateConnectorError::TlsRequired);
+    }
+
+    let has_token = state.is_jwt_authorization(authorization, Scope::Listen, mount_path);
+
+    if let Some(min_auth) = mount.min_sub_auth() {
+        let mechanism = authorization.and_then(AuthMechanism::of);
+        if mechanism.map(|m| m < min_auth).unwrap_or(true) {
+            warn!(
+                "{} used {:?} to subscribe to mount {}, which requires at least {:?}",
+                remote, mechanism, mount_path, min_auth
+            );
+            return Err(CreateConnectorError::Unauthorized);
+        }
+
+        // The mechanism only tells what the credentials look like, so they
+        // must also be valid, even if the mount has no credentials of its
+        // own
+        let verified = request.is_admin
+            || match mechanism {
+                Some(AuthMechanism::Jwt) => has_token,
+                _ => mount.is_sub_authorization(authorization),
+            };
+        if !verified {
+            warn!(
+                "{} sent invalid {:?} credentials for mount {}",
+                remote, mechanism, mount_path
+            );
+            return Err(CreateConnectorError::Unauthorized);
+        }
+    }
+
+    if !request.is_admin
+        && mount.requires_sub_auth()
+        && !mount.is_sub_authorization(authorization)
+        && !has_token
+    {
+        return Err(CreateConnectorError::Unauthorized);
+    }
+
+    if let (false, Some(secret)) = (request.is_admin, mount.url_signing_secret()) {
+        let verified = signed_url::verify(
+            secret,
+            mount_path,
+            request.query.get("token"),
+            request.query.get("expires"),
+            SystemTime::now(),
+        );
+        if let Err(e) = verified {
+            warn!(
+                "{} sent a bad signed URL for mount {}: {}",
+                remote, mount_path, e
+            );
+            return Err(CreateConnectorError::Unauthorized);
+        }
+    }
+
+    // Listeners with a signed URL (which was verified above) or a token
+    // were handed their URL, so they need no `Referer`
+    if !request.is_admin
+        && !mount.allows_referer(request.referer)
+        && mount.url_signing_secret().is_none()
+        && !has_token
+    {
+        warn!(
+            "{} with Referer {:?} is not allowed to subscribe to mount {}",
+            remote, request.referer, mount_path
+        );
+        return Err(CreateConnectorError::Forbidden);
+    }
+
+    // The endpoint is told that a listener connects, which a probe does
+    // not do
+    if let (false, Some(url_auth)) = (request.head, mount.url_auth()) {
+        let allowed = url_auth
+            .allows(mount_path, remote, request.user_agent, authorization)
+            .await;
+        if !allowed {
+            warn!(
+                "{} was not authorized to subscribe to mount {}",
+                remote, mount_path
+            );
+            return Err(CreateConnectorError::Unauthorized);
+        }
+    }
+
+    Ok(())
 }
 
-impl<T> Into<Result<T, CreateConnectorError>> for CreateConnectorError {
-    fn into(self) -> Result<T, CreateConnectorError> {
-        Err(self)
+impl<T> From<CreateConnectorError> for Result<T, CreateConnectorError> {
+    fn from(e: CreateConnectorError) -> Self {
+        Err(e)
     }
 }
 
 #[derive(Debug)]
 enum ConnectorKind {
     Sink {
-        mount_meta: IceMeta,
-        data_rx: UnboundedReceiver<Vec<u8>>,
-        content_type: String,
+        mount: Arc<Mount>,
+        data_rx: DataReceiver,
+        gzip: bool,
+        quirks: Quirks,
+        state: Arc<State>,
+        remote_ip: IpAddr,
+        /// Headers added to the response, e.g. CORS headers and hints
+        /// towards a rendition the subscriber can sustain
+        extra_headers: Vec<String>,
+        /// Keeps this subscriber counted towards connection limits
+        slots: Vec<ConnectionSlot>,
+        buffering_proxies: Option<BufferingProxyConfig>,
+        session: Session,
+        /// Interleaves ICY metadata with the data, if the subscriber
+        /// asked for it
+        icy: Option<Box<IcyMuxer>>,
+    },
+    /// A `HEAD` request for a mount
+    Head {
+        mount: Arc<Mount>,
+        gzip: bool,
+        quirks: Quirks,
+        extra_headers: Vec<String>,
     },
     Source {
-        subscriber_rx: SubReceiver,
-        stats_sender: StatSender,
-        start_stats: Stats,
+        state: Arc<State>,
+        mount: Arc<Mount>,
+        data_tx: DataSender,
+        stats: Arc<MountStats>,
+        buffer_pool: Arc<BufferPool>,
+        chunk_size: usize,
+        /// Headers that inform the source of how its mount is configured
+        report_headers: Vec<String>,
     },
 }
 
@@ -69,22 +293,35 @@ type Error = (
     BufReader<OwnedReadHalf>,
 );
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum SubDisconnectReason {
     SourceDisconnected,
     ClientDisconnected,
+    /// The client could not keep up with the data sent by the source
+    Lagged,
+    /// The client is a buffering proxy that stopped accepting data
+    Stalled,
+    /// The client was disconnected by an admin
+    Killed,
+    /// The client is moved to another mount by an admin
+    Moved,
 }
 
 impl<T> Connector<T>
 where
     T: std::fmt::Debug,
 {
+    #[allow(clippy::too_many_arguments)]
     pub async fn parse(
         remote: T,
+        remote_ip: IpAddr,
+        local_addr: SocketAddr,
         config: &Config,
-        state: Arc<RwLock<State>>,
+        state: Arc<State>,
         method: &str,
+        http_version: Option<u8>,
         mount_path: &str,
+        query: &Query,
         content_type: Option<&str>,
         authorization: Option<&str>,
         write_half: OwnedWriteHalf,
@@ -94,15 +331,9 @@ where
     where
         T: std::fmt::Debug,
     {
-        let authorization = authorization.map(|s| s.to_string());
+        let authorization = listener_authorization(authorization, query);
 
-        let is_admin = authorization
-            .as_ref()
-            .map(|a| {
-                config.admin_authorization.is_some()
-                    && Some(a) == config.admin_authorization.as_ref()
-            })
-            .unwrap_or(false);
+        let is_admin = super::is_admin_authorization(config, &state, authorization.as_deref());
 
         debug!("Parsing TCP request from {:?}", remote);
         if is_admin {
@@ -129,20 +360,42 @@ where
                 error!(SourceMissingContentType);
             };
 
-            let (subs_tx, subs_rx) = tokio::sync::mpsc::unbounded_channel();
-            let (stats_tx, stats_rx) = tokio::sync::watch::channel(Stats::new());
+            let (data_tx, _) = tokio::sync::broadcast::channel(SUBSCRIBER_QUEUE_CHUNKS);
 
             let meta = IceMeta::from(headers);
+            let buffer_pool = state.buffer_pool().clone();
+            let source_user = authorization
+                .as_deref()
+                .and_then(auth::basic_credentials)
+                .map(|(user, _)| user);
 
-            let found_mount = { state.read().await.find_mount(mount_path).cloned() };
-            let start_stats = if let Some(mount) = found_mount {
+            let mount = if let Some(mount) = state.find_mount(mount_path) {
                 debug!(
                     "{:?} is attempting to become source for existing mount {}",
                     remote, mount_path
                 );
 
-                let auth = mount.source_auth();
-                if !is_admin && !auth.is_none() && auth != &authorization.map(|v| v.to_string()) {
+                if mount.is_retired() {
+                    error!(MountDoesNotExist(mount_path.to_string()));
+                }
+
+                if !mount.allows_ip(remote_ip) {
+                    warn!(
+                        "{:?} is not allowed to become a source for mount {}",
+                        remote, mount_path
+                    );
+                    error!(Forbidden);
+                }
+
+                if !is_admin
+                    && mount.requires_source_auth()
+                    && !mount.is_source_authorization(authorization.as_deref())
+                    && !state.is_jwt_authorization(
+                        authorization.as_deref(),
+                        Scope::Source,
+                        mount_path,
+                    )
+                {
                     warn!(
                         "{:?} was not authorized to become a source for mount {}",
                         remote, mount_path
@@ -150,13 +403,9 @@ where
                     error!(Unauthorized);
                 }
 
-                if mount.is_connected() {
+                trace!("SOURCE: {:?} ICE metadata: {:?}", remote, meta);
+                if !mount.try_set_source(data_tx.downgrade(), content_type.to_string(), meta) {
                     error!(MountHasSource(mount_path.to_string()));
-                } else {
-                    trace!("SOURCE: {:?} ICE metadata: {:?}", remote, meta);
-                    let mut state = state.write().await;
-                    let mount = state.find_mount_mut(mount_path).unwrap();
-                    mount.set_source(subs_tx, stats_rx, content_type.to_string(), meta);
                 }
 
                 info!(
@@ -167,7 +416,7 @@ where
                     mount.stats()
                 );
 
-                mount.stats()
+                mount
             } else {
                 debug!(
                     "{:?} is attempting to create and become source for mount {}",
@@ -175,61 +424,234 @@ where
                 );
                 debug!("SOURCE: {:?} ICE metadata : {:?}", remote, meta);
 
-                if !is_admin && !config.allow_unauthenticated_mounts {
-                    warn!(
-                        "{:?} was not authorized to become a source for mount {}",
-                        remote, mount_path
-                    );
-                    error!(Unauthorized);
+                let mut mount_config = config.mount_defaults.clone().unwrap_or_default();
+                let default_auth = mount_config.has_source_auth();
+                if !default_auth {
+                    mount_config.source_auth = authorization.clone();
                 }
-
                 let mount = Mount::new(
                     content_type.to_string(),
-                    subs_tx,
-                    stats_rx,
-                    authorization,
-                    None,
-                    false,
+                    data_tx.downgrade(),
                     meta,
-                    None,
+                    mount_config,
                 );
 
-                {
-                    let mut state = state.write().await;
-                    state.add_mount(mount_path.to_string(), mount);
+                if !mount.allows_ip(remote_ip) {
+                    warn!("{:?} is not allowed to create mount {}", remote, mount_path);
+                    error!(Forbidden);
                 }
 
+                // Credentials in the mount defaults take precedence over
+                // `allow_unauthenticated_mounts`
+                let authorized = is_admin
+                    || if default_auth {
+                        mount.is_source_authorization(authorization.as_deref())
+                    } else {
+                        config.allow_unauthenticated_mounts
+                    }
+                    || state.is_jwt_authorization(
+                        authorization.as_deref(),
+                        Scope::Source,
+                        mount_path,
+                    );
+                if !authorized {
+                    warn!(
+                        "{:?} was not authorized to become a source for mount {}",
+                        remote, mount_path
+                    );
+                    error!(Unauthorized);
+                }
+
+                let mount = if let Some(mount) = state.add_mount(mount_path.to_string(), mount) {
+                    mount
+                } else {
+                    error!(MountHasSource(mount_path.to_string()));
+                };
+                mount.stats_handle().add_source_connect();
+
                 info!(
                     "Created mount {} with content type {}.",
                     mount_path, content_type
                 );
-                Stats::new()
+                mount
             };
+            mount.set_source_user(source_user);
+
+            let mut report_headers = vec![
+                format!(
+                    "icy-url: {}",
+                    super::stream_url(
+                        config,
+                        mount_path,
+                        &mount,
+                        find_header(headers, "Host"),
+                        find_header(headers, "X-Forwarded-Host"),
+                        local_addr,
+                    )
+                ),
+                format!("X-Peroxidecast-Chunk-Size: {}", mount.chunk_size()),
+            ];
+            if let Some(max_listeners) = mount.max_listeners() {
+                report_headers.push(format!("X-Peroxidecast-Max-Listeners: {}", max_listeners));
+            }
+            if let Some(max_clients) = config.max_clients {
+                report_headers.push(format!("X-Peroxidecast-Max-Clients: {}", max_clients));
+            }
 
             ConnectorKind::Source {
-                subscriber_rx: subs_rx,
-                stats_sender: stats_tx,
-                start_stats,
+                data_tx,
+                stats: mount.stats_handle().clone(),
+                buffer_pool,
+                chunk_size: mount.chunk_size(),
+                report_headers,
+                mount,
+                state,
             }
-        } else if method == "GET" {
-            if let Some(mount) = state.read().await.find_mount(mount_path) {
-                let auth = mount.sub_auth().clone();
-                if auth.is_some() && auth != authorization {
-                    error!(Unauthorized);
+        } else if method == "GET" || method == "HEAD" {
+            // A HEAD request is answered with the headers of a GET request,
+            // without subscribing to the mount
+            let head = method == "HEAD";
+
+            if let Some(mount) = state.find_mount(mount_path) {
+                if mount.is_retired() {
+                    error!(MountDoesNotExist(mount_path.to_string()));
+                }
+                let request = ListenerRequest {
+                    remote_ip,
+                    mount_path,
+                    user_agent: find_header(headers, "User-Agent"),
+                    referer: find_header(headers, "Referer"),
+                    forwarded_proto: forwarded_proto(
+                        config,
+                        remote_ip,
+                        find_header(headers, "X-Forwarded-Proto"),
+                    ),
+                    authorization: authorization.as_deref(),
+                    query,
+                    is_admin,
+                    head,
+                };
+                if let Err(e) = admit_listener(&state, &mount, &request).await {
+                    return Err((e, write_half, read_half));
                 }
 
-                if !mount.is_connected() {
+                if !head && state.egress().map(|e| e.is_congested()).unwrap_or(false) {
+                    warn!(
+                        "Rejecting {:?}: the outgoing bandwidth for listeners is used up",
+                        remote
+                    );
+                    error!(Congested);
+                }
+
+                let slot = if head {
+                    None
+                } else if let Some(slot) = state.listeners().try_acquire(config.max_clients) {
+                    Some(slot)
+                } else {
+                    warn!(
+                        "Rejecting {:?}: the maximum amount of clients is connected",
+                        remote
+                    );
+                    error!(ServerFull);
+                };
+
+                let mount_slot = if head {
+                    None
+                } else if let Some(slot) = mount
+                    .stats_handle()
+                    .subscribers()
+                    .try_acquire(mount.max_listeners())
+                {
+                    Some(slot)
+                } else {
+                    warn!(
+                        "Rejecting {:?}: the maximum amount of listeners is connected to mount {}",
+                        remote, mount_path
+                    );
+                    error!(MountFull(mount_path.to_string()));
+                };
+
+                let data_rx = if head {
+                    if !mount.is_connected() {
+                        error!(MountNotConnected(mount_path.to_string()));
+                    }
+                    None
+                } else if let Some(data_rx) = mount.subscribe() {
+                    Some(data_rx)
+                } else {
                     error!(MountNotConnected(mount_path.to_string()));
+                };
+
+                let accepts_gzip = find_header(headers, "Accept-Encoding")
+                    .map(|v| v.split(',').any(|e| e.trim().starts_with("gzip")))
+                    .unwrap_or(false);
+
+                let quirks = quirks::for_request(&config.quirk_rules, http_version, headers)
+                    .merge(mount.quirks());
+                if quirks != Quirks::default() {
+                    debug!("Applying quirks {:?} to {:?}", quirks, remote);
                 }
 
-                let (data_tx, data_rx) = tokio::sync::mpsc::unbounded_channel();
-                mount.sub_sender().send(data_tx).ok();
-                let meta = mount.metadata();
+                let mut extra_headers = config
+                    .cors
+                    .as_ref()
+                    .map(|cors| cors.headers(find_header(headers, "Origin")))
+                    .unwrap_or_default();
+                if let Some(estimate) = state.bandwidth().get(&remote_ip) {
+                    extra_headers.push(format!("X-Peroxidecast-Bandwidth: {}", estimate));
+                    if let Some(rendition) =
+                        bandwidth::suggest_rendition(&state, mount_path, &mount, estimate)
+                    {
+                        debug!(
+                            "Suggesting rendition {} of mount {} to {:?}",
+                            rendition, mount_path, remote
+                        );
+                        extra_headers.push(format!("X-Peroxidecast-Rendition: {}", rendition));
+                    }
+                }
+
+                let gzip = accepts_gzip && mount.compress();
 
-                ConnectorKind::Sink {
-                    mount_meta: meta,
-                    data_rx,
-                    content_type: mount.content_type().to_string(),
+                // Metadata cannot be interleaved with compressed data
+                let icy_metadata = !gzip
+                    && find_header(headers, "Icy-MetaData")
+                        .map(|v| v.trim() == "1")
+                        .unwrap_or(false);
+                if icy_metadata {
+                    extra_headers.push(format!("icy-metaint: {}", icy::METAINT));
+                }
+
+                if let Some(data_rx) = data_rx {
+                    let session = state.sessions().start(
+                        mount_path,
+                        remote_ip,
+                        state.country(remote_ip),
+                        find_header(headers, "User-Agent"),
+                        authorization.as_deref().and_then(auth::credential_id),
+                        icy_metadata,
+                    );
+                    extra_headers.push(format!("X-Peroxidecast-Session: {}", session.id()));
+                    tracing::Span::current().record("session", session.id());
+                    ConnectorKind::Sink {
+                        data_rx,
+                        gzip,
+                        quirks,
+                        extra_headers,
+                        remote_ip,
+                        state,
+                        mount,
+                        slots: slot.into_iter().chain(mount_slot).collect(),
+                        buffering_proxies: config.buffering_proxies.clone(),
+                        session,
+                        icy: icy_metadata.then(|| Box::new(IcyMuxer::new())),
+                    }
+                } else {
+                    ConnectorKind::Head {
+                        mount,
+                        gzip,
+                        quirks,
+                        extra_headers,
+                    }
                 }
             } else {
                 error!(MountDoesNotExist(mount_path.to_string()));
@@ -250,121 +672,374 @@ where
     pub async fn run(mut self) {
         match &mut self.kind {
             ConnectorKind::Sink {
-                mount_meta,
+                mount,
                 ref mut data_rx,
-                content_type,
+                gzip,
+                quirks,
+                state,
+                remote_ip,
+                extra_headers,
+                slots,
+                buffering_proxies,
+                session,
+                icy,
             } => {
                 info!(
                     "SUB: {:?} connected to mount {}",
                     self.remote, self.mount_path
                 );
-                let disconnect_reason =
-                    Self::run_sink(mount_meta, &mut self.write_half, data_rx, content_type).await;
+                state.events().emit(EventKind::ListenerConnected {
+                    mount: self.mount_path.clone(),
+                    session: session.id(),
+                    remote_ip: *remote_ip,
+                });
+                let mut estimator = Estimator::new();
+                let mut proxy = buffering_proxies.take().map(|config| {
+                    let counters = vec![
+                        state.listeners().clone(),
+                        mount.stats_handle().subscribers().clone(),
+                    ];
+                    ProxyTracker::new(config, counters, std::mem::take(slots))
+                });
+
+                let headers = Self::sink_headers(mount, *gzip, extra_headers);
+                let headers: Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
+                BasicHttpResponse::ok(&headers)
+                    .send_with_quirks(&mut self.write_half, *quirks)
+                    .await;
+
+                let mut encoder = if *gzip {
+                    Some(GzEncoder::new(Vec::new(), Compression::default()))
+                } else {
+                    None
+                };
+
+                let kill = session.kill_signal();
+                let mut moves = session.move_signal();
+                let disconnect_reason = loop {
+                    let reason = tokio::select! {
+                        reason = Self::run_sink(
+                            mount,
+                            &mut self.write_half,
+                            data_rx,
+                            encoder.as_mut(),
+                            &mut estimator,
+                            proxy.as_mut(),
+                            state.egress(),
+                            session,
+                            &mut moves,
+                            icy.as_deref_mut(),
+                        ) => reason,
+                        _ = kill.notified() => SubDisconnectReason::Killed,
+                    };
+
+                    if reason != SubDisconnectReason::Moved {
+                        break reason;
+                    }
+
+                    let target = moves.borrow_and_update().clone();
+                    let target = match target {
+                        Some(target) => target,
+                        None => continue,
+                    };
+                    let target_rx = match target.mount.subscribe() {
+                        Some(data_rx) => data_rx,
+                        None => {
+                            warn!(
+                                "SUB: {:?} cannot move to mount {}, which is not connected",
+                                self.remote, target.mount_name
+                            );
+                            continue;
+                        }
+                    };
+
+                    info!(
+                        "SUB: {:?} moved from mount {} to mount {}",
+                        self.remote, self.mount_path, target.mount_name
+                    );
+                    let from = mount.stats_handle().subscribers().clone();
+                    let to = target.mount.stats_handle().subscribers();
+                    if let Some(proxy) = proxy.as_mut() {
+                        proxy.transfer(&from, to);
+                    }
+                    for slot in slots.iter_mut() {
+                        if Arc::ptr_eq(slot.counter(), &from) {
+                            slot.transfer(to);
+                        }
+                    }
+
+                    *data_rx = target_rx;
+                    *mount = target.mount;
+                    self.mount_path = target.mount_name;
+                    tracing::Span::current().record("mount", self.mount_path.as_str());
+                };
                 info!(
                     "SUB: {:?} disconnected from mount {}. Reason: {:?}",
                     self.remote, self.mount_path, disconnect_reason
-                )
+                );
+                let info = state.sessions().get(session.id());
+                state.events().emit(EventKind::ListenerDisconnected {
+                    mount: self.mount_path.clone(),
+                    session: session.id(),
+                    remote_ip: *remote_ip,
+                    reason: format!("{:?}", disconnect_reason),
+                    connected_secs: info.as_ref().map(|i| i.connected_secs).unwrap_or(0),
+                    bytes_sent: info.map(|i| i.bytes_sent).unwrap_or(0),
+                });
+
+                if let Some(estimate) = estimator.estimate() {
+                    debug!(
+                        "SUB: {:?} sustained an estimated {}/s",
+                        self.remote,
+                        bytesize::ByteSize(estimate)
+                    );
+                    state.bandwidth().record(*remote_ip, estimate);
+                }
             }
-            ConnectorKind::Source {
-                ref mut subscriber_rx,
-                stats_sender,
-                start_stats,
+            ConnectorKind::Head {
+                mount,
+                gzip,
+                quirks,
+                extra_headers,
             } => {
-                info!(
-                    "SOURCE: {:?} connected to mount {}",
+                debug!(
+                    "HEAD: {:?} requested the headers of mount {}",
                     self.remote, self.mount_path
                 );
-                Self::run_source(
-                    start_stats.clone(),
-                    subscriber_rx,
-                    &stats_sender,
-                    &mut self.write_half,
-                    &mut self.read_half,
-                )
-                .await;
+                let headers = Self::sink_headers(mount, *gzip, extra_headers);
+                let headers: Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
+                BasicHttpResponse::ok(&headers)
+                    .send_with_quirks(&mut self.write_half, *quirks)
+                    .await;
+            }
+            ConnectorKind::Source {
+                state,
+                mount,
+                data_tx,
+                stats,
+                buffer_pool,
+                chunk_size,
+                report_headers,
+            } => {
                 info!(
-                    "SOURCE: {:?} disconnected from mount {}.",
+                    "SOURCE: {:?} connected to mount {}",
                     self.remote, self.mount_path
                 );
+                state.events().emit(EventKind::SourceConnected {
+                    mount: self.mount_path.clone(),
+                });
+                taps::spawn(&self.mount_path, mount, data_tx);
+                snapshot::spawn(&self.mount_path, mount, data_tx);
+                #[cfg(feature = "loudness")]
+                crate::loudness::spawn(&self.mount_path, mount, data_tx);
+                #[cfg(feature = "fingerprint")]
+                crate::fingerprint::spawn(&self.mount_path, mount, data_tx);
+                transcription::spawn(state, &self.mount_path, mount, data_tx);
+                let kill = mount.source_kill_signal();
+                tokio::select! {
+                    _ = Self::run_source(
+                        data_tx,
+                        stats,
+                        buffer_pool,
+                        *chunk_size,
+                        report_headers,
+                        &mut self.write_half,
+                        &mut self.read_half,
+                    ) => {
+                        info!(
+                            "SOURCE: {:?} disconnected from mount {}.",
+                            self.remote, self.mount_path
+                        );
+                    }
+                    _ = kill.notified() => {
+                        info!(
+                            "SOURCE: {:?} was disconnected from mount {} by an admin.",
+                            self.remote, self.mount_path
+                        );
+                    }
+                }
+                state.events().emit(EventKind::SourceDisconnected {
+                    mount: self.mount_path.clone(),
+                });
+            }
+        }
+    }
+
+    /// The headers of the response to a subscriber of `mount`
+    fn sink_headers(mount: &Mount, gzip: bool, extra_headers: &[String]) -> Vec<String> {
+        let mut headers = mount.metadata().as_headers();
+        headers.push(format!("Content-Type: {}", mount.content_type()));
+        headers.push("Cache-Control: no-cache".to_string());
+        headers.extend(extra_headers.iter().cloned());
+        if gzip {
+            headers.push("Content-Encoding: gzip".to_string());
+        }
+
+        // Headers configured for the mount replace the ones we would
+        // otherwise send. Line breaks would end the header early, so
+        // headers that contain them are ignored.
+        let is_valid = |h: &str| !h.contains(['\r', '\n']);
+        for (name, value) in mount
+            .headers()
+            .iter()
+            .filter(|(name, value)| is_valid(name) && is_valid(value))
+        {
+            headers.retain(|h| {
+                h.split_once(':')
+                    .map(|(n, _)| !n.trim().eq_ignore_ascii_case(name))
+                    .unwrap_or(true)
+            });
+            headers.push(format!("{}: {}", name, value));
+        }
+        headers
+    }
+
+    /// Write `data` to a subscriber. If the subscriber turns out to be a
+    /// buffering proxy that stalls, it is no longer counted while it is
+    /// stalled, and disconnected if it stays stalled or falls too far
+    /// behind.
+    async fn write_chunk(
+        write_half: &mut OwnedWriteHalf,
+        data: &[u8],
+        data_rx: &DataReceiver,
+        proxy: Option<&mut ProxyTracker>,
+    ) -> Result<(), SubDisconnectReason> {
+        let proxy = if let Some(proxy) = proxy {
+            proxy
+        } else {
+            return write_half
+                .write_all(data)
+                .await
+                .map_err(|_| SubDisconnectReason::ClientDisconnected);
+        };
+
+        let start = Instant::now();
+        let write = write_half.write_all(data);
+        tokio::pin!(write);
+
+        let mut wait = proxy.config().stall();
+        let result = loop {
+            match tokio::time::timeout(wait, &mut write).await {
+                Ok(result) => break result,
+                Err(_) => {
+                    wait = Duration::from_secs(1);
+                    let was_stalled = proxy.is_stalled();
+                    match proxy.blocked(start.elapsed(), data_rx.len()) {
+                        Verdict::Wait => {}
+                        Verdict::Stalled => return Err(SubDisconnectReason::Stalled),
+                        Verdict::Lagged => return Err(SubDisconnectReason::Lagged),
+                    }
+                    if !was_stalled && proxy.is_stalled() {
+                        debug!("Subscriber is a buffering proxy that stalled");
+                    }
+                }
             }
+        };
+
+        if result.is_err() {
+            Err(SubDisconnectReason::ClientDisconnected)
+        } else {
+            if start.elapsed() >= proxy.config().stall() {
+                proxy.resumed();
+            } else {
+                proxy.accepted(data.len());
+            }
+            Ok(())
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn run_sink(
-        mount_meta: &mut IceMeta,
+        mount: &Mount,
         write_half: &mut OwnedWriteHalf,
-        data_rx: &mut UnboundedReceiver<Vec<u8>>,
-        content_type: &String,
+        data_rx: &mut DataReceiver,
+        mut encoder: Option<&mut GzEncoder<Vec<u8>>>,
+        estimator: &mut Estimator,
+        mut proxy: Option<&mut ProxyTracker>,
+        egress: Option<&EgressLimiter>,
+        session: &mut Session,
+        moves: &mut watch::Receiver<Option<MoveTo>>,
+        mut icy: Option<&mut IcyMuxer>,
     ) -> SubDisconnectReason {
-        let headers = mount_meta.as_headers();
-        let mut transformed: Vec<&str> = headers.iter().map(|h| h.as_str()).collect();
-        let content_type = format!("Content-Type: {}", content_type);
-        transformed.push(&content_type);
+        let stats = mount.stats_handle();
 
-        let no_cache = "Cache-Control: no-cache";
-        transformed.push(no_cache);
+        loop {
+            let received = tokio::select! {
+                received = data_rx.recv() => received,
+                Ok(()) = moves.changed() => return SubDisconnectReason::Moved,
+            };
 
-        BasicHttpResponse::ok(&transformed).send(write_half).await;
+            match received {
+                Ok(bytes) => {
+                    let transformed;
+                    let data = if let Some(encoder) = encoder.as_deref_mut() {
+                        // Flush after every chunk so that subscribers don't have
+                        // to wait for the compressor to fill up a block
+                        encoder
+                            .write_all(&bytes)
+                            .and_then(|_| encoder.flush())
+                            .expect("Writing to a Vec does not fail");
+                        transformed = std::mem::take(encoder.get_mut());
+                        &transformed[..]
+                    } else if let Some(icy) = icy.as_deref_mut() {
+                        let mut muxed = Vec::with_capacity(bytes.len() + 1);
+                        icy.push(
+                            &bytes,
+                            || (mount.song(), mount.song_url()),
+                            || session.next_message(),
+                            &mut muxed,
+                        );
+                        transformed = muxed;
+                        &transformed[..]
+                    } else {
+                        &bytes[..]
+                    };
 
-        while let Some(bytes) = data_rx.recv().await {
-            if write_half.write_all(&bytes).await.is_err() {
-                return SubDisconnectReason::ClientDisconnected;
+                    if let Some(egress) = egress {
+                        egress.reserve(data.len()).await;
+                    }
+
+                    let start = Instant::now();
+                    let result =
+                        Self::write_chunk(write_half, data, data_rx, proxy.as_deref_mut()).await;
+
+                    if let Err(reason) = result {
+                        return reason;
+                    }
+                    estimator.record(data.len(), start.elapsed());
+                    stats.add_bytes_out(data.len());
+                    session.add_bytes_sent(data.len());
+                    session.set_queued_chunks(data_rx.len());
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Subscriber lagged behind by {} chunks", missed);
+                    return SubDisconnectReason::Lagged;
+                }
+                Err(RecvError::Closed) => return SubDisconnectReason::SourceDisconnected,
             }
         }
-
-        SubDisconnectReason::SourceDisconnected
     }
 
     async fn do_data_mirroring(
         read_half: &mut BufReader<OwnedReadHalf>,
-        mut stats: Stats,
-        subs: Arc<RwLock<Vec<UnboundedSender<Vec<u8>>>>>,
-        stats_tx: &StatSender,
+        data_tx: &DataSender,
+        stats: &MountStats,
+        buffer_pool: &Arc<BufferPool>,
+        chunk_size: usize,
     ) {
-        let mut buffer = Vec::with_capacity(16384);
-
-        let last_sub_clean = Instant::now();
-
         loop {
-            buffer.clear();
-            let buf = read_half.read_buf(&mut buffer).await;
+            let mut buffer = buffer_pool.get(chunk_size);
+            let buf = read_half.read_buf(&mut *buffer).await;
 
             if let Ok(bytes) = buf {
-                stats.bytes_in += bytes;
+                stats.add_bytes_in(bytes);
                 if bytes == 0 {
                     break;
                 }
 
-                let mut subs_to_remove = false;
-                {
-                    let subs = subs.read().await;
-                    let sub_count = subs.len();
-                    stats.sub_count = sub_count;
-
-                    for sub in subs.iter() {
-                        if let Err(_) = sub.send(buffer.clone()) {
-                            stats.sub_count -= 1;
-                            subs_to_remove = true;
-                        } else {
-                            stats.bytes_out += bytes;
-                        }
-                    }
-                }
-                // Remove disconnected subs from the list
-                if subs_to_remove
-                    && Instant::now().duration_since(last_sub_clean) > Duration::from_secs(10)
-                {
-                    let mut write_lock = subs.write().await;
-                    let filtered = write_lock
-                        .clone()
-                        .into_iter()
-                        .filter(|sub| !sub.is_closed())
-                        .collect();
-                    *write_lock = filtered;
-                }
-                if stats_tx.send(stats).is_err() {
-                    break;
-                }
+                // Sending only fails if there are no subscribers at all,
+                // which is fine.
+                data_tx.send(Arc::new(buffer)).ok();
             } else {
                 break;
             }
@@ -372,28 +1047,178 @@ where
     }
 
     async fn run_source(
-        stats: Stats,
-        subs_rx: &mut SubReceiver,
-        stats_tx: &StatSender,
+        data_tx: &DataSender,
+        stats: &MountStats,
+        buffer_pool: &Arc<BufferPool>,
+        chunk_size: usize,
+        report_headers: &[String],
         write_half: &mut OwnedWriteHalf,
         read_half: &mut BufReader<OwnedReadHalf>,
     ) {
-        BasicHttpResponse::OK.send(write_half).await;
+        let headers: Vec<&str> = report_headers.iter().map(|h| h.as_str()).collect();
+        BasicHttpResponse::ok(&headers).send(write_half).await;
 
-        let subs = Arc::new(RwLock::new(Vec::new()));
+        Self::do_data_mirroring(read_half, data_tx, stats, buffer_pool, chunk_size).await;
+    }
+}
 
-        let add_subs = async {
-            while let Some(sub) = subs_rx.recv().await {
-                let mut subs = subs.write().await;
-                subs.push(sub);
-            }
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::{auth::basic_authorization, config::MountConfig};
+
+    fn mount(config: MountConfig) -> Mount {
+        Mount::new(
+            "audio/mpeg".to_string(),
+            tokio::sync::broadcast::channel(1).0.downgrade(),
+            IceMeta::default(),
+            config,
+        )
+    }
+
+    async fn admit(
+        state: &State,
+        mount: &Mount,
+        authorization: Option<&str>,
+    ) -> Result<(), CreateConnectorError> {
+        let query = Query::parse("");
+        let request = ListenerRequest {
+            remote_ip: IpAddr::from(Ipv4Addr::LOCALHOST),
+            mount_path: "/live",
+            user_agent: None,
+            referer: None,
+            forwarded_proto: None,
+            authorization,
+            query: &query,
+            is_admin: false,
+            head: false,
         };
+        admit_listener(state, mount, &request).await
+    }
+
+    #[tokio::test]
+    async fn min_sub_auth_rejects_forged_credentials() {
+        let state = State::new();
+        let jwt = mount(MountConfig {
+            min_sub_auth: Some(AuthMechanism::Jwt),
+            ..MountConfig::default()
+        });
+        for forged in ["Bearer a.b.c", "Bearer eyJhbGciOiJIUzI1NiJ9.e30.c2ln"] {
+            assert!(matches!(
+                admit(&state, &jwt, Some(forged)).await,
+                Err(CreateConnectorError::Unauthorized)
+            ));
+        }
+
+        let basic = mount(MountConfig {
+            min_sub_auth: Some(AuthMechanism::Basic),
+            ..MountConfig::default()
+        });
+        for forged in [
+            basic_authorization("anyone", "anything"),
+            "Bearer opaque".to_string(),
+        ] {
+            assert!(matches!(
+                admit(&state, &basic, Some(&forged)).await,
+                Err(CreateConnectorError::Unauthorized)
+            ));
+        }
+        assert!(matches!(
+            admit(&state, &basic, None).await,
+            Err(CreateConnectorError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn min_sub_auth_accepts_valid_credentials() {
+        let state = State::new();
+        let mount = mount(MountConfig {
+            min_sub_auth: Some(AuthMechanism::Basic),
+            sub_auth: Some("dj:hackme".to_string()),
+            ..MountConfig::default()
+        });
+        let valid = basic_authorization("dj", "hackme");
+        assert!(admit(&state, &mount, Some(&valid)).await.is_ok());
+        let wrong = basic_authorization("dj", "wrong");
+        assert!(matches!(
+            admit(&state, &mount, Some(&wrong)).await,
+            Err(CreateConnectorError::Unauthorized)
+        ));
+    }
 
-        let send_data = Self::do_data_mirroring(read_half, stats, subs.clone(), stats_tx);
+    #[tokio::test]
+    async fn min_sub_auth_checks_json_web_tokens() {
+        use jsonwebtoken::{encode, EncodingKey, Header};
 
-        tokio::select! {
-            _ = add_subs => {},
-            _ = send_data => {},
+        let mut state = State::new();
+        state.set_jwt(Some(
+            crate::jwt::JwtVerifier::new(crate::jwt::JwtConfig {
+                secret: Some("secret".to_string()),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let mount = mount(MountConfig {
+            min_sub_auth: Some(AuthMechanism::Jwt),
+            ..MountConfig::default()
+        });
+
+        let exp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 600;
+        let token = |secret: &str| {
+            let claims = serde_json::json!({ "scope": "listen:/live", "exp": exp });
+            let key = EncodingKey::from_secret(secret.as_bytes());
+            format!(
+                "Bearer {}",
+                encode(&Header::default(), &claims, &key).unwrap()
+            )
         };
+        assert!(admit(&state, &mount, Some(&token("secret"))).await.is_ok());
+        assert!(matches!(
+            admit(&state, &mount, Some(&token("forged"))).await,
+            Err(CreateConnectorError::Unauthorized)
+        ));
+    }
+
+    #[tokio::test]
+    async fn require_tls_ignores_forwarded_proto_from_untrusted_peers() {
+        let config: Config = toml::from_str(
+            "allow_unauthenticated_mounts = true\ntrusted_proxies = [\"10.0.0.0/8\"]\n[mounts]",
+        )
+        .unwrap();
+        let state = State::new();
+        let mount = mount(MountConfig {
+            require_tls: true,
+            ..MountConfig::default()
+        });
+
+        let query = Query::parse("");
+        for (remote_ip, admitted) in [
+            (IpAddr::from(Ipv4Addr::new(192, 0, 2, 1)), false),
+            (IpAddr::from(Ipv4Addr::new(10, 0, 0, 1)), true),
+        ] {
+            let request = ListenerRequest {
+                remote_ip,
+                mount_path: "/live",
+                user_agent: None,
+                referer: None,
+                forwarded_proto: forwarded_proto(&config, remote_ip, Some("https")),
+                authorization: None,
+                query: &query,
+                is_admin: false,
+                head: false,
+            };
+            let result = admit_listener(&state, &mount, &request).await;
+            if admitted {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(CreateConnectorError::TlsRequired)));
+            }
+        }
     }
 }
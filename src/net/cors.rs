@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+/// The headers of streams that browser players may read
+const EXPOSED_HEADERS: &str = "icy-name, icy-description, icy-genre, icy-url, icy-pub, \
+    ice-audio-info, X-Peroxidecast-Bandwidth, X-Peroxidecast-Rendition, X-Peroxidecast-Session";
+
+/// The methods that browsers may use in cross-origin requests
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
+/// Which cross-origin requests browsers may make to the API and to
+/// streams
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// The origins (e.g. `https://player.example.com`) that may make
+    /// cross-origin requests, or `*` to allow any origin
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    /// The request headers that cross-origin requests may carry
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    /// How long browsers may cache the response to a preflight request
+    pub max_age_secs: Option<u64>,
+}
+
+impl CorsConfig {
+    /// The value of `Access-Control-Allow-Origin` for a request from
+    /// `origin`, if it is allowed
+    fn allow_origin<'o>(&'o self, origin: Option<&'o str>) -> Option<&'o str> {
+        if self.allowed_origins.iter().any(|o| o == "*") {
+            Some("*")
+        } else {
+            let origin = origin?;
+            self.allowed_origins
+                .iter()
+                .any(|o| o == origin)
+                .then_some(origin)
+        }
+    }
+
+    /// The headers to add to a response to a request from `origin`
+    pub fn headers(&self, origin: Option<&str>) -> Vec<String> {
+        let mut headers = Vec::new();
+        if let Some(allowed) = self.allow_origin(origin) {
+            headers.push(format!("Access-Control-Allow-Origin: {}", allowed));
+            headers.push(format!(
+                "Access-Control-Expose-Headers: {}",
+                EXPOSED_HEADERS
+            ));
+        }
+        if !self.allowed_origins.iter().any(|o| o == "*") {
+            headers.push("Vary: Origin".to_string());
+        }
+        headers
+    }
+
+    /// The headers to add to the response to a preflight (`OPTIONS`)
+    /// request from `origin`
+    pub fn preflight_headers(&self, origin: Option<&str>) -> Vec<String> {
+        let mut headers = self.headers(origin);
+        if self.allow_origin(origin).is_some() {
+            headers.push(format!("Access-Control-Allow-Methods: {}", ALLOWED_METHODS));
+            if !self.allowed_headers.is_empty() {
+                headers.push(format!(
+                    "Access-Control-Allow-Headers: {}",
+                    self.allowed_headers.join(", ")
+                ));
+            }
+            if let Some(max_age) = self.max_age_secs {
+                headers.push(format!("Access-Control-Max-Age: {}", max_age));
+            }
+        }
+        headers
+    }
+}
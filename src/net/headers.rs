@@ -0,0 +1,19 @@
+use httparse::Header;
+
+/// Find the raw value of the header called `name`.
+///
+/// Header names are case-insensitive, so clients sending e.g.
+/// `content-type` are treated the same as those sending `Content-Type`.
+pub fn find_header_bytes<'h>(headers: &[Header<'h>], name: &str) -> Option<&'h [u8]> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value)
+}
+
+/// Find the value of the header called `name`, if it is valid UTF-8.
+///
+/// Header names are matched case-insensitively.
+pub fn find_header<'h>(headers: &[Header<'h>], name: &str) -> Option<&'h str> {
+    find_header_bytes(headers, name).and_then(|v| std::str::from_utf8(v).ok())
+}
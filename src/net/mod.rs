@@ -1,3 +1,21 @@
+mod client;
+pub use client::*;
+
+mod compression;
+pub use compression::*;
+
+mod cors;
+pub use cors::*;
+
+mod headers;
+pub use headers::*;
+
+mod query;
+pub use query::*;
+
+mod router;
+pub(crate) use router::*;
+
 mod connector;
 pub use connector::*;
 
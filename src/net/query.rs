@@ -0,0 +1,61 @@
+/// The parameters in the query string of a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    params: Vec<(String, String)>,
+    /// All parameters with a valid UTF-8 name, with their values as bytes
+    raw_params: Vec<(String, Vec<u8>)>,
+}
+
+impl Query {
+    /// Parse a query string (without the leading `?`). Names and values
+    /// are percent-decoded, and parameters that are not valid UTF-8 once
+    /// decoded are ignored.
+    pub fn parse(query: &str) -> Self {
+        let raw_params: Vec<(String, Vec<u8>)> = query
+            .split('&')
+            .filter(|p| !p.is_empty())
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                let name = urlencoding::decode(name).ok()?;
+                let value = urlencoding::decode_binary(value.as_bytes());
+                Some((name.into_owned(), value.into_owned()))
+            })
+            .collect();
+
+        let params = raw_params
+            .iter()
+            .filter_map(|(name, value)| {
+                let value = std::str::from_utf8(value).ok()?;
+                Some((name.clone(), value.to_string()))
+            })
+            .collect();
+
+        Self { params, raw_params }
+    }
+
+    /// The value of the first parameter called `name`
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The percent-decoded bytes of the first parameter called `name`,
+    /// even if they are not valid UTF-8
+    pub fn get_bytes(&self, name: &str) -> Option<&[u8]> {
+        self.raw_params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_slice())
+    }
+}
+
+/// Split the target of a request into its path and its query, so that
+/// e.g. `/stream?nocache=123` is routed to `/stream`
+pub fn split_target(target: &str) -> (&str, Query) {
+    match target.split_once('?') {
+        Some((path, query)) => (path, Query::parse(query)),
+        None => (target, Query::default()),
+    }
+}
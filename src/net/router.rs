@@ -0,0 +1,1844 @@
+//! The routes of the API and of the static files.
+//!
+//! Requests for streams are not routed here: their response is a
+//! [`Handoff`], after which the socket is handed to a [`super::Connector`].
+
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    path::Component,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequestParts, Path, Request, State as Api},
+    http::{
+        header::{
+            ACCEPT_ENCODING, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+            LOCATION, VARY,
+        },
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{any, get, post},
+    Extension, Router,
+};
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tracing::{debug, error, info, trace, warn};
+
+use crate::{
+    admin_ui,
+    api::{CurrentSong, HistorySample, IcecastStatus, MountHistory, MountInfo},
+    auth::{self, ApiKeyScope, Role},
+    config::Config,
+    dependencies::DependencyGraph,
+    grafana,
+    jwt::Scope,
+    prometheus, retention,
+    server::ReloadSummary,
+    sessions::{MessageError, MoveTo, SessionInfo},
+    snapshot,
+    state::{is_authorization, Mount, MountAccessUpdate, State, StreamUrl},
+    status_page::{escape_xml, StatusPage},
+    templates::{self, ErrorPage},
+};
+
+use super::{
+    admit_listener, forwarded_proto, listener_authorization, CreateConnectorError, Encoding,
+    ListenerRequest, Query, MIN_COMPRESSED_SIZE,
+};
+
+/// The methods that are answered by the server
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, SOURCE, OPTIONS";
+
+/// Loads and applies the configuration again
+pub(crate) type Reloader = Arc<dyn Fn() -> Result<ReloadSummary, String> + Send + Sync>;
+
+/// Everything that the routes need to answer a request
+#[derive(Clone)]
+struct ApiState {
+    config: Arc<Config>,
+    state: Arc<State>,
+    reload: Option<Reloader>,
+}
+
+/// The addresses of the connection that a request was received on
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Peer {
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+}
+
+/// Marks the response to a request for a stream, which is not answered
+/// by the router but by handing the connection to a connector
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Handoff;
+
+impl IntoResponse for Handoff {
+    fn into_response(self) -> Response {
+        let mut response = Response::default();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Query {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Query::parse(parts.uri.query().unwrap_or("")))
+    }
+}
+
+/// Build the router for the API and static files
+pub(crate) fn router(config: Arc<Config>, state: Arc<State>, reload: Option<Reloader>) -> Router {
+    let api = ApiState {
+        config,
+        state,
+        reload,
+    };
+
+    Router::new()
+        .route("/", get(root))
+        .route("/favicon.ico", get(static_file))
+        .route("/static/{*path}", get(static_file))
+        .route("/mount_info", get(mount_info))
+        .route("/status", get(status_page))
+        .route("/playlist.m3u", get(playlist_m3u))
+        .route("/playlist.pls", get(playlist_pls))
+        .route("/status-json.xsl", get(status_json))
+        .route("/7.html", get(seven_html))
+        .route("/currentsong", get(currentsong))
+        .route("/statistics", get(statistics))
+        .route("/metrics", get(metrics))
+        .route("/admin/ui", get(admin_ui_redirect))
+        .route("/admin/ui/", get(admin_ui))
+        .route("/admin/ui/{file}", get(admin_ui))
+        .route("/admin/mounts", get(admin_mounts))
+        .route("/admin/debug/recent_failures", get(recent_failures))
+        .route("/admin/dependencies", get(dependencies))
+        .route("/admin/relays", get(relays))
+        .route("/admin/metadata", any(metadata))
+        .route("/admin/listclients", get(listclients))
+        .route("/admin/killclient", get(killclient))
+        .route("/admin/killsource", get(killsource))
+        .route("/admin/moveclients", get(moveclients))
+        .route(
+            "/admin/mount_config",
+            get(mount_config).post(update_mount_config),
+        )
+        .route("/admin/purge", post(purge))
+        .route("/admin/ban", get(ban))
+        .route("/admin/unban", get(unban))
+        .route("/admin/bans", get(bans))
+        .route("/admin/reloadconfig", get(reloadconfig).post(reloadconfig))
+        .route("/admin/sessions", get(sessions))
+        .route("/admin/sessions/summary", get(session_summary))
+        .route("/admin/sessions/{id}", get(session))
+        .route("/admin/sessions/{id}/message", post(session_message))
+        .route("/admin/{*path}", any(unknown_admin))
+        .route("/api/history", get(history))
+        .route("/api/v1/grafana", get(StatusCode::OK))
+        .route("/api/v1/grafana/", get(StatusCode::OK))
+        .route("/api/v1/grafana/search", post(grafana_search))
+        .route("/api/v1/grafana/metrics", post(grafana_metrics))
+        .route("/api/v1/grafana/query", post(grafana_query))
+        .route("/api/v1/grafana/{*path}", any(StatusCode::NOT_FOUND))
+        .route("/api/v1/mounts/{*path}", any(mounts))
+        .route("/api/mounts/{*path}", any(mounts))
+        .fallback(fallback)
+        .layer(middleware::from_fn(log_auth_failures))
+        .layer(middleware::from_fn_with_state(api.clone(), rate_limit))
+        .layer(middleware::from_fn_with_state(api.clone(), error_page))
+        .layer(middleware::from_fn_with_state(api.clone(), cors))
+        .layer(middleware::from_fn(compress))
+        .with_state(api)
+}
+
+/// Add the configured CORS headers to all responses, and answer
+/// preflight requests for all paths, including streams
+async fn cors(Api(api): Api<ApiState>, request: Request, next: Next) -> Response {
+    let origin = request
+        .headers()
+        .get("Origin")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    let origin = origin.as_deref();
+
+    if request.method() == Method::OPTIONS {
+        let mut headers = vec![format!("Allow: {}", ALLOWED_METHODS)];
+        if let Some(cors) = &api.config.cors {
+            headers.extend(cors.preflight_headers(origin));
+        }
+
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        append_headers(response.headers_mut(), &headers);
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    if let Some(cors) = &api.config.cors {
+        append_headers(response.headers_mut(), &cors.headers(origin));
+    }
+    response
+}
+
+/// Compress textual responses (e.g. `/mount_info`, `/status-json.xsl`
+/// and `/api/history`) for clients that accept it
+async fn compress(request: Request, next: Next) -> Response {
+    let encoding = request
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Encoding::negotiate);
+    // The length of the response to a HEAD request must match that of
+    // the uncompressed response to a GET request
+    let head = request.method() == Method::HEAD;
+
+    let response = next.run(request).await;
+    let Some(encoding) = encoding.filter(|_| !head) else {
+        return response;
+    };
+
+    let compressible = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type.starts_with("text/")
+                || content_type.contains("json")
+                || content_type.contains("xml")
+                || content_type.contains("javascript")
+        });
+    if !compressible || response.headers().contains_key(CONTENT_ENCODING) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Could not read response to compress it: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    if body.len() < MIN_COMPRESSED_SIZE {
+        return Response::from_parts(parts, Body::from(body));
+    }
+
+    match encoding.compress(&body) {
+        Ok(compressed) => {
+            parts.headers.remove(CONTENT_LENGTH);
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+            Response::from_parts(parts, Body::from(compressed))
+        }
+        Err(e) => {
+            warn!("Could not compress response: {}", e);
+            Response::from_parts(parts, Body::from(body))
+        }
+    }
+}
+
+/// Turn away clients that make too many requests to the admin API or to
+/// `/mount_info`
+async fn rate_limit(
+    Api(api): Api<ApiState>,
+    peer: Extension<Peer>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let path = request.uri().path();
+    let limited = path.starts_with("/admin/") || path == "/mount_info";
+
+    if let (true, Some(rate_limiter)) = (limited, api.state.rate_limiter()) {
+        let ip = peer.remote_addr.ip();
+        if !rate_limiter.try_acquire(ip) {
+            debug!("Rate limiting {} on {}", ip, path);
+            return (StatusCode::TOO_MANY_REQUESTS, [("Retry-After", "1")]).into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+/// A response to a request that was rejected for its credentials, which
+/// `log_auth_failures` logs along with the role that the client tried to
+/// act in
+fn unauthorized(role: Role) -> Response {
+    let mut response = StatusCode::UNAUTHORIZED.into_response();
+    response.extensions_mut().insert(role);
+    response
+}
+
+/// Log the requests that were rejected for their credentials
+async fn log_auth_failures(peer: Extension<Peer>, request: Request, next: Next) -> Response {
+    let path = request.uri().path().to_string();
+    let authorization = authorization(request.headers());
+
+    let response = next.run(request).await;
+    if let Some(role) = response.extensions().get::<Role>() {
+        auth::log_failure(
+            *role,
+            peer.remote_addr.ip(),
+            &path,
+            authorization.as_deref(),
+        );
+    }
+    response
+}
+
+/// Replace the body of failed requests from browsers with the error
+/// page template, if one is configured
+async fn error_page(Api(api): Api<ApiState>, request: Request, next: Next) -> Response {
+    let accept = request
+        .headers()
+        .get("Accept")
+        .and_then(|v| v.to_str().ok());
+    let wants_page = request.method() == Method::GET && templates::accepts_html(accept);
+    let path = request.uri().path().to_string();
+
+    let response = next.run(request).await;
+    let status = response.status();
+    if !wants_page || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
+
+    let page = ErrorPage {
+        status: status.as_u16(),
+        reason: status.canonical_reason().unwrap_or("Error").to_string(),
+        path,
+    };
+    let Some(html) = api
+        .state
+        .templates()
+        .and_then(|templates| templates.render_error(&page))
+    else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    Response::from_parts(parts, Body::from(html))
+}
+
+/// Append headers in the form of `Name: value` to `headers`
+fn append_headers(headers: &mut HeaderMap, lines: &[String]) {
+    for line in lines {
+        let parsed = line.split_once(':').and_then(|(name, value)| {
+            let name = HeaderName::from_bytes(name.trim().as_bytes()).ok()?;
+            let value = HeaderValue::from_str(value.trim()).ok()?;
+            Some((name, value))
+        });
+        if let Some((name, value)) = parsed {
+            headers.append(name, value);
+        }
+    }
+}
+
+/// Respond with `value` as (pretty-printed) JSON
+fn json<T: Serialize>(value: &T) -> Response {
+    match serde_json::to_string_pretty(value) {
+        Ok(string) => ([(CONTENT_TYPE, "application/json")], string).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn authorization(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+}
+
+fn is_admin(api: &ApiState, headers: &HeaderMap) -> bool {
+    is_admin_authorization(&api.config, &api.state, authorization(headers).as_deref())
+}
+
+/// Whether the request may use the admin endpoints that change nothing
+fn is_admin_reader(api: &ApiState, headers: &HeaderMap) -> bool {
+    let authorization = authorization(headers);
+    authorization
+        .as_deref()
+        .and_then(|authorization| auth::api_key_scope(&api.config.api_keys, authorization))
+        .is_some()
+        || is_admin_authorization(&api.config, &api.state, authorization.as_deref())
+}
+
+/// Whether `authorization` holds admin credentials, or an API key with
+/// the `admin` scope
+pub(crate) fn is_admin_authorization(
+    config: &Config,
+    state: &State,
+    authorization: Option<&str>,
+) -> bool {
+    let has_admin_key = authorization
+        .and_then(|authorization| auth::api_key_scope(&config.api_keys, authorization))
+        == Some(ApiKeyScope::Admin);
+
+    has_admin_key
+        || is_authorization(
+            authorization,
+            config.admin_credentials().as_deref(),
+            state.admin_htpasswd(),
+        )
+}
+
+/// Build the public URL of the stream of mount `mount_name`, as seen by
+/// a client that sent a request with the given `Host` and
+/// `X-Forwarded-Host` to `local_addr`
+pub(crate) fn stream_url(
+    config: &Config,
+    mount_name: &str,
+    mount: &Mount,
+    host: Option<&str>,
+    x_forwarded_host: Option<&str>,
+    local_addr: SocketAddr,
+) -> String {
+    let stream_url = mount
+        .stream_url()
+        .clone()
+        .or(config.default_stream_url.clone())
+        .unwrap_or_default();
+
+    let host = host
+        .map(String::from)
+        .unwrap_or(format!("{:?}", local_addr));
+
+    match stream_url {
+        StreamUrl::Hostname => format!("{}{}", host, mount_name),
+        StreamUrl::XForwardedHostName => {
+            format!(
+                "{}{}",
+                x_forwarded_host.map(String::from).unwrap_or(host),
+                mount_name
+            )
+        }
+        StreamUrl::Static(value) => value,
+    }
+}
+
+/// The absolute URL at which `mount` can be played: its static
+/// `stream_url`, or else the mount under `base_url`, or else the mount
+/// under the host that the client requested
+fn playable_url(
+    config: &Config,
+    mount_name: &str,
+    mount: &Mount,
+    headers: &HeaderMap,
+    local_addr: SocketAddr,
+) -> String {
+    if let (Some(base_url), false) = (
+        &config.base_url,
+        matches!(mount.stream_url(), Some(StreamUrl::Static(_))),
+    ) {
+        return format!("{}{}", base_url.trim_end_matches('/'), mount_name);
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let url = stream_url(
+        config,
+        mount_name,
+        mount,
+        header("Host"),
+        header("X-Forwarded-Host"),
+        local_addr,
+    );
+    if url.contains("://") {
+        url
+    } else {
+        // Behind a proxy that terminates TLS, the client used https
+        let scheme = match header("X-Forwarded-Proto") {
+            Some(proto) if proto.eq_ignore_ascii_case("https") => "https",
+            _ => "http",
+        };
+        format!("{}://{}", scheme, url)
+    }
+}
+
+/// An entry of a playlist
+struct PlaylistEntry {
+    title: String,
+    url: String,
+}
+
+impl PlaylistEntry {
+    fn new(
+        api: &ApiState,
+        mount_name: &str,
+        mount: &Mount,
+        headers: &HeaderMap,
+        peer: &Peer,
+    ) -> Self {
+        let title = mount.metadata().name().unwrap_or(mount_name).to_string();
+        Self {
+            // Playlists are line-based
+            title: title.replace(['\r', '\n'], " "),
+            url: playable_url(&api.config, mount_name, mount, headers, peer.local_addr),
+        }
+    }
+}
+
+/// An M3U playlist, which media players open to play its entries
+fn m3u_playlist(entries: &[PlaylistEntry]) -> Response {
+    let mut playlist = String::from("#EXTM3U\n");
+    for entry in entries {
+        playlist.push_str(&format!("#EXTINF:-1,{}\n{}\n", entry.title, entry.url));
+    }
+
+    ([(CONTENT_TYPE, "audio/x-mpegurl")], playlist).into_response()
+}
+
+/// A PLS playlist, the format of SHOUTcast
+fn pls_playlist(entries: &[PlaylistEntry]) -> Response {
+    let mut playlist = format!("[playlist]\nNumberOfEntries={}\n", entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        playlist.push_str(&format!(
+            "File{n}={}\nTitle{n}={}\nLength{n}=-1\n",
+            entry.url, entry.title
+        ));
+    }
+    playlist.push_str("Version=2\n");
+
+    ([(CONTENT_TYPE, "audio/x-scpls")], playlist).into_response()
+}
+
+/// The entries of the playlists of the whole station: all mounts that
+/// are not hidden and have a source, ordered by name
+fn station_playlist(api: &ApiState, headers: &HeaderMap, peer: &Peer) -> Vec<PlaylistEntry> {
+    let mut mounts = api.state.visible_mounts();
+    mounts.retain(|(_, mount)| mount.is_connected());
+    mounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    mounts
+        .iter()
+        .map(|(name, mount)| PlaylistEntry::new(api, name, mount, headers, peer))
+        .collect()
+}
+
+async fn playlist_m3u(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    m3u_playlist(&station_playlist(&api, &headers, &peer))
+}
+
+async fn playlist_pls(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    pls_playlist(&station_playlist(&api, &headers, &peer))
+}
+
+async fn mount_info(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let mount_info: Vec<MountInfo> = api
+        .state
+        .visible_mounts()
+        .iter()
+        .map(|(n, m)| {
+            let stream_url = stream_url(
+                &api.config,
+                n,
+                m,
+                header("Host"),
+                header("X-Forwarded-Host"),
+                peer.local_addr,
+            );
+            MountInfo::from_named_mount(n, m, stream_url)
+        })
+        .collect();
+
+    json(&mount_info)
+}
+
+/// The HTML status page, listing the mounts that are not hidden. It is
+/// rendered with the status template if one is configured.
+async fn status_page(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    let page = StatusPage::from_state(&api.state, |name, mount| {
+        playable_url(&api.config, name, mount, &headers, peer.local_addr)
+    });
+
+    let html = api
+        .state
+        .templates()
+        .and_then(|templates| templates.render_status(&page))
+        .unwrap_or_else(|| page.to_html());
+
+    ([(CONTENT_TYPE, "text/html; charset=utf-8")], html).into_response()
+}
+
+/// The status of the server in the schema of Icecast's `/status-json.xsl`
+async fn status_json(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    // The host name without the port
+    let host = header("Host")
+        .map(|host| match host.rsplit_once(':') {
+            Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+            _ => host,
+        })
+        .map(String::from)
+        .unwrap_or_else(|| peer.local_addr.ip().to_string());
+
+    let status = IcecastStatus::from_state(&api.state, &host, |name, mount| {
+        playable_url(&api.config, name, mount, &headers, peer.local_addr)
+    });
+
+    json(&status)
+}
+
+/// The statistics of a mount, as reported by SHOUTcast
+struct ShoutcastStream {
+    name: String,
+    on_air: bool,
+    listeners: usize,
+    peak_listeners: usize,
+    max_listeners: usize,
+    unique_listeners: usize,
+    bitrate: u32,
+    genre: String,
+    url: String,
+    title: String,
+    song: String,
+    content_type: String,
+}
+
+/// The statistics of all visible mounts, sorted by name. Like SHOUTcast stream
+/// IDs, the `sid` of a stream is its position in this list plus one.
+fn shoutcast_streams(api: &ApiState) -> Vec<ShoutcastStream> {
+    let mut mounts = api.state.visible_mounts();
+    mounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    mounts
+        .into_iter()
+        .map(|(name, mount)| {
+            let meta = mount.metadata();
+            let subscribers = mount.stats_handle().subscribers();
+            let mut ips: Vec<_> = api
+                .state
+                .sessions()
+                .for_mount(&name)
+                .into_iter()
+                .map(|s| s.remote_ip)
+                .collect();
+            ips.sort();
+            ips.dedup();
+
+            ShoutcastStream {
+                on_air: mount.is_connected(),
+                listeners: subscribers.count(),
+                peak_listeners: subscribers.peak(),
+                max_listeners: mount
+                    .max_listeners()
+                    .or(api.config.max_clients)
+                    .unwrap_or(0),
+                unique_listeners: ips.len(),
+                bitrate: mount.bitrate().unwrap_or(0),
+                genre: meta.genre().unwrap_or("").to_string(),
+                url: meta.url().unwrap_or("").to_string(),
+                title: meta.name().unwrap_or("").to_string(),
+                song: mount.song().unwrap_or_default(),
+                content_type: mount.content_type(),
+                name,
+            }
+        })
+        .collect()
+}
+
+/// The statistics of a stream in the format of SHOUTcast's `/7.html`:
+/// `<listeners>,<status>,<peak>,<max>,<unique>,<bitrate>,<song>`. The
+/// stream is selected with `sid=<n>` or `mount=<name>`, and defaults to
+/// the first stream.
+async fn seven_html(Api(api): Api<ApiState>, query: Query) -> Response {
+    let streams = shoutcast_streams(&api);
+    let stream = if let Some(mount) = query.get("mount") {
+        streams.iter().find(|s| s.name == mount)
+    } else {
+        let sid: usize = query.get("sid").and_then(|s| s.parse().ok()).unwrap_or(1);
+        sid.checked_sub(1).and_then(|idx| streams.get(idx))
+    };
+
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let body = format!(
+        "<html><body>{},{},{},{},{},{},{}</body></html>",
+        stream.listeners,
+        stream.on_air as u8,
+        stream.peak_listeners,
+        stream.max_listeners,
+        stream.unique_listeners,
+        stream.bitrate,
+        escape_xml(&stream.song),
+    );
+    ([(CONTENT_TYPE, "text/html")], body).into_response()
+}
+
+/// The current song of the mount called `mount`, or of the `sid`th mount
+/// by name like on SHOUTcast. As plain text by default, or as JSON with
+/// `format=json`.
+async fn currentsong(Api(api): Api<ApiState>, query: Query) -> Response {
+    let mount = if let Some(mount_name) = query.get("mount") {
+        api.state
+            .find_mount(mount_name)
+            .map(|mount| (mount_name.to_string(), mount))
+    } else {
+        let mut mounts = api.state.visible_mounts();
+        mounts.sort_by(|a, b| a.0.cmp(&b.0));
+        let sid: usize = query.get("sid").and_then(|s| s.parse().ok()).unwrap_or(1);
+        sid.checked_sub(1)
+            .filter(|idx| *idx < mounts.len())
+            .map(|idx| mounts.swap_remove(idx))
+    };
+
+    let (mount_name, mount) = match mount {
+        Some(mount) => mount,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let song = mount.song();
+    if query.get("format") == Some("json") {
+        let started_at = mount
+            .song_history()
+            .into_iter()
+            .next()
+            .filter(|played| Some(&played.song) == song.as_ref())
+            .map(|played| played.started_at);
+
+        json(&CurrentSong {
+            mount: mount_name,
+            on_air: mount.is_connected(),
+            song,
+            url: mount.song_url(),
+            started_at,
+        })
+    } else {
+        (
+            [(CONTENT_TYPE, "text/plain; charset=utf-8")],
+            song.unwrap_or_default(),
+        )
+            .into_response()
+    }
+}
+
+/// The statistics of all streams, in the XML format of SHOUTcast's
+/// `/statistics`
+async fn statistics(Api(api): Api<ApiState>) -> Response {
+    let streams = shoutcast_streams(&api);
+    let sum = |f: fn(&ShoutcastStream) -> usize| streams.iter().map(f).sum::<usize>();
+
+    let mut xml = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\" ?>\n<SHOUTCASTSERVER>",
+    );
+    xml.push_str(&format!("<TOTALSTREAMS>{}</TOTALSTREAMS>", streams.len()));
+    xml.push_str(&format!(
+        "<ACTIVESTREAMS>{}</ACTIVESTREAMS>",
+        streams.iter().filter(|s| s.on_air).count()
+    ));
+    xml.push_str(&format!(
+        "<CURRENTLISTENERS>{}</CURRENTLISTENERS>",
+        sum(|s| s.listeners)
+    ));
+    xml.push_str(&format!(
+        "<PEAKLISTENERS>{}</PEAKLISTENERS>",
+        api.state.listeners().peak()
+    ));
+    xml.push_str(&format!(
+        "<MAXLISTENERS>{}</MAXLISTENERS>",
+        api.config.max_clients.unwrap_or(0)
+    ));
+    xml.push_str(&format!(
+        "<UNIQUELISTENERS>{}</UNIQUELISTENERS>",
+        sum(|s| s.unique_listeners)
+    ));
+    xml.push_str(&format!(
+        "<VERSION>Peroxidecast {}</VERSION>",
+        env!("CARGO_PKG_VERSION")
+    ));
+
+    xml.push_str("<STREAMSTATS>");
+    for (idx, stream) in streams.iter().enumerate() {
+        xml.push_str(&format!("<STREAM id=\"{}\">", idx + 1));
+        xml.push_str(&format!(
+            "<CURRENTLISTENERS>{}</CURRENTLISTENERS>",
+            stream.listeners
+        ));
+        xml.push_str(&format!(
+            "<PEAKLISTENERS>{}</PEAKLISTENERS>",
+            stream.peak_listeners
+        ));
+        xml.push_str(&format!(
+            "<MAXLISTENERS>{}</MAXLISTENERS>",
+            stream.max_listeners
+        ));
+        xml.push_str(&format!(
+            "<UNIQUELISTENERS>{}</UNIQUELISTENERS>",
+            stream.unique_listeners
+        ));
+        xml.push_str(&format!("<BITRATE>{}</BITRATE>", stream.bitrate));
+        xml.push_str(&format!(
+            "<SERVERGENRE>{}</SERVERGENRE>",
+            escape_xml(&stream.genre)
+        ));
+        xml.push_str(&format!(
+            "<SERVERURL>{}</SERVERURL>",
+            escape_xml(&stream.url)
+        ));
+        xml.push_str(&format!(
+            "<SERVERTITLE>{}</SERVERTITLE>",
+            escape_xml(&stream.title)
+        ));
+        xml.push_str(&format!(
+            "<SONGTITLE>{}</SONGTITLE>",
+            escape_xml(&stream.song)
+        ));
+        xml.push_str(&format!(
+            "<STREAMSTATUS>{}</STREAMSTATUS>",
+            stream.on_air as u8
+        ));
+        xml.push_str(&format!(
+            "<STREAMPATH>{}</STREAMPATH>",
+            escape_xml(&stream.name)
+        ));
+        xml.push_str(&format!(
+            "<CONTENT>{}</CONTENT>",
+            escape_xml(&stream.content_type)
+        ));
+        xml.push_str("</STREAM>");
+    }
+    xml.push_str("</STREAMSTATS></SHOUTCASTSERVER>\n");
+
+    ([(CONTENT_TYPE, "text/xml")], xml).into_response()
+}
+
+/// The metrics of the server, in the Prometheus text format
+async fn metrics(Api(api): Api<ApiState>) -> Response {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        prometheus::render(&api.state),
+    )
+        .into_response()
+}
+
+async fn recent_failures(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&api.state.failures().entries())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+async fn dependencies(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&DependencyGraph::from_config(&api.config).report())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+async fn relays(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&api.state.relays())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+/// Update the song of a mount, as sources do through
+/// `/admin/metadata?mount=<mount>&mode=updinfo&song=<song>`.
+///
+/// Like Icecast, `artist=` and `title=` may be sent instead of `song=`,
+/// `url=` sets a URL about the song and `charset=` sets the encoding of
+/// the parameters (UTF-8 or ISO-8859-1, which old encoders use).
+async fn metadata(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    trace!("Admin metadata request: {:?}", query);
+
+    let auth = if let Some(auth) = authorization(&headers) {
+        auth
+    } else {
+        return unauthorized(Role::Source);
+    };
+
+    let (mount, mount_name) = if let Some(mount_name) = query.get("mount") {
+        if let Some(mount) = api.state.find_mount(mount_name) {
+            (mount, mount_name)
+        } else {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    } else {
+        error!("Could not find mount name for admin request.");
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    if !is_admin(&api, &headers)
+        && mount.requires_source_auth()
+        && !mount.is_source_authorization(Some(&auth))
+        && !api
+            .state
+            .is_jwt_authorization(Some(&auth), Scope::Source, mount_name)
+    {
+        return unauthorized(Role::Source);
+    }
+
+    if Some("updinfo") != query.get("mode") {
+        return StatusCode::BAD_REQUEST.into_response();
+    }
+
+    let decode: fn(&[u8]) -> Option<String> = match query.get("charset") {
+        None => |v| String::from_utf8(v.to_vec()).ok(),
+        Some(charset) => match charset.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => |v| String::from_utf8(v.to_vec()).ok(),
+            "iso-8859-1" | "latin1" | "l1" => |v| Some(v.iter().map(|&b| b as char).collect()),
+            _ => {
+                debug!("Unsupported metadata charset {}", charset);
+                return StatusCode::BAD_REQUEST.into_response();
+            }
+        },
+    };
+    let param = |name| query.get_bytes(name).and_then(decode);
+
+    let song = match (param("song"), param("artist"), param("title")) {
+        (Some(song), _, _) => song,
+        (None, Some(artist), Some(title)) if !artist.is_empty() => {
+            format!("{} - {}", artist, title)
+        }
+        (None, _, Some(title)) => title,
+        (None, Some(artist), None) => artist,
+        (None, None, None) => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let url = param("url").filter(|url| !url.is_empty());
+
+    info!(
+        "Updating mount {}. Setting song name to {} (URL: {:?})",
+        mount_name, song, url
+    );
+
+    mount.set_song_with_url(song, url);
+    StatusCode::OK.into_response()
+}
+
+/// The listeners of a mount, as Icecast-compatible XML or, with
+/// `format=json`, as JSON
+async fn listclients(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    let (mount, mount_name) = if let Some(mount_name) = query.get("mount") {
+        if let Some(mount) = api.state.find_mount(mount_name) {
+            (mount, mount_name)
+        } else {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    } else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    // Like Icecast, sources may list the clients of their own mount
+    let auth = authorization(&headers);
+    let is_source = mount.is_source_authorization(auth.as_deref())
+        || api
+            .state
+            .is_jwt_authorization(auth.as_deref(), Scope::Source, mount_name);
+    if !is_admin_reader(&api, &headers) && !is_source {
+        return unauthorized(Role::Admin);
+    }
+
+    let sessions = api.state.sessions().for_mount(mount_name);
+    if query.get("format") == Some("json") {
+        json(&sessions)
+    } else {
+        (
+            [(CONTENT_TYPE, "text/xml")],
+            listclients_xml(mount_name, &sessions),
+        )
+            .into_response()
+    }
+}
+
+/// Render the listeners of mount `mount_name` like Icecast's
+/// `/admin/listclients`
+fn listclients_xml(mount_name: &str, sessions: &[SessionInfo]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\"?>\n<icestats>\n");
+    xml.push_str(&format!(
+        "  <source mount=\"{}\">\n",
+        escape_xml(mount_name)
+    ));
+    xml.push_str(&format!("    <Listeners>{}</Listeners>\n", sessions.len()));
+    for session in sessions {
+        xml.push_str("    <listener>\n");
+        xml.push_str(&format!("      <IP>{}</IP>\n", session.remote_ip));
+        xml.push_str(&format!(
+            "      <UserAgent>{}</UserAgent>\n",
+            escape_xml(session.user_agent.as_deref().unwrap_or(""))
+        ));
+        xml.push_str(&format!(
+            "      <Connected>{}</Connected>\n",
+            session.connected_secs
+        ));
+        xml.push_str(&format!("      <ID>{}</ID>\n", session.id));
+        xml.push_str("    </listener>\n");
+    }
+    xml.push_str("  </source>\n</icestats>\n");
+    xml
+}
+
+/// Disconnect the listener with session ID `id` from `mount`, answering
+/// like Icecast
+async fn killclient(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    let (mount, mount_name) = if let Some(mount_name) = query.get("mount") {
+        if let Some(mount) = api.state.find_mount(mount_name) {
+            (mount, mount_name)
+        } else {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    } else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let id: u64 = match query.get("id").map(str::parse) {
+        Some(Ok(id)) => id,
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let auth = authorization(&headers);
+    let is_source = mount.is_source_authorization(auth.as_deref())
+        || api
+            .state
+            .is_jwt_authorization(auth.as_deref(), Scope::Source, mount_name);
+    if !is_admin(&api, &headers) && !is_source {
+        return unauthorized(Role::Admin);
+    }
+
+    let sessions = api.state.sessions();
+    let (status, message, success) =
+        if sessions.get(id).map(|s| s.mount == mount_name) == Some(true) && sessions.kill(id) {
+            info!("Disconnecting session {} from mount {}", id, mount_name);
+            (StatusCode::OK, format!("Client {} removed", id), 1)
+        } else {
+            (StatusCode::NOT_FOUND, format!("Client {} not found", id), 0)
+        };
+
+    (
+        status,
+        [(CONTENT_TYPE, "text/xml")],
+        iceresponse(&message, success),
+    )
+        .into_response()
+}
+
+/// The XML response of Icecast to admin commands
+fn iceresponse(message: &str, success: u8) -> String {
+    format!(
+        "<?xml version=\"1.0\"?>\n<iceresponse><message>{}</message><return>{}</return></iceresponse>\n",
+        message, success
+    )
+}
+
+/// Disconnect the source of `mount`, answering like Icecast
+async fn killsource(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let (mount, mount_name) = if let Some(mount_name) = query.get("mount") {
+        if let Some(mount) = api.state.find_mount(mount_name) {
+            (mount, mount_name)
+        } else {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    } else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    let (status, message, success) = if mount.kill_source() {
+        info!("Disconnecting the source of mount {}", mount_name);
+        (StatusCode::OK, "Source Removed", 1)
+    } else {
+        (StatusCode::NOT_FOUND, "Source not connected", 0)
+    };
+    (
+        status,
+        [(CONTENT_TYPE, "text/xml")],
+        iceresponse(message, success),
+    )
+        .into_response()
+}
+
+/// Move all listeners of `mount` to `destination`, answering like Icecast
+async fn moveclients(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let (mount_name, destination_name) = match (query.get("mount"), query.get("destination")) {
+        (Some(mount), Some(destination)) if mount != destination => (mount, destination),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    if api.state.find_mount(mount_name).is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let destination = match api.state.find_mount(destination_name) {
+        Some(destination) if destination.is_connected() => destination,
+        _ => {
+            let message = format!("Destination {} not mounted", destination_name);
+            return (
+                StatusCode::NOT_FOUND,
+                [(CONTENT_TYPE, "text/xml")],
+                iceresponse(&message, 0),
+            )
+                .into_response();
+        }
+    };
+
+    let moved = api.state.sessions().move_mount(
+        mount_name,
+        MoveTo {
+            mount_name: destination_name.to_string(),
+            mount: destination,
+        },
+    );
+    info!(
+        "Moving {} listeners from mount {} to mount {}",
+        moved, mount_name, destination_name
+    );
+
+    let message = format!("Clients moved from {} to {}", mount_name, destination_name);
+    ([(CONTENT_TYPE, "text/xml")], iceresponse(&message, 1)).into_response()
+}
+
+/// The settings of `mount` that can be changed while it is live. These
+/// include its credentials, so read-only API keys may not see them.
+async fn mount_config(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    match query
+        .get("mount")
+        .and_then(|name| api.state.find_mount(name))
+    {
+        Some(mount) => json(&mount.access()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Change the settings of `mount` (a JSON [`MountAccessUpdate`] in the
+/// request body) without disconnecting its source or listeners
+async fn update_mount_config(
+    Api(api): Api<ApiState>,
+    query: Query,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let (mount, mount_name) = match query.get("mount") {
+        Some(mount_name) => match api.state.find_mount(mount_name) {
+            Some(mount) => (mount, mount_name),
+            None => return StatusCode::NOT_FOUND.into_response(),
+        },
+        None => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    let update: MountAccessUpdate = match serde_json::from_slice(&body) {
+        Ok(update) => update,
+        Err(e) => {
+            debug!("Invalid mount config update for {}: {}", mount_name, e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+
+    info!("Updating the config of mount {}", mount_name);
+    json(&mount.update_access(update))
+}
+
+async fn sessions(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&api.state.sessions().list())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+async fn session_summary(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&api.state.sessions().summary())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+async fn session(Api(api): Api<ApiState>, Path(id): Path<u64>, headers: HeaderMap) -> Response {
+    if !is_admin_reader(&api, &headers) {
+        unauthorized(Role::Admin)
+    } else if let Some(session) = api.state.sessions().get(id) {
+        json(&session)
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Show a one-off message (the request body) to the listener of a
+/// session, in place of the title of the current song
+async fn session_message(
+    Api(api): Api<ApiState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let message = match std::str::from_utf8(&body).map(str::trim) {
+        Ok(message) if !message.is_empty() => message.to_string(),
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    info!("Sending message to session {}: {}", id, message);
+    match api.state.sessions().send_message(id, message) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(MessageError::NotFound) => StatusCode::NOT_FOUND.into_response(),
+        Err(MessageError::NotSupported) => StatusCode::CONFLICT.into_response(),
+        Err(MessageError::QueueFull) => StatusCode::TOO_MANY_REQUESTS.into_response(),
+    }
+}
+
+/// Remove all recorded data, or, with `ip=<address>`, the recorded data
+/// about the clients at that address
+async fn purge(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let ip = match query.get("ip").map(|ip| ip.parse()) {
+        Some(Ok(ip)) => Some(ip),
+        Some(Err(_)) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+
+    match retention::purge(&api.state, ip) {
+        Ok(report) => {
+            info!("Purged recorded data (ip: {:?}): {:?}", ip, report);
+            json(&report)
+        }
+        Err(e) => {
+            error!("Purging recorded data failed: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
+/// Ban the address `ip`, for `duration` (e.g. `1h`) or until it is
+/// unbanned, and disconnect its listeners
+async fn ban(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let ip: IpAddr = match query.get("ip").map(str::parse) {
+        Some(Ok(ip)) => ip,
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+    let duration = match query.get("duration").map(humantime::parse_duration) {
+        Some(Ok(duration)) => Some(duration),
+        Some(Err(_)) => return StatusCode::BAD_REQUEST.into_response(),
+        None => None,
+    };
+
+    api.state.bans().ban(ip, duration);
+    let sessions = api.state.sessions();
+    let disconnected = sessions
+        .list()
+        .into_iter()
+        .filter(|session| session.remote_ip.to_canonical() == ip.to_canonical())
+        .filter(|session| sessions.kill(session.id))
+        .count();
+    info!(
+        "Banned {} (duration: {:?}), disconnecting {} listeners",
+        ip, duration, disconnected
+    );
+    StatusCode::OK.into_response()
+}
+
+/// Lift the ban of the address `ip`
+async fn unban(Api(api): Api<ApiState>, query: Query, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let ip: IpAddr = match query.get("ip").map(str::parse) {
+        Some(Ok(ip)) => ip,
+        _ => return StatusCode::BAD_REQUEST.into_response(),
+    };
+
+    if api.state.bans().unban(ip) {
+        info!("Unbanned {}", ip);
+        StatusCode::OK.into_response()
+    } else {
+        StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+async fn bans(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if is_admin_reader(&api, &headers) {
+        json(&api.state.bans().list())
+    } else {
+        unauthorized(Role::Admin)
+    }
+}
+
+/// Load the configuration again and apply it, answering with what changed
+async fn reloadconfig(Api(api): Api<ApiState>, headers: HeaderMap) -> Response {
+    if !is_admin(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let reload = match &api.reload {
+        Some(reload) => reload.clone(),
+        None => return StatusCode::NOT_IMPLEMENTED.into_response(),
+    };
+
+    // The configuration is read from disk
+    match tokio::task::spawn_blocking(move || reload()).await {
+        Ok(Ok(summary)) => json(&summary),
+        Ok(Err(e)) => (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+async fn admin_ui_redirect() -> Response {
+    (StatusCode::MOVED_PERMANENTLY, [(LOCATION, "/admin/ui/")]).into_response()
+}
+
+/// The files of the web admin UI
+async fn admin_ui(uri: Uri) -> Response {
+    let name = uri.path().strip_prefix("/admin/ui/").unwrap_or_default();
+    match admin_ui::file(name) {
+        Some((content_type, contents)) => (
+            [
+                (CONTENT_TYPE, content_type),
+                (
+                    HeaderName::from_static("content-security-policy"),
+                    "default-src 'self'",
+                ),
+            ],
+            contents,
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// All mounts, including hidden ones, like `/mount_info`
+async fn admin_mounts(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    headers: HeaderMap,
+) -> Response {
+    if !is_admin_reader(&api, &headers) {
+        return unauthorized(Role::Admin);
+    }
+
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let mount_info: Vec<MountInfo> = api
+        .state
+        .mounts()
+        .iter()
+        .map(|(n, m)| {
+            let stream_url = stream_url(
+                &api.config,
+                n,
+                m,
+                header("Host"),
+                header("X-Forwarded-Host"),
+                peer.local_addr,
+            );
+            MountInfo::from_named_mount(n, m, stream_url)
+        })
+        .collect();
+
+    json(&mount_info)
+}
+
+async fn unknown_admin(uri: Uri) -> StatusCode {
+    error!("Unknown admin request. {}", uri);
+    StatusCode::BAD_REQUEST
+}
+
+async fn grafana_search(Api(api): Api<ApiState>) -> Response {
+    json(&grafana::search(api.state.history()))
+}
+
+async fn grafana_metrics(Api(api): Api<ApiState>) -> Response {
+    json(&grafana::metrics(api.state.history()))
+}
+
+/// The statistics history of a mount over the last `range` (e.g. `7d`,
+/// defaults to 24 hours), from the history database if it is configured
+async fn history(Api(api): Api<ApiState>, query: Query) -> Response {
+    let Some(mount) = query.get("mount").map(str::to_string) else {
+        return (StatusCode::BAD_REQUEST, "Missing mount").into_response();
+    };
+    let range = match query.get("range").map(humantime::parse_duration) {
+        Some(Ok(range)) => range,
+        Some(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid range: {}", e)).into_response()
+        }
+        None => Duration::from_secs(24 * 60 * 60),
+    };
+
+    let to = SystemTime::now();
+    let Some(from) = to.checked_sub(range) else {
+        return (StatusCode::BAD_REQUEST, "Invalid range").into_response();
+    };
+
+    let persistent = api.state.history_db().is_some();
+    let samples = if persistent {
+        let state = api.state.clone();
+        let name = mount.clone();
+        let samples = tokio::task::spawn_blocking(move || {
+            state
+                .history_db()
+                .expect("History database was set above")
+                .query(&name, from, to)
+        })
+        .await;
+
+        match samples {
+            Ok(Ok(samples)) => samples,
+            Ok(Err(e)) => {
+                error!("Could not query the history database: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+            Err(e) => {
+                error!("Querying the history database failed: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    } else {
+        api.state.history().query(&mount, from, to)
+    };
+
+    json(&MountHistory {
+        mount,
+        from: humantime::format_rfc3339_seconds(from).to_string(),
+        to: humantime::format_rfc3339_seconds(to).to_string(),
+        persistent,
+        samples: samples.iter().map(HistorySample::from).collect(),
+    })
+}
+
+async fn grafana_query(Api(api): Api<ApiState>, body: Bytes) -> Response {
+    let query = serde_json::from_slice(&body)
+        .ok()
+        .and_then(|query| grafana::query(api.state.history(), &query));
+
+    if let Some(series) = query {
+        json(&series)
+    } else {
+        StatusCode::BAD_REQUEST.into_response()
+    }
+}
+
+/// The resources of a mount, under `/api/v1/mounts/<mount>/` and
+/// `/api/mounts/<mount>/`
+async fn mounts(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    Path(path): Path<String>,
+    method: Method,
+    query: Query,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(mount_name) = path.strip_suffix("/snapshot") {
+        if method == Method::GET {
+            snapshot(&api, &peer, &format!("/{}", mount_name), &query, &headers).await
+        } else {
+            StatusCode::METHOD_NOT_ALLOWED.into_response()
+        }
+    } else if let Some(mount_name) = path.strip_suffix("/history") {
+        if method == Method::GET {
+            song_history(&api, &format!("/{}", mount_name))
+        } else {
+            StatusCode::METHOD_NOT_ALLOWED.into_response()
+        }
+    } else {
+        // A stream that happens to be mounted under the API
+        Handoff.into_response()
+    }
+}
+
+/// The last played songs of mount `mount_name`, the most recent one first
+fn song_history(api: &ApiState, mount_name: &str) -> Response {
+    match api.state.find_mount(mount_name) {
+        Some(mount) => json(&mount.song_history()),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// A clip of the last seconds of mount `mount_name`
+async fn snapshot(
+    api: &ApiState,
+    peer: &Peer,
+    mount_name: &str,
+    query: &Query,
+    headers: &HeaderMap,
+) -> Response {
+    let mount = match api.state.find_mount(mount_name) {
+        Some(mount) => mount,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let buffer = if let Some(buffer) = mount.snapshot() {
+        buffer
+    } else {
+        debug!("Snapshots are not enabled for mount {}", mount_name);
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // A snapshot holds the audio of the mount, so only listeners that
+    // may subscribe to it may download one
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let authorization = listener_authorization(authorization(headers).as_deref(), query);
+    let request = ListenerRequest {
+        remote_ip: peer.remote_addr.ip(),
+        mount_path: mount_name,
+        user_agent: header("User-Agent"),
+        referer: header("Referer"),
+        forwarded_proto: forwarded_proto(
+            &api.config,
+            peer.remote_addr.ip(),
+            header("X-Forwarded-Proto"),
+        ),
+        authorization: authorization.as_deref(),
+        query,
+        is_admin: is_admin(api, headers),
+        head: false,
+    };
+    match admit_listener(&api.state, &mount, &request).await {
+        Ok(()) => {}
+        Err(CreateConnectorError::Unauthorized) => return unauthorized(Role::Listener),
+        Err(_) => return StatusCode::FORBIDDEN.into_response(),
+    }
+
+    let seconds = match query.get("seconds").map(|s| s.parse::<u64>()) {
+        Some(Ok(seconds)) if seconds > 0 => seconds,
+        Some(_) => return StatusCode::BAD_REQUEST.into_response(),
+        None => snapshot::DEFAULT_CLIP_SECS,
+    };
+
+    let clip = {
+        let buffer = buffer.lock().unwrap();
+        let length = Duration::from_secs(seconds).min(buffer.retention());
+        buffer.clip(length)
+    };
+
+    let clip = if let Some(clip) = clip {
+        clip
+    } else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let file_name = mount_name.trim_matches('/').replace('/', "-");
+    let disposition = format!(
+        "attachment; filename=\"{}-snapshot.{}\"",
+        file_name, clip.extension
+    );
+
+    (
+        [
+            (CONTENT_TYPE, clip.content_type),
+            (CONTENT_DISPOSITION, disposition),
+        ],
+        clip.data,
+    )
+        .into_response()
+}
+
+/// A redirect to the default mount, keeping the query of `uri`
+fn redirect_to_default_mount(api: &ApiState, uri: &Uri) -> Option<Response> {
+    let default_mount = api.config.default_mount.as_ref()?;
+    let location = match uri.query() {
+        Some(query) => format!("{}?{}", default_mount.mount, query),
+        None => default_mount.mount.clone(),
+    };
+    Some((StatusCode::FOUND, [(LOCATION, location)]).into_response())
+}
+
+async fn root(api: Api<ApiState>, uri: Uri, peer: Extension<Peer>) -> Response {
+    match redirect_to_default_mount(&api, &uri) {
+        Some(redirect) => redirect,
+        None => static_file(api, uri, peer).await,
+    }
+}
+
+/// Hand requests that are not for the API to a connector, answer
+/// requests for playlists of mounts, or redirect requests for unknown
+/// paths to the default mount if so configured
+async fn fallback(
+    Api(api): Api<ApiState>,
+    Extension(peer): Extension<Peer>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    let get = method == Method::GET || method == Method::HEAD;
+
+    // `<mount>.m3u` is a playlist of the mount, unless a mount has that
+    // name itself
+    if let Some(mount_name) = uri.path().strip_suffix(".m3u") {
+        let playlist = get && api.state.find_mount(uri.path()).is_none();
+        if let Some(mount) = api.state.find_mount(mount_name).filter(|_| playlist) {
+            let entry = PlaylistEntry::new(&api, mount_name, &mount, &headers, &peer);
+            return m3u_playlist(&[entry]);
+        }
+    }
+
+    let redirect_unknown = api
+        .config
+        .default_mount
+        .as_ref()
+        .map(|default_mount| default_mount.redirect_unknown)
+        .unwrap_or(false);
+
+    if redirect_unknown && get && api.state.find_mount(uri.path()).is_none() {
+        if let Some(redirect) = redirect_to_default_mount(&api, &uri) {
+            return redirect;
+        }
+    }
+
+    Handoff.into_response()
+}
+
+async fn static_file(Api(api): Api<ApiState>, uri: Uri, peer: Extension<Peer>) -> Response {
+    let uri = match uri.path() {
+        "/" => "/static/index.html",
+        "/favicon.ico" => "/static/favicon.ico",
+        uri => uri,
+    };
+    let stripped = &uri["/static/".len()..];
+
+    let mut path = if let Some(static_sources) = &api.config.static_source_dir {
+        static_sources.clone()
+    } else {
+        warn!("Got request for static files, but no static file path is configured!");
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    // Only plain names, so that the path cannot leave the directory, e.g.
+    // through `..` or by being absolute (as in `/static//etc/passwd`)
+    for component in std::path::Path::new(stripped).components() {
+        match component {
+            Component::Normal(part) if part != "~" => path.push(part),
+            _ => return StatusCode::NOT_FOUND.into_response(),
+        }
+    }
+    debug!("Serving file {:?} to {:?}", path, peer.remote_addr);
+
+    let file_length = match std::fs::metadata(&path) {
+        Ok(file) if !file.is_dir() => file.len(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let file = if let Ok(file) = tokio::fs::File::open(&path).await {
+        file
+    } else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mime_type = mime_guess::from_path(&path)
+        .first_or(mime_guess::mime::TEXT_PLAIN)
+        .to_string();
+
+    let chunks = futures_util::stream::unfold(file, |mut file| async move {
+        let mut buffer = vec![0u8; 32 * 1024];
+        match file.read(&mut buffer).await {
+            Ok(0) => None,
+            Ok(read) => {
+                buffer.truncate(read);
+                Some((Ok::<_, std::io::Error>(buffer), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+
+    (
+        [
+            (CONTENT_LENGTH, file_length.to_string()),
+            (CONTENT_TYPE, mime_type),
+        ],
+        Body::from_stream(chunks),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        auth::AuthMechanism,
+        config::MountConfig,
+        state::{IceMeta, Mount},
+    };
+
+    const CONFIG: &str = r#"
+        allow_unauthenticated_mounts = true
+        trusted_proxies = ["10.0.0.1/32"]
+
+        [[api_keys]]
+        key = "reader"
+        scope = "read"
+
+        [[api_keys]]
+        key = "writer"
+        scope = "admin"
+
+        [jwt]
+        secret = "secret"
+
+        [mounts]
+    "#;
+
+    fn api() -> (Arc<Config>, Arc<State>) {
+        let config: Config = toml::from_str(CONFIG).unwrap();
+        let mut state = State::new();
+        state.set_jwt(Some(
+            crate::jwt::JwtVerifier::new(config.jwt.clone().unwrap()).unwrap(),
+        ));
+
+        let mount = |config: MountConfig| {
+            Mount::new(
+                "audio/mpeg".to_string(),
+                tokio::sync::broadcast::channel(1).0.downgrade(),
+                IceMeta::default(),
+                MountConfig {
+                    snapshot_secs: Some(10),
+                    ..config
+                },
+            )
+        };
+        state.add_mount(
+            "/live".to_string(),
+            mount(MountConfig {
+                min_sub_auth: Some(AuthMechanism::Jwt),
+                ..MountConfig::default()
+            }),
+        );
+        state.add_mount(
+            "/tls".to_string(),
+            mount(MountConfig {
+                require_tls: true,
+                ..MountConfig::default()
+            }),
+        );
+        (Arc::new(config), Arc::new(state))
+    }
+
+    async fn get(uri: &str, remote_ip: Ipv4Addr, headers: &[(&str, &str)]) -> StatusCode {
+        let (config, state) = api();
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let request = request
+            .extension(Peer {
+                local_addr: SocketAddr::from((Ipv4Addr::LOCALHOST, 8000)),
+                remote_addr: SocketAddr::from((IpAddr::from(remote_ip), 40000)),
+            })
+            .body(Body::empty())
+            .unwrap();
+        router(config, state, None)
+            .oneshot(request)
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn read_only_keys_cannot_see_mount_config() {
+        let uri = "/admin/mount_config?mount=/live";
+        let local = Ipv4Addr::LOCALHOST;
+        for authorization in ["Bearer reader", "Bearer forged", "Basic YWRtaW46aGFja21l"] {
+            assert_eq!(
+                get(uri, local, &[("Authorization", authorization)]).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            get(uri, local, &[("Authorization", "Bearer writer")]).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_reject_forged_tokens() {
+        let uri = "/api/v1/mounts/live/snapshot";
+        let local = Ipv4Addr::LOCALHOST;
+        for authorization in ["Bearer a.b.c", "Bearer eyJhbGciOiJIUzI1NiJ9.e30.c2ln"] {
+            assert_eq!(
+                get(uri, local, &[("Authorization", authorization)]).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(get(uri, local, &[]).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn snapshots_trust_forwarded_proto_from_proxies_only() {
+        let uri = "/api/v1/mounts/tls/snapshot";
+        let https = [("X-Forwarded-Proto", "https")];
+        assert_eq!(
+            get(uri, Ipv4Addr::new(192, 0, 2, 1), &https).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            get(uri, Ipv4Addr::new(10, 0, 0, 1), &[]).await,
+            StatusCode::FORBIDDEN
+        );
+        // Admitted, but the mount has no audio to clip yet
+        assert_eq!(
+            get(uri, Ipv4Addr::new(10, 0, 0, 1), &https).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+}
@@ -1,23 +1,34 @@
-use std::{net::SocketAddr, sync::Arc, time::Instant};
+use std::{fmt::Display, net::SocketAddr, sync::Arc, time::Duration};
 
+use axum::{
+    body::{Body, HttpBody},
+    http::{self, header::CONTENT_LENGTH, StatusCode},
+    response::Response,
+    Router,
+};
+use http_body_util::BodyExt;
 use httparse::{Header, Request};
-use log::{debug, error, info, trace, warn};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
     io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
     net::{
         tcp::{OwnedReadHalf, OwnedWriteHalf},
         TcpStream,
     },
-    sync::RwLock,
 };
+use tower::ServiceExt;
+use tracing::{debug, field, info_span, warn, Instrument};
 
 use crate::{
-    api::MountInfo,
-    config::Config,
-    state::{State, StreamUrl},
+    auth::{self, Role},
+    config::{Config, SocketConfig},
+    failures::FailedConnection,
+    quirks::{self, Quirks},
+    state::State,
+    templates::{self, ErrorPage},
 };
 
-use super::{Connector, CreateConnectorError};
+use super::{find_header, split_target, Connector, CreateConnectorError, Handoff, Peer};
 
 pub struct BasicHttpResponse<'a> {
     code: u16,
@@ -28,13 +39,29 @@ pub struct BasicHttpResponse<'a> {
 impl<'a> BasicHttpResponse<'a> {
     pub const OK: Self = Self::no_headers(200, "OK");
     pub const UNAUTHORIZED: Self = Self::no_headers(401, "Unauthorized");
+    pub const FORBIDDEN: Self = Self::no_headers(403, "Forbidden");
     pub const NOT_FOUND: Self = Self::no_headers(404, "Not found");
     pub const BAD_REQUEST: Self = Self::no_headers(400, "Bad Request");
+    pub const TOO_MANY_REQUESTS: Self = Self::no_headers(429, "Too Many Requests");
+    pub const HEADERS_TOO_LARGE: Self = Self::no_headers(431, "Request Header Fields Too Large");
+    /// The body is not read, so the connection is closed after this
+    pub const PAYLOAD_TOO_LARGE: Self = Self::new(
+        413,
+        "Payload Too Large",
+        &["Connection: close", "Content-Length: 0"],
+    );
     pub const CONFLICT: Self = Self::no_headers(409, "Conflict");
     pub const INTERNAL_SERVER_ERROR: Self = Self::no_headers(500, "Internal server error");
-
+    pub const SERVICE_UNAVAILABLE: Self = Self::new(
+        503,
+        "Service Unavailable",
+        &["Retry-After: 30", "Content-Length: 0"],
+    );
+
+    /// A response without a body, which still states its length so that
+    /// the connection can be kept alive
     const fn no_headers(code: u16, name: &'static str) -> Self {
-        Self::new(code, name, &[])
+        Self::new(code, name, &["Content-Length: 0"])
     }
 
     pub const fn new(code: u16, name: &'static str, headers: &'a [&'a str]) -> Self {
@@ -55,10 +82,24 @@ impl<'a> BasicHttpResponse<'a> {
     where
         T: AsyncWrite + Unpin,
     {
-        let mut string = format!("HTTP/1.1 {} {}\r\n", self.code, self.name);
+        self.send_with_quirks(write, Quirks::default()).await
+    }
+
+    pub async fn send_with_quirks<T>(&self, write: &mut T, quirks: Quirks)
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let mut string = format!("{} {} {}\r\n", quirks.protocol(), self.code, self.name);
 
         for header in self.headers {
-            string.push_str(header);
+            match header.split_once(':') {
+                Some((name, value)) if quirks.lowercase_headers => {
+                    string.push_str(&name.to_ascii_lowercase());
+                    string.push(':');
+                    string.push_str(value);
+                }
+                _ => string.push_str(header),
+            }
             string.push_str("\r\n");
         }
 
@@ -66,31 +107,45 @@ impl<'a> BasicHttpResponse<'a> {
 
         write.write_all(string.as_bytes()).await.ok();
     }
+
+    /// Send this response with `html` as its body
+    pub async fn send_html<T>(&self, write: &mut T, quirks: Quirks, html: &str)
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let length = format!("Content-Length: {}", html.len());
+        let mut headers: Vec<&str> = self
+            .headers
+            .iter()
+            .copied()
+            .filter(|header| {
+                !header
+                    .split_once(':')
+                    .is_some_and(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            })
+            .collect();
+        headers.push("Content-Type: text/html; charset=utf-8");
+        headers.push(&length);
+
+        BasicHttpResponse::new(self.code, self.name, &headers)
+            .send_with_quirks(write, quirks)
+            .await;
+        write.write_all(html.as_bytes()).await.ok();
+    }
 }
 
 pub struct SocketHandler {
     config: Config,
-    state: Arc<RwLock<State>>,
+    state: Arc<State>,
     local_addr: SocketAddr,
     remote_addr: SocketAddr,
     socket: (BufReader<OwnedReadHalf>, OwnedWriteHalf),
+    /// The router for the API and static files
+    router: Router,
 }
 
-fn find_header<'a>(
-    mut headers: impl Iterator<Item = &'a Header<'a>>,
-    name: &str,
-) -> Option<String> {
-    headers
-        .find(|h| h.name == name)
-        .map(|h| {
-            if let Ok(value) = std::str::from_utf8(h.value) {
-                Some(value.to_string())
-            } else {
-                None
-            }
-        })
-        .flatten()
-}
+/// The maximum size of a request body that we are willing to read
+const MAX_BODY_SIZE: usize = 64 * 1024;
 
 impl SocketHandler {
     pub fn new(
@@ -98,8 +153,11 @@ impl SocketHandler {
         local_addr: SocketAddr,
         remote_addr: SocketAddr,
         socket: TcpStream,
-        state: Arc<RwLock<State>>,
+        state: Arc<State>,
+        router: Router,
     ) -> Self {
+        Self::configure_socket(&config.socket, &socket);
+
         let (read_half, write_half) = socket.into_split();
         let reader = BufReader::new(read_half);
 
@@ -109,322 +167,468 @@ impl SocketHandler {
             remote_addr,
             socket: (reader, write_half),
             state,
+            router,
         }
     }
 
-    async fn mount_info(&mut self, request: Request<'_, '_>, method: &str) {
-        let mut write_half = &mut self.socket.1;
-
-        if method == "GET" {
-            let json_data: Vec<MountInfo> = self
-                .state
-                .read()
-                .await
-                .mounts()
-                .map(|(n, m)| {
-                    let stream_url = m
-                        .stream_url()
-                        .clone()
-                        .or(self.config.default_stream_url.clone())
-                        .unwrap_or(StreamUrl::default());
-
-                    let x_forwarded_host = find_header(request.headers.iter(), "X-Forwarded-Host");
-
-                    let host = find_header(request.headers.iter(), "Host")
-                        .unwrap_or(format!("{:?}", self.local_addr));
-
-                    let stream_url = match stream_url {
-                        StreamUrl::Hostname => format!("{}{}", host, n),
-                        StreamUrl::XForwardedHostName => {
-                            format!("{}{}", x_forwarded_host.unwrap_or(host), n)
-                        }
-                        StreamUrl::Static(value) => value,
-                    };
+    fn configure_socket(config: &SocketConfig, socket: &TcpStream) {
+        if config.nodelay {
+            if let Err(e) = socket.set_nodelay(true) {
+                warn!("Failed to set TCP_NODELAY. Error: {:?}", e);
+            }
+        }
 
-                    MountInfo::from_named_mount(&n, m, stream_url)
-                })
-                .collect();
+        let sock_ref = SockRef::from(socket);
 
-            if let Ok(string) = serde_json::to_string_pretty(&json_data) {
-                let content_type = "Content-Type: application/json";
-                let content_length = &format!("Content-Length: {}", string.as_bytes().len());
+        if let Some(secs) = config.keepalive_secs {
+            let keepalive = TcpKeepalive::new()
+                .with_time(Duration::from_secs(secs))
+                .with_interval(Duration::from_secs(secs));
+            if let Err(e) = sock_ref.set_tcp_keepalive(&keepalive) {
+                warn!("Failed to enable TCP keepalive. Error: {:?}", e);
+            }
+        }
 
-                BasicHttpResponse::ok(&[content_type, content_length])
-                    .send(write_half)
-                    .await;
-                write_half.write_all(string.as_bytes()).await.ok();
-            } else {
-                BasicHttpResponse::INTERNAL_SERVER_ERROR
-                    .send(&mut write_half)
-                    .await;
+        if let Some(size) = config.send_buffer_size {
+            if let Err(e) = sock_ref.set_send_buffer_size(size) {
+                warn!("Failed to set send buffer size. Error: {:?}", e);
             }
-        } else {
-            BasicHttpResponse::BAD_REQUEST.send(write_half).await;
         }
-        return;
     }
 
-    async fn admin(&mut self, uri: &str, request: Request<'_, '_>) {
-        let write_half = &mut self.socket.1;
+    /// Record a failed connection, if configured to do so
+    fn record_failure(&self, reason: impl Display, headers: &[Header<'_>], request: &[u8]) {
+        if let Some(capacity) = self.config.recent_failures {
+            let failure = FailedConnection::new(self.remote_addr, reason, headers, request);
+            self.state.failures().record(capacity, failure);
+        }
+    }
 
-        info!("Got admin request: {}", uri);
+    /// Read from the socket until `buffer` is at least `len` bytes long
+    async fn read_to_len(&mut self, buffer: &mut Vec<u8>, len: usize) -> bool {
+        while buffer.len() < len {
+            let mut limited = (&mut self.socket.0).take((len - buffer.len()) as u64);
+            match limited.read_buf(buffer).await {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {}
+            }
+        }
+        true
+    }
 
-        let auth = if let Some(auth) = find_header(request.headers.iter(), "Authorization") {
-            auth
-        } else {
-            BasicHttpResponse::UNAUTHORIZED.send(write_half).await;
-            return;
+    /// Convert a request into one that can be routed by the [`Router`]
+    fn api_request(
+        &self,
+        request: &Request<'_, '_>,
+        body: Option<Vec<u8>>,
+    ) -> Result<http::Request<Body>, http::Error> {
+        let version = match request.version {
+            Some(0) => http::Version::HTTP_10,
+            _ => http::Version::HTTP_11,
         };
 
-        let is_admin = self.config.admin_authorization.is_some()
-            && Some(&auth) == self.config.admin_authorization.as_ref();
-
-        info!("{} is using admin credentials.", self.remote_addr);
-
-        let uri = &uri["/admin/".len()..];
-
-        if uri.starts_with("metadata?") {
-            let values = &uri["metadata?".len()..].split("&");
-            trace!(
-                "Admin metadata request: {}",
-                values.clone().collect::<String>()
-            );
-
-            let find_key = |name: &str| {
-                values.clone().find(|v| v.starts_with(name)).map(|v| {
-                    let value = &v[name.len()..];
-                    urlencoding::decode(value).expect("UTF-8").to_string()
-                })
-            };
+        let mut builder = http::Request::builder()
+            .method(request.method.unwrap_or_default())
+            .uri(request.path.unwrap_or_default())
+            .version(version)
+            .extension(Peer {
+                local_addr: self.local_addr,
+                remote_addr: self.remote_addr,
+            });
+        for header in request.headers.iter() {
+            builder = builder.header(header.name, header.value);
+        }
 
-            let (mount, mount_name) = if let Some(mount_name) = find_key("mount=") {
-                let state = self.state.read().await;
-                let mount = if let Some((_, mount)) = state.mounts().find(|m| m.0 == &mount_name) {
-                    (mount.clone(), mount_name)
-                } else {
-                    BasicHttpResponse::NOT_FOUND.send(write_half).await;
-                    return;
-                };
-                mount
-            } else {
-                error!("Could not find mount name for admin request.");
-                BasicHttpResponse::BAD_REQUEST.send(write_half).await;
-                return;
-            };
+        builder.body(body.map(Body::from).unwrap_or_else(Body::empty))
+    }
 
-            if !is_admin
-                && mount.source_auth().is_some()
-                && mount.source_auth() != &Some(auth.into())
-            {
-                BasicHttpResponse::UNAUTHORIZED.send(write_half).await;
-                return;
+    /// Send a response of the [`Router`].
+    ///
+    /// Returns whether the length of the response was known to the
+    /// client, so that the connection can be kept alive.
+    async fn send_response(&mut self, response: Response) -> bool {
+        let write_half = &mut self.socket.1;
+        let (mut parts, mut body) = response.into_parts();
+
+        // Responses without a body must not state a length
+        let bodiless =
+            parts.status == StatusCode::NO_CONTENT || parts.status == StatusCode::NOT_MODIFIED;
+        if bodiless {
+            parts.headers.remove(CONTENT_LENGTH);
+        } else if !parts.headers.contains_key(CONTENT_LENGTH) {
+            if let Some(length) = body.size_hint().exact() {
+                parts.headers.insert(CONTENT_LENGTH, length.into());
             }
+        }
+        let length_known = bodiless || parts.headers.contains_key(CONTENT_LENGTH);
+
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            parts.status.as_u16(),
+            parts.status.canonical_reason().unwrap_or("")
+        )
+        .into_bytes();
+        for (name, value) in parts.headers.iter() {
+            head.extend_from_slice(name.as_str().as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
 
-            if Some("updinfo".to_string()) != find_key("mode=") {
-                BasicHttpResponse::BAD_REQUEST.send(write_half).await;
-                return;
-            }
+        if write_half.write_all(&head).await.is_err() {
+            return false;
+        }
 
-            let song = if let Some(song) = find_key("song=") {
-                song
-            } else {
-                BasicHttpResponse::BAD_REQUEST.send(write_half).await;
-                return;
+        while let Some(frame) = body.frame().await {
+            let data = match frame.map(|f| f.into_data()) {
+                Ok(Ok(data)) => data,
+                Ok(Err(_)) => continue,
+                Err(e) => {
+                    warn!("Failed to send response to {}: {}", self.remote_addr, e);
+                    return false;
+                }
             };
-
-            info!(
-                "Updating mount {}. Setting song name to {}",
-                mount_name, song
-            );
-
-            {
-                let mut state = self.state.write().await;
-                state
-                    .mounts_mut()
-                    .find(|m| m.0 == &mount_name)
-                    .map(|m| m.1.set_song(song.to_string()));
+            if write_half.write_all(&data).await.is_err() {
+                return false;
             }
-        } else {
-            error!("Unknown admin request. {}", uri);
-            BasicHttpResponse::BAD_REQUEST.send(write_half).await;
         }
-    }
 
-    pub async fn static_file(&mut self, uri: &str) {
-        let stripped = &uri["/static/".len()..];
-        let write_half = &mut self.socket.1;
-
-        if let Some(static_sources) = &self.config.static_source_dir {
-            let mut path = static_sources.clone();
+        length_known
+    }
 
-            for part in stripped.split("/") {
-                if part == "." || part == ".." || part == "~" {
-                    BasicHttpResponse::NOT_FOUND.send(write_half).await;
-                    return;
+    /// Read from the socket until `buffer` contains the request line and
+    /// all headers of a request.
+    ///
+    /// Returns the length of the request head, or `None` if the client
+    /// disconnected or sent an invalid request, in which case it has
+    /// already been dealt with.
+    async fn read_request_head(&mut self, buffer: &mut Vec<u8>) -> Option<usize> {
+        let max_header_bytes = self.config.socket.max_header_bytes();
+        let max_headers = self.config.socket.max_headers();
+
+        loop {
+            // A pipelined request may already be buffered completely
+            if !buffer.is_empty() {
+                let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+                let mut request = httparse::Request::new(&mut headers);
+                match request.parse(buffer) {
+                    Ok(httparse::Status::Complete(len)) => return Some(len),
+                    Ok(httparse::Status::Partial) => {}
+                    Err(httparse::Error::TooManyHeaders) => {
+                        self.record_failure("Too many headers", &[], buffer);
+                        BasicHttpResponse::HEADERS_TOO_LARGE
+                            .send(&mut self.socket.1)
+                            .await;
+                        return None;
+                    }
+                    Err(e) => {
+                        self.record_failure(e, request.headers, buffer);
+                        return None;
+                    }
                 }
             }
 
-            path.push(stripped);
-            debug!("Serving file {:?} to {:?}", path, self.remote_addr);
-
-            if let Ok(file) = std::fs::metadata(path.clone()) {
-                let file_length = file.len();
-                if file.is_dir() {
-                    BasicHttpResponse::NOT_FOUND.send(write_half).await;
-                } else {
-                    let mut file = if let Ok(file) = tokio::fs::File::open(path.clone()).await {
-                        file
-                    } else {
-                        BasicHttpResponse::NOT_FOUND.send(write_half).await;
-                        return;
-                    };
+            if buffer.len() >= max_header_bytes {
+                self.record_failure("Request headers too large", &[], buffer);
+                BasicHttpResponse::HEADERS_TOO_LARGE
+                    .send(&mut self.socket.1)
+                    .await;
+                return None;
+            }
 
-                    let content_length = format!("Content-Length: {}", file_length);
-                    let mime_type = format!(
-                        "Content-Type: {}",
-                        mime_guess::from_path(path.clone()).first_or(mime_guess::mime::TEXT_PLAIN)
-                    );
-                    BasicHttpResponse::ok(&[&content_length, &mime_type])
-                        .send(write_half)
-                        .await;
-
-                    let mut buffer = [0u8; 32 * 1024];
-                    while let Ok(data) = file.read(&mut buffer).await {
-                        if data == 0 {
-                            break;
-                        }
-                        if let Err(_) = write_half.write_all(&buffer[..data]).await {
-                            break;
-                        }
-                    }
+            let mut limited = (&mut self.socket.0).take((max_header_bytes - buffer.len()) as u64);
+            match limited.read_buf(buffer).await {
+                Ok(0) => {
+                    debug!("{} disconnected before sending a request", self.remote_addr);
+                    return None;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    debug!("Failed to read request from {}: {}", self.remote_addr, e);
+                    return None;
                 }
-            } else {
-                BasicHttpResponse::NOT_FOUND.send(write_half).await;
             }
-        } else {
-            warn!("Got request for static files, but no static file path is configured!");
-            BasicHttpResponse::NOT_FOUND.send(write_half).await;
         }
     }
 
     pub async fn run(mut self) {
-        let mut headers = [httparse::EMPTY_HEADER; 64];
-        let mut request_buffer = Vec::with_capacity(2048);
-
-        let read_half = &mut self.socket.0;
-
-        let bytes = read_half.read_buf(&mut request_buffer).await.unwrap();
-
-        let mut request = httparse::Request::new(&mut headers);
-
-        let result = request.parse(&request_buffer[..bytes]);
-
-        if let Err(_) = result {
-            // TODO handle parse error
+        let ip = self.remote_addr.ip();
+        let allowed = self
+            .config
+            .ip_access
+            .as_ref()
+            .map(|access| access.allows(ip))
+            .unwrap_or(true);
+        if !allowed || self.state.bans().is_banned(ip) {
+            warn!(
+                "Rejecting {}: {} is not allowed to connect",
+                self.remote_addr, ip
+            );
+            BasicHttpResponse::FORBIDDEN.send(&mut self.socket.1).await;
             return;
         }
 
-        let uri = if let Some(path) = request.path {
-            path
+        let _ip_slot = if let Some(slot) = self
+            .state
+            .ip_connections()
+            .try_acquire(ip, self.config.max_connections_per_ip)
+        {
+            slot
         } else {
-            // TODO handle parse error
+            warn!(
+                "Rejecting {}: too many connections from {}",
+                self.remote_addr, ip
+            );
+            BasicHttpResponse::TOO_MANY_REQUESTS
+                .send(&mut self.socket.1)
+                .await;
             return;
         };
 
-        let method = if let Some(method) = request.method {
-            method
-        } else {
-            // TODO handle parse error
-            return;
-        };
+        let max_headers = self.config.socket.max_headers();
+        let mut request_buffer = Vec::with_capacity(self.config.socket.max_header_bytes());
+        let timeout = self.config.socket.header_timeout();
+
+        // Requests for the API and static files may be followed by more
+        // requests on the same connection, until the client asks to close
+        // it or a stream is requested
+        for request_count in 0.. {
+            let header_len =
+                match tokio::time::timeout(timeout, self.read_request_head(&mut request_buffer))
+                    .await
+                {
+                    Ok(Some(header_len)) => header_len,
+                    Ok(None) => return,
+                    Err(_) if request_count > 0 && request_buffer.is_empty() => {
+                        debug!("Closing idle connection from {}", self.remote_addr);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!(
+                            "{} did not send a request within {}",
+                            self.remote_addr,
+                            humantime::format_duration(timeout)
+                        );
+                        self.record_failure("Request timed out", &[], &request_buffer);
+                        return;
+                    }
+                };
 
-        if uri == "/favicon.ico" || uri == "/" || uri.starts_with("/static/") {
-            let uri = if uri == "/" {
-                "/static/index.html"
-            } else if uri == "/favicon.ico" {
-                "/static/favicon.ico"
-            } else {
-                uri
+            let (body_len, is_source) = {
+                let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+                let mut request = httparse::Request::new(&mut headers);
+                request.parse(&request_buffer).ok();
+                let body_len = find_header(request.headers, "Content-Length")
+                    .and_then(|v| v.trim().parse::<usize>().ok())
+                    .unwrap_or(0);
+                (body_len, request.method == Some("SOURCE"))
             };
-            self.static_file(uri).await;
-        } else if uri == "/mount_info" {
-            let start = Instant::now();
-            self.mount_info(request, method).await;
-            let duration = Instant::now().duration_since(start);
-            trace!(
-                "Computed and responded with mount info in {}",
-                humantime::format_duration(duration)
-            );
-        } else if uri.starts_with("/admin/") {
-            self.admin(uri, request).await;
-            return;
-        } else {
-            let content_type = if let Some(value) = request
-                .headers
-                .iter()
-                .find(|h| h.name == "Content-Type")
-                .map(|h| h.value)
+
+            if !is_source && body_len > MAX_BODY_SIZE {
+                self.record_failure("Request body too large", &[], &request_buffer[..header_len]);
+                BasicHttpResponse::PAYLOAD_TOO_LARGE
+                    .send(&mut self.socket.1)
+                    .await;
+                return;
+            }
+
+            // Sources stream their body, everything else sends it along
+            // with the request
+            let body = if is_source {
+                None
+            } else if self
+                .read_to_len(&mut request_buffer, header_len + body_len)
+                .await
             {
-                if let Ok(value) = std::str::from_utf8(value) {
-                    Some(value)
-                } else {
-                    None
-                }
+                Some(header_len..header_len + body_len)
             } else {
-                None
+                return;
             };
+            let consumed = body.as_ref().map(|body| body.end);
 
-            let authorization = if let Some(value) = request
-                .headers
-                .iter()
-                .find(|h| h.name == "Authorization")
-                .map(|h| h.value)
-            {
-                if let Ok(value) = std::str::from_utf8(value) {
-                    Some(value)
-                } else {
-                    None
+            let keep_alive = {
+                let bytes = request_buffer.len();
+
+                let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
+                let mut request = httparse::Request::new(&mut headers);
+                if let Err(e) = request.parse(&request_buffer) {
+                    self.record_failure(e, request.headers, &request_buffer);
+                    return;
                 }
-            } else {
-                None
-            };
 
-            let (reader, write_half) = self.socket;
-
-            let connector = Connector::parse(
-                self.remote_addr,
-                &self.config,
-                self.state,
-                method,
-                uri,
-                content_type,
-                authorization,
-                write_half,
-                reader,
-                request.headers,
-            )
-            .await;
+                let (uri, query) = if let Some(target) = request.path {
+                    split_target(target)
+                } else {
+                    // TODO handle parse error
+                    self.record_failure("Missing path", request.headers, &request_buffer[..bytes]);
+                    return;
+                };
 
-            match connector {
-                Ok(connector) => connector.run().await,
-                Err((e, mut write_half, _)) => {
-                    debug!(
-                        "Connection to {:?} failed. Reason: {:?}",
-                        self.remote_addr, e
+                let method = if let Some(method) = request.method {
+                    method
+                } else {
+                    // TODO handle parse error
+                    self.record_failure(
+                        "Missing method",
+                        request.headers,
+                        &request_buffer[..bytes],
                     );
-                    let response = match e {
-                        CreateConnectorError::UnknownMethod(_) => BasicHttpResponse::BAD_REQUEST,
-                        CreateConnectorError::MountHasSource(_) => BasicHttpResponse::CONFLICT,
-                        CreateConnectorError::MountDoesNotExist(_) => BasicHttpResponse::NOT_FOUND,
-                        CreateConnectorError::SourceMissingContentType => {
-                            BasicHttpResponse::BAD_REQUEST
-                        }
-                        CreateConnectorError::Unauthorized => BasicHttpResponse::UNAUTHORIZED,
-                        CreateConnectorError::MountNotConnected(_) => BasicHttpResponse::NOT_FOUND,
+                    return;
+                };
+
+                let keep_alive = consumed.is_some()
+                    && match find_header(request.headers, "Connection") {
+                        Some(v) if v.eq_ignore_ascii_case("close") => false,
+                        Some(v) if v.eq_ignore_ascii_case("keep-alive") => true,
+                        _ => request.version == Some(1),
                     };
 
-                    response.send(&mut write_half).await;
+                let body = body.map(|body| request_buffer[body].to_vec());
+                let api_request = match self.api_request(&request, body) {
+                    Ok(api_request) => api_request,
+                    Err(e) => {
+                        self.record_failure(e, request.headers, &request_buffer[..bytes]);
+                        BasicHttpResponse::BAD_REQUEST
+                            .send(&mut self.socket.1)
+                            .await;
+                        return;
+                    }
+                };
+
+                let response = match self.router.clone().oneshot(api_request).await {
+                    Ok(response) => response,
+                    Err(infallible) => match infallible {},
+                };
+
+                if response.extensions().get::<Handoff>().is_some() {
+                    let content_type = find_header(request.headers, "Content-Type");
+                    let authorization = find_header(request.headers, "Authorization");
+
+                    let (reader, write_half) = self.socket;
+
+                    // The session ID is recorded once the connection turns
+                    // out to be a listener
+                    let span = info_span!("stream", mount = uri, session = field::Empty);
+
+                    let connector = Connector::parse(
+                        self.remote_addr,
+                        self.remote_addr.ip(),
+                        self.local_addr,
+                        &self.config,
+                        self.state.clone(),
+                        method,
+                        request.version,
+                        uri,
+                        &query,
+                        content_type,
+                        authorization,
+                        write_half,
+                        reader,
+                        request.headers,
+                    )
+                    .instrument(span.clone())
+                    .await;
+
+                    match connector {
+                        Ok(connector) => connector.run().instrument(span).await,
+                        Err((e, mut write_half, _)) => {
+                            debug!(
+                                "Connection to {:?} failed. Reason: {:?}",
+                                self.remote_addr, e
+                            );
+                            if let Some(capacity) = self.config.recent_failures {
+                                let failure = FailedConnection::new(
+                                    self.remote_addr,
+                                    format!("{:?}", e),
+                                    request.headers,
+                                    &request_buffer[..bytes],
+                                );
+                                self.state.failures().record(capacity, failure);
+                            }
+                            let response = match e {
+                                CreateConnectorError::UnknownMethod(_) => {
+                                    BasicHttpResponse::BAD_REQUEST
+                                }
+                                CreateConnectorError::MountHasSource(_) => {
+                                    BasicHttpResponse::CONFLICT
+                                }
+                                CreateConnectorError::MountDoesNotExist(_) => {
+                                    BasicHttpResponse::NOT_FOUND
+                                }
+                                CreateConnectorError::SourceMissingContentType => {
+                                    BasicHttpResponse::BAD_REQUEST
+                                }
+                                CreateConnectorError::Unauthorized => {
+                                    let role = if method == "SOURCE" {
+                                        Role::Source
+                                    } else {
+                                        Role::Listener
+                                    };
+                                    auth::log_failure(
+                                        role,
+                                        self.remote_addr.ip(),
+                                        uri,
+                                        authorization,
+                                    );
+                                    BasicHttpResponse::UNAUTHORIZED
+                                }
+                                CreateConnectorError::MountNotConnected(_) => {
+                                    BasicHttpResponse::NOT_FOUND
+                                }
+                                CreateConnectorError::ServerFull => {
+                                    BasicHttpResponse::SERVICE_UNAVAILABLE
+                                }
+                                CreateConnectorError::MountFull(_) => {
+                                    BasicHttpResponse::SERVICE_UNAVAILABLE
+                                }
+                                CreateConnectorError::TlsRequired => BasicHttpResponse::FORBIDDEN,
+                                CreateConnectorError::Forbidden => BasicHttpResponse::FORBIDDEN,
+                                CreateConnectorError::Congested => {
+                                    BasicHttpResponse::SERVICE_UNAVAILABLE
+                                }
+                            };
+
+                            let quirks = quirks::for_request(
+                                &self.config.quirk_rules,
+                                request.version,
+                                request.headers,
+                            );
+                            // Browsers get the error page template, if any
+                            let accept = find_header(request.headers, "Accept");
+                            let html = (method == "GET" && templates::accepts_html(accept))
+                                .then(|| self.state.templates())
+                                .flatten()
+                                .and_then(|templates| {
+                                    templates.render_error(&ErrorPage {
+                                        status: response.code,
+                                        reason: response.name.to_string(),
+                                        path: uri.to_string(),
+                                    })
+                                });
+                            match html {
+                                Some(html) => {
+                                    response.send_html(&mut write_half, quirks, &html).await
+                                }
+                                None => response.send_with_quirks(&mut write_half, quirks).await,
+                            }
+                        }
+                    }
+
+                    // The connection now belongs to a stream, or has been
+                    // rejected
+                    return;
+                }
+
+                let sent_length = self.send_response(response).await;
+                keep_alive && sent_length
+            };
+
+            match consumed {
+                Some(consumed) if keep_alive => {
+                    request_buffer.drain(..consumed);
                 }
+                _ => return,
             }
         }
     }
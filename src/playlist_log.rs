@@ -0,0 +1,70 @@
+//! An append-only log of the songs played on all mounts, in the format of
+//! Icecast's `playlist.log`:
+//!
+//! ```text
+//! 11/Feb/2024:12:00:00 +0000|/live|12|Artist - Title
+//! ```
+
+use std::{path::Path, time::SystemTime};
+
+use tracing::warn;
+
+use crate::logfile::{LogFile, LogRotationConfig};
+
+#[derive(Debug)]
+pub struct PlaylistLog {
+    file: LogFile,
+}
+
+impl PlaylistLog {
+    /// Open the log at `path` for appending, creating it if necessary
+    pub fn open(path: &Path, rotation: LogRotationConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            file: LogFile::open(path, rotation)?,
+        })
+    }
+
+    /// The file that the log is written to
+    pub fn file(&self) -> &LogFile {
+        &self.file
+    }
+
+    /// Record that `song` started playing on mount `mount_name`, which
+    /// has `listeners` listeners
+    pub fn write(&self, mount_name: &str, listeners: usize, song: &str) {
+        let song: String = song
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        let line = format!(
+            "{}|{}|{}|{}\n",
+            timestamp(SystemTime::now()),
+            mount_name,
+            listeners,
+            song
+        );
+
+        if let Err(e) = self.file.write(&line) {
+            warn!("Could not write to the playlist log: {}", e);
+        }
+    }
+}
+
+/// Format `time` like the Common Log Format, e.g.
+/// `11/Feb/2024:12:00:00 +0000`
+fn timestamp(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // e.g. 2024-02-11T12:00:00Z
+    let rfc3339 = humantime::format_rfc3339_seconds(time).to_string();
+    let month = rfc3339[5..7].parse::<usize>().unwrap_or(1);
+    format!(
+        "{}/{}/{}:{} +0000",
+        &rfc3339[8..10],
+        MONTHS[month - 1],
+        &rfc3339[0..4],
+        &rfc3339[11..19],
+    )
+}
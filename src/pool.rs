@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+
+/// The size of the buffers used for mounts that do not configure
+/// a chunk size
+pub const DEFAULT_CHUNK_SIZE: usize = 16384;
+
+/// The maximum amount of idle buffers kept around for reuse
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+/// A pool of byte buffers used for ingesting source data.
+///
+/// Buffers are pooled per size, and are returned to the pool once the
+/// last reference to them is dropped, so a chunk that is fanned out to
+/// many subscribers is only recycled after all of them have written it.
+#[derive(Debug)]
+pub struct BufferPool {
+    free: Mutex<HashMap<usize, Vec<Vec<u8>>>>,
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+}
+
+/// A snapshot of the statistics of a [`BufferPool`]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct PoolMetrics {
+    /// The amount of buffers that had to be freshly allocated
+    pub allocated: usize,
+    /// The amount of times a buffer was taken from the pool
+    pub reused: usize,
+    /// The amount of idle buffers currently held by the pool
+    pub idle: usize,
+    /// The total capacity of the idle buffers currently held by the pool
+    pub idle_bytes: usize,
+}
+
+impl BufferPool {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            free: Mutex::new(HashMap::new()),
+            allocated: AtomicUsize::new(0),
+            reused: AtomicUsize::new(0),
+        })
+    }
+
+    /// Take an empty buffer that can hold `size` bytes from the pool,
+    /// allocating a new one if none are available
+    pub fn get(self: &Arc<Self>, size: usize) -> PooledBuffer {
+        let buf = self
+            .free
+            .lock()
+            .unwrap()
+            .get_mut(&size)
+            .and_then(|free| free.pop());
+
+        let buf = if let Some(buf) = buf {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+            buf
+        } else {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(size)
+        };
+
+        PooledBuffer {
+            buf,
+            size,
+            pool: self.clone(),
+        }
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        let free = self.free.lock().unwrap();
+        PoolMetrics {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            idle: free.values().map(|f| f.len()).sum(),
+            idle_bytes: free.iter().map(|(size, f)| size * f.len()).sum(),
+        }
+    }
+
+    fn release(&self, size: usize, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut free = self.free.lock().unwrap();
+        if free.values().map(|f| f.len()).sum::<usize>() < MAX_POOLED_BUFFERS {
+            free.entry(size).or_default().push(buf);
+        }
+    }
+}
+
+/// A buffer that is returned to its [`BufferPool`] when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    size: usize,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.release(self.size, std::mem::take(&mut self.buf));
+    }
+}
@@ -0,0 +1,203 @@
+//! Rendering of the metrics of the server in the Prometheus text
+//! exposition format, which is served under `/metrics`.
+
+use std::fmt::Write;
+
+use crate::{api::ServerMetrics, state::State};
+
+/// A metric that is reported for every mount
+type MountMetric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&MountValues) -> f64,
+);
+
+struct MountValues {
+    listeners: usize,
+    listener_peak: usize,
+    on_air: bool,
+    bytes_in: usize,
+    bytes_out: usize,
+    source_connects: usize,
+}
+
+const MOUNT_METRICS: [MountMetric; 6] = [
+    (
+        "peroxidecast_mount_listeners",
+        "gauge",
+        "The amount of listeners of the mount",
+        |m| m.listeners as f64,
+    ),
+    (
+        "peroxidecast_mount_listener_peak",
+        "gauge",
+        "The largest amount of listeners that the mount had at once",
+        |m| m.listener_peak as f64,
+    ),
+    (
+        "peroxidecast_mount_on_air",
+        "gauge",
+        "Whether a source is connected to the mount",
+        |m| m.on_air as u8 as f64,
+    ),
+    (
+        "peroxidecast_mount_bytes_in_total",
+        "counter",
+        "The amount of bytes received from the sources of the mount",
+        |m| m.bytes_in as f64,
+    ),
+    (
+        "peroxidecast_mount_bytes_out_total",
+        "counter",
+        "The amount of bytes sent to the listeners of the mount",
+        |m| m.bytes_out as f64,
+    ),
+    (
+        "peroxidecast_mount_source_connects_total",
+        "counter",
+        "The amount of times a source connected to the mount",
+        |m| m.source_connects as f64,
+    ),
+];
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    writeln!(out, "{} {}", name, value).unwrap();
+}
+
+/// Render the metrics of the server and of all mounts in `state`
+pub fn render(state: &State) -> String {
+    let metrics = ServerMetrics::from_state(state);
+    let mut out = String::new();
+
+    metric(
+        &mut out,
+        "peroxidecast_listeners",
+        "gauge",
+        "The amount of listeners of all mounts",
+        metrics.listeners as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_listener_peak",
+        "gauge",
+        "The largest amount of listeners that were connected at once",
+        state.listeners().peak() as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_mounts",
+        "gauge",
+        "The amount of mounts",
+        metrics.mounts.len() as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_sources",
+        "gauge",
+        "The amount of mounts with a connected source",
+        metrics.sources as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_bytes_in_total",
+        "counter",
+        "The amount of bytes received from sources",
+        metrics.bytes_in as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_bytes_out_total",
+        "counter",
+        "The amount of bytes sent to listeners",
+        metrics.bytes_out as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_source_connects_total",
+        "counter",
+        "The amount of times a source connected to any mount",
+        metrics
+            .mounts
+            .iter()
+            .map(|m| m.stats.source_connects)
+            .sum::<usize>() as f64,
+    );
+    metric(
+        &mut out,
+        "peroxidecast_buffer_pool_idle_bytes",
+        "gauge",
+        "The capacity of the idle buffers held by the buffer pool",
+        metrics.buffer_pool.idle_bytes as f64,
+    );
+
+    let mounts: Vec<(&str, MountValues)> = metrics
+        .mounts
+        .iter()
+        .map(|m| {
+            let listener_peak = state
+                .find_mount(&m.name)
+                .map(|mount| mount.stats_handle().subscribers().peak())
+                .unwrap_or(0);
+            let values = MountValues {
+                listeners: m.stats.sub_count,
+                listener_peak,
+                on_air: m.on_air,
+                bytes_in: m.stats.bytes_in,
+                bytes_out: m.stats.bytes_out,
+                source_connects: m.stats.source_connects,
+            };
+            (m.name.as_str(), values)
+        })
+        .collect();
+
+    for (name, kind, help, value) in MOUNT_METRICS {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        for (mount, values) in &mounts {
+            writeln!(
+                out,
+                "{}{{mount=\"{}\"}} {}",
+                name,
+                escape(mount),
+                value(values)
+            )
+            .unwrap();
+        }
+    }
+
+    let loudness: Vec<_> = metrics
+        .mounts
+        .iter()
+        .filter_map(|m| Some((&m.name, m.loudness?)))
+        .collect();
+    if !loudness.is_empty() {
+        writeln!(
+            out,
+            "# HELP peroxidecast_mount_loudness_lufs The integrated loudness of the current source"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE peroxidecast_mount_loudness_lufs gauge").unwrap();
+        for (mount, loudness) in loudness {
+            writeln!(
+                out,
+                "peroxidecast_mount_loudness_lufs{{mount=\"{}\"}} {}",
+                escape(mount),
+                loudness
+            )
+            .unwrap();
+        }
+    }
+
+    out
+}
@@ -0,0 +1,178 @@
+//! Handling of listeners behind buffering proxies, such as CDN edges,
+//! that accept a large amount of data without any backpressure and then
+//! stall until their own listeners catch up.
+//!
+//! Such a listener is no longer counted while it is stalled, and it is
+//! disconnected if it stays stalled for too long or falls too far behind,
+//! instead of waiting for TCP to give up on it.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::{ConnectionCounter, ConnectionSlot};
+
+const DEFAULT_BURST_BYTES: usize = 1024 * 1024;
+const DEFAULT_STALL_SECS: u64 = 2;
+const DEFAULT_STALL_TIMEOUT_SECS: u64 = 15;
+const DEFAULT_MAX_QUEUE_CHUNKS: usize = 32;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BufferingProxyConfig {
+    /// A listener that accepted at least this many bytes without stalling
+    /// is considered to be a buffering proxy once it stalls. Defaults to
+    /// 1 MiB.
+    pub burst_bytes: Option<usize>,
+    /// A listener is stalled once a write to it has been blocked for this
+    /// many seconds. Defaults to 2 seconds.
+    pub stall_secs: Option<u64>,
+    /// Disconnect a buffering proxy once a write to it has been blocked
+    /// for this many seconds. Defaults to 15 seconds.
+    pub stall_timeout_secs: Option<u64>,
+    /// Disconnect a stalled buffering proxy once this many chunks are
+    /// queued for it. Defaults to 32 chunks.
+    pub max_queue_chunks: Option<usize>,
+}
+
+impl BufferingProxyConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            burst_bytes: Some(self.burst_bytes.unwrap_or(DEFAULT_BURST_BYTES)),
+            stall_secs: Some(self.stall_secs.unwrap_or(DEFAULT_STALL_SECS)),
+            stall_timeout_secs: Some(
+                self.stall_timeout_secs
+                    .unwrap_or(DEFAULT_STALL_TIMEOUT_SECS),
+            ),
+            max_queue_chunks: Some(self.max_queue_chunks.unwrap_or(DEFAULT_MAX_QUEUE_CHUNKS)),
+        }
+    }
+
+    pub fn burst_bytes(&self) -> usize {
+        self.burst_bytes.unwrap_or(DEFAULT_BURST_BYTES)
+    }
+
+    pub fn stall(&self) -> Duration {
+        Duration::from_secs(self.stall_secs.unwrap_or(DEFAULT_STALL_SECS))
+    }
+
+    pub fn stall_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.stall_timeout_secs
+                .unwrap_or(DEFAULT_STALL_TIMEOUT_SECS),
+        )
+    }
+
+    pub fn max_queue_chunks(&self) -> usize {
+        self.max_queue_chunks.unwrap_or(DEFAULT_MAX_QUEUE_CHUNKS)
+    }
+}
+
+/// What to do with a listener that a write is blocked on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Wait,
+    /// The listener has been stalled for too long
+    Stalled,
+    /// Too much data is queued for the listener
+    Lagged,
+}
+
+/// Tracks whether a listener behaves like a buffering proxy, and holds
+/// the slots that count it as a listener
+#[derive(Debug)]
+pub struct ProxyTracker {
+    config: BufferingProxyConfig,
+    accepted: usize,
+    is_proxy: bool,
+    counters: Vec<Arc<ConnectionCounter>>,
+    slots: Vec<ConnectionSlot>,
+}
+
+impl ProxyTracker {
+    /// Track a listener that is counted by `slots`, which were acquired
+    /// from `counters`
+    pub fn new(
+        config: BufferingProxyConfig,
+        counters: Vec<Arc<ConnectionCounter>>,
+        slots: Vec<ConnectionSlot>,
+    ) -> Self {
+        Self {
+            config,
+            accepted: 0,
+            is_proxy: false,
+            counters,
+            slots,
+        }
+    }
+
+    pub fn config(&self) -> &BufferingProxyConfig {
+        &self.config
+    }
+
+    pub fn is_proxy(&self) -> bool {
+        self.is_proxy
+    }
+
+    /// Whether the listener is currently not counted, because it stalled
+    pub fn is_stalled(&self) -> bool {
+        self.is_proxy && self.slots.is_empty()
+    }
+
+    /// Record that the listener accepted `bytes` without stalling
+    pub fn accepted(&mut self, bytes: usize) {
+        self.accepted = self.accepted.saturating_add(bytes);
+    }
+
+    /// Decide what to do with a listener that a write has been blocked on
+    /// for `blocked`, while `queued` chunks are waiting to be sent to it
+    pub fn blocked(&mut self, blocked: Duration, queued: usize) -> Verdict {
+        if blocked < self.config.stall() {
+            return Verdict::Wait;
+        }
+
+        if !self.is_proxy && self.accepted >= self.config.burst_bytes() {
+            self.is_proxy = true;
+        }
+
+        if !self.is_proxy {
+            Verdict::Wait
+        } else if blocked >= self.config.stall_timeout() {
+            Verdict::Stalled
+        } else if queued > self.config.max_queue_chunks() {
+            Verdict::Lagged
+        } else {
+            // Stop counting the listener until it resumes
+            self.slots.clear();
+            Verdict::Wait
+        }
+    }
+
+    /// Count the listener with `to` instead of `from`, e.g. because it
+    /// moved to another mount
+    pub fn transfer(&mut self, from: &Arc<ConnectionCounter>, to: &Arc<ConnectionCounter>) {
+        for counter in &mut self.counters {
+            if Arc::ptr_eq(counter, from) {
+                *counter = to.clone();
+            }
+        }
+        for slot in &mut self.slots {
+            if Arc::ptr_eq(slot.counter(), from) {
+                slot.transfer(to);
+            }
+        }
+    }
+
+    /// Record that a blocked write completed
+    pub fn resumed(&mut self) {
+        if self.is_stalled() {
+            self.slots = self
+                .counters
+                .iter()
+                .filter_map(|counter| counter.try_acquire(None))
+                .collect();
+        }
+        self.accepted = 0;
+    }
+}
@@ -0,0 +1,124 @@
+//! Compatibility quirks for clients that do not cope well with
+//! regular HTTP/1.1 responses.
+
+use httparse::Header;
+use serde::{Deserialize, Serialize};
+
+use crate::net::find_header;
+
+/// Deviations from a regular HTTP/1.1 response
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quirks {
+    /// Respond with an `ICY 200 OK` status line
+    pub icy_status_line: bool,
+    /// Respond with an `HTTP/1.0` status line, so that clients do not
+    /// expect chunking or keep-alive
+    pub http10: bool,
+    /// Send all header names in lower case
+    pub lowercase_headers: bool,
+}
+
+impl Quirks {
+    /// Combine the quirks of `self` and `other`
+    pub fn merge(self, other: Quirks) -> Quirks {
+        Quirks {
+            icy_status_line: self.icy_status_line || other.icy_status_line,
+            http10: self.http10 || other.http10,
+            lowercase_headers: self.lowercase_headers || other.lowercase_headers,
+        }
+    }
+
+    /// The protocol to use in the status line of a response
+    pub fn protocol(&self) -> &'static str {
+        if self.icy_status_line {
+            "ICY"
+        } else if self.http10 {
+            "HTTP/1.0"
+        } else {
+            "HTTP/1.1"
+        }
+    }
+}
+
+/// A named set of quirks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuirkProfile {
+    #[serde(rename = "winamp2")]
+    Winamp2,
+    #[serde(rename = "old-hardware-radio")]
+    OldHardwareRadio,
+    #[serde(rename = "vlc<3")]
+    VlcPre3,
+}
+
+impl QuirkProfile {
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            QuirkProfile::Winamp2 => Quirks {
+                icy_status_line: true,
+                ..Default::default()
+            },
+            QuirkProfile::OldHardwareRadio => Quirks {
+                icy_status_line: true,
+                lowercase_headers: true,
+                ..Default::default()
+            },
+            QuirkProfile::VlcPre3 => Quirks {
+                http10: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Apply `profile` to clients whose `User-Agent` contains `user_agent`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuirkRule {
+    pub user_agent: String,
+    pub profile: QuirkProfile,
+}
+
+const BUILTIN_RULES: [(&str, QuirkProfile); 5] = [
+    ("Winamp/2", QuirkProfile::Winamp2),
+    ("WinampMPEG/2", QuirkProfile::Winamp2),
+    ("VLC/0.", QuirkProfile::VlcPre3),
+    ("VLC/1.", QuirkProfile::VlcPre3),
+    ("VLC/2.", QuirkProfile::VlcPre3),
+];
+
+/// Find the quirks for a client with the given user agent.
+///
+/// `rules` are checked before the built-in rules, and the first match wins.
+pub fn for_user_agent(rules: &[QuirkRule], user_agent: &str) -> Quirks {
+    rules
+        .iter()
+        .map(|r| (r.user_agent.as_str(), r.profile))
+        .chain(BUILTIN_RULES)
+        .find(|(ua, _)| user_agent.contains(ua))
+        .map(|(_, profile)| profile.quirks())
+        .unwrap_or_default()
+}
+
+/// Find the quirks for a client, based on its `User-Agent` and on the
+/// flavor of its request.
+///
+/// Clients that make HTTP/1.0 requests get an HTTP/1.0 response. If they
+/// also ask for ICY metadata, they are taken to be SHOUTcast-era clients
+/// and get an `ICY 200 OK` status line.
+pub fn for_request(rules: &[QuirkRule], http_version: Option<u8>, headers: &[Header]) -> Quirks {
+    let user_agent = find_header(headers, "User-Agent").unwrap_or("");
+    let mut quirks = for_user_agent(rules, user_agent);
+
+    if http_version == Some(0) {
+        quirks.http10 = true;
+
+        let wants_icy_metadata = find_header(headers, "Icy-MetaData")
+            .map(|v| v.trim() == "1")
+            .unwrap_or(false);
+        if wants_icy_metadata {
+            quirks.icy_status_line = true;
+        }
+    }
+
+    quirks
+}
@@ -0,0 +1,89 @@
+//! Per-client rate limits for the admin API and `/mount_info`.
+//!
+//! Every client IP address gets a token bucket, so that brute forcing
+//! the admin credentials or polling aggressively is slowed down without
+//! taking time away from streaming traffic.
+
+use std::{net::IpAddr, time::Instant};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_BURST: u32 = 10;
+
+/// The maximum amount of tracked clients, after which idle clients are
+/// forgotten
+const MAX_CLIENTS: usize = 10_000;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// The amount of requests that a client may make per second, on
+    /// average
+    pub requests_per_sec: f64,
+    /// The amount of requests that a client may make at once. Defaults
+    /// to 10.
+    pub burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            burst: Some(self.burst.unwrap_or(DEFAULT_BURST)),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// The amount of requests that may be made right away
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets that limit the requests of every client
+#[derive(Debug)]
+pub struct RateLimiter {
+    requests_per_sec: f64,
+    burst: f64,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            requests_per_sec: config.requests_per_sec.max(0.0),
+            burst: config.burst.unwrap_or(DEFAULT_BURST).max(1) as f64,
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Take a token from the bucket of `ip`, returning whether it may
+    /// make a request
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_CLIENTS {
+            self.buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+                bucket.tokens + elapsed * self.requests_per_sec < self.burst
+            });
+        }
+
+        let mut bucket = self.buckets.entry(ip.to_canonical()).or_insert(Bucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.requests_per_sec).min(self.burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
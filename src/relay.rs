@@ -0,0 +1,564 @@
+//! Pull relays: mounts whose data is pulled from one of several upstream
+//! servers instead of being pushed by a source.
+//!
+//! Upstreams are listed in order of preference. All upstreams are probed
+//! periodically, and the relay fails over to the next healthy upstream
+//! when the active one disconnects, delivers too little data, or stops
+//! updating its metadata. Once a more preferred upstream is healthy
+//! again, the relay fails back to it.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncReadExt, sync::broadcast};
+use tracing::{debug, info, warn};
+
+use crate::{
+    events::EventKind,
+    net::{self, HttpStream},
+    pool::BufferPool,
+    snapshot,
+    state::{DataSender, IceMeta, Mount, State},
+    taps, transcription,
+};
+
+const DEFAULT_PROBE_INTERVAL_SECS: u64 = 30;
+const DEFAULT_PROBE_METHOD: &str = "HEAD";
+
+/// The time to wait before trying again when no upstream could be used
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The amount of chunks that may be queued for a subscriber of a relay
+const RELAY_QUEUE_CHUNKS: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// The `http://` URLs of the upstream streams, in order of preference
+    pub upstreams: Vec<String>,
+    /// The time between two health probes of the upstreams, in seconds.
+    /// Defaults to 30 seconds.
+    pub probe_interval_secs: Option<u64>,
+    /// The request method used to probe upstreams. Defaults to `HEAD`;
+    /// use `GET` for upstreams that do not support `HEAD`, in which
+    /// case only the response head is read.
+    pub probe_method: Option<String>,
+    /// Fail over when the active upstream delivers fewer bytes per second
+    /// than this, averaged over a probe interval
+    pub min_bytes_per_sec: Option<u64>,
+    /// Fail over when the stream title sent by the active upstream has
+    /// not changed for this many seconds
+    pub max_metadata_age_secs: Option<u64>,
+}
+
+impl RelayConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            probe_interval_secs: Some(
+                self.probe_interval_secs
+                    .unwrap_or(DEFAULT_PROBE_INTERVAL_SECS),
+            ),
+            probe_method: Some(
+                self.probe_method
+                    .unwrap_or_else(|| DEFAULT_PROBE_METHOD.to_string()),
+            ),
+            ..self
+        }
+    }
+
+    fn probe_interval(&self) -> Duration {
+        Duration::from_secs(
+            self.probe_interval_secs
+                .unwrap_or(DEFAULT_PROBE_INTERVAL_SECS)
+                .max(1),
+        )
+    }
+}
+
+/// The result of the most recent health probe of an upstream
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpstreamStatus {
+    pub url: String,
+    /// `None` if the upstream has not been probed yet
+    pub healthy: Option<bool>,
+    pub last_probe: Option<String>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Failover {
+    pub time: String,
+    pub upstream: String,
+    pub reason: String,
+}
+
+/// The state of a relay, as shown in the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct RelayStatus {
+    pub mount: String,
+    /// The upstream that is currently relayed
+    pub active: Option<String>,
+    pub upstreams: Vec<UpstreamStatus>,
+    pub failovers: usize,
+    pub last_failover: Option<Failover>,
+}
+
+impl RelayStatus {
+    fn new(mount: &str, config: &RelayConfig) -> Self {
+        Self {
+            mount: mount.to_string(),
+            active: None,
+            upstreams: config
+                .upstreams
+                .iter()
+                .map(|url| UpstreamStatus {
+                    url: url.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            failovers: 0,
+            last_failover: None,
+        }
+    }
+
+    /// The most preferred upstream that is not known to be unhealthy,
+    /// skipping `exclude`
+    fn preferred(&self, exclude: Option<usize>) -> Option<usize> {
+        self.upstreams
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| Some(*idx) != exclude)
+            .find(|(_, u)| u.healthy != Some(false))
+            .map(|(idx, _)| idx)
+    }
+}
+
+/// Start relaying all mounts that have a relay configuration, in the
+/// order given by `mount_order`
+pub fn spawn_all(state: &Arc<State>, mount_order: &[String]) {
+    for name in mount_order {
+        let mount = if let Some(mount) = state.find_mount(name) {
+            mount
+        } else {
+            continue;
+        };
+
+        if let Some(config) = mount.relay() {
+            if config.upstreams.is_empty() {
+                warn!("Relay {} has no upstreams", name);
+                continue;
+            }
+
+            let status = Arc::new(Mutex::new(RelayStatus::new(name, config)));
+            state.add_relay(name.clone(), status.clone());
+
+            tokio::spawn(probe(config.clone(), status.clone()));
+            tokio::spawn(relay(state.clone(), name.clone(), mount.clone(), status));
+        }
+    }
+}
+
+async fn probe(config: RelayConfig, status: Arc<Mutex<RelayStatus>>) {
+    let method = config
+        .probe_method
+        .as_deref()
+        .unwrap_or(DEFAULT_PROBE_METHOD);
+    let mut interval = tokio::time::interval(config.probe_interval());
+
+    loop {
+        interval.tick().await;
+
+        // Nobody is interested in the status of the relay anymore
+        if Arc::strong_count(&status) == 1 {
+            return;
+        }
+
+        for (idx, url) in config.upstreams.iter().enumerate() {
+            let start = Instant::now();
+            let result = tokio::time::timeout(
+                config.probe_interval(),
+                net::open(method, url, &[("Icy-MetaData", "1")]),
+            )
+            .await;
+            let latency = start.elapsed();
+
+            let (healthy, code, error) = match result {
+                Ok(Ok(response)) => (response.is_success(), Some(response.status), None),
+                Ok(Err(e)) => (false, None, Some(e.to_string())),
+                Err(_) => (false, None, Some("probe timed out".to_string())),
+            };
+
+            debug!(
+                "Probed upstream {}: healthy: {}, status: {:?}, error: {:?}",
+                url, healthy, code, error
+            );
+
+            let mut status = status.lock().unwrap();
+            status.upstreams[idx] = UpstreamStatus {
+                url: url.clone(),
+                healthy: Some(healthy),
+                last_probe: Some(humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+                status: code,
+                error,
+                latency_ms: Some(latency.as_millis() as u64),
+            };
+        }
+    }
+}
+
+/// Why a relay stopped using an upstream
+enum Stop {
+    /// The upstream failed or is not healthy
+    Failed(String),
+    /// A more preferred upstream is healthy again
+    FailBack(usize),
+    /// The mount has a source that is not the relay
+    Occupied,
+}
+
+async fn relay(
+    state: Arc<State>,
+    name: String,
+    mount: Arc<Mount>,
+    status: Arc<Mutex<RelayStatus>>,
+) {
+    let config = mount
+        .relay()
+        .expect("Relays have a relay configuration")
+        .clone();
+    let mut last_failed = None;
+
+    loop {
+        // The mount may have been removed or replaced by a configuration
+        // reload
+        let current = state.find_mount(&name);
+        if mount.is_retired() || !current.map(|m| Arc::ptr_eq(&m, &mount)).unwrap_or(false) {
+            info!("Mount {} was removed, no longer relaying it", name);
+            state.remove_relay(&name, &status);
+            return;
+        }
+
+        let preferred = status.lock().unwrap().preferred(last_failed);
+        let idx = if let Some(idx) = preferred {
+            idx
+        } else {
+            // All upstreams are unhealthy, so try them in turn
+            tokio::time::sleep(RETRY_DELAY).await;
+            last_failed
+                .map(|l| (l + 1) % config.upstreams.len())
+                .unwrap_or(0)
+        };
+        let url = &config.upstreams[idx];
+
+        let stop = match connect(&state, &name, url).await {
+            Ok((response, mount, data_tx)) => {
+                info!("Relaying {} to mount {}", url, name);
+                status.lock().unwrap().active = Some(url.clone());
+                state.events().emit(EventKind::SourceConnected {
+                    mount: name.clone(),
+                });
+
+                taps::spawn(&name, &mount, &data_tx);
+                snapshot::spawn(&name, &mount, &data_tx);
+                #[cfg(feature = "loudness")]
+                crate::loudness::spawn(&name, &mount, &data_tx);
+                #[cfg(feature = "fingerprint")]
+                crate::fingerprint::spawn(&name, &mount, &data_tx);
+                transcription::spawn(&state, &name, &mount, &data_tx);
+
+                let stop = pump(
+                    &config,
+                    &mount,
+                    state.buffer_pool(),
+                    &status,
+                    idx,
+                    response,
+                    data_tx,
+                )
+                .await;
+                status.lock().unwrap().active = None;
+                state.events().emit(EventKind::SourceDisconnected {
+                    mount: name.clone(),
+                });
+                stop
+            }
+            Err(stop) => stop,
+        };
+
+        let reason = match stop {
+            Stop::Occupied => {
+                debug!("Mount {} has a source, not relaying it", name);
+                last_failed = None;
+                tokio::time::sleep(RETRY_DELAY).await;
+                continue;
+            }
+            Stop::FailBack(preferred) => {
+                info!(
+                    "Relay {} is failing back from {} to {}",
+                    name, url, config.upstreams[preferred]
+                );
+                last_failed = None;
+                format!("failing back to {}", config.upstreams[preferred])
+            }
+            Stop::Failed(reason) => {
+                warn!("Relay {} stopped using upstream {}: {}", name, url, reason);
+                last_failed = Some(idx);
+                status.lock().unwrap().upstreams[idx].healthy = Some(false);
+                reason
+            }
+        };
+
+        let mut status = status.lock().unwrap();
+        status.failovers += 1;
+        status.last_failover = Some(Failover {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            upstream: url.clone(),
+            reason,
+        });
+    }
+}
+
+/// Connect to `url` and make the relay the source of its mount
+async fn connect(
+    state: &State,
+    name: &str,
+    url: &str,
+) -> Result<(HttpStream, Arc<Mount>, DataSender), Stop> {
+    let response = net::open("GET", url, &[("Icy-MetaData", "1")])
+        .await
+        .map_err(|e| Stop::Failed(e.to_string()))?;
+
+    if !response.is_success() {
+        return Err(Stop::Failed(format!(
+            "upstream responded with status {}",
+            response.status
+        )));
+    }
+
+    let content_type = response
+        .header("Content-Type")
+        .unwrap_or("application/octet-stream")
+        .to_string();
+
+    // Upstreams send `icy-*` headers, which are read the same way as
+    // the `ice-*` headers sent by sources
+    let ice_headers: Vec<(String, String)> = response
+        .headers
+        .iter()
+        .filter_map(|(n, v)| {
+            n.to_ascii_lowercase()
+                .strip_prefix("icy-")
+                .map(|n| (format!("ice-{}", n), v.clone()))
+        })
+        .collect();
+    let headers: Vec<httparse::Header> = ice_headers
+        .iter()
+        .map(|(n, v)| httparse::Header {
+            name: n,
+            value: v.as_bytes(),
+        })
+        .collect();
+    let meta = IceMeta::from(&headers[..]);
+
+    let (data_tx, _) = broadcast::channel(RELAY_QUEUE_CHUNKS);
+
+    let mount = state
+        .find_mount(name)
+        .ok_or_else(|| Stop::Failed("the relay mount no longer exists".to_string()))?;
+    if !mount.try_set_source(data_tx.downgrade(), content_type, meta) {
+        return Err(Stop::Occupied);
+    }
+
+    Ok((response, mount, data_tx))
+}
+
+enum Event {
+    Read(std::io::Result<usize>),
+    Check,
+    Killed,
+}
+
+/// Relay data from `response` until the upstream fails or should no
+/// longer be used
+async fn pump(
+    config: &RelayConfig,
+    mount: &Mount,
+    pool: &Arc<BufferPool>,
+    status: &Mutex<RelayStatus>,
+    idx: usize,
+    response: HttpStream,
+    data_tx: DataSender,
+) -> Stop {
+    let metaint = response
+        .header("icy-metaint")
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|m| *m > 0);
+    let mut demuxer = IcyDemuxer::new(metaint);
+
+    let stats = mount.stats_handle();
+    let mut stream = response.stream;
+    let mut pending = Some(response.body_start);
+
+    let mut check = tokio::time::interval(config.probe_interval());
+    check.tick().await;
+    let mut bytes_since_check = 0;
+    let mut last_title_change = Instant::now();
+    let kill = mount.source_kill_signal();
+
+    loop {
+        let mut raw = pool.get(mount.chunk_size());
+        if let Some(start) = pending.take() {
+            raw.extend_from_slice(&start);
+        } else {
+            let event = tokio::select! {
+                read = stream.read_buf(&mut *raw) => Event::Read(read),
+                _ = check.tick() => Event::Check,
+                _ = kill.notified() => Event::Killed,
+            };
+
+            match event {
+                Event::Read(Ok(0)) => {
+                    return Stop::Failed("upstream closed the connection".to_string())
+                }
+                Event::Read(Ok(_)) => {}
+                Event::Read(Err(e)) => return Stop::Failed(e.to_string()),
+                Event::Killed => return Stop::Failed("killed by an admin".to_string()),
+                Event::Check => {
+                    let elapsed = config.probe_interval().as_secs().max(1);
+                    if let Some(min) = config.min_bytes_per_sec {
+                        let rate = bytes_since_check as u64 / elapsed;
+                        if rate < min {
+                            return Stop::Failed(format!(
+                                "byte rate of {} B/s is below {} B/s",
+                                rate, min
+                            ));
+                        }
+                    }
+                    if let Some(max_age) = config.max_metadata_age_secs {
+                        if metaint.is_some() && last_title_change.elapsed().as_secs() > max_age {
+                            return Stop::Failed(format!(
+                                "metadata has not changed in {} seconds",
+                                max_age
+                            ));
+                        }
+                    }
+                    if let Some(preferred) = status.lock().unwrap().preferred(None) {
+                        if preferred < idx {
+                            return Stop::FailBack(preferred);
+                        }
+                    }
+                    bytes_since_check = 0;
+                    continue;
+                }
+            }
+        }
+
+        stats.add_bytes_in(raw.len());
+        bytes_since_check += raw.len();
+
+        let mut audio = pool.get(mount.chunk_size());
+        if let Some(title) = demuxer.push(&raw, &mut audio) {
+            if mount.song().as_deref() != Some(&title) {
+                debug!("Upstream changed the stream title to {}", title);
+                last_title_change = Instant::now();
+                mount.set_song(title);
+            }
+        }
+
+        if !audio.is_empty() {
+            // Sending only fails if there are no subscribers at all,
+            // which is fine.
+            data_tx.send(Arc::new(audio)).ok();
+        }
+    }
+}
+
+/// Separates the interleaved ICY metadata from the data sent by an
+/// upstream that was asked for it with `Icy-MetaData: 1`
+struct IcyDemuxer {
+    metaint: Option<usize>,
+    /// The amount of data bytes until the next metadata block
+    until_meta: usize,
+    /// The remaining length of the metadata block that is being read,
+    /// or `None` if its length byte has not been read yet
+    meta_remaining: Option<usize>,
+    in_meta: bool,
+    meta: Vec<u8>,
+}
+
+impl IcyDemuxer {
+    fn new(metaint: Option<usize>) -> Self {
+        Self {
+            metaint,
+            until_meta: metaint.unwrap_or(0),
+            meta_remaining: None,
+            in_meta: false,
+            meta: Vec::new(),
+        }
+    }
+
+    /// Append the data in `input` to `out`, returning the stream title if
+    /// a metadata block containing one was completed
+    fn push(&mut self, mut input: &[u8], out: &mut Vec<u8>) -> Option<String> {
+        let metaint = if let Some(metaint) = self.metaint {
+            metaint
+        } else {
+            out.extend_from_slice(input);
+            return None;
+        };
+
+        let mut title = None;
+        while !input.is_empty() {
+            if !self.in_meta {
+                let len = self.until_meta.min(input.len());
+                out.extend_from_slice(&input[..len]);
+                input = &input[len..];
+                self.until_meta -= len;
+                if self.until_meta == 0 {
+                    self.in_meta = true;
+                    self.meta_remaining = None;
+                }
+                continue;
+            }
+
+            let remaining = match self.meta_remaining {
+                Some(remaining) => remaining,
+                None => {
+                    let remaining = input[0] as usize * 16;
+                    input = &input[1..];
+                    self.meta.clear();
+                    self.meta_remaining = Some(remaining);
+                    remaining
+                }
+            };
+
+            let len = remaining.min(input.len());
+            self.meta.extend_from_slice(&input[..len]);
+            input = &input[len..];
+            self.meta_remaining = Some(remaining - len);
+
+            if remaining - len == 0 {
+                self.in_meta = false;
+                self.until_meta = metaint;
+                if !self.meta.is_empty() {
+                    title = Self::stream_title(&self.meta).or(title);
+                }
+            }
+        }
+
+        title
+    }
+
+    fn stream_title(meta: &[u8]) -> Option<String> {
+        let meta = String::from_utf8_lossy(meta);
+        let start = meta.find("StreamTitle='")? + "StreamTitle='".len();
+        let end = meta[start..].find("';")? + start;
+        Some(meta[start..end].to_string())
+    }
+}
@@ -0,0 +1,90 @@
+//! Retention of the data that the server keeps about its mounts and
+//! clients, and purging of that data on request, e.g. to comply with
+//! deletion duties.
+
+use std::{net::IpAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::state::State;
+
+/// How long samples of the statistics history are kept by default
+const DEFAULT_STATS_HISTORY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long recorded data is kept
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// How long samples of the statistics history are kept, in seconds.
+    /// Defaults to 24 hours.
+    pub stats_history_secs: Option<u64>,
+    /// How long failed connections are kept, in seconds. By default, they
+    /// are only removed once `recent_failures` newer ones are recorded.
+    pub failures_secs: Option<u64>,
+}
+
+impl RetentionConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            stats_history_secs: Some(self.stats_history().as_secs()),
+            ..self
+        }
+    }
+
+    pub fn stats_history(&self) -> Duration {
+        self.stats_history_secs
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STATS_HISTORY)
+    }
+
+    pub fn failures(&self) -> Option<Duration> {
+        self.failures_secs.map(Duration::from_secs)
+    }
+}
+
+/// The amount of records that were removed by a purge
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct PurgeReport {
+    pub failures: usize,
+    pub stats_samples: usize,
+    /// The samples that were removed from the history database
+    pub stored_samples: usize,
+    pub bandwidth_estimates: usize,
+}
+
+/// Remove the recorded data that has exceeded its retention period
+pub fn prune(state: &State, config: &RetentionConfig) {
+    state.history().prune(config.stats_history());
+    if let Some(max_age) = config.failures() {
+        state.failures().prune(max_age);
+    }
+}
+
+/// Remove all recorded data about clients at `ip`, or all recorded data
+/// if `ip` is `None`.
+///
+/// The statistics history (in memory and in the history database) does
+/// not contain any data about single clients, so it is only purged as a
+/// whole.
+pub fn purge(state: &State, ip: Option<IpAddr>) -> Result<PurgeReport, String> {
+    Ok(match ip {
+        Some(ip) => PurgeReport {
+            failures: state.failures().purge(|f| f.remote_ip() == Some(ip)),
+            stats_samples: 0,
+            stored_samples: 0,
+            bandwidth_estimates: state.bandwidth().purge(|i| *i == ip),
+        },
+        None => PurgeReport {
+            failures: state.failures().purge(|_| true),
+            stats_samples: state.history().clear(),
+            stored_samples: match state.history_db() {
+                Some(db) => db
+                    .clear()
+                    .map_err(|e| format!("Could not clear the history database: {}", e))?,
+                None => 0,
+            },
+            bandwidth_estimates: state.bandwidth().purge(|_| true),
+        },
+    })
+}
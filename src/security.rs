@@ -0,0 +1,133 @@
+//! Dropping root privileges after the listening socket has been bound.
+//!
+//! Like Icecast's `<security>` section, this lets the server be started
+//! as root to bind a privileged port (e.g. 80), after which it changes
+//! its root directory and continues as an unprivileged user.
+
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// The user to continue as
+    pub user: Option<String>,
+    /// The group to continue as. Defaults to the primary group of `user`.
+    pub group: Option<String>,
+    /// Change the root directory to this directory. All paths in the
+    /// configuration (e.g. `static_source_dir`) are then relative to it,
+    /// and the configuration file must be reachable at the same path
+    /// inside it to be reloaded. The binary must be reachable likewise
+    /// to hand the listening socket over on `SIGUSR2`. This requires
+    /// starting the server as root, and should be combined with `user`.
+    pub chroot: Option<PathBuf>,
+}
+
+/// Change the root directory and drop to the user and group described
+/// by `config`.
+///
+/// Steps that are already in effect are skipped, so that a process that
+/// the listening socket was handed over to (see [`crate::handover`], and
+/// `handed_over`) does not fail to drop privileges that it never had.
+/// Otherwise, changing the root directory requires running as root.
+#[cfg(unix)]
+pub fn drop_privileges(config: &SecurityConfig, handed_over: bool) -> io::Result<()> {
+    use std::ffi::CString;
+
+    fn c_string(value: &str) -> io::Result<CString> {
+        CString::new(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn not_found(what: &str, name: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} {:?} does not exist", what, name),
+        )
+    }
+
+    if config.chroot.is_some() && config.user.is_none() {
+        tracing::warn!(
+            "security.chroot is set without security.user, so the server keeps running as {}",
+            if unsafe { libc::geteuid() } == 0 {
+                "root"
+            } else {
+                "the current user"
+            }
+        );
+    }
+
+    // Look up the user and group before changing the root directory,
+    // which usually does not contain `/etc/passwd` and `/etc/group`.
+    //
+    // Safety: the returned records are copied before any other call
+    // that could overwrite them, and this runs before the server starts
+    // any other work
+    let user = match &config.user {
+        Some(name) => {
+            let c_name = c_string(name)?;
+            let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+            if passwd.is_null() {
+                return Err(not_found("User", name));
+            }
+            let passwd = unsafe { &*passwd };
+            Some((c_name, passwd.pw_uid, passwd.pw_gid))
+        }
+        None => None,
+    };
+    let gid = match &config.group {
+        Some(name) => {
+            let c_name = c_string(name)?;
+            let group = unsafe { libc::getgrnam(c_name.as_ptr()) };
+            if group.is_null() {
+                return Err(not_found("Group", name));
+            }
+            Some(unsafe { &*group }.gr_gid)
+        }
+        None => user.as_ref().map(|(_, _, gid)| *gid),
+    };
+
+    // Safety: the calls below only take plain values and NUL-terminated
+    // strings that outlive them
+    if let Some(chroot) = &config.chroot {
+        if unsafe { libc::geteuid() } == 0 {
+            std::os::unix::fs::chroot(chroot)?;
+            std::env::set_current_dir("/")?;
+        } else if !handed_over {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "changing the root directory to {} requires running as root",
+                    chroot.display()
+                ),
+            ));
+        }
+    }
+
+    if let Some(gid) = gid {
+        if unsafe { libc::getegid() } != gid {
+            let groups = match &user {
+                Some((name, _, _)) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+                None => unsafe { libc::setgroups(1, &gid) },
+            };
+            if groups != 0 || unsafe { libc::setgid(gid) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+
+    if let Some((_, uid, _)) = user {
+        if unsafe { libc::geteuid() } != uid && unsafe { libc::setuid(uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_config: &SecurityConfig, _handed_over: bool) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "dropping privileges is only supported on Unix",
+    ))
+}
@@ -0,0 +1,316 @@
+//! A self-test that boots the server on an ephemeral port and sends data
+//! from a source to a listener through the full pipeline, for packaging
+//! smoke tests and deployment pipelines.
+
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use b64::ToBase64;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    auth::{self, AuthMechanism},
+    config::{Config, MountConfig},
+    net, Server,
+};
+
+/// The mount that the self-test sends data through
+const MOUNT: &str = "/.peroxidecast-self-test";
+/// The content type of the data sent through the self-test mount
+const CONTENT_TYPE: &str = "application/octet-stream";
+/// The amount of data sent through the self-test mount
+const PAYLOAD_BYTES: usize = 256 * 1024;
+/// The time within which every step must complete
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The outcome of a single step of the self-test
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+    pub duration: Duration,
+}
+
+/// The outcome of the self-test
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub steps: Vec<Step>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|s| s.result.is_ok())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for step in &self.steps {
+            let duration =
+                humantime::format_duration(Duration::from_millis(step.duration.as_millis() as u64));
+            match &step.result {
+                Ok(()) => writeln!(f, "PASS {} ({})", step.name, duration)?,
+                Err(e) => writeln!(f, "FAIL {} ({}): {}", step.name, duration, e)?,
+            }
+        }
+
+        if self.passed() {
+            write!(f, "Self-test passed")
+        } else {
+            write!(f, "Self-test failed")
+        }
+    }
+}
+
+/// Generate credentials that are only used by a single self-test
+fn credentials() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let secret = format!("self-test:{:x}{:x}", nanos, std::process::id());
+    format!("Basic {}", secret.as_bytes().to_base64(b64::STANDARD))
+}
+
+/// Read the head of a response from `stream`, returning its status code
+async fn read_status(stream: &mut TcpStream) -> Result<u16, String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        let byte = stream.read_u8().await.map_err(|e| e.to_string())?;
+        head.push(byte);
+    }
+
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    match response.parse(&head) {
+        Ok(httparse::Status::Complete(_)) => Ok(response.code.unwrap_or_default()),
+        _ => Err("malformed response".to_string()),
+    }
+}
+
+async fn connect_source(address: SocketAddr, authorization: &str) -> Result<TcpStream, String> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let request = format!(
+        "SOURCE {} HTTP/1.0\r\nAuthorization: {}\r\nContent-Type: {}\r\nice-name: Self-test\r\n\r\n",
+        MOUNT, authorization, CONTENT_TYPE
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(stream)
+}
+
+/// Runs the steps of the self-test, stopping at the first failure
+struct Runner {
+    report: Report,
+}
+
+impl Runner {
+    async fn step<T, F>(&mut self, name: &'static str, step: F) -> Option<T>
+    where
+        F: std::future::Future<Output = Result<T, String>>,
+    {
+        if !self.report.passed() {
+            return None;
+        }
+
+        let start = Instant::now();
+        let result = match tokio::time::timeout(STEP_TIMEOUT, step).await {
+            Ok(result) => result,
+            Err(_) => Err(format!(
+                "timed out after {}",
+                humantime::format_duration(STEP_TIMEOUT)
+            )),
+        };
+
+        let (value, result) = match result {
+            Ok(value) => (Some(value), Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        self.report.steps.push(Step {
+            name,
+            result,
+            duration: start.elapsed(),
+        });
+        value
+    }
+}
+
+/// Boot a server with `config` on an ephemeral port, send data from a
+/// source to a listener through a dedicated mount, and report whether
+/// every step succeeded.
+pub async fn run(mut config: Config) -> Report {
+    let mut runner = Runner {
+        report: Report::default(),
+    };
+
+    let authorization = credentials();
+    if config.mounts.contains_key(MOUNT) {
+        runner
+            .step("Add self-test mount", async {
+                Err::<(), _>(format!("mount {} is already configured", MOUNT))
+            })
+            .await;
+        return runner.report;
+    }
+    config.mounts.insert(
+        MOUNT.to_string(),
+        MountConfig {
+            source_auth: Some(authorization.clone()),
+            sub_auth: Some(authorization.clone()),
+            ..Default::default()
+        },
+    );
+
+    let admin_authorization = match (
+        &config.admin_authorization,
+        &config.admin_username,
+        &config.admin_password,
+    ) {
+        (Some(authorization), _, _) => Some(authorization.clone()),
+        (None, Some(user), Some(password)) if !auth::is_password_hash(password) => {
+            Some(auth::basic_authorization(user, password))
+        }
+        _ => None,
+    };
+
+    let address = runner
+        .step("Listen on an ephemeral port", async {
+            let listener = TcpListener::bind(("127.0.0.1", 0))
+                .await
+                .map_err(|e| e.to_string())?;
+            let address = listener.local_addr().map_err(|e| e.to_string())?;
+            tokio::spawn(Server::new(config).run(listener));
+            Ok(address)
+        })
+        .await;
+    let address = if let Some(address) = address {
+        address
+    } else {
+        return runner.report;
+    };
+    let url = format!("http://{}{}", address, MOUNT);
+
+    runner
+        .step("Reject a source with wrong credentials", async {
+            let mut stream = connect_source(address, "Basic d3Jvbmc6d3Jvbmc=").await?;
+            match read_status(&mut stream).await? {
+                401 => Ok(()),
+                status => Err(format!("expected status 401, got {}", status)),
+            }
+        })
+        .await;
+
+    let source = runner
+        .step("Connect a source", async {
+            let mut stream = connect_source(address, &authorization).await?;
+            match read_status(&mut stream).await? {
+                200 => Ok(stream),
+                status => Err(format!("expected status 200, got {}", status)),
+            }
+        })
+        .await;
+
+    runner
+        .step("Reject a listener without credentials", async {
+            let response = net::open("GET", &url, &[])
+                .await
+                .map_err(|e| e.to_string())?;
+            match response.status {
+                401 => Ok(()),
+                status => Err(format!("expected status 401, got {}", status)),
+            }
+        })
+        .await;
+
+    let listener = runner
+        .step("Connect a listener", async {
+            let response = net::open("GET", &url, &[("Authorization", &authorization)])
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.is_success() {
+                Err(format!("expected status 200, got {}", response.status))
+            } else if response.header("Content-Type") != Some(CONTENT_TYPE) {
+                Err(format!(
+                    "expected content type {}, got {:?}",
+                    CONTENT_TYPE,
+                    response.header("Content-Type")
+                ))
+            } else {
+                Ok(response)
+            }
+        })
+        .await;
+
+    if let (Some(mut source), Some(mut listener)) = (source, listener) {
+        runner
+            .step("Send data from the source to the listener", async {
+                let payload: Vec<u8> = (0..PAYLOAD_BYTES).map(|i| (i % 251) as u8).collect();
+
+                let send = async {
+                    source
+                        .write_all(&payload)
+                        .await
+                        .map_err(|e| format!("sending failed: {}", e))
+                };
+
+                let receive = async {
+                    let mut received = std::mem::take(&mut listener.body_start);
+                    while received.len() < PAYLOAD_BYTES {
+                        let read = listener
+                            .stream
+                            .read_buf(&mut received)
+                            .await
+                            .map_err(|e| format!("receiving failed: {}", e))?;
+                        if read == 0 {
+                            return Err(format!(
+                                "listener was disconnected after {} bytes",
+                                received.len()
+                            ));
+                        }
+                    }
+                    Ok(received)
+                };
+
+                let (sent, received) = tokio::join!(send, receive);
+                sent?;
+                if received? == payload {
+                    Ok(())
+                } else {
+                    Err("the listener received different data than was sent".to_string())
+                }
+            })
+            .await;
+    }
+
+    // Only admin credentials that are configured as the value of the
+    // header, or as a plain user name and password, can be sent
+    if let Some(admin_authorization) =
+        admin_authorization.filter(|a| AuthMechanism::of(a).is_some())
+    {
+        runner
+            .step("Use the admin API", async {
+                let url = format!("http://{}/admin/dependencies", address);
+                let response = net::open("GET", &url, &[("Authorization", &admin_authorization)])
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if response.is_success() {
+                    Ok(())
+                } else {
+                    Err(format!("expected status 200, got {}", response.status))
+                }
+            })
+            .await;
+    }
+
+    runner.report
+}
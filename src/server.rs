@@ -0,0 +1,556 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::{
+    api::ServerMetrics,
+    billing,
+    config::{Config, MountConfig},
+    dependencies::DependencyGraph,
+    egress::EgressLimiter,
+    features,
+    geoip::GeoIp,
+    handover::{self, HandoverSignal},
+    history_db::{self, HistoryDb},
+    hooks,
+    htpasswd::Htpasswd,
+    influxdb,
+    jwt::{self, JwtVerifier},
+    net::{self, SocketHandler},
+    playlist_log::PlaylistLog,
+    ratelimit::RateLimiter,
+    relay, retention,
+    state::{IceMeta, Mount, MountAccessUpdate, State},
+    statsd, systemd,
+    templates::Templates,
+    webhooks, yp,
+};
+
+type ConfigLoader = Arc<dyn Fn() -> Result<Config, String> + Send + Sync>;
+
+/// What [`Server::reload`] changed
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReloadSummary {
+    /// The top-level settings that changed, which apply to new
+    /// connections
+    pub changed_settings: Vec<String>,
+    pub added_mounts: Vec<String>,
+    /// Mounts that are no longer configured. Their source and listeners
+    /// stay connected, and the mounts are removed once all of them have
+    /// disconnected.
+    pub removed_mounts: Vec<String>,
+    /// Changed mounts that nobody was connected to, which were recreated
+    /// with their new configuration
+    pub replaced_mounts: Vec<String>,
+    /// Changed mounts that are in use, of which the credentials and the
+    /// listener limit were updated. They are replaced with their new
+    /// configuration once nobody is connected to them.
+    pub updated_mounts: Vec<String>,
+}
+
+/// A handle to a running (or to be run) Peroxidecast server.
+///
+/// Cloning the handle is cheap: all clones refer to the same
+/// server state.
+#[derive(Clone)]
+pub struct Server {
+    /// The current configuration, which is replaced by [`Server::reload`]
+    config: Arc<watch::Sender<Arc<Config>>>,
+    /// Loads the configuration again, e.g. from the configuration file
+    config_loader: Option<ConfigLoader>,
+    state: Arc<State>,
+    /// The order in which mounts are started, such that every mount
+    /// is started after the mounts it depends on
+    mount_order: Arc<Vec<String>>,
+    /// The changes to mounts that were in use when the configuration
+    /// was reloaded
+    pending_mounts: Arc<Mutex<PendingMounts>>,
+}
+
+/// Changes to mounts that are applied once nobody is connected to them
+#[derive(Debug, Default)]
+struct PendingMounts {
+    /// The new configuration of mounts that were changed
+    replaced: BTreeMap<String, MountConfig>,
+    /// Mounts that are no longer configured
+    removed: BTreeSet<String>,
+}
+
+impl Server {
+    /// Create a new server, setting up all mounts that are
+    /// described in `config`.
+    pub fn new(config: Config) -> Self {
+        let mut state = State::new();
+        state.set_egress(config.egress.as_ref().map(EgressLimiter::new));
+        state.set_rate_limiter(config.rate_limit.as_ref().map(RateLimiter::new));
+        state.set_admin_htpasswd(config.admin_htpasswd.as_deref().map(Htpasswd::open));
+        state.set_jwt(config.jwt.clone().and_then(|jwt| {
+            JwtVerifier::new(jwt)
+                .map_err(|e| error!("Could not set up JWT authentication: {}", e))
+                .ok()
+        }));
+        state.set_geoip(config.geoip_db.as_ref().and_then(|path| {
+            GeoIp::open(path)
+                .map_err(|e| error!("Could not open GeoIP database {}: {}", path.display(), e))
+                .ok()
+        }));
+        state.set_playlist_log(config.playlist_log.as_ref().and_then(|path| {
+            PlaylistLog::open(path, config.log_rotation.clone().unwrap_or_default())
+                .map_err(|e| error!("Could not open playlist log {}: {}", path.display(), e))
+                .ok()
+        }));
+        state.set_history_db(config.history_db.as_ref().and_then(|history_db| {
+            HistoryDb::open(&history_db.path)
+                .map_err(|e| {
+                    error!(
+                        "Could not open history database {}: {}",
+                        history_db.path.display(),
+                        e
+                    )
+                })
+                .ok()
+        }));
+        state.set_templates(config.templates.as_ref().and_then(|templates| {
+            Templates::load(templates)
+                .map_err(|e| error!("Could not load templates: {}", e))
+                .ok()
+        }));
+        debug!("Optional features compiled in: {:?}", features::enabled());
+
+        let mount_order = match DependencyGraph::from_config(&config).startup_order() {
+            Ok(order) => order,
+            Err(e) => {
+                error!("Invalid mount dependencies, ignoring them: {}", e);
+                config.mounts.keys().cloned().collect()
+            }
+        };
+
+        for mount_name in &mount_order {
+            state.add_mount(
+                mount_name.to_string(),
+                configured_mount(mount_name, &config.mounts[mount_name]),
+            );
+        }
+
+        Self {
+            config: Arc::new(watch::Sender::new(Arc::new(config))),
+            config_loader: None,
+            state: Arc::new(state),
+            mount_order: Arc::new(mount_order),
+            pending_mounts: Arc::default(),
+        }
+    }
+
+    /// Reload the configuration with `loader` when the process receives
+    /// `SIGHUP` or an admin requests `/admin/reloadconfig`
+    pub fn with_config_loader(
+        mut self,
+        loader: impl Fn() -> Result<Config, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config_loader = Some(Arc::new(loader));
+        self
+    }
+
+    /// The current configuration
+    pub fn config(&self) -> Arc<Config> {
+        self.config.borrow().clone()
+    }
+
+    /// Apply `config` without restarting active streams.
+    ///
+    /// The limits and access rules that are checked for every connection
+    /// apply to new connections. Mounts that were added are created, and
+    /// mounts that were removed no longer accept sources and listeners:
+    /// the ones that are connected stay connected, and the mount is
+    /// removed once they have all disconnected. Mounts that were changed
+    /// are replaced if nobody is connected to them. Otherwise they get
+    /// their new credentials and listener limit right away, and are
+    /// replaced once nobody is connected to them anymore.
+    ///
+    /// Background tasks (e.g. the StatsD, InfluxDB, YP and webhook
+    /// clients) and the settings that are set up with the server (e.g.
+    /// JWT, GeoIP and htpasswd files) keep their configuration until the
+    /// server is restarted.
+    pub fn reload(&self, config: Config) -> ReloadSummary {
+        let old = self.config();
+        let mut summary = ReloadSummary {
+            changed_settings: changed_settings(&old, &config),
+            ..Default::default()
+        };
+
+        let mut pending = self.pending_mounts.lock().unwrap();
+
+        for mount_name in old.mounts.keys() {
+            if config.mounts.contains_key(mount_name) {
+                continue;
+            }
+            pending.replaced.remove(mount_name);
+
+            match self.state.find_mount(mount_name) {
+                Some(mount) if mount.is_idle() => {
+                    self.state.remove_mount(mount_name);
+                    info!("Removed mount {}", mount_name);
+                }
+                Some(mount) => {
+                    // Removing the mount right away would let a source
+                    // create a new mount with the same name while the
+                    // listeners of this one are still attached
+                    mount.set_retired(true);
+                    pending.removed.insert(mount_name.clone());
+                    info!(
+                        "Mount {} is in use, removing it once everyone has disconnected",
+                        mount_name
+                    );
+                }
+                None => continue,
+            }
+            summary.removed_mounts.push(mount_name.clone());
+        }
+
+        let mount_order = DependencyGraph::from_config(&config)
+            .startup_order()
+            .unwrap_or_else(|e| {
+                error!("Invalid mount dependencies, ignoring them: {}", e);
+                config.mounts.keys().cloned().collect()
+            });
+
+        for mount_name in &mount_order {
+            let mount_config = &config.mounts[mount_name];
+            let mount = match self.state.find_mount(mount_name) {
+                Some(mount) => {
+                    // A mount that was removed before, and is still in use
+                    if pending.removed.remove(mount_name) {
+                        mount.set_retired(false);
+                    }
+                    mount
+                }
+                None => {
+                    info!("Adding mount {}", mount_name);
+                    self.state.add_mount(
+                        mount_name.clone(),
+                        configured_mount(mount_name, mount_config),
+                    );
+                    summary.added_mounts.push(mount_name.clone());
+                    continue;
+                }
+            };
+
+            // Compare against the configuration that the mount runs with,
+            // or will run with once it is no longer in use
+            if same_mount_config(mount.config(), mount_config) {
+                // Undo a change that was still pending
+                if pending.replaced.remove(mount_name).is_some() {
+                    mount.update_access(access_update(mount_config));
+                }
+                continue;
+            }
+            if pending
+                .replaced
+                .get(mount_name)
+                .is_some_and(|replaced| same_mount_config(replaced, mount_config))
+            {
+                continue;
+            }
+
+            if mount.is_idle() {
+                info!("Replacing mount {} with its new configuration", mount_name);
+                self.state.remove_mount(mount_name);
+                self.state.add_mount(
+                    mount_name.clone(),
+                    configured_mount(mount_name, mount_config),
+                );
+                summary.replaced_mounts.push(mount_name.clone());
+            } else {
+                info!(
+                    "Mount {} is in use, updating its credentials and listener limit, \
+                     and replacing it once everyone has disconnected",
+                    mount_name
+                );
+                mount.update_access(access_update(mount_config));
+                pending
+                    .replaced
+                    .insert(mount_name.clone(), mount_config.clone());
+                summary.updated_mounts.push(mount_name.clone());
+            }
+        }
+        drop(pending);
+        relay::spawn_all(&self.state, &summary.added_mounts);
+        relay::spawn_all(&self.state, &summary.replaced_mounts);
+
+        self.config.send_replace(Arc::new(config));
+        info!("Reloaded the configuration: {:?}", summary);
+        summary
+    }
+
+    /// Apply the changes to mounts that were postponed by
+    /// [`Server::reload`] because the mounts were in use, if nobody is
+    /// connected to them anymore
+    fn apply_pending_mounts(&self) {
+        let mut pending = self.pending_mounts.lock().unwrap();
+
+        let mut replaced = Vec::new();
+        pending.replaced.retain(|mount_name, config| {
+            match self.state.find_mount(mount_name) {
+                Some(mount) if !mount.is_idle() => return true,
+                Some(_) => {
+                    info!(
+                        "Mount {} is no longer in use, replacing it with its new configuration",
+                        mount_name
+                    );
+                    self.state.remove_mount(mount_name);
+                    self.state
+                        .add_mount(mount_name.clone(), configured_mount(mount_name, config));
+                    replaced.push(mount_name.clone());
+                }
+                None => {}
+            }
+            false
+        });
+
+        pending.removed.retain(|mount_name| {
+            match self.state.find_mount(mount_name) {
+                Some(mount) if !mount.is_idle() => return true,
+                Some(_) => {
+                    self.state.remove_mount(mount_name);
+                    info!("Removed mount {}, which is no longer in use", mount_name);
+                }
+                None => {}
+            }
+            false
+        });
+        drop(pending);
+
+        relay::spawn_all(&self.state, &replaced);
+    }
+
+    /// Load the configuration again with the loader that was set with
+    /// [`Server::with_config_loader`], and apply it with
+    /// [`Server::reload`]
+    pub fn reload_config(&self) -> Result<ReloadSummary, String> {
+        let loader = self
+            .config_loader
+            .as_ref()
+            .ok_or("the configuration of this server cannot be reloaded")?;
+        let config = loader().map_err(|e| {
+            error!("Not reloading the configuration: {}", e);
+            e
+        })?;
+        Ok(self.reload(config))
+    }
+
+    pub fn state(&self) -> &Arc<State> {
+        &self.state
+    }
+
+    /// Take a snapshot of the current mounts, their statistics and
+    /// the active sessions.
+    pub fn metrics(&self) -> ServerMetrics {
+        ServerMetrics::from_state(&self.state)
+    }
+
+    /// Accept and handle connections on `tcp_listener`.
+    ///
+    /// On `SIGUSR2`, the listener is handed over to a new process (see
+    /// [`handover`]), after which this returns once all connections
+    /// have closed.
+    pub async fn run(self, tcp_listener: TcpListener) {
+        let config = self.config();
+        relay::spawn_all(&self.state, &self.mount_order);
+        if let Some(billing) = &config.billing {
+            billing::spawn(billing.clone(), self.state.clone());
+        }
+        if let Some(statsd) = &config.statsd {
+            statsd::spawn(statsd.clone(), self.state.clone());
+        }
+        if let Some(influxdb) = &config.influxdb {
+            influxdb::spawn(influxdb.clone(), self.state.clone());
+        }
+        if let (Some(history_db), Some(_)) = (&config.history_db, self.state.history_db()) {
+            history_db::spawn(history_db.clone(), self.state.clone());
+        }
+        hooks::spawn(&config.mounts, self.state.clone());
+        if let Some(yp) = &config.yp {
+            yp::spawn(yp.clone(), self.state.clone());
+        }
+        for webhook in &config.webhooks {
+            webhooks::spawn(webhook.clone(), self.state.clone());
+        }
+        jwt::spawn(self.state.clone());
+
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            match signal(SignalKind::user_defined1()) {
+                Ok(mut reopen) => {
+                    let state = self.state.clone();
+                    tokio::spawn(async move {
+                        while reopen.recv().await.is_some() {
+                            state.reopen_logs();
+                        }
+                    });
+                }
+                Err(e) => warn!(
+                    "Could not listen for SIGUSR1, logs cannot be reopened: {}",
+                    e
+                ),
+            }
+
+            if self.config_loader.is_some() {
+                match signal(SignalKind::hangup()) {
+                    Ok(mut hangup) => {
+                        let server = self.clone();
+                        tokio::spawn(async move {
+                            while hangup.recv().await.is_some() {
+                                // Failures are logged by `reload_config`
+                                let _ = server.reload_config();
+                            }
+                        });
+                    }
+                    Err(e) => warn!(
+                        "Could not listen for SIGHUP, the configuration cannot be reloaded: {}",
+                        e
+                    ),
+                }
+            }
+        }
+
+        let housekeeping = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                let metrics = housekeeping.metrics();
+                debug!("Mount stats:");
+                for mount in &metrics.mounts {
+                    debug!("{}: {}", mount.name, mount.stats)
+                }
+                debug!("Buffer pool: {:?}", metrics.buffer_pool);
+                housekeeping
+                    .state
+                    .history()
+                    .record(SystemTime::now(), &metrics);
+                retention::prune(
+                    &housekeeping.state,
+                    &housekeeping.config().retention.clone().unwrap_or_default(),
+                );
+                housekeeping.apply_pending_mounts();
+                housekeeping.state.clean_disconnected_mounts();
+            }
+        });
+
+        let reloader = self.config_loader.as_ref().map(|_| {
+            let server = self.clone();
+            Arc::new(move || server.reload_config()) as net::Reloader
+        });
+        let mut config_rx = self.config.subscribe();
+        let mut config = config_rx.borrow_and_update().clone();
+        let mut router = net::router(config.clone(), self.state.clone(), reloader.clone());
+
+        let mut handover = HandoverSignal::new();
+        systemd::spawn();
+        for connection_id in 1u64.. {
+            let accepted = tokio::select! {
+                accepted = tcp_listener.accept() => accepted,
+                _ = handover.recv() => match handover::spawn_successor(&tcp_listener) {
+                    Ok(child) => {
+                        info!("Handed the listening socket over to process {}", child.id());
+                        if let Err(e) = systemd::notify(&format!("MAINPID={}\nSTOPPING=1", child.id())) {
+                            warn!("Could not notify systemd: {}", e);
+                        }
+                        break;
+                    }
+                    Err(e) => {
+                        error!("Could not start a new process to hand over to: {}", e);
+                        continue;
+                    }
+                },
+            };
+
+            match accepted {
+                Ok((socket, addr)) => {
+                    if config_rx.has_changed().unwrap_or(false) {
+                        config = config_rx.borrow_and_update().clone();
+                        router = net::router(config.clone(), self.state.clone(), reloader.clone());
+                    }
+
+                    let handler = SocketHandler::new(
+                        (*config).clone(),
+                        socket.local_addr().unwrap(),
+                        addr,
+                        socket,
+                        self.state.clone(),
+                        router.clone(),
+                    );
+                    // All log lines about a connection carry its ID and
+                    // remote address
+                    let span = info_span!("conn", id = connection_id, remote = %addr);
+                    tokio::spawn(handler.run().instrument(span));
+                }
+                Err(e) => error!("Socket error: {:?}", e),
+            }
+        }
+
+        drop(tcp_listener);
+        while self.state.ip_connections().total() > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        info!("All connections have closed");
+    }
+}
+
+/// Create the mount `mount_name` as it is configured by `config`
+fn configured_mount(mount_name: &str, config: &MountConfig) -> Mount {
+    let missing = features::missing(config);
+    if !missing.is_empty() {
+        warn!(
+            "Mount {} uses features that are not compiled in, which are disabled: {}",
+            mount_name,
+            missing.join(", ")
+        );
+    }
+
+    Mount::new(
+        "".to_string(),
+        tokio::sync::broadcast::channel(1).0.downgrade(),
+        IceMeta::default(),
+        config.clone(),
+    )
+}
+
+/// The settings of `config` that can be changed while a mount is in use
+fn access_update(config: &MountConfig) -> MountAccessUpdate {
+    MountAccessUpdate {
+        source_auth: Some(config.source_auth.clone()),
+        sub_auth: Some(config.sub_auth.clone()),
+        max_listeners: Some(config.max_listeners),
+        public: Some(config.public),
+    }
+}
+
+/// Whether two configurations of a mount are the same
+fn same_mount_config(a: &MountConfig, b: &MountConfig) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The top-level settings (other than the mounts) that differ between
+/// `old` and `new`
+fn changed_settings(old: &Config, new: &Config) -> Vec<String> {
+    let (old, new) = match (serde_json::to_value(old), serde_json::to_value(new)) {
+        (Ok(serde_json::Value::Object(old)), Ok(serde_json::Value::Object(new))) => (old, new),
+        _ => return Vec::new(),
+    };
+
+    new.iter()
+        .filter(|(key, value)| *key != "mounts" && old.get(*key) != Some(*value))
+        .map(|(key, _)| key.clone())
+        .collect()
+}
@@ -0,0 +1,317 @@
+//! A registry of the listeners that are connected, each with a unique
+//! session ID, so that single listeners can be reported on, sent
+//! messages, e.g. "your preview expires in 5 minutes", moved to another
+//! mount and disconnected.
+
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime},
+};
+
+use dashmap::DashMap;
+use serde::Serialize;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    watch, Notify,
+};
+
+use crate::state::Mount;
+
+/// The amount of messages that may wait to be delivered to a listener
+const MESSAGE_QUEUE: usize = 4;
+
+/// A description of a listener session
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub mount: String,
+    pub remote_ip: IpAddr,
+    /// The ISO 3166-1 alpha-2 code of the country of the listener, if it
+    /// is known
+    pub country: Option<String>,
+    pub user_agent: Option<String>,
+    /// Who the listener authenticated as, if anyone (see
+    /// [`auth::credential_id`](crate::auth::credential_id))
+    pub credential: Option<String>,
+    /// The time at which the listener connected, in RFC 3339 format
+    pub connected_at: String,
+    /// The amount of seconds that the listener has been connected
+    pub connected_secs: u64,
+    /// Whether the listener asked for ICY metadata, which is required
+    /// to deliver messages to it
+    pub icy_metadata: bool,
+    /// The amount of bytes sent to the listener so far
+    pub bytes_sent: u64,
+    /// The amount of chunks that the listener was behind on the source
+    /// when the last chunk was sent to it. A listener that stays behind,
+    /// or of which `bytes_sent` stops growing, is stuck or has a
+    /// connection that is too slow.
+    pub queued_chunks: usize,
+}
+
+/// Aggregate statistics about listener sessions
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SessionSummary {
+    /// The amount of listeners that are currently connected
+    pub connected: usize,
+    /// The amount of sessions that have ended
+    pub ended: u64,
+    /// The average duration of the sessions that have ended, in seconds
+    pub average_duration_secs: f64,
+    /// The amount of connected listeners that are behind on their source
+    pub lagging: usize,
+}
+
+/// The mount to which a listener is moved
+#[derive(Debug, Clone)]
+pub struct MoveTo {
+    pub mount_name: String,
+    pub mount: Arc<Mount>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    info: SessionInfo,
+    connected: Instant,
+    bytes_sent: Arc<AtomicU64>,
+    queued_chunks: Arc<AtomicUsize>,
+    messages: Option<mpsc::Sender<String>>,
+    kill: Arc<Notify>,
+    moves: watch::Sender<Option<MoveTo>>,
+}
+
+impl Entry {
+    fn info(&self) -> SessionInfo {
+        SessionInfo {
+            connected_secs: self.connected.elapsed().as_secs(),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            queued_chunks: self.queued_chunks.load(Ordering::Relaxed),
+            ..self.info.clone()
+        }
+    }
+}
+
+/// Why a message could not be sent to a session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageError {
+    /// There is no session with the given ID
+    NotFound,
+    /// The listener did not ask for metadata, so messages cannot be
+    /// delivered to it
+    NotSupported,
+    /// Too many messages are waiting to be delivered to the listener
+    QueueFull,
+}
+
+/// All listener sessions
+#[derive(Debug, Default)]
+pub struct Sessions {
+    next_id: AtomicU64,
+    sessions: DashMap<u64, Entry>,
+    /// The amount of sessions that have ended
+    ended: AtomicU64,
+    /// The summed duration of the sessions that have ended, in milliseconds
+    ended_millis: AtomicU64,
+}
+
+impl Sessions {
+    /// Start a session for a listener of `mount` at `remote_ip` in
+    /// `country` that authenticated as `credential`. Messages
+    /// can only be sent to the session if the listener asked for ICY
+    /// metadata.
+    ///
+    /// The session ends when the returned [`Session`] is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        mount: &str,
+        remote_ip: IpAddr,
+        country: Option<String>,
+        user_agent: Option<&str>,
+        credential: Option<String>,
+        icy_metadata: bool,
+    ) -> Session {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+
+        let (tx, rx) = if icy_metadata {
+            let (tx, rx) = mpsc::channel(MESSAGE_QUEUE);
+            (Some(tx), Some(rx))
+        } else {
+            (None, None)
+        };
+
+        let info = SessionInfo {
+            id,
+            mount: mount.to_string(),
+            remote_ip,
+            country,
+            user_agent: user_agent.map(String::from),
+            credential,
+            connected_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            connected_secs: 0,
+            icy_metadata,
+            bytes_sent: 0,
+            queued_chunks: 0,
+        };
+        let bytes_sent = Arc::new(AtomicU64::new(0));
+        let queued_chunks = Arc::new(AtomicUsize::new(0));
+        let kill = Arc::new(Notify::new());
+        let (moves, moves_rx) = watch::channel(None);
+        self.sessions.insert(
+            id,
+            Entry {
+                info,
+                connected: Instant::now(),
+                bytes_sent: bytes_sent.clone(),
+                queued_chunks: queued_chunks.clone(),
+                messages: tx,
+                kill: kill.clone(),
+                moves,
+            },
+        );
+
+        Session {
+            id,
+            sessions: self.clone(),
+            bytes_sent,
+            queued_chunks,
+            messages: rx,
+            kill,
+            moves: moves_rx,
+        }
+    }
+
+    /// All sessions, ordered by the time they were started
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<SessionInfo> = self.sessions.iter().map(|e| e.info()).collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    /// Aggregate statistics about all sessions
+    pub fn summary(&self) -> SessionSummary {
+        let ended = self.ended.load(Ordering::Relaxed);
+        let ended_millis = self.ended_millis.load(Ordering::Relaxed);
+
+        SessionSummary {
+            connected: self.sessions.len(),
+            ended,
+            average_duration_secs: if ended == 0 {
+                0.0
+            } else {
+                ended_millis as f64 / ended as f64 / 1000.0
+            },
+            lagging: self
+                .sessions
+                .iter()
+                .filter(|e| e.queued_chunks.load(Ordering::Relaxed) > 0)
+                .count(),
+        }
+    }
+
+    /// The sessions of the listeners of `mount`, ordered by the time
+    /// they were started
+    pub fn for_mount(&self, mount: &str) -> Vec<SessionInfo> {
+        let mut sessions = self.list();
+        sessions.retain(|s| s.mount == mount);
+        sessions
+    }
+
+    /// The session with ID `id`, if it is still connected
+    pub fn get(&self, id: u64) -> Option<SessionInfo> {
+        self.sessions.get(&id).map(|e| e.info())
+    }
+
+    /// Send a one-off `message` to session `id`
+    pub fn send_message(&self, id: u64, message: String) -> Result<(), MessageError> {
+        let entry = self.sessions.get(&id).ok_or(MessageError::NotFound)?;
+        let messages = entry.messages.as_ref().ok_or(MessageError::NotSupported)?;
+
+        messages.try_send(message).map_err(|e| match e {
+            TrySendError::Full(_) => MessageError::QueueFull,
+            TrySendError::Closed(_) => MessageError::NotFound,
+        })
+    }
+
+    /// Move all listeners of `from` to `to`, returning the amount of
+    /// listeners that were moved
+    pub fn move_mount(&self, from: &str, to: MoveTo) -> usize {
+        let mut moved = 0;
+        for mut entry in self.sessions.iter_mut() {
+            if entry.info.mount == from {
+                entry.info.mount = to.mount_name.clone();
+                entry.moves.send_replace(Some(to.clone()));
+                moved += 1;
+            }
+        }
+        moved
+    }
+
+    /// Disconnect the listener of session `id`. Returns `false` if there
+    /// is no such session.
+    pub fn kill(&self, id: u64) -> bool {
+        if let Some(entry) = self.sessions.get(&id) {
+            entry.kill.notify_one();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The session of a connected listener
+#[derive(Debug)]
+pub struct Session {
+    id: u64,
+    sessions: Arc<Sessions>,
+    bytes_sent: Arc<AtomicU64>,
+    queued_chunks: Arc<AtomicUsize>,
+    messages: Option<mpsc::Receiver<String>>,
+    kill: Arc<Notify>,
+    moves: watch::Receiver<Option<MoveTo>>,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn add_bytes_sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Record that the listener is `chunks` chunks behind on its source
+    pub fn set_queued_chunks(&self, chunks: usize) {
+        self.queued_chunks.store(chunks, Ordering::Relaxed);
+    }
+
+    /// Take the next message for this listener, if there is one
+    pub fn next_message(&mut self) -> Option<String> {
+        self.messages.as_mut()?.try_recv().ok()
+    }
+
+    /// Notified once the listener should be disconnected
+    pub fn kill_signal(&self) -> Arc<Notify> {
+        self.kill.clone()
+    }
+
+    /// Changes to the mount that the listener should be moved to
+    pub fn move_signal(&self) -> watch::Receiver<Option<MoveTo>> {
+        self.moves.clone()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some((_, entry)) = self.sessions.sessions.remove(&self.id) {
+            let duration = entry.connected.elapsed().as_millis() as u64;
+            self.sessions.ended.fetch_add(1, Ordering::Relaxed);
+            self.sessions
+                .ended_millis
+                .fetch_add(duration, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Signed, expiring stream URLs, e.g. for member-only streams.
+//!
+//! A listener URL like `/live?expires=1700000000&token=<token>` is valid
+//! until the UNIX timestamp `expires`, where `token` is the lowercase hex
+//! HMAC-SHA256 of `<mount>:<expires>` (e.g. `/live:1700000000`) keyed
+//! with the `url_signing_secret` of the mount. Websites can hand out such
+//! URLs without the server having to call back to them.
+
+use std::{
+    fmt::Display,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+
+use crate::auth;
+
+/// Why a signed URL was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignedUrlError {
+    /// The URL has no `token` or `expires` parameter
+    Missing,
+    /// `expires` is not a UNIX timestamp
+    InvalidExpiry,
+    /// The URL expired
+    Expired,
+    /// The token was not made with the secret of the mount
+    InvalidToken,
+}
+
+impl Display for SignedUrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignedUrlError::Missing => write!(f, "no token or expiry"),
+            SignedUrlError::InvalidExpiry => write!(f, "invalid expiry"),
+            SignedUrlError::Expired => write!(f, "expired"),
+            SignedUrlError::InvalidToken => write!(f, "invalid token"),
+        }
+    }
+}
+
+/// The token for mount `mount_name` that is valid until `expires`
+pub fn sign(secret: &str, mount_name: &str, expires: u64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{}:{}", mount_name, expires).as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Check the `token` and `expires` parameters of a URL of mount
+/// `mount_name` at time `now`
+pub fn verify(
+    secret: &str,
+    mount_name: &str,
+    token: Option<&str>,
+    expires: Option<&str>,
+    now: SystemTime,
+) -> Result<(), SignedUrlError> {
+    let (token, expires) = match (token, expires) {
+        (Some(token), Some(expires)) => (token, expires),
+        _ => return Err(SignedUrlError::Missing),
+    };
+    let expires: u64 = expires.parse().map_err(|_| SignedUrlError::InvalidExpiry)?;
+
+    let now = now
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    if expires < now {
+        return Err(SignedUrlError::Expired);
+    }
+
+    let expected = sign(secret, mount_name, expires);
+    if auth::secrets_equal(&expected, &token.to_ascii_lowercase()) {
+        Ok(())
+    } else {
+        Err(SignedUrlError::InvalidToken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    const TOKEN: &str = "19eedb2641601e34d4369721be0e7d0e5307573d53c6a2f0d739842540905c36";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn sign_is_hmac_sha256_of_mount_and_expiry() {
+        assert_eq!(sign("secret", "/live", 1_700_000_000), TOKEN);
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let verify_at =
+            |token, now| verify("secret", "/live", Some(token), Some("1700000000"), at(now));
+        assert_eq!(verify_at(TOKEN, 1_600_000_000), Ok(()));
+        assert_eq!(verify_at(TOKEN, 1_700_000_000), Ok(()));
+        assert_eq!(
+            verify_at(&TOKEN.to_ascii_uppercase(), 1_600_000_000),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_urls() {
+        let now = at(1_600_000_000);
+        assert_eq!(
+            verify("secret", "/live", None, Some("1700000000"), now),
+            Err(SignedUrlError::Missing)
+        );
+        assert_eq!(
+            verify("secret", "/live", Some(TOKEN), None, now),
+            Err(SignedUrlError::Missing)
+        );
+        assert_eq!(
+            verify("secret", "/live", Some(TOKEN), Some("soon"), now),
+            Err(SignedUrlError::InvalidExpiry)
+        );
+        assert_eq!(
+            verify(
+                "secret",
+                "/live",
+                Some(TOKEN),
+                Some("1700000000"),
+                at(1_700_000_001)
+            ),
+            Err(SignedUrlError::Expired)
+        );
+        assert_eq!(
+            verify("secret", "/live", Some(TOKEN), Some("1700000001"), now),
+            Err(SignedUrlError::InvalidToken)
+        );
+        assert_eq!(
+            verify("other", "/live", Some(TOKEN), Some("1700000000"), now),
+            Err(SignedUrlError::InvalidToken)
+        );
+        assert_eq!(
+            verify("secret", "/other", Some(TOKEN), Some("1700000000"), now),
+            Err(SignedUrlError::InvalidToken)
+        );
+        assert_eq!(
+            verify(
+                "secret",
+                "/live",
+                Some(&TOKEN[1..]),
+                Some("1700000000"),
+                now
+            ),
+            Err(SignedUrlError::InvalidToken)
+        );
+    }
+}
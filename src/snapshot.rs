@@ -0,0 +1,379 @@
+//! A rolling buffer of the most recent data of a mount, so that the last
+//! few seconds of a stream can be downloaded as a clip, e.g. to answer
+//! "what just played?" support requests or to investigate silences.
+//!
+//! Clips are cut at frame (MPEG audio, ADTS) or page (Ogg) boundaries,
+//! so that they can be played back like any other file.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+
+use crate::state::{Chunk, DataReceiver, DataSender, Mount};
+
+/// The length of a clip if a request does not ask for one
+pub const DEFAULT_CLIP_SECS: u64 = 10;
+
+/// The amount of data at the start of a source that is kept around
+/// to find the headers of Ogg streams
+const OGG_HEADER_CAPTURE_BYTES: usize = 64 * 1024;
+
+/// The way in which the data of a mount is framed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// MPEG audio (e.g. MP3)
+    Mpeg,
+    /// AAC in ADTS frames
+    Adts,
+    Ogg,
+    /// Unknown framing, clips are returned as-is
+    None,
+}
+
+impl Framing {
+    fn of(content_type: &str) -> Self {
+        let content_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase();
+
+        match content_type.as_str() {
+            "audio/mpeg" | "audio/mp3" | "audio/mpa" => Self::Mpeg,
+            "audio/aac" | "audio/aacp" | "audio/x-aac" => Self::Adts,
+            "audio/ogg" | "application/ogg" | "video/ogg" | "audio/opus" => Self::Ogg,
+            _ => Self::None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Framing::Mpeg => "mp3",
+            Framing::Adts => "aac",
+            Framing::Ogg => "ogg",
+            Framing::None => "bin",
+        }
+    }
+}
+
+/// A clip of the most recent data of a mount
+#[derive(Debug, Clone)]
+pub struct Clip {
+    pub content_type: String,
+    /// The file extension that matches the content type of the clip
+    pub extension: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// The most recent data sent by the source of a mount
+#[derive(Debug)]
+pub struct SnapshotBuffer {
+    retention: Duration,
+    content_type: String,
+    chunks: VecDeque<(Instant, Chunk)>,
+    /// The first bytes sent by the source, which contain the headers
+    /// of Ogg streams
+    stream_start: Vec<u8>,
+}
+
+impl SnapshotBuffer {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            content_type: String::new(),
+            chunks: VecDeque::new(),
+            stream_start: Vec::new(),
+        }
+    }
+
+    /// The maximum length of a clip
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Forget all data, because a new source with `content_type`
+    /// has connected
+    fn reset(&mut self, content_type: String) {
+        self.content_type = content_type;
+        self.chunks.clear();
+        self.stream_start.clear();
+    }
+
+    fn push(&mut self, chunk: Chunk) {
+        let now = Instant::now();
+
+        if self.stream_start.len() < OGG_HEADER_CAPTURE_BYTES
+            && Framing::of(&self.content_type) == Framing::Ogg
+        {
+            let missing = OGG_HEADER_CAPTURE_BYTES - self.stream_start.len();
+            self.stream_start
+                .extend_from_slice(&chunk[..missing.min(chunk.len())]);
+        }
+
+        self.chunks.push_back((now, chunk));
+        while let Some((received, _)) = self.chunks.front() {
+            if now.duration_since(*received) > self.retention {
+                self.chunks.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Cut a clip of (at most) the last `length` of data, or `None` if
+    /// there is no data.
+    pub fn clip(&self, length: Duration) -> Option<Clip> {
+        let now = Instant::now();
+        let data: Vec<u8> = self
+            .chunks
+            .iter()
+            .filter(|(received, _)| now.duration_since(*received) <= length)
+            .flat_map(|(_, chunk)| chunk.iter().copied())
+            .collect();
+
+        let framing = Framing::of(&self.content_type);
+        let data = match framing {
+            Framing::Mpeg => frame_aligned(&data, mpeg_frame_len),
+            Framing::Adts => frame_aligned(&data, adts_frame_len),
+            Framing::Ogg => ogg_clip(&self.stream_start, &data),
+            Framing::None => data,
+        };
+
+        if data.is_empty() {
+            None
+        } else {
+            Some(Clip {
+                content_type: self.content_type.clone(),
+                extension: framing.extension(),
+                data,
+            })
+        }
+    }
+}
+
+/// Keep the data of the current source of `mount` in its snapshot
+/// buffer, if it has one. Stops once the source that sends to `data_tx`
+/// disconnects, but the data is kept until the next source connects.
+pub fn spawn(mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender) {
+    if let Some(buffer) = mount.snapshot() {
+        buffer.lock().unwrap().reset(mount.content_type());
+        tokio::spawn(run(
+            mount_name.to_string(),
+            mount.clone(),
+            data_tx.subscribe(),
+        ));
+    }
+}
+
+async fn run(mount_name: String, mount: Arc<Mount>, mut data_rx: DataReceiver) {
+    let buffer: &Mutex<SnapshotBuffer> = match mount.snapshot() {
+        Some(buffer) => buffer,
+        None => return,
+    };
+
+    info!("Keeping snapshots of mount {}", mount_name);
+
+    loop {
+        match data_rx.recv().await {
+            Ok(chunk) => buffer.lock().unwrap().push(chunk),
+            Err(RecvError::Lagged(missed)) => {
+                debug!(
+                    "Snapshot buffer of mount {} skipped {} chunks",
+                    mount_name, missed
+                );
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+
+    debug!("Stopped keeping snapshots of mount {}", mount_name);
+}
+
+/// Cut `data` to the frames that it contains completely, where
+/// `frame_len` returns the length of the frame whose header starts
+/// at the given data.
+///
+/// The first frame is the first one that is followed by another frame
+/// (or by the end of the data), to avoid mistaking the data of a frame
+/// for a header.
+fn frame_aligned(data: &[u8], frame_len: fn(&[u8]) -> Option<usize>) -> Vec<u8> {
+    let start = (0..data.len()).find(|&offset| match frame_len(&data[offset..]) {
+        Some(len) => {
+            let next = offset + len;
+            next == data.len() || (next < data.len() && frame_len(&data[next..]).is_some())
+        }
+        None => false,
+    });
+
+    let start = match start {
+        Some(start) => start,
+        None => return Vec::new(),
+    };
+
+    let mut end = start;
+    while let Some(len) = frame_len(&data[end..]) {
+        if end + len > data.len() {
+            break;
+        }
+        end += len;
+    }
+
+    data[start..end].to_vec()
+}
+
+/// The length of the MPEG audio frame whose header starts `data`
+fn mpeg_frame_len(data: &[u8]) -> Option<usize> {
+    const BITRATES_V1: [[u32; 15]; 3] = [
+        [
+            0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+        ],
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+        ],
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+    ];
+    const BITRATES_V2: [[u32; 15]; 3] = [
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ];
+    const SAMPLE_RATES: [u32; 3] = [44100, 48000, 32000];
+
+    let header = data.get(..4)?;
+    if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+        return None;
+    }
+
+    // 0: MPEG 2.5, 2: MPEG 2, 3: MPEG 1
+    let version = (header[1] >> 3) & 0b11;
+    // 1: Layer III, 2: Layer II, 3: Layer I
+    let layer = (header[1] >> 1) & 0b11;
+    let bitrate_idx = (header[2] >> 4) as usize;
+    let sample_rate_idx = ((header[2] >> 2) & 0b11) as usize;
+    let padding = ((header[2] >> 1) & 1) as u32;
+
+    if version == 1 || layer == 0 || bitrate_idx == 0 || bitrate_idx == 15 || sample_rate_idx == 3 {
+        return None;
+    }
+
+    let layer_idx = (3 - layer) as usize;
+    let (bitrate, sample_rate) = match version {
+        3 => (
+            BITRATES_V1[layer_idx][bitrate_idx],
+            SAMPLE_RATES[sample_rate_idx],
+        ),
+        2 => (
+            BITRATES_V2[layer_idx][bitrate_idx],
+            SAMPLE_RATES[sample_rate_idx] / 2,
+        ),
+        _ => (
+            BITRATES_V2[layer_idx][bitrate_idx],
+            SAMPLE_RATES[sample_rate_idx] / 4,
+        ),
+    };
+    let bitrate = bitrate * 1000;
+
+    let len = match (layer, version) {
+        (3, _) => (12 * bitrate / sample_rate + padding) * 4,
+        (1, 0 | 2) => 72 * bitrate / sample_rate + padding,
+        _ => 144 * bitrate / sample_rate + padding,
+    };
+
+    Some(len as usize)
+}
+
+/// The length of the ADTS frame whose header starts `data`
+fn adts_frame_len(data: &[u8]) -> Option<usize> {
+    let header = data.get(..7)?;
+    // Sync word, and a layer that is always 0
+    if header[0] != 0xFF || header[1] & 0xF6 != 0xF0 {
+        return None;
+    }
+
+    let len = ((header[3] as usize & 0b11) << 11)
+        | ((header[4] as usize) << 3)
+        | ((header[5] as usize) >> 5);
+
+    if len < 7 {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// A page of an Ogg stream
+struct OggPage<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> OggPage<'a> {
+    /// Parse the page that starts `data`, if it is complete
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.get(..5)? != b"OggS\0" {
+            return None;
+        }
+
+        let segments = *data.get(26)? as usize;
+        let segment_table = data.get(27..27 + segments)?;
+        let len = 27 + segments + segment_table.iter().map(|&s| s as usize).sum::<usize>();
+
+        data.get(..len).map(|data| Self { data })
+    }
+
+    /// Whether this page starts a logical stream
+    fn is_bos(&self) -> bool {
+        self.data[5] & 0x02 != 0
+    }
+
+    fn granule_position(&self) -> u64 {
+        u64::from_le_bytes(self.data[6..14].try_into().unwrap())
+    }
+}
+
+/// Iterate over the complete pages in `data`, starting at the first one
+fn ogg_pages(data: &[u8]) -> impl Iterator<Item = OggPage<'_>> {
+    let mut offset = (0..data.len())
+        .find(|&offset| OggPage::parse(&data[offset..]).is_some())
+        .unwrap_or(data.len());
+
+    std::iter::from_fn(move || {
+        let page = OggPage::parse(&data[offset..])?;
+        offset += page.data.len();
+        Some(page)
+    })
+}
+
+/// Cut `data` to the Ogg pages that it contains completely.
+///
+/// Ogg streams can only be decoded after their header pages, so these
+/// are taken from `stream_start` and put in front of the clip, unless
+/// the clip starts a new logical stream itself.
+fn ogg_clip(stream_start: &[u8], data: &[u8]) -> Vec<u8> {
+    let pages: Vec<OggPage> = ogg_pages(data).collect();
+    if pages.is_empty() {
+        return Vec::new();
+    }
+
+    let mut clip = Vec::new();
+    let pages = if let Some(bos) = pages.iter().position(|p| p.is_bos()) {
+        &pages[bos..]
+    } else {
+        ogg_pages(stream_start)
+            .take_while(|p| p.granule_position() == 0)
+            .for_each(|p| clip.extend_from_slice(p.data));
+        &pages[..]
+    };
+
+    pages.iter().for_each(|p| clip.extend_from_slice(p.data));
+    clip
+}
@@ -1,12 +1,51 @@
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Display,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, SystemTime},
+};
 
 use bytesize::ByteSize;
+use dashmap::{mapref::entry::Entry, DashMap};
 use httparse::Header;
 use serde::{Deserialize, Serialize};
 use serde_with::skip_serializing_none;
 use tokio::sync::{
-    mpsc::{UnboundedReceiver, UnboundedSender},
-    watch::{Receiver as WatchReceiver, Sender as WatchSender},
+    broadcast::{Receiver as BroadcastReceiver, Sender as BroadcastSender, WeakSender},
+    Notify,
+};
+
+use crate::{
+    access::{Bans, UserAgentFilter},
+    auth::{self, AuthMechanism},
+    bandwidth::BandwidthEstimates,
+    config::MountConfig,
+    egress::EgressLimiter,
+    events::{EventKind, Events},
+    failures::FailureLog,
+    features,
+    fingerprint::{Fingerprint, FingerprintConfig},
+    geoip::GeoIp,
+    history::StatsHistory,
+    history_db::HistoryDb,
+    htpasswd::Htpasswd,
+    jwt::{JwtVerifier, Scope},
+    net::find_header,
+    playlist_log::PlaylistLog,
+    pool::{BufferPool, PooledBuffer, DEFAULT_CHUNK_SIZE},
+    quirks::Quirks,
+    ratelimit::RateLimiter,
+    relay::{RelayConfig, RelayStatus},
+    sessions::Sessions,
+    snapshot::SnapshotBuffer,
+    taps::TapConfig,
+    templates::Templates,
+    transcription::TranscriptionConfig,
+    url_auth::UrlAuth,
 };
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -14,6 +53,21 @@ pub struct Stats {
     pub sub_count: usize,
     pub bytes_in: usize,
     pub bytes_out: usize,
+    /// The amount of times a source connected to the mount
+    #[serde(default)]
+    pub source_connects: usize,
+    /// The largest amount of subscribers that were connected at once
+    #[serde(default)]
+    pub peak_listeners: usize,
+    /// The amount of times a subscriber connected to the mount
+    #[serde(default)]
+    pub listener_connections: usize,
+    /// How long the current source has been connected, in seconds
+    #[serde(default)]
+    pub source_connected_secs: u64,
+    /// How long the server has been running, in seconds
+    #[serde(default)]
+    pub uptime_secs: u64,
 }
 
 impl Default for Stats {
@@ -28,6 +82,11 @@ impl Stats {
             bytes_in: 0,
             bytes_out: 0,
             sub_count: 0,
+            source_connects: 0,
+            peak_listeners: 0,
+            listener_connections: 0,
+            source_connected_secs: 0,
+            uptime_secs: 0,
         }
     }
 }
@@ -35,19 +94,195 @@ impl Stats {
 impl Display for Stats {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_str(&format!(
-            "sub count: {}, bytes in: {}, bytes out: {}",
+            "sub count: {} (peak {}), bytes in: {}, bytes out: {}",
             self.sub_count,
+            self.peak_listeners,
             ByteSize(self.bytes_in as u64),
             ByteSize(self.bytes_out as u64)
         ))
     }
 }
 
-pub type StatReceiver = WatchReceiver<Stats>;
-pub type StatSender = WatchSender<Stats>;
+/// The live statistics of a mount.
+///
+/// The counters are shared between the mount and the tasks serving its
+/// source and subscribers, which update them directly.
+#[derive(Debug, Default)]
+pub struct MountStats {
+    subscribers: Arc<ConnectionCounter>,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    source_connects: AtomicUsize,
+}
+
+impl MountStats {
+    /// The counters of the mount. The durations are left at zero, as
+    /// they are tracked by the [`Mount`] itself.
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            sub_count: self.subscribers.count(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            source_connects: self.source_connects.load(Ordering::Relaxed),
+            peak_listeners: self.subscribers.peak(),
+            listener_connections: self.subscribers.total(),
+            ..Stats::new()
+        }
+    }
+
+    pub fn add_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_source_connect(&self) {
+        self.source_connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The subscribers connected to the mount
+    pub fn subscribers(&self) -> &Arc<ConnectionCounter> {
+        &self.subscribers
+    }
+}
+
+/// A counter of active connections, which may be limited
+#[derive(Debug, Default)]
+pub struct ConnectionCounter {
+    count: AtomicUsize,
+    /// The largest amount of connections that was counted at once
+    peak: AtomicUsize,
+    /// The amount of connections that were ever counted
+    total: AtomicUsize,
+}
+
+impl ConnectionCounter {
+    /// Count a new connection, unless that would exceed `max`.
+    ///
+    /// The connection stops being counted once the returned
+    /// [`ConnectionSlot`] is dropped.
+    pub fn try_acquire(self: &Arc<Self>, max: Option<usize>) -> Option<ConnectionSlot> {
+        let max = max.unwrap_or(usize::MAX);
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|count| {
+                self.peak.fetch_max(count + 1, Ordering::AcqRel);
+                self.total.fetch_add(1, Ordering::Relaxed);
+                ConnectionSlot(self.clone())
+            })
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// The largest amount of connections that was counted at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Acquire)
+    }
+
+    /// The amount of connections that were ever counted
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+/// A connection counted by a [`ConnectionCounter`]
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<ConnectionCounter>);
 
-pub type SubSender = UnboundedSender<UnboundedSender<Vec<u8>>>;
-pub type SubReceiver = UnboundedReceiver<UnboundedSender<Vec<u8>>>;
+impl ConnectionSlot {
+    /// The counter that counts this connection
+    pub fn counter(&self) -> &Arc<ConnectionCounter> {
+        &self.0
+    }
+
+    /// Count this connection with `counter` instead, regardless of the
+    /// limit of `counter`
+    pub fn transfer(&mut self, counter: &Arc<ConnectionCounter>) {
+        let count = counter.count.fetch_add(1, Ordering::AcqRel);
+        counter.peak.fetch_max(count + 1, Ordering::AcqRel);
+        counter.total.fetch_add(1, Ordering::Relaxed);
+        let previous = std::mem::replace(&mut self.0, counter.clone());
+        previous.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.count.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Counts active connections per remote IP address
+#[derive(Debug, Default)]
+pub struct IpConnections {
+    counts: DashMap<IpAddr, usize>,
+}
+
+impl IpConnections {
+    /// The amount of connections from all addresses together
+    pub fn total(&self) -> usize {
+        self.counts.iter().map(|count| *count.value()).sum()
+    }
+
+    /// Count a new connection from `ip`, unless that would exceed `max`.
+    ///
+    /// The connection stops being counted once the returned [`IpSlot`]
+    /// is dropped.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr, max: Option<usize>) -> Option<IpSlot> {
+        let mut count = self.counts.entry(ip).or_insert(0);
+        if max.map(|max| *count >= max).unwrap_or(false) {
+            let unused = *count == 0;
+            drop(count);
+            if unused {
+                self.counts.remove_if(&ip, |_, count| *count == 0);
+            }
+            return None;
+        }
+
+        *count += 1;
+        Some(IpSlot {
+            connections: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// A connection counted by [`IpConnections`]
+#[derive(Debug)]
+pub struct IpSlot {
+    connections: Arc<IpConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        if let Entry::Occupied(mut e) = self.connections.counts.entry(self.ip) {
+            *e.get_mut() -= 1;
+            if *e.get() == 0 {
+                e.remove();
+            }
+        }
+    }
+}
+
+/// A chunk of stream data, shared between all subscribers of a mount
+pub type Chunk = Arc<PooledBuffer>;
+
+/// The amount of metadata events that may be queued for a subscriber
+const METADATA_EVENT_QUEUE: usize = 16;
+
+/// The amount of songs that are remembered per mount by default
+pub const DEFAULT_SONG_HISTORY: usize = 10;
+
+pub type DataSender = BroadcastSender<Chunk>;
+pub type DataReceiver = BroadcastReceiver<Chunk>;
 
 #[skip_serializing_none]
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
@@ -66,21 +301,15 @@ pub struct IceMeta {
 
 impl<'a> From<&'a [Header<'a>]> for IceMeta {
     fn from(v: &'a [Header]) -> Self {
-        use std::str::FromStr;
-
         let mut me = IceMeta::default();
         macro_rules! extract_val {
             ($field: ident, $name: literal) => {
                 extract_val!($field, String, $name);
             };
             ($field: ident, $ty: ty, $name: literal) => {
-                if let Some(value) = v.iter().find(|h| h.name == $name).map(|v| v.value) {
-                    if let Ok(string) = std::str::from_utf8(value) {
-                        if string != "" {
-                            if let Ok(value) = <$ty>::from_str(string) {
-                                me.$field = Some(value.clone())
-                            }
-                        }
+                if let Some(string) = find_header(v, $name) {
+                    if !string.is_empty() {
+                        me.$field = string.parse::<$ty>().ok();
                     }
                 }
             };
@@ -101,6 +330,58 @@ impl<'a> From<&'a [Header<'a>]> for IceMeta {
 }
 
 impl IceMeta {
+    /// Whether the source asked for the stream to be listed in YP
+    /// directories
+    pub fn public(&self) -> bool {
+        self.public == Some(1)
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn genre(&self) -> Option<&str> {
+        self.genre.as_deref()
+    }
+
+    pub fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    pub fn audio_info(&self) -> Option<&str> {
+        self.audio_info.as_deref()
+    }
+
+    /// The value of parameter `name` (with or without an `ice-` prefix)
+    /// in `ice-audio-info`
+    fn audio_info_param(&self, name: &str) -> Option<&str> {
+        self.audio_info.as_ref()?.split(';').find_map(|param| {
+            let (param_name, value) = param.split_once('=')?;
+            let param_name = param_name.trim();
+            let param_name = param_name.strip_prefix("ice-").unwrap_or(param_name);
+            param_name.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    /// The bitrate in kbit/s that the source reported in `ice-audio-info`
+    pub fn bitrate(&self) -> Option<u32> {
+        self.audio_info_param("bitrate")?.parse().ok()
+    }
+
+    /// The amount of channels that the source reported in `ice-audio-info`
+    pub fn channels(&self) -> Option<u32> {
+        self.audio_info_param("channels")?.parse().ok()
+    }
+
+    /// The sample rate that the source reported in `ice-audio-info`
+    pub fn samplerate(&self) -> Option<u32> {
+        self.audio_info_param("samplerate")?.parse().ok()
+    }
+
     pub fn as_headers(&self) -> Vec<String> {
         let mut vec = Vec::new();
 
@@ -126,9 +407,29 @@ impl IceMeta {
     }
 }
 
+/// A song that was played on a mount
+#[derive(Debug, Clone, Serialize)]
+pub struct PlayedSong {
+    pub song: String,
+    pub url: Option<String>,
+    /// The time at which the song started, in RFC 3339 format
+    pub started_at: String,
+}
+
+/// The metadata of a mount, published whenever it changes
+#[derive(Debug, Clone, Serialize)]
+pub struct MetadataEvent {
+    pub content_type: String,
+    pub song: Option<String>,
+    /// A URL about the current song, e.g. of the album art
+    pub song_url: Option<String>,
+    #[serde(flatten)]
+    pub meta: IceMeta,
+}
+
 /// The URL prefix that should be used to construct the
 /// final stream's URL
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 #[serde(tag = "url_type", content = "url_value")]
 pub enum StreamUrl {
     /// Use the `Host` header sent by the client. If this header is absent,
@@ -140,6 +441,7 @@ pub enum StreamUrl {
     /// absent, the value of the `Host` header is used as fallback. If the `Host`
     /// header is absent as well, the local address of the socket that received the client's
     /// request is used as fallback
+    #[default]
     #[serde(rename = "x-forwarded-hostname")]
     XForwardedHostName,
     /// This static string is used as stream URL
@@ -147,160 +449,903 @@ pub enum StreamUrl {
     Static(String),
 }
 
-impl Default for StreamUrl {
-    fn default() -> Self {
-        Self::XForwardedHostName
+/// The part of a mount that is replaced whenever a new source connects
+#[derive(Debug, Clone)]
+struct MountSource {
+    content_type: String,
+    data_sender: WeakSender<Chunk>,
+    meta: IceMeta,
+    /// Notified when the source should be disconnected
+    kill: Arc<Notify>,
+    /// The time at which the source connected
+    connected_at: Option<SystemTime>,
+    /// The user name with which the source authenticated, if any
+    user: Option<String>,
+}
+
+impl MountSource {
+    fn is_connected(&self) -> bool {
+        self.data_sender.strong_count() > 0
     }
 }
 
-#[derive(Debug, Clone)]
+/// The settings of a mount that can be changed while it is live, without
+/// disconnecting its source or listeners
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MountAccess {
+    pub source_auth: Option<String>,
+    pub sub_auth: Option<String>,
+    pub max_listeners: Option<usize>,
+    /// Whether this mount is listed in YP directories. If not set, the
+    /// `ice-public` header sent by the source decides.
+    pub public: Option<bool>,
+}
+
+/// A change to the [`MountAccess`] of a mount. Fields that are absent are
+/// left unchanged, and fields that are `null` are cleared.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct MountAccessUpdate {
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub source_auth: Option<Option<String>>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub sub_auth: Option<Option<String>>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub max_listeners: Option<Option<usize>>,
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    pub public: Option<Option<bool>>,
+}
+
+/// Whether `authorization` matches the configured credential `expected`
+/// (see [`auth::verify`]), or holds credentials that are in `htpasswd`
+pub fn is_authorization(
+    authorization: Option<&str>,
+    expected: Option<&str>,
+    htpasswd: Option<&Htpasswd>,
+) -> bool {
+    let authorization = match authorization {
+        Some(authorization) => authorization,
+        None => return false,
+    };
+    expected
+        .map(|expected| auth::verify(expected, authorization))
+        .unwrap_or(false)
+        || htpasswd
+            .map(|htpasswd| htpasswd.verify(authorization))
+            .unwrap_or(false)
+}
+
+/// A mount point.
+///
+/// Mounts are shared between connections through an `Arc`, so all
+/// mutable state lives behind locks that are local to the mount.
+#[derive(Debug)]
 pub struct Mount {
-    content_type: String,
-    sub_sender: SubSender,
-    stat_receiver: StatReceiver,
-    permanent: bool,
-    source_auth: Option<String>,
-    sub_auth: Option<String>,
-    song: Option<String>,
-    meta: IceMeta,
-    stream_url: Option<StreamUrl>,
+    source: RwLock<MountSource>,
+    stats: Arc<MountStats>,
+    song: RwLock<Option<String>>,
+    song_url: RwLock<Option<String>>,
+    /// The last played songs, the most recent one last
+    song_history: Mutex<VecDeque<PlayedSong>>,
+    metadata_events: BroadcastSender<MetadataEvent>,
+    /// The integrated loudness of the current source, in LUFS
+    loudness: RwLock<Option<f64>>,
+    /// The most recent fingerprint of the current source
+    fingerprint: RwLock<Option<Fingerprint>>,
+    /// The most recent data of this mount, if snapshots are enabled
+    snapshot: Option<Mutex<SnapshotBuffer>>,
+    access: RwLock<MountAccess>,
+    /// The time at which the server that this mount belongs to started
+    server_started: SystemTime,
+    /// The log to which song changes are written, along with the name of
+    /// this mount
+    playlist_log: Option<(String, Arc<PlaylistLog>)>,
+    /// The endpoint that authenticates listeners, if any
+    url_auth: Option<UrlAuth>,
+    user_agents: Option<UserAgentFilter>,
+    source_htpasswd: Option<Htpasswd>,
+    sub_htpasswd: Option<Htpasswd>,
+    /// The publisher of the events of the server, along with the name of
+    /// this mount
+    events: Option<(String, Events)>,
+    /// Whether this mount is no longer configured, and only stays until
+    /// its source and listeners disconnect
+    retired: AtomicBool,
+    config: MountConfig,
 }
 
 impl Mount {
     pub fn new(
         content_type: String,
-        sub_sender: SubSender,
-        stat_receiver: StatReceiver,
-        source_auth: Option<String>,
-        sub_auth: Option<String>,
-        permanent: bool,
+        data_sender: WeakSender<Chunk>,
         meta: IceMeta,
-        stream_url: Option<StreamUrl>,
+        config: MountConfig,
     ) -> Self {
+        let stats = Arc::new(MountStats::default());
+
         Self {
-            content_type,
-            sub_sender,
-            stat_receiver,
-            source_auth,
-            sub_auth,
-            permanent,
-            meta,
-            song: None,
-            stream_url,
+            source: RwLock::new(MountSource {
+                content_type,
+                connected_at: (data_sender.strong_count() > 0).then(SystemTime::now),
+                user: None,
+                data_sender,
+                meta,
+                kill: Arc::default(),
+            }),
+            stats,
+            song: RwLock::new(None),
+            song_url: RwLock::new(None),
+            song_history: Mutex::default(),
+            metadata_events: BroadcastSender::new(METADATA_EVENT_QUEUE),
+            loudness: RwLock::new(None),
+            fingerprint: RwLock::new(None),
+            snapshot: config
+                .snapshot_secs
+                .map(|secs| Mutex::new(SnapshotBuffer::new(Duration::from_secs(secs)))),
+            access: RwLock::new(MountAccess {
+                source_auth: config.source_auth.clone(),
+                sub_auth: config.sub_auth.clone(),
+                max_listeners: config.max_listeners,
+                public: config.public,
+            }),
+            server_started: SystemTime::now(),
+            playlist_log: None,
+            url_auth: config.auth_url.clone().map(UrlAuth::new),
+            user_agents: config.user_agents.as_ref().map(UserAgentFilter::new),
+            source_htpasswd: config.source_htpasswd.as_deref().map(Htpasswd::open),
+            sub_htpasswd: config.sub_htpasswd.as_deref().map(Htpasswd::open),
+            events: None,
+            retired: AtomicBool::new(false),
+            config,
+        }
+    }
+
+    pub fn source_auth(&self) -> Option<String> {
+        self.access.read().unwrap().source_auth.clone()
+    }
+
+    pub fn sub_auth(&self) -> Option<String> {
+        self.access.read().unwrap().sub_auth.clone()
+    }
+
+    /// Whether sources must authenticate to send to this mount
+    pub fn requires_source_auth(&self) -> bool {
+        self.source_auth().is_some()
+            || self.source_htpasswd.is_some()
+            || !self.config.source_users.is_empty()
+    }
+
+    /// Whether `authorization` holds the credentials of a source of this
+    /// mount
+    pub fn is_source_authorization(&self, authorization: Option<&str>) -> bool {
+        is_authorization(
+            authorization,
+            self.source_auth().as_deref(),
+            self.source_htpasswd.as_ref(),
+        ) || authorization
+            .map(|authorization| auth::verify_users(&self.config.source_users, authorization))
+            .unwrap_or(false)
+    }
+
+    /// Whether subscribers must authenticate to subscribe to this mount
+    pub fn requires_sub_auth(&self) -> bool {
+        self.sub_auth().is_some()
+            || self.sub_htpasswd.is_some()
+            || !self.config.sub_users.is_empty()
+    }
+
+    /// Whether `authorization` holds the credentials of a subscriber of
+    /// this mount
+    pub fn is_sub_authorization(&self, authorization: Option<&str>) -> bool {
+        is_authorization(
+            authorization,
+            self.sub_auth().as_deref(),
+            self.sub_htpasswd.as_ref(),
+        ) || authorization
+            .map(|authorization| auth::verify_users(&self.config.sub_users, authorization))
+            .unwrap_or(false)
+    }
+
+    /// The maximum amount of subscribers of this mount
+    pub fn max_listeners(&self) -> Option<usize> {
+        self.access.read().unwrap().max_listeners
+    }
+
+    /// The settings of this mount that can be changed while it is live
+    pub fn access(&self) -> MountAccess {
+        self.access.read().unwrap().clone()
+    }
+
+    /// Apply `update` to the settings of this mount. Connected sources
+    /// and listeners are not affected, even if they would no longer be
+    /// allowed to connect.
+    pub fn update_access(&self, update: MountAccessUpdate) -> MountAccess {
+        let mut access = self.access.write().unwrap();
+        if let Some(source_auth) = update.source_auth {
+            access.source_auth = source_auth;
+        }
+        if let Some(sub_auth) = update.sub_auth {
+            access.sub_auth = sub_auth;
+        }
+        if let Some(max_listeners) = update.max_listeners {
+            access.max_listeners = max_listeners;
         }
+        if let Some(public) = update.public {
+            access.public = public;
+        }
+        access.clone()
+    }
+
+    /// Stop (or resume) accepting new sources and listeners. The ones
+    /// that are connected stay connected.
+    pub fn set_retired(&self, retired: bool) {
+        self.retired.store(retired, Ordering::Relaxed);
+    }
+
+    /// Whether this mount no longer accepts new sources and listeners
+    pub fn is_retired(&self) -> bool {
+        self.retired.load(Ordering::Relaxed)
+    }
+
+    /// Whether no source and no listeners are connected to this mount
+    pub fn is_idle(&self) -> bool {
+        !self.is_connected() && self.stats.subscribers().count() == 0
+    }
+
+    /// The secret with which the URLs of listeners must be signed, if any
+    pub fn url_signing_secret(&self) -> Option<&str> {
+        self.config.url_signing_secret.as_deref()
+    }
+
+    /// Whether a listener in `country` may subscribe to this mount
+    pub fn allows_country(&self, country: Option<&str>) -> bool {
+        self.config
+            .countries
+            .as_ref()
+            .map(|countries| countries.allows(country))
+            .unwrap_or(true)
     }
 
-    pub fn source_auth(&self) -> &Option<String> {
-        &self.source_auth
+    /// Whether a listener with `user_agent` may subscribe to this mount
+    pub fn allows_user_agent(&self, user_agent: Option<&str>) -> bool {
+        self.user_agents
+            .as_ref()
+            .map(|filter| filter.allows(user_agent))
+            .unwrap_or(true)
     }
 
-    pub fn sub_auth(&self) -> &Option<String> {
-        &self.sub_auth
+    /// Whether a listener with `referer` may subscribe to this mount
+    pub fn allows_referer(&self, referer: Option<&str>) -> bool {
+        self.config
+            .referers
+            .as_ref()
+            .map(|referers| referers.allows(referer))
+            .unwrap_or(true)
     }
 
-    pub fn sub_sender(&self) -> &SubSender {
-        &self.sub_sender
+    /// Whether a client at `ip` may connect to this mount
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        self.config
+            .ip_access
+            .as_ref()
+            .map(|access| access.allows(ip))
+            .unwrap_or(true)
     }
 
-    pub fn content_type(&self) -> &str {
-        &self.content_type
+    /// The endpoint that decides whether listeners may subscribe, if any
+    pub fn url_auth(&self) -> Option<&UrlAuth> {
+        self.url_auth.as_ref()
+    }
+
+    /// The weakest authentication mechanism that subscribers may use
+    pub fn min_sub_auth(&self) -> Option<AuthMechanism> {
+        self.config.min_sub_auth
+    }
+
+    /// Whether subscribers must connect over TLS
+    pub fn require_tls(&self) -> bool {
+        self.config.require_tls
+    }
+
+    /// Whether data sent to subscribers of this mount may be compressed
+    pub fn compress(&self) -> bool {
+        self.config.compress && {
+            let content_type = self.content_type();
+            !(content_type.starts_with("audio/") || content_type.starts_with("video/"))
+        }
+    }
+
+    /// Subscribe to the data sent by the source of this mount, if
+    /// one is connected
+    pub fn subscribe(&self) -> Option<DataReceiver> {
+        let source = self.source.read().unwrap();
+        source.data_sender.upgrade().map(|s| s.subscribe())
+    }
+
+    pub fn content_type(&self) -> String {
+        self.source.read().unwrap().content_type.clone()
     }
 
     pub fn stats(&self) -> Stats {
-        self.stat_receiver.borrow().clone()
+        let elapsed = |time: SystemTime| time.elapsed().map(|d| d.as_secs()).unwrap_or(0);
+        Stats {
+            source_connected_secs: self.source_connected_at().map(elapsed).unwrap_or(0),
+            uptime_secs: elapsed(self.server_started),
+            ..self.stats.snapshot()
+        }
+    }
+
+    /// The live statistics of this mount, which outlive any single source
+    pub fn stats_handle(&self) -> &Arc<MountStats> {
+        &self.stats
     }
 
-    pub fn set_source(
-        &mut self,
-        sub_sender: SubSender,
-        stat_receiver: StatReceiver,
+    /// Replace the source of this mount.
+    ///
+    /// Returns `false` and leaves the mount untouched if it
+    /// already has a connected source.
+    pub fn try_set_source(
+        &self,
+        data_sender: WeakSender<Chunk>,
         content_type: String,
         meta: IceMeta,
-    ) {
-        self.sub_sender = sub_sender;
-        self.stat_receiver = stat_receiver;
-        self.content_type = content_type;
-        self.meta = meta;
+    ) -> bool {
+        let mut source = self.source.write().unwrap();
+        if source.is_connected() {
+            return false;
+        }
+
+        *source = MountSource {
+            content_type,
+            data_sender,
+            meta,
+            kill: Arc::default(),
+            connected_at: Some(SystemTime::now()),
+            user: None,
+        };
+        drop(source);
+
+        self.stats.add_source_connect();
+
+        self.notify_metadata();
+        true
     }
 
     pub fn is_connected(&self) -> bool {
-        !self.sub_sender.is_closed()
+        self.source.read().unwrap().is_connected()
+    }
+
+    /// Whether this mount should be listed in YP directories
+    pub fn is_public(&self) -> bool {
+        !self.is_hidden()
+            && self
+                .access
+                .read()
+                .unwrap()
+                .public
+                .unwrap_or_else(|| self.metadata().public())
+    }
+
+    /// Whether this mount is left out of mount listings and status pages
+    pub fn is_hidden(&self) -> bool {
+        self.config.hidden
+    }
+
+    /// The time at which the current source connected
+    pub fn source_connected_at(&self) -> Option<SystemTime> {
+        let source = self.source.read().unwrap();
+        source.connected_at.filter(|_| source.is_connected())
+    }
+
+    /// Record the user name with which the current source authenticated
+    pub fn set_source_user(&self, user: Option<String>) {
+        self.source.write().unwrap().user = user;
+    }
+
+    /// The user name with which the current source authenticated, if any
+    pub fn source_user(&self) -> Option<String> {
+        let source = self.source.read().unwrap();
+        source.user.clone().filter(|_| source.is_connected())
+    }
+
+    /// Notified once the current source should be disconnected
+    pub fn source_kill_signal(&self) -> Arc<Notify> {
+        self.source.read().unwrap().kill.clone()
+    }
+
+    /// Disconnect the current source, so that a new source can connect.
+    /// Returns `false` if no source is connected.
+    pub fn kill_source(&self) -> bool {
+        let source = self.source.read().unwrap();
+        if source.is_connected() {
+            source.kill.notify_one();
+            true
+        } else {
+            false
+        }
     }
 
     pub fn metadata(&self) -> IceMeta {
-        self.meta.clone()
+        self.source.read().unwrap().meta.clone()
+    }
+
+    pub fn set_song(&self, song: String) {
+        self.set_song_with_url(song, None);
+    }
+
+    /// Set the current song, along with a URL about it
+    pub fn set_song_with_url(&self, song: String, url: Option<String>) {
+        // Sources tend to repeat the metadata of the current song
+        let changed = self.song.read().unwrap().as_ref() != Some(&song);
+
+        let capacity = self.config.song_history.unwrap_or(DEFAULT_SONG_HISTORY);
+        if changed && capacity > 0 {
+            let mut history = self.song_history.lock().unwrap();
+            if history.len() >= capacity {
+                history.pop_front();
+            }
+            history.push_back(PlayedSong {
+                song: song.clone(),
+                url: url.clone(),
+                started_at: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            });
+        }
+
+        if let (true, Some((name, log))) = (changed, &self.playlist_log) {
+            log.write(name, self.stats.subscribers().count(), &song);
+        }
+        if let (true, Some((name, events))) = (changed, &self.events) {
+            events.emit(EventKind::MetadataChanged {
+                mount: name.clone(),
+                song: Some(song.clone()),
+                url: url.clone(),
+            });
+        }
+
+        *self.song.write().unwrap() = Some(song);
+        *self.song_url.write().unwrap() = url;
+        self.notify_metadata();
+    }
+
+    /// The last played songs, the most recent one first
+    pub fn song_history(&self) -> Vec<PlayedSong> {
+        self.song_history
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    /// The current metadata of this mount
+    pub fn metadata_event(&self) -> MetadataEvent {
+        let source = self.source.read().unwrap();
+        MetadataEvent {
+            content_type: source.content_type.clone(),
+            song: self.song(),
+            song_url: self.song_url(),
+            meta: source.meta.clone(),
+        }
+    }
+
+    /// Subscribe to changes of the metadata of this mount
+    pub fn subscribe_metadata(&self) -> BroadcastReceiver<MetadataEvent> {
+        self.metadata_events.subscribe()
+    }
+
+    fn notify_metadata(&self) {
+        // Sending only fails if nobody is interested, which is fine.
+        self.metadata_events.send(self.metadata_event()).ok();
+    }
+
+    /// The integrated loudness that this mount should not exceed, in LUFS
+    pub fn loudness_target(&self) -> Option<f64> {
+        self.config.loudness_target
+    }
+
+    /// The most recently measured integrated loudness of the current
+    /// source, in LUFS
+    pub fn loudness(&self) -> Option<f64> {
+        *self.loudness.read().unwrap()
+    }
+
+    pub fn set_loudness(&self, loudness: Option<f64>) {
+        *self.loudness.write().unwrap() = loudness;
+    }
+
+    /// Whether the measured loudness exceeds the loudness target
+    pub fn exceeds_loudness_target(&self) -> bool {
+        match (self.loudness(), self.loudness_target()) {
+            (Some(loudness), Some(target)) => loudness > target,
+            _ => false,
+        }
+    }
+
+    /// How this mount is fingerprinted, if at all
+    pub fn fingerprint_config(&self) -> Option<&FingerprintConfig> {
+        self.config.fingerprint.as_ref()
+    }
+
+    /// The most recent fingerprint of the current source
+    pub fn fingerprint(&self) -> Option<Fingerprint> {
+        self.fingerprint.read().unwrap().clone()
+    }
+
+    pub fn set_fingerprint(&self, fingerprint: Option<Fingerprint>) {
+        *self.fingerprint.write().unwrap() = fingerprint;
+    }
+
+    /// The upstreams from which this mount is relayed
+    pub fn relay(&self) -> Option<&RelayConfig> {
+        self.config.relay.as_ref()
+    }
+
+    /// The configuration that this mount was created with
+    pub fn config(&self) -> &MountConfig {
+        &self.config
+    }
+
+    /// The speech-to-text service that captions this mount
+    pub fn transcription(&self) -> Option<&TranscriptionConfig> {
+        self.config.transcription.as_ref()
+    }
+
+    /// The nominal bitrate of this mount, in kbit/s
+    pub fn bitrate(&self) -> Option<u32> {
+        self.config
+            .bitrate_kbps
+            .or_else(|| self.metadata().bitrate())
+    }
+
+    /// The mounts that carry the same stream at other bitrates
+    pub fn renditions(&self) -> &[String] {
+        &self.config.renditions
+    }
+
+    /// The features that this mount is configured to use, but that are
+    /// not compiled in
+    pub fn missing_features(&self) -> Vec<&'static str> {
+        features::missing(&self.config)
+    }
+
+    /// The headers to add to the responses to subscribers
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.config.headers
+    }
+
+    pub fn snapshot(&self) -> Option<&Mutex<SnapshotBuffer>> {
+        self.snapshot.as_ref()
+    }
+
+    /// The taps that replicate this mount to message brokers
+    pub fn taps(&self) -> &[TapConfig] {
+        &self.config.taps
+    }
+
+    /// The tags to add to the points of this mount that are written to
+    /// InfluxDB
+    pub fn influxdb_tags(&self) -> &BTreeMap<String, String> {
+        &self.config.influxdb_tags
     }
 
-    pub fn set_song(&mut self, song: String) {
-        self.song = Some(song);
+    pub fn song(&self) -> Option<String> {
+        self.song.read().unwrap().clone()
     }
 
-    pub fn song(&self) -> &Option<String> {
-        &self.song
+    /// A URL about the current song, if the source sent one
+    pub fn song_url(&self) -> Option<String> {
+        self.song_url.read().unwrap().clone()
+    }
+
+    /// The size of the chunks in which source data is read
+    pub fn chunk_size(&self) -> usize {
+        self.config
+            .chunk_size
+            .filter(|size| *size > 0)
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// The compatibility quirks to apply to all subscribers
+    pub fn quirks(&self) -> Quirks {
+        self.config.quirks.map(|p| p.quirks()).unwrap_or_default()
     }
 
     pub fn stream_url(&self) -> &Option<StreamUrl> {
-        &self.stream_url
+        &self.config.stream_url
     }
 }
 
+#[derive(Debug)]
 pub struct State {
-    mounts: HashMap<String, Mount>,
+    mounts: DashMap<String, Arc<Mount>>,
+    buffer_pool: Arc<BufferPool>,
+    history: StatsHistory,
+    history_db: Option<HistoryDb>,
+    failures: FailureLog,
+    listeners: Arc<ConnectionCounter>,
+    ip_connections: Arc<IpConnections>,
+    bans: Bans,
+    relays: DashMap<String, Arc<Mutex<RelayStatus>>>,
+    bandwidth: BandwidthEstimates,
+    sessions: Arc<Sessions>,
+    egress: Option<EgressLimiter>,
+    rate_limiter: Option<RateLimiter>,
+    playlist_log: Option<Arc<PlaylistLog>>,
+    admin_htpasswd: Option<Htpasswd>,
+    jwt: Option<JwtVerifier>,
+    geoip: Option<GeoIp>,
+    templates: Option<Templates>,
+    events: Events,
+    started: SystemTime,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
-            mounts: HashMap::default(),
+            mounts: DashMap::default(),
+            buffer_pool: BufferPool::new(),
+            history: StatsHistory::default(),
+            history_db: None,
+            failures: FailureLog::default(),
+            listeners: Arc::default(),
+            ip_connections: Arc::default(),
+            bans: Bans::default(),
+            relays: DashMap::default(),
+            bandwidth: BandwidthEstimates::default(),
+            sessions: Arc::default(),
+            egress: None,
+            rate_limiter: None,
+            playlist_log: None,
+            admin_htpasswd: None,
+            jwt: None,
+            geoip: None,
+            templates: None,
+            events: Events::default(),
+            started: SystemTime::now(),
         }
     }
 
-    pub fn add_mount(&mut self, mount_name: String, stream: Mount) -> bool {
-        if !self.mounts.contains_key(&mount_name) {
-            self.mounts.insert(mount_name, stream);
-            true
+    /// The time at which the server started
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Limit the data sent to all listeners together with `egress`
+    pub fn set_egress(&mut self, egress: Option<EgressLimiter>) {
+        self.egress = egress;
+    }
+
+    /// Limit the requests that clients make to the admin API with
+    /// `rate_limiter`
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<RateLimiter>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    /// The limiter of requests to the admin API, if any
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    /// Write the songs played on mounts that are added from now on to
+    /// `playlist_log`
+    pub fn set_playlist_log(&mut self, playlist_log: Option<PlaylistLog>) {
+        self.playlist_log = playlist_log.map(Arc::new);
+    }
+
+    /// Also accept the admin credentials in `admin_htpasswd`
+    pub fn set_admin_htpasswd(&mut self, admin_htpasswd: Option<Htpasswd>) {
+        self.admin_htpasswd = admin_htpasswd;
+    }
+
+    /// The file with additional admin credentials, if any
+    pub fn admin_htpasswd(&self) -> Option<&Htpasswd> {
+        self.admin_htpasswd.as_ref()
+    }
+
+    /// Also accept listeners and sources with tokens that `jwt` validates
+    pub fn set_jwt(&mut self, jwt: Option<JwtVerifier>) {
+        self.jwt = jwt;
+    }
+
+    /// The verifier of JSON Web Tokens, if any
+    pub fn jwt(&self) -> Option<&JwtVerifier> {
+        self.jwt.as_ref()
+    }
+
+    /// Look up the country of listeners in `geoip`
+    pub fn set_geoip(&mut self, geoip: Option<GeoIp>) {
+        self.geoip = geoip;
+    }
+
+    /// The ISO 3166-1 alpha-2 code of the country of `ip`, if it is known
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        self.geoip.as_ref().and_then(|geoip| geoip.country(ip))
+    }
+
+    /// Render the status page and error pages with `templates`
+    pub fn set_templates(&mut self, templates: Option<Templates>) {
+        self.templates = templates;
+    }
+
+    /// The templates that replace the built-in pages, if any
+    pub fn templates(&self) -> Option<&Templates> {
+        self.templates.as_ref()
+    }
+
+    /// Whether `authorization` holds a JSON Web Token that grants `scope`
+    /// on mount `mount_name`
+    pub fn is_jwt_authorization(
+        &self,
+        authorization: Option<&str>,
+        scope: Scope,
+        mount_name: &str,
+    ) -> bool {
+        match (&self.jwt, authorization) {
+            (Some(jwt), Some(authorization)) => jwt.allows(authorization, scope, mount_name),
+            _ => false,
+        }
+    }
+
+    /// Reopen all log files, e.g. after they were rotated by `logrotate`
+    pub fn reopen_logs(&self) {
+        if let Some(playlist_log) = &self.playlist_log {
+            playlist_log.file().reopen();
+        }
+    }
+
+    /// Store the statistics history of all mounts in `history_db`
+    pub fn set_history_db(&mut self, history_db: Option<HistoryDb>) {
+        self.history_db = history_db;
+    }
+
+    /// Add a mount, if no mount with the same name exists yet.
+    ///
+    /// Returns the mount that was added.
+    pub fn add_mount(&self, mount_name: String, mut mount: Mount) -> Option<Arc<Mount>> {
+        if let Entry::Vacant(e) = self.mounts.entry(mount_name) {
+            mount.server_started = self.started;
+            mount.playlist_log = self.playlist_log.clone().map(|log| (e.key().clone(), log));
+            mount.events = Some((e.key().clone(), self.events.clone()));
+            self.events.emit(EventKind::MountCreated {
+                mount: e.key().clone(),
+            });
+            Some(e.insert(Arc::new(mount)).clone())
         } else {
-            false
+            None
         }
     }
 
-    pub fn find_mount(&self, mount_name: &str) -> Option<&Mount> {
-        self.mounts.get(mount_name)
+    /// Remove the mount `mount_name`, so that no new sources and
+    /// listeners can connect to it. The source and listeners that are
+    /// connected stay connected until the source disconnects.
+    pub fn remove_mount(&self, mount_name: &str) -> Option<Arc<Mount>> {
+        let (name, mount) = self.mounts.remove(mount_name)?;
+        self.events.emit(EventKind::MountRemoved { mount: name });
+        Some(mount)
+    }
+
+    /// The pool from which buffers for source data are taken
+    pub fn buffer_pool(&self) -> &Arc<BufferPool> {
+        &self.buffer_pool
     }
 
-    pub fn find_mount_mut(&mut self, mount_name: &str) -> Option<&mut Mount> {
-        self.mounts.get_mut(mount_name)
+    /// The recorded history of the statistics of all mounts
+    pub fn history(&self) -> &StatsHistory {
+        &self.history
     }
 
-    pub fn clean_disconnected_mounts(&mut self) -> usize {
-        let mut to_remove = Vec::new();
-        for (mount_name, mount) in self.mounts.iter() {
-            if !(mount.is_connected() || mount.permanent) {
-                to_remove.push(mount_name.clone());
+    /// The database in which the statistics history is stored, if any
+    pub fn history_db(&self) -> Option<&HistoryDb> {
+        self.history_db.as_ref()
+    }
+
+    /// The most recent failed connections
+    pub fn failures(&self) -> &FailureLog {
+        &self.failures
+    }
+
+    /// The subscribers connected to any mount
+    pub fn listeners(&self) -> &Arc<ConnectionCounter> {
+        &self.listeners
+    }
+
+    /// All connections, counted per remote IP address
+    pub fn ip_connections(&self) -> &Arc<IpConnections> {
+        &self.ip_connections
+    }
+
+    /// The addresses that were banned by admins
+    pub fn bans(&self) -> &Bans {
+        &self.bans
+    }
+
+    /// The last throughput estimates of disconnected listeners
+    pub fn bandwidth(&self) -> &BandwidthEstimates {
+        &self.bandwidth
+    }
+
+    /// The sessions of the connected listeners
+    pub fn sessions(&self) -> &Arc<Sessions> {
+        &self.sessions
+    }
+
+    /// The publisher of events about mounts, sources and listeners
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// The limit on the data sent to all listeners together, if any
+    pub fn egress(&self) -> Option<&EgressLimiter> {
+        self.egress.as_ref()
+    }
+
+    pub fn find_mount(&self, mount_name: &str) -> Option<Arc<Mount>> {
+        self.mounts.get(mount_name).map(|m| m.clone())
+    }
+
+    pub fn clean_disconnected_mounts(&self) -> usize {
+        let before = self.mounts.len();
+        self.mounts.retain(|name, mount| {
+            let keep = mount.is_connected() || mount.config.permanent;
+            if !keep {
+                self.events.emit(EventKind::MountRemoved {
+                    mount: name.clone(),
+                });
             }
-        }
-        for to_remove in &to_remove {
-            self.mounts.remove(to_remove);
-        }
-        to_remove.len()
+            keep
+        });
+        before - self.mounts.len()
     }
 
     pub fn get_mount_stats(&self) -> HashMap<String, Stats> {
-        let mut map = HashMap::new();
+        self.mounts
+            .iter()
+            .map(|m| (m.key().to_string(), m.stats()))
+            .collect()
+    }
 
-        for (mount_name, mount) in self.mounts.iter() {
-            let stats = mount.stats();
-            map.insert(mount_name.to_string(), stats.to_owned());
-        }
+    pub fn add_relay(&self, mount_name: String, status: Arc<Mutex<RelayStatus>>) {
+        self.relays.insert(mount_name, status);
+    }
+
+    /// Forget the relay of `mount_name`, if `status` is still its status
+    pub fn remove_relay(&self, mount_name: &str, status: &Arc<Mutex<RelayStatus>>) {
+        self.relays
+            .remove_if(mount_name, |_, current| Arc::ptr_eq(current, status));
+    }
 
-        map
+    /// The status of all relays, sorted by mount name
+    pub fn relays(&self) -> Vec<RelayStatus> {
+        let mut relays: Vec<RelayStatus> = self
+            .relays
+            .iter()
+            .map(|r| r.value().lock().unwrap().clone())
+            .collect();
+        relays.sort_by(|a, b| a.mount.cmp(&b.mount));
+        relays
     }
 
-    pub fn mounts(&self) -> impl Iterator<Item = (&String, &Mount)> {
-        self.mounts.iter()
+    /// A snapshot of all mounts that currently exist
+    pub fn mounts(&self) -> Vec<(String, Arc<Mount>)> {
+        self.mounts
+            .iter()
+            .map(|m| (m.key().clone(), m.value().clone()))
+            .collect()
     }
 
-    pub fn mounts_mut(&mut self) -> impl Iterator<Item = (&String, &mut Mount)> {
-        self.mounts.iter_mut()
+    /// All mounts that are not hidden, i.e. those that may be listed
+    /// publicly
+    pub fn visible_mounts(&self) -> Vec<(String, Arc<Mount>)> {
+        self.mounts
+            .iter()
+            .filter(|m| !m.value().is_hidden())
+            .map(|m| (m.key().clone(), m.value().clone()))
+            .collect()
     }
 }
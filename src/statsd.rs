@@ -0,0 +1,166 @@
+//! Emission of per-mount metrics to a StatsD server over UDP.
+//!
+//! Every interval, the following metrics are sent for every mount, named
+//! `<prefix>.<mount>.<metric>`:
+//!
+//! * `listeners` and `on_air`, as gauges;
+//! * `bytes_in`, `bytes_out` and `source_connects`, as counters that
+//!   contain the increase since the previous interval.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::state::{State, Stats};
+
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+
+const DEFAULT_PREFIX: &str = "peroxidecast";
+
+/// The largest payload that is sent in one datagram, so that datagrams
+/// are not fragmented on common networks
+const MAX_DATAGRAM: usize = 1432;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsdConfig {
+    /// The `host:port` of the StatsD server
+    pub address: String,
+    /// The prefix of the names of all metrics. Defaults to `peroxidecast`.
+    pub prefix: Option<String>,
+    /// The interval at which metrics are sent, in seconds. Defaults to 10
+    /// seconds.
+    pub interval_secs: Option<u64>,
+}
+
+impl StatsdConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            prefix: Some(self.prefix.unwrap_or_else(|| DEFAULT_PREFIX.to_string())),
+            interval_secs: Some(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS)),
+            ..self
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs.unwrap_or(DEFAULT_INTERVAL_SECS).max(1))
+    }
+
+    pub fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(DEFAULT_PREFIX)
+    }
+}
+
+/// Start sending the metrics of all mounts in `state`
+pub fn spawn(config: StatsdConfig, state: Arc<State>) {
+    tokio::spawn(run(config, state));
+}
+
+async fn run(config: StatsdConfig, state: Arc<State>) {
+    let mut interval = tokio::time::interval(config.interval());
+    // The statistics of every mount when they were last sent
+    let mut previous: HashMap<String, Stats> = HashMap::new();
+
+    loop {
+        interval.tick().await;
+
+        let mut lines = Vec::new();
+        let mut current = HashMap::new();
+        for (name, mount) in state.mounts() {
+            let stats = mount.stats();
+            let before = previous.get(&name);
+            let delta = |value: fn(&Stats) -> usize| {
+                value(&stats).saturating_sub(before.map(value).unwrap_or(0))
+            };
+            let metric = format!("{}.{}", config.prefix(), metric_name(&name));
+
+            lines.push(format!("{}.listeners:{}|g", metric, stats.sub_count));
+            lines.push(format!(
+                "{}.on_air:{}|g",
+                metric,
+                mount.is_connected() as u8
+            ));
+            lines.push(format!("{}.bytes_in:{}|c", metric, delta(|s| s.bytes_in)));
+            lines.push(format!("{}.bytes_out:{}|c", metric, delta(|s| s.bytes_out)));
+            lines.push(format!(
+                "{}.source_connects:{}|c",
+                metric,
+                delta(|s| s.source_connects)
+            ));
+
+            current.insert(name, stats);
+        }
+        previous = current;
+
+        if let Err(e) = send(&config.address, &lines).await {
+            warn!(
+                "Could not send metrics to StatsD at {}: {}",
+                config.address, e
+            );
+        }
+    }
+}
+
+/// The name of a mount as a component of a metric name, which may not
+/// contain `.`, `:` or `|`
+fn metric_name(mount: &str) -> String {
+    let name: String = mount
+        .trim_start_matches('/')
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if name.is_empty() {
+        "root".to_string()
+    } else {
+        name
+    }
+}
+
+/// Send `lines` to `address`, in as few datagrams as possible
+async fn send(address: &str, lines: &[String]) -> std::io::Result<()> {
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+    let bind: SocketAddr = if target.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+
+    let mut datagram = String::new();
+    let mut datagrams = 0;
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM {
+            socket.send_to(datagram.as_bytes(), target).await?;
+            datagrams += 1;
+            datagram.clear();
+        }
+        if !datagram.is_empty() {
+            datagram.push('\n');
+        }
+        datagram.push_str(line);
+    }
+    if !datagram.is_empty() {
+        socket.send_to(datagram.as_bytes(), target).await?;
+        datagrams += 1;
+    }
+
+    debug!(
+        "Sent {} metrics to StatsD in {} datagrams",
+        lines.len(),
+        datagrams
+    );
+    Ok(())
+}
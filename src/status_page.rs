@@ -0,0 +1,152 @@
+//! The HTML status page served under `/status`, which lists the mounts
+//! that are not hidden with their listeners, current song and a link to
+//! play them.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+
+use crate::state::{Mount, State};
+
+/// A mount as shown on the status page
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusMount {
+    pub name: String,
+    /// The name that the source gave the stream, or else the name of the
+    /// mount
+    pub title: String,
+    pub description: Option<String>,
+    pub genre: Option<String>,
+    /// The website of the stream
+    pub url: Option<String>,
+    pub content_type: String,
+    /// The bitrate of the stream, in kbit/s
+    pub bitrate: Option<u32>,
+    pub on_air: bool,
+    pub listeners: usize,
+    pub max_listeners: Option<usize>,
+    pub song: Option<String>,
+    /// The URL at which the stream can be played
+    pub stream_url: String,
+}
+
+/// The data shown on the status page
+#[derive(Debug, Clone, Serialize)]
+pub struct StatusPage {
+    pub mounts: Vec<StatusMount>,
+}
+
+impl StatusPage {
+    /// Collect the mounts that are not hidden, ordered by name.
+    /// `stream_url` determines the URL at which a mount can be played.
+    pub fn from_state(state: &State, stream_url: impl Fn(&str, &Mount) -> String) -> Self {
+        let mut mounts: Vec<StatusMount> = state
+            .visible_mounts()
+            .into_iter()
+            .map(|(name, mount)| {
+                let metadata = mount.metadata();
+                StatusMount {
+                    title: metadata.name().unwrap_or(&name).to_string(),
+                    description: metadata.description().map(String::from),
+                    genre: metadata.genre().map(String::from),
+                    url: metadata.url().map(String::from),
+                    content_type: mount.content_type(),
+                    bitrate: mount.bitrate(),
+                    on_air: mount.is_connected(),
+                    listeners: mount.stats().sub_count,
+                    max_listeners: mount.max_listeners(),
+                    song: mount.song(),
+                    stream_url: stream_url(&name, &mount),
+                    name,
+                }
+            })
+            .collect();
+        mounts.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self { mounts }
+    }
+
+    /// Render the built-in page
+    pub fn to_html(&self) -> String {
+        let mut html = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+             <title>Status</title>\n<style>\n\
+             body { font-family: sans-serif; max-width: 48em; margin: 2em auto; padding: 0 1em; }\n\
+             .mount { border-bottom: 1px solid #ccc; padding: 1em 0; }\n\
+             .mount h2 { margin: 0 0 0.25em; }\n\
+             .details { color: #666; }\n\
+             audio { width: 100%; margin-top: 0.5em; }\n\
+             </style>\n</head>\n<body>\n<h1>Status</h1>\n",
+        );
+
+        if self.mounts.is_empty() {
+            html.push_str("<p>There are no streams.</p>\n");
+        }
+
+        for mount in &self.mounts {
+            let _ = writeln!(html, "<div class=\"mount\">");
+            let _ = writeln!(html, "<h2>{}</h2>", escape_xml(&mount.title));
+            if let Some(description) = &mount.description {
+                let _ = writeln!(html, "<p>{}</p>", escape_xml(description));
+            }
+
+            let mut details = vec![escape_xml(&mount.name)];
+            if !mount.on_air {
+                details.push("Off air".to_string());
+            }
+            details.push(match mount.max_listeners {
+                Some(max) => format!("{} of {} listeners", mount.listeners, max),
+                None if mount.listeners == 1 => "1 listener".to_string(),
+                None => format!("{} listeners", mount.listeners),
+            });
+            if let Some(genre) = &mount.genre {
+                details.push(escape_xml(genre));
+            }
+            if let Some(bitrate) = mount.bitrate {
+                details.push(format!("{} kbit/s", bitrate));
+            }
+            let _ = writeln!(
+                html,
+                "<p class=\"details\">{}</p>",
+                details.join(" &middot; ")
+            );
+
+            if let Some(song) = &mount.song {
+                let _ = writeln!(html, "<p>Now playing: {}</p>", escape_xml(song));
+            }
+            // The URL is sent by the source, so only web links are linked
+            if let Some(url) = &mount.url {
+                let escaped = escape_xml(url);
+                if url.starts_with("http://") || url.starts_with("https://") {
+                    let _ = writeln!(html, "<p><a href=\"{}\">{}</a></p>", escaped, escaped);
+                } else {
+                    let _ = writeln!(html, "<p>{}</p>", escaped);
+                }
+            }
+
+            if mount.on_air {
+                let link = escape_xml(&mount.name);
+                let _ = writeln!(
+                    html,
+                    "<audio controls preload=\"none\" src=\"{}\"></audio>\n<p><a href=\"{}\">Play</a></p>",
+                    link, link
+                );
+            }
+            let _ = writeln!(html, "</div>");
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+/// Escape `value` for use in XML and HTML text and attribute values
+pub(crate) fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -0,0 +1,112 @@
+//! Integration with systemd: socket activation and service notifications.
+//!
+//! With socket activation, systemd binds the listening socket and passes
+//! it to the server (`LISTEN_PID`/`LISTEN_FDS`). With `Type=notify`, the
+//! server tells systemd when it is ready to accept connections, and keeps
+//! sending keep-alive messages if the unit sets `WatchdogSec=`.
+//!
+//! When the listening socket is handed over to a new process (see
+//! [`crate::handover`]), that process becomes the main process of the
+//! service, which requires `NotifyAccess=all` in the unit.
+
+use std::{io, net::TcpListener, time::Duration};
+
+/// The first file descriptor passed by systemd
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening socket that was passed by systemd, if any.
+///
+/// Only the first passed socket is used.
+#[cfg(unix)]
+pub fn activated_listener() -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let fds: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    if pid != std::process::id() || fds < 1 {
+        return None;
+    }
+    if fds > 1 {
+        tracing::warn!("systemd passed {} sockets, only the first one is used", fds);
+    }
+
+    // Safety: systemd passed this descriptor to us for exactly this
+    // purpose, and it is not used anywhere else
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) };
+    socket2::SockRef::from(&listener).set_cloexec(true).ok()?;
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+pub fn activated_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Send `state` to the service manager, if the server was started with
+/// `NOTIFY_SOCKET` set.
+///
+/// See `sd_notify(3)` for the possible states.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let socket = UnixDatagram::unbound()?;
+    match path.to_str().and_then(|path| path.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<()> {
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, if the service manager expects it
+pub fn watchdog_interval() -> Option<Duration> {
+    // `WATCHDOG_PID` is not checked: after a socket handover, the new
+    // process takes over the watchdog as the service's main process
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    // Notify twice per timeout, so a single late message does not get
+    // the server killed
+    Some(Duration::from_micros(usec) / 2).filter(|interval| !interval.is_zero())
+}
+
+/// Tell the service manager that the server is ready, and keep sending
+/// watchdog notifications for as long as the runtime is alive
+pub fn spawn() {
+    if let Err(e) = notify("READY=1") {
+        tracing::warn!("Could not notify systemd: {}", e);
+    }
+
+    if let Some(interval) = watchdog_interval() {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = notify("WATCHDOG=1") {
+                    tracing::warn!("Could not notify the systemd watchdog: {}", e);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,299 @@
+//! Taps that replicate the data and metadata of a mount to a message
+//! broker, so that other services can consume a stream without
+//! connecting to Peroxidecast as a listener.
+//!
+//! Only the small subset of the NATS and MQTT (3.1.1, QoS 0) protocols
+//! that is required to publish messages is implemented.
+
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::broadcast::{error::RecvError, Receiver as BroadcastReceiver},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
+
+use crate::state::{Chunk, DataReceiver, DataSender, MetadataEvent, Mount};
+
+/// The minimum time between two attempts to connect to a broker
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Broker {
+    Nats,
+    Mqtt,
+}
+
+/// A tap that publishes the data and/or metadata of a mount to a broker.
+///
+/// Data is published to `<topic>.data` (NATS) or `<topic>/data` (MQTT),
+/// and metadata events are published as JSON to `<topic>.metadata` or
+/// `<topic>/metadata`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TapConfig {
+    pub broker: Broker,
+    /// The address of the broker, e.g. `127.0.0.1:4222`
+    pub address: String,
+    pub topic: String,
+    /// Publish the chunks of data sent by the source
+    #[serde(default = "default_true")]
+    pub data: bool,
+    /// Publish the metadata of the mount whenever it changes
+    #[serde(default = "default_true")]
+    pub metadata: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl TapConfig {
+    fn subject(&self, kind: &str) -> String {
+        match self.broker {
+            Broker::Nats => format!("{}.{}", self.topic, kind),
+            Broker::Mqtt => format!("{}/{}", self.topic, kind),
+        }
+    }
+}
+
+/// Start all taps of `mount`. The taps stop once the source that
+/// sends to `data_tx` disconnects.
+pub fn spawn(mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender) {
+    for tap in mount.taps() {
+        let tap = Tap {
+            config: tap.clone(),
+            mount_name: mount_name.to_string(),
+            connection: None,
+            last_attempt: None,
+        };
+        tokio::spawn(tap.run(
+            mount.clone(),
+            data_tx.subscribe(),
+            mount.subscribe_metadata(),
+        ));
+    }
+}
+
+enum Event {
+    Data(Result<Chunk, RecvError>),
+    Metadata(Option<Box<MetadataEvent>>),
+    /// The amount of bytes received from the broker
+    Incoming(usize),
+}
+
+struct Tap {
+    config: TapConfig,
+    mount_name: String,
+    connection: Option<Connection>,
+    last_attempt: Option<Instant>,
+}
+
+impl Tap {
+    async fn run(
+        mut self,
+        mount: Arc<Mount>,
+        mut data_rx: DataReceiver,
+        mut metadata_rx: BroadcastReceiver<MetadataEvent>,
+    ) {
+        info!(
+            "Starting {:?} tap of mount {} to {}",
+            self.config.broker, self.mount_name, self.config.address
+        );
+
+        if self.config.metadata {
+            self.publish_metadata(&mount.metadata_event()).await;
+        }
+
+        loop {
+            let mut inbox = Vec::new();
+            let incoming = async {
+                match &mut self.connection {
+                    Some(connection) => connection.read.read_buf(&mut inbox).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let event = tokio::select! {
+                data = data_rx.recv() => Event::Data(data),
+                event = metadata_rx.recv() => Event::Metadata(event.ok().map(Box::new)),
+                read = incoming => Event::Incoming(read.unwrap_or(0)),
+            };
+
+            match event {
+                Event::Data(Ok(chunk)) => {
+                    if self.config.data {
+                        let subject = self.config.subject("data");
+                        self.publish(&subject, &chunk).await;
+                    }
+                }
+                Event::Data(Err(RecvError::Lagged(missed))) => {
+                    warn!(
+                        "Tap of mount {} to {} skipped {} chunks",
+                        self.mount_name, self.config.address, missed
+                    );
+                }
+                Event::Data(Err(RecvError::Closed)) => break,
+                Event::Metadata(Some(event)) => {
+                    if self.config.metadata {
+                        self.publish_metadata(&event).await;
+                    }
+                }
+                Event::Metadata(None) => {}
+                Event::Incoming(0) => {
+                    warn!("Broker {} closed the connection", self.config.address);
+                    self.connection = None;
+                }
+                Event::Incoming(_) => {
+                    if let Some(connection) = &mut self.connection {
+                        if connection.handle_incoming(&inbox).await.is_err() {
+                            self.connection = None;
+                        }
+                    }
+                }
+            }
+        }
+
+        info!(
+            "Stopped tap of mount {} to {}",
+            self.mount_name, self.config.address
+        );
+    }
+
+    async fn publish_metadata(&mut self, event: &MetadataEvent) {
+        let payload = serde_json::to_vec(event).expect("Metadata can always be serialized");
+        let subject = self.config.subject("metadata");
+        self.publish(&subject, &payload).await;
+    }
+
+    /// Publish `payload`, (re)connecting to the broker if required.
+    ///
+    /// Messages are dropped while the broker cannot be reached.
+    async fn publish(&mut self, subject: &str, payload: &[u8]) {
+        if self.connection.is_none() {
+            let may_connect = self
+                .last_attempt
+                .map(|t| t.elapsed() >= RECONNECT_INTERVAL)
+                .unwrap_or(true);
+            if !may_connect {
+                return;
+            }
+
+            self.last_attempt = Some(Instant::now());
+            match Connection::connect(self.config.broker, &self.config.address).await {
+                Ok(connection) => {
+                    debug!("Connected to broker {}", self.config.address);
+                    self.connection = Some(connection);
+                }
+                Err(e) => {
+                    warn!("Could not connect to broker {}: {}", self.config.address, e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(connection) = &mut self.connection {
+            if let Err(e) = connection.publish(subject, payload).await {
+                warn!("Publishing to broker {} failed: {}", self.config.address, e);
+                self.connection = None;
+            }
+        }
+    }
+}
+
+struct Connection {
+    broker: Broker,
+    read: OwnedReadHalf,
+    write: OwnedWriteHalf,
+}
+
+impl Connection {
+    async fn connect(broker: Broker, address: &str) -> std::io::Result<Self> {
+        let (read, mut write) = TcpStream::connect(address).await?.into_split();
+
+        match broker {
+            Broker::Nats => {
+                write
+                    .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"peroxidecast\"}\r\n")
+                    .await?;
+            }
+            Broker::Mqtt => {
+                let mut body = Vec::new();
+                mqtt_string(&mut body, b"MQTT");
+                // Protocol level 4 (3.1.1), clean session, keepalive disabled
+                body.extend_from_slice(&[4, 0x02, 0, 0]);
+                mqtt_string(&mut body, b"peroxidecast");
+                write.write_all(&mqtt_packet(0x10, &body)).await?;
+            }
+        }
+
+        Ok(Self {
+            broker,
+            read,
+            write,
+        })
+    }
+
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> std::io::Result<()> {
+        match self.broker {
+            Broker::Nats => {
+                let mut message = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+                message.extend_from_slice(payload);
+                message.extend_from_slice(b"\r\n");
+                self.write.write_all(&message).await
+            }
+            Broker::Mqtt => {
+                let mut body = Vec::with_capacity(subject.len() + payload.len() + 2);
+                mqtt_string(&mut body, subject.as_bytes());
+                body.extend_from_slice(payload);
+                self.write.write_all(&mqtt_packet(0x30, &body)).await
+            }
+        }
+    }
+
+    /// Respond to data sent by the broker. NATS servers disconnect
+    /// clients that do not answer their pings, while MQTT brokers only
+    /// send acknowledgements that can be ignored.
+    async fn handle_incoming(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if self.broker == Broker::Nats {
+            let text = String::from_utf8_lossy(data);
+            for line in text.lines() {
+                if line.starts_with("PING") {
+                    self.write.write_all(b"PONG\r\n").await?;
+                } else if line.starts_with("-ERR") {
+                    warn!("NATS error: {}", line);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn mqtt_string(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+fn mqtt_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
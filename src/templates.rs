@@ -0,0 +1,92 @@
+//! Templates that operators can supply to replace the built-in status
+//! page and error pages, e.g. to match the branding of their station.
+//!
+//! Templates use the Jinja syntax (as implemented by `minijinja`), and
+//! values are HTML-escaped unless they are marked `|safe`. The status
+//! template receives the [`StatusPage`] (`mounts`), the error template
+//! an [`ErrorPage`] (`status`, `reason` and `path`).
+
+use std::path::PathBuf;
+
+use minijinja::{value::Serde, Environment, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::status_page::StatusPage;
+
+/// The names under which the templates are added, which end in `.html`
+/// so that values are escaped
+const STATUS: &str = "status.html";
+const ERROR: &str = "error.html";
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TemplateConfig {
+    /// The template of the status page at `/status`
+    pub status: Option<PathBuf>,
+    /// The template of the pages that are shown to browsers when a
+    /// request fails, e.g. for a mount that does not exist
+    pub error: Option<PathBuf>,
+}
+
+/// The data shown on an error page
+#[derive(Debug, Clone, Serialize)]
+pub struct ErrorPage {
+    /// The HTTP status code
+    pub status: u16,
+    /// The reason phrase of the status code, e.g. "Not Found"
+    pub reason: String,
+    /// The path that was requested
+    pub path: String,
+}
+
+/// Whether a client with the `Accept` header `accept` wants an HTML page,
+/// as opposed to e.g. a media player or a script
+pub fn accepts_html(accept: Option<&str>) -> bool {
+    accept.is_some_and(|accept| {
+        accept.split(',').any(|range| {
+            let media_type = range.split(';').next().unwrap_or("").trim();
+            media_type.eq_ignore_ascii_case("text/html")
+        })
+    })
+}
+
+/// The templates that were loaded on startup
+#[derive(Debug)]
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    /// Read and compile the templates described by `config`
+    pub fn load(config: &TemplateConfig) -> Result<Self, String> {
+        let mut env = Environment::new();
+        for (name, path) in [(STATUS, &config.status), (ERROR, &config.error)] {
+            let Some(path) = path else {
+                continue;
+            };
+            let source = std::fs::read_to_string(path)
+                .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+            env.add_template_owned(name, source)
+                .map_err(|e| format!("Invalid template {}: {}", path.display(), e))?;
+        }
+
+        Ok(Self { env })
+    }
+
+    /// Render the status page, if there is a template for it
+    pub fn render_status(&self, page: &StatusPage) -> Option<String> {
+        self.render(STATUS, Value::from(Serde(page)))
+    }
+
+    /// Render an error page, if there is a template for it
+    pub fn render_error(&self, page: &ErrorPage) -> Option<String> {
+        self.render(ERROR, Value::from(Serde(page)))
+    }
+
+    fn render(&self, name: &str, context: Value) -> Option<String> {
+        let template = self.env.get_template(name).ok()?;
+        template
+            .render(context)
+            .map_err(|e| tracing::warn!("Could not render template {}: {}", name, e))
+            .ok()
+    }
+}
@@ -0,0 +1,214 @@
+//! Live captioning of mounts through an external speech-to-text service.
+//!
+//! The audio of a mount is cut into segments, which are sent to the
+//! service as the body of a `POST` request with the content type of the
+//! mount. The service responds with either a JSON object containing a
+//! `text` field, or with the plain text of the transcript.
+//!
+//! Captions are published on a paired mount (`<mount>/captions` by
+//! default) as a `text/event-stream`, so that they can be consumed with
+//! an `EventSource` as well as by regular data mount subscribers.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::MountConfig,
+    net,
+    pool::DEFAULT_CHUNK_SIZE,
+    state::{DataSender, IceMeta, Mount, State},
+};
+
+/// The amount of segments that may be queued for transcription before
+/// new segments are dropped
+const SEGMENT_QUEUE: usize = 4;
+
+/// The amount of captions that may be queued for a subscriber
+const CAPTION_QUEUE: usize = 64;
+
+const DEFAULT_SEGMENT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TranscriptionConfig {
+    /// The `http://` URL of the speech-to-text service
+    pub url: String,
+    /// The length of the segments sent to the service, in seconds.
+    /// Defaults to 5 seconds.
+    pub segment_secs: Option<u64>,
+    /// The mount on which captions are published. Defaults to
+    /// `<mount>/captions`.
+    pub caption_mount: Option<String>,
+}
+
+impl TranscriptionConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            segment_secs: Some(self.segment_secs.unwrap_or(DEFAULT_SEGMENT_SECS)),
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TranscriptResponse {
+    text: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Caption<'a> {
+    text: &'a str,
+    time: String,
+}
+
+/// Start transcribing `mount`, if it is configured to be transcribed.
+/// Transcription stops once the source that sends to `data_tx`
+/// disconnects.
+pub fn spawn(state: &State, mount_name: &str, mount: &Arc<Mount>, data_tx: &DataSender) {
+    let config = if let Some(config) = mount.transcription() {
+        config.clone()
+    } else {
+        return;
+    };
+
+    let caption_name = config
+        .caption_mount
+        .clone()
+        .unwrap_or_else(|| format!("{}/captions", mount_name));
+    let content_type = "text/event-stream".to_string();
+    let (caption_tx, _) = broadcast::channel(CAPTION_QUEUE);
+
+    let caption_mount = if let Some(caption_mount) = state.find_mount(&caption_name) {
+        if !caption_mount.try_set_source(caption_tx.downgrade(), content_type, IceMeta::default()) {
+            warn!(
+                "Not transcribing mount {}: caption mount {} already has a source",
+                mount_name, caption_name
+            );
+            return;
+        }
+        caption_mount
+    } else {
+        let caption_mount = Mount::new(
+            content_type,
+            caption_tx.downgrade(),
+            IceMeta::default(),
+            MountConfig::default(),
+        );
+        if let Some(caption_mount) = state.add_mount(caption_name.clone(), caption_mount) {
+            caption_mount.stats_handle().add_source_connect();
+            caption_mount
+        } else {
+            warn!(
+                "Not transcribing mount {}: could not create caption mount {}",
+                mount_name, caption_name
+            );
+            return;
+        }
+    };
+
+    info!(
+        "Transcribing mount {} to caption mount {}",
+        mount_name, caption_name
+    );
+
+    let segment_length = Duration::from_secs(config.segment_secs.unwrap_or(DEFAULT_SEGMENT_SECS));
+    let (segment_tx, mut segment_rx) = mpsc::channel::<Vec<u8>>(SEGMENT_QUEUE);
+    let mut data_rx = data_tx.subscribe();
+    let name = mount_name.to_string();
+
+    tokio::spawn(async move {
+        let mut segment = Vec::new();
+        let mut segment_start = Instant::now();
+
+        loop {
+            match data_rx.recv().await {
+                Ok(chunk) => segment.extend_from_slice(&chunk),
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("Transcription of {} skipped {} chunks", name, missed);
+                }
+                Err(RecvError::Closed) => break,
+            }
+
+            if segment_start.elapsed() >= segment_length {
+                segment_start = Instant::now();
+                if segment_tx.try_send(std::mem::take(&mut segment)).is_err() {
+                    warn!(
+                        "Dropping a segment of mount {}: the transcription service is not keeping up",
+                        name
+                    );
+                }
+            }
+        }
+
+        if !segment.is_empty() {
+            segment_tx.send(segment).await.ok();
+        }
+    });
+
+    let content_type = mount.content_type();
+    let buffer_pool = state.buffer_pool().clone();
+    let name = mount_name.to_string();
+
+    tokio::spawn(async move {
+        // Keeps the caption mount connected for as long as segments are
+        // being transcribed
+        let caption_tx = caption_tx;
+
+        while let Some(segment) = segment_rx.recv().await {
+            let response = match net::post(&config.url, &content_type, &segment).await {
+                Ok(response) if response.is_success() => response,
+                Ok(response) => {
+                    warn!(
+                        "Transcription service responded to a segment of mount {} with status {}",
+                        name, response.status
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not send a segment of mount {} to the transcription service: {}",
+                        name, e
+                    );
+                    continue;
+                }
+            };
+
+            let text = serde_json::from_slice::<TranscriptResponse>(&response.body)
+                .map(|r| r.text)
+                .unwrap_or_else(|_| String::from_utf8_lossy(&response.body).to_string());
+            let text = text.trim();
+            if text.is_empty() {
+                continue;
+            }
+
+            let caption = Caption {
+                text,
+                time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            };
+            let event = format!(
+                "event: caption\ndata: {}\n\n",
+                serde_json::to_string(&caption).expect("Captions can always be serialized")
+            );
+
+            let mut buffer = buffer_pool.get(DEFAULT_CHUNK_SIZE);
+            buffer.extend_from_slice(event.as_bytes());
+            caption_mount.stats_handle().add_bytes_in(buffer.len());
+
+            // Sending only fails if there are no subscribers at all,
+            // which is fine.
+            caption_tx.send(Arc::new(buffer)).ok();
+        }
+
+        info!("Stopped transcribing mount {}", name);
+    });
+}
@@ -0,0 +1,242 @@
+//! Listener authentication by an external HTTP endpoint, like Icecast's
+//! `url` authentication.
+//!
+//! Whenever a listener connects, a `listener_add` request is `POST`ed to
+//! the endpoint as a form with the fields `action`, `mount`, `user`,
+//! `pass`, `ip` and `agent`. The listener is allowed if the endpoint
+//! responds with a `2xx` status and the header `icecast-auth-user: 1`.
+//!
+//! Decisions are cached per mount, IP address, `User-Agent` and
+//! credentials, so that reconnecting players do not hit the endpoint for
+//! every connection.
+//!
+//! As the form carries the passwords of listeners, the endpoint must be
+//! reached over `https://`, unless `insecure_http` is set.
+
+use std::{io::ErrorKind, net::IpAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::{
+    auth::{self, DecisionCache},
+    net,
+};
+
+const DEFAULT_CACHE_SECS: u64 = 60;
+const DEFAULT_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UrlAuthConfig {
+    /// The `https://` URL of the endpoint that decides whether a listener
+    /// is allowed
+    pub url: String,
+    /// Also allow an `http://` URL. The user names and passwords of
+    /// listeners are then sent in plain text, so this is only safe if the
+    /// endpoint runs on the same host or a trusted network.
+    #[serde(default)]
+    pub insecure_http: bool,
+    /// How long a decision is remembered, in seconds. Defaults to 60
+    /// seconds.
+    pub cache_secs: Option<u64>,
+    /// How long the endpoint may take to decide, in seconds. Defaults to
+    /// 5 seconds.
+    pub timeout_secs: Option<u64>,
+    /// Allow listeners if the endpoint cannot be reached, instead of
+    /// rejecting them.
+    ///
+    /// This admits everyone, including listeners that the endpoint would
+    /// reject, whenever the endpoint is down or can be made unreachable,
+    /// so it should only be used for mounts where availability matters
+    /// more than access control. Every such admission is logged as a
+    /// warning.
+    #[serde(default)]
+    pub fail_open: bool,
+}
+
+impl UrlAuthConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            cache_secs: Some(self.cache_secs.unwrap_or(DEFAULT_CACHE_SECS)),
+            timeout_secs: Some(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            ..self
+        }
+    }
+
+    pub fn cache(&self) -> Duration {
+        Duration::from_secs(self.cache_secs.unwrap_or(DEFAULT_CACHE_SECS))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// Why credentials may not be sent to `url`, if they may not
+    pub fn url_error(&self) -> Option<String> {
+        if self.url.starts_with("https://")
+            || (self.insecure_http && self.url.starts_with("http://"))
+        {
+            None
+        } else if self.url.starts_with("http://") {
+            Some(format!(
+                "url {:?} does not use `https://`, and insecure_http is not set",
+                self.url
+            ))
+        } else {
+            Some(format!("url {:?} does not start with `https://`", self.url))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    remote_ip: IpAddr,
+    user_agent: Option<String>,
+    authorization: Option<String>,
+}
+
+/// The listener authentication of a mount
+#[derive(Debug)]
+pub struct UrlAuth {
+    config: UrlAuthConfig,
+    cache: DecisionCache<CacheKey>,
+}
+
+impl UrlAuth {
+    pub fn new(config: UrlAuthConfig) -> Self {
+        Self {
+            cache: DecisionCache::new(config.cache()),
+            config,
+        }
+    }
+
+    /// Ask the endpoint whether a listener may subscribe to mount
+    /// `mount_name`, or use a cached decision
+    pub async fn allows(
+        &self,
+        mount_name: &str,
+        remote_ip: IpAddr,
+        user_agent: Option<&str>,
+        authorization: Option<&str>,
+    ) -> bool {
+        let key = CacheKey {
+            remote_ip,
+            user_agent: user_agent.map(String::from),
+            authorization: authorization.map(String::from),
+        };
+
+        if let Some(allowed) = self.cache.get(&key) {
+            return allowed;
+        }
+
+        // Never fail open here, as the endpoint is not down but misconfigured
+        if let Some(e) = self.config.url_error() {
+            warn!(
+                "Rejecting {} for mount {}: auth_url {}",
+                remote_ip, mount_name, e
+            );
+            return false;
+        }
+
+        let (user, pass) = authorization
+            .and_then(auth::basic_credentials)
+            .unwrap_or_default();
+        let ip = remote_ip.to_string();
+        let form = [
+            ("action", "listener_add"),
+            ("mount", mount_name),
+            ("user", user.as_str()),
+            ("pass", pass.as_str()),
+            ("ip", ip.as_str()),
+            ("agent", user_agent.unwrap_or_default()),
+        ];
+        let body = form
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let post = net::post(
+            &self.config.url,
+            "application/x-www-form-urlencoded",
+            body.as_bytes(),
+        );
+        let response = tokio::time::timeout(self.config.timeout(), post)
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+
+        let allowed = match response {
+            Ok(response) => {
+                let allowed = response.is_success()
+                    && response.header("icecast-auth-user").map(str::trim) == Some("1");
+                if !allowed {
+                    debug!(
+                        "{} denied {} access to mount {}: {}",
+                        self.config.url,
+                        remote_ip,
+                        mount_name,
+                        response
+                            .header("icecast-auth-message")
+                            .unwrap_or("no reason given")
+                    );
+                }
+                allowed
+            }
+            Err(e) => {
+                // Errors are not cached, so that listeners get in once
+                // the endpoint recovers
+                warn!(
+                    "Could not authenticate {} for mount {} at {}: {}",
+                    remote_ip, mount_name, self.config.url, e
+                );
+                if self.config.fail_open {
+                    warn!(
+                        "Admitting {} to mount {} WITHOUT authentication, because fail_open is set",
+                        remote_ip, mount_name
+                    );
+                }
+                return self.config.fail_open;
+            }
+        };
+
+        self.cache.insert(key, allowed);
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(url: &str, insecure_http: bool) -> UrlAuthConfig {
+        UrlAuthConfig {
+            url: url.to_string(),
+            insecure_http,
+            ..UrlAuthConfig::default()
+        }
+    }
+
+    #[test]
+    fn credentials_are_only_sent_over_https() {
+        assert_eq!(config("https://auth.example.com/", false).url_error(), None);
+        assert_eq!(config("http://127.0.0.1:8080/", true).url_error(), None);
+        assert!(config("http://auth.example.com/", false)
+            .url_error()
+            .is_some());
+        assert!(config("auth.example.com", true).url_error().is_some());
+    }
+
+    #[tokio::test]
+    async fn insecure_urls_are_rejected_even_when_failing_open() {
+        let auth = UrlAuth::new(UrlAuthConfig {
+            fail_open: true,
+            ..config("http://127.0.0.1:9/", false)
+        });
+        let allowed = auth
+            .allows("/live", IpAddr::from([127, 0, 0, 1]), None, None)
+            .await;
+        assert!(!allowed);
+    }
+}
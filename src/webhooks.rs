@@ -0,0 +1,119 @@
+//! Delivery of [events](crate::events) to HTTP endpoints, e.g. to post
+//! alerts to a chat or to update the now-playing information of a
+//! website.
+//!
+//! Every event is sent as a JSON object in a `POST` request. Failed
+//! deliveries are retried with exponential backoff. Events are delivered
+//! to each webhook in order, so an endpoint that keeps failing delays the
+//! events after it, and events are dropped if too many of them pile up.
+
+use std::{io::ErrorKind, sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tracing::{debug, warn};
+
+use crate::{events::Event, net, state::State};
+
+const DEFAULT_RETRIES: u32 = 3;
+
+/// The delay before the first retry, which doubles for every next retry
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How long a single delivery may take before it counts as failed
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// The `http://` or `https://` URL to which events are sent, e.g.
+    /// the webhook URL of a Discord or Slack channel
+    pub url: String,
+    /// The names of the events to send, e.g. `source_connected`. All
+    /// events are sent if this is empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// How often a failed delivery is retried. Defaults to 3.
+    pub retries: Option<u32>,
+}
+
+impl WebhookConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            retries: Some(self.retries.unwrap_or(DEFAULT_RETRIES)),
+            ..self
+        }
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event.kind.name())
+    }
+}
+
+/// Start delivering the events of `state` to `config.url`
+pub fn spawn(config: WebhookConfig, state: Arc<State>) {
+    let events = state.events().subscribe();
+    tokio::spawn(run(config, events));
+}
+
+async fn run(config: WebhookConfig, mut events: Receiver<Event>) {
+    loop {
+        match events.recv().await {
+            Ok(event) if config.wants(&event) => deliver(&config, &event).await,
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => {
+                warn!(
+                    "Webhook {} fell behind, dropped {} events",
+                    config.url, missed
+                )
+            }
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+async fn deliver(config: &WebhookConfig, event: &Event) {
+    let body = serde_json::to_vec(event).expect("Events can always be serialized");
+    let retries = config.retries.unwrap_or(DEFAULT_RETRIES);
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        let post = net::post(&config.url, "application/json", &body);
+        let result = tokio::time::timeout(DELIVERY_TIMEOUT, post)
+            .await
+            .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()));
+        match result {
+            Ok(response) if response.is_success() => {
+                debug!("Delivered {} to webhook {}", event.kind.name(), config.url);
+                return;
+            }
+            Ok(response) => debug!(
+                "Webhook {} answered {} with status {}",
+                config.url,
+                event.kind.name(),
+                response.status
+            ),
+            Err(e) => debug!(
+                "Could not deliver {} to webhook {}: {}",
+                event.kind.name(),
+                config.url,
+                e
+            ),
+        }
+    }
+
+    warn!(
+        "Giving up on delivering {} to webhook {} after {} attempts",
+        event.kind.name(),
+        config.url,
+        retries + 1
+    );
+}
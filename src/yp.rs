@@ -0,0 +1,265 @@
+//! Listing public mounts in YP directories like <https://dir.xiph.org>,
+//! using the protocol of Icecast.
+//!
+//! A mount is listed with an `add` request once its source connects,
+//! after which the directory hands out a listing ID (`SID`) and the
+//! interval at which the listing must be refreshed. Every refresh
+//! (`touch`) reports the current song and listener count, and the
+//! listing is removed with a `remove` request once the source
+//! disconnects or the mount is no longer public.
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    net::{self, HttpResponse},
+    state::{Mount, State, StreamUrl},
+};
+
+const DEFAULT_TIMEOUT_SECS: u64 = 15;
+
+/// The interval at which listings are refreshed if the directory does
+/// not specify one
+const DEFAULT_TOUCH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The shortest refresh interval, whatever the directory asks for
+const MIN_TOUCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before trying to list a mount again after a
+/// directory rejected it
+const RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// The interval at which mounts are checked for changes that must be
+/// reported to the directories
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct YpConfig {
+    /// The URLs of the directories, e.g.
+    /// `http://dir.xiph.org/cgi-bin/yp-cgi`
+    pub directories: Vec<String>,
+    /// The URL at which this server is reachable by listeners, e.g.
+    /// `http://radio.example.com:8000`. The name of a mount is appended
+    /// to this to get its listen URL, unless the mount has a static
+    /// stream URL.
+    pub public_url: String,
+    /// How long requests to a directory may take, in seconds. Defaults
+    /// to 15 seconds.
+    pub timeout_secs: Option<u64>,
+}
+
+impl YpConfig {
+    /// This configuration with the settings that are not set replaced
+    /// by their defaults
+    pub fn with_defaults(self) -> Self {
+        Self {
+            timeout_secs: Some(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            ..self
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// The URL at which listeners can reach `mount`
+    fn listen_url(&self, mount_name: &str, mount: &Mount) -> String {
+        match mount.stream_url() {
+            Some(StreamUrl::Static(url)) => url.clone(),
+            _ => format!("{}{}", self.public_url.trim_end_matches('/'), mount_name),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Listing {
+    sid: String,
+    touch_interval: Duration,
+    last_touch: Instant,
+}
+
+/// Start listing the public mounts of `state` in all directories
+pub fn spawn(config: YpConfig, state: Arc<State>) {
+    for directory in config.directories.clone() {
+        tokio::spawn(run(config.clone(), directory, state.clone()));
+    }
+}
+
+async fn run(config: YpConfig, directory: String, state: Arc<State>) {
+    let mut listings: HashMap<String, Listing> = HashMap::new();
+    // Mounts that the directory rejected, and when that happened
+    let mut rejected: HashMap<String, Instant> = HashMap::new();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let public: HashMap<String, Arc<Mount>> = state
+            .mounts()
+            .into_iter()
+            .filter(|(_, mount)| mount.is_connected() && mount.is_public())
+            .collect();
+
+        // Mounts that went away or are no longer public
+        let gone: Vec<String> = listings
+            .keys()
+            .filter(|name| !public.contains_key(*name))
+            .cloned()
+            .collect();
+        for name in gone {
+            if let Some(listing) = listings.remove(&name) {
+                let form = [("action", "remove"), ("sid", listing.sid.as_str())];
+                match send(&config, &directory, &form).await {
+                    Ok(_) => info!("Removed mount {} from {}", name, directory),
+                    Err(e) => warn!("Could not remove mount {} from {}: {}", name, directory, e),
+                }
+            }
+        }
+        rejected.retain(|name, at| public.contains_key(name) && at.elapsed() < RETRY_DELAY);
+
+        for (name, mount) in &public {
+            match listings.get_mut(name) {
+                Some(listing) if listing.last_touch.elapsed() >= listing.touch_interval => {
+                    match touch(&config, &directory, listing, mount).await {
+                        Ok(()) => listing.last_touch = Instant::now(),
+                        Err(e) => {
+                            // The listing may have expired, so add it anew
+                            warn!("Could not refresh mount {} at {}: {}", name, directory, e);
+                            listings.remove(name);
+                        }
+                    }
+                }
+                Some(_) => {}
+                None if rejected.contains_key(name) => {}
+                None => match add(&config, &directory, name, mount).await {
+                    Ok(listing) => {
+                        info!("Listed mount {} at {}", name, directory);
+                        listings.insert(name.clone(), listing);
+                    }
+                    Err(e) => {
+                        warn!("Could not list mount {} at {}: {}", name, directory, e);
+                        rejected.insert(name.clone(), Instant::now());
+                    }
+                },
+            }
+        }
+    }
+}
+
+async fn add(
+    config: &YpConfig,
+    directory: &str,
+    mount_name: &str,
+    mount: &Mount,
+) -> std::io::Result<Listing> {
+    let meta = mount.metadata();
+    let listen_url = config.listen_url(mount_name, mount);
+    let content_type = mount.content_type();
+    let bitrate = mount.bitrate().map(|b| b.to_string()).unwrap_or_default();
+
+    let form = [
+        ("action", "add"),
+        ("sn", meta.name().unwrap_or(mount_name)),
+        ("genre", meta.genre().unwrap_or_default()),
+        ("cpswd", ""),
+        ("desc", meta.description().unwrap_or_default()),
+        ("url", meta.url().unwrap_or_default()),
+        ("listenurl", listen_url.as_str()),
+        ("type", content_type.as_str()),
+        ("stype", ""),
+        ("b", bitrate.as_str()),
+    ];
+    let response = send(config, directory, &form).await?;
+
+    let sid = response
+        .header("SID")
+        .filter(|sid| !sid.is_empty())
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidData, "no SID in response"))?;
+    let touch_interval = response
+        .header("TouchFreq")
+        .and_then(|f| f.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_TOUCH_INTERVAL)
+        .max(MIN_TOUCH_INTERVAL);
+    debug!(
+        "Mount {} has SID {} at {}, refreshing every {:?}",
+        mount_name, sid, directory, touch_interval
+    );
+
+    Ok(Listing {
+        sid: sid.trim().to_string(),
+        touch_interval,
+        last_touch: Instant::now(),
+    })
+}
+
+async fn touch(
+    config: &YpConfig,
+    directory: &str,
+    listing: &Listing,
+    mount: &Mount,
+) -> std::io::Result<()> {
+    let song = mount.song().unwrap_or_default();
+    let listeners = mount.stats().sub_count.to_string();
+    let max_listeners = mount
+        .max_listeners()
+        .map(|m| m.to_string())
+        .unwrap_or_default();
+
+    let form = [
+        ("action", "touch"),
+        ("sid", listing.sid.as_str()),
+        ("st", song.as_str()),
+        ("listeners", listeners.as_str()),
+        ("max_listeners", max_listeners.as_str()),
+        ("stype", ""),
+    ];
+    send(config, directory, &form).await.map(|_| ())
+}
+
+/// Send `form` to `directory`, failing if the directory does not accept it
+async fn send(
+    config: &YpConfig,
+    directory: &str,
+    form: &[(&str, &str)],
+) -> std::io::Result<HttpResponse> {
+    let body = form
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let post = net::post(
+        directory,
+        "application/x-www-form-urlencoded",
+        body.as_bytes(),
+    );
+    let response = tokio::time::timeout(config.timeout(), post)
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))?;
+
+    if !response.is_success() {
+        return Err(std::io::Error::other(format!(
+            "directory responded with status {}",
+            response.status
+        )));
+    }
+    // Directories answer with `YPResponse: 1` if they accepted the
+    // request, and explain why not in `YPMessage` otherwise
+    if response.header("YPResponse").map(str::trim) != Some("1") {
+        let message = response.header("YPMessage").unwrap_or("no reason given");
+        return Err(std::io::Error::other(format!(
+            "directory refused: {}",
+            message
+        )));
+    }
+
+    Ok(response)
+}